@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, Criterion};
-use kv_store::KVS;
+use kvs_engine::KVS;
 use std::sync::{Arc, Barrier};
 use std::thread;
 use rand::Rng;
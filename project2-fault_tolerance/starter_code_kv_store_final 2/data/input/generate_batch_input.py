@@ -0,0 +1,42 @@
+import random
+
+def generate_trace_files(file_paths, num_keys, operation_ratio, max_batch_size, num_operations_per_file):
+
+    # Initialize the value counter for 'put' and 'mput' operations
+    put_value_counter = 1
+
+    # Generate the operations based on the given ratios
+    names = ['get', 'put', 'delete', 'mget', 'mput', 'mdelete']
+    operations = [name for name, ratio in zip(names, operation_ratio) for _ in range(ratio)]
+
+    # Generate trace files, every client has keys of its own
+    num_keys_per_client = num_keys // len(file_paths)
+    for i, file_path in enumerate(file_paths):
+        key_set = [f"key_{i}" for i in range(num_keys_per_client * i, min(num_keys_per_client * (i + 1), num_keys))]
+        with open(file_path, "w") as f:
+            for _ in range(num_operations_per_file):
+                operation = random.choice(operations)
+                if operation in ('mget', 'mput', 'mdelete'):
+                    keys = random.sample(key_set, random.randint(2, max_batch_size))
+                else:
+                    keys = [random.choice(key_set)]
+
+                # Writes include a value after every key
+                words = [operation]
+                for key in keys:
+                    words.append(key)
+                    if operation in ('put', 'mput'):
+                        words.append(str(put_value_counter))
+                        put_value_counter += 1
+                f.write(" ".join(words) + "\n")
+
+if __name__ == '__main__':
+    num_files = 3
+    file_path = './batch'  # Directory to store trace files
+    num_keys = 48
+    # get, put, delete, mget, mput, mdelete
+    operation_ratio = [3, 2, 1, 2, 2, 1]
+    max_batch_size = 6
+    num_operations_per_file = 2000
+
+    generate_trace_files([file_path + str(i + 2) + ".txt" for i in range(num_files)], num_keys, operation_ratio, max_batch_size, num_operations_per_file)
@@ -0,0 +1,203 @@
+use crate::kvs::{Operation, Pid};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+// Admission control between a server's network thread and its workers (see
+// bin/server.rs).
+//
+// Every worker takes its operations from a FairQueue. Operations from other
+// servers and the controllers (replication, heartbeats, raft) go in a lane of
+// their own that is never refused and always served first, since the
+// requests already admitted wait for them. Client requests wait in a queue
+// per client, served in turns, one request of every client with something
+// queued before the second of any, so a client that sends a lot only makes
+// itself wait. A request another server passed on (Forward, RaftForward)
+// queues as one of the client it is for. A client gets at most
+// client_capacity requests queued and all clients together capacity; a
+// request over either is answered right away with Busy(seq_no,
+// retry_after_ms) to its client, and the client sends it again after a
+// jittered backoff (see client.rs). The requests a worker parks for a key
+// with a write or read in flight are held to the same capacities (see
+// server.rs).
+//
+// Usage:
+// let queue = WorkerQueue::new(capacity, client_capacity);
+// match admit(&queue, pid, operation) {     // network thread
+//     Ok(()) => {}
+//     Err((client_pid, busy)) => send(client_pid, busy),
+// }
+// if let Some((pid, operation)) = queue.pop(timeout) { node.step(pid, operation) }   // worker
+
+// Client requests a worker queues at most, over every client
+pub const QUEUE_CAPACITY: usize = 1024;
+// Requests of one client a worker queues at most
+pub const CLIENT_QUEUE_CAPACITY: usize = 64;
+// How long a refused client waits at least before it sends again
+pub const BUSY_RETRY_AFTER_MS: u64 = 10;
+
+type Message = (Pid, Operation);
+
+// The seq_no of a client request, none for every other operation
+pub fn client_seq_no(operation: &Operation) -> Option<usize> {
+    match operation {
+        Operation::Get(_, seq_no)
+        | Operation::Put(_, _, seq_no)
+        | Operation::Delete(_, seq_no)
+        | Operation::MultiGet(_, seq_no)
+        | Operation::MultiPut(_, seq_no)
+        | Operation::MultiDelete(_, seq_no)
+        | Operation::Cas(.., seq_no)
+        | Operation::PutIfAbsent(.., seq_no)
+        | Operation::Incr(.., seq_no)
+        | Operation::TxnCommit(.., seq_no)
+        | Operation::ReadReplica(_, seq_no, _) => Some(*seq_no),
+        Operation::Session(request) => client_seq_no(request),
+        _ => None,
+    }
+}
+
+// The client an operation received from pid is a request of, with its
+// seq_no: pid itself, or the client of a request another server passed on
+fn client_request(pid: Pid, operation: &Operation) -> Option<(Pid, usize)> {
+    match operation {
+        Operation::Forward(client_pid, request) | Operation::RaftForward(client_pid, request) => {
+            client_seq_no(request).map(|seq_no| (*client_pid, seq_no))
+        }
+        operation => client_seq_no(operation).map(|seq_no| (pid, seq_no)),
+    }
+}
+
+// Bounded per-client queues served in turns, behind a lane of control
+// messages that is not bounded
+pub struct FairQueue<T> {
+    capacity: usize,
+    client_capacity: usize,
+    control: VecDeque<T>,
+    clients: HashMap<Pid, VecDeque<T>>,
+    turns: VecDeque<Pid>, // the clients with requests queued, the next to serve first
+    queued: usize,        // client requests over every client
+}
+
+impl<T> FairQueue<T> {
+    pub fn new(capacity: usize, client_capacity: usize) -> Self {
+        FairQueue {
+            capacity: capacity.max(1),
+            client_capacity: client_capacity.max(1),
+            control: VecDeque::new(),
+            clients: HashMap::new(),
+            turns: VecDeque::new(),
+            queued: 0,
+        }
+    }
+
+    pub fn push_control(&mut self, item: T) {
+        self.control.push_back(item);
+    }
+
+    // Queues a request of client_pid, or hands it back if the client or the
+    // queue is full
+    pub fn push_client(&mut self, client_pid: Pid, item: T) -> Result<(), T> {
+        let client_queued = self.clients.get(&client_pid).map_or(0, |queue| queue.len());
+        if client_queued >= self.client_capacity || self.queued >= self.capacity {
+            return Err(item);
+        }
+        let queue = self.clients.entry(client_pid).or_default();
+        if queue.is_empty() {
+            self.turns.push_back(client_pid);
+        }
+        queue.push_back(item);
+        self.queued += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if let Some(item) = self.control.pop_front() {
+            return Some(item);
+        }
+        let client_pid = self.turns.pop_front()?;
+        let queue = self.clients.get_mut(&client_pid).unwrap();
+        let item = queue.pop_front().unwrap();
+        if queue.is_empty() {
+            self.clients.remove(&client_pid);
+        } else {
+            self.turns.push_back(client_pid);
+        }
+        self.queued -= 1;
+        Some(item)
+    }
+
+    // Client requests queued
+    pub fn queued(&self) -> usize {
+        self.queued
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.control.is_empty() && self.queued == 0
+    }
+}
+
+// The FairQueue of a worker, shared with the network thread
+pub struct WorkerQueue {
+    queue: Mutex<FairQueue<Message>>,
+    ready: Condvar,
+    busy: Mutex<u64>, // requests refused since the server started
+}
+
+impl WorkerQueue {
+    pub fn new(capacity: usize, client_capacity: usize) -> Self {
+        WorkerQueue {
+            queue: Mutex::new(FairQueue::new(capacity, client_capacity)),
+            ready: Condvar::new(),
+            busy: Mutex::new(0),
+        }
+    }
+
+    pub fn push_control(&self, message: Message) {
+        self.queue.lock().unwrap().push_control(message);
+        self.ready.notify_one();
+    }
+
+    // Whether the request of client_pid was queued, it is counted as refused
+    // if not
+    pub fn push_client(&self, client_pid: Pid, message: Message) -> bool {
+        let pushed = self.queue.lock().unwrap().push_client(client_pid, message).is_ok();
+        match pushed {
+            true => self.ready.notify_one(),
+            false => *self.busy.lock().unwrap() += 1,
+        }
+        pushed
+    }
+
+    // The next message, waiting up to timeout for one
+    pub fn pop(&self, timeout: Duration) -> Option<Message> {
+        let queue = self.queue.lock().unwrap();
+        let (mut queue, _) = self.ready.wait_timeout_while(queue, timeout, |queue| queue.is_empty()).unwrap();
+        queue.pop()
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queue.lock().unwrap().queued()
+    }
+
+    pub fn busy(&self) -> u64 {
+        *self.busy.lock().unwrap()
+    }
+}
+
+// Queues an operation the network thread received from pid. A client request
+// that does not fit comes back as the Busy to answer it with and the client
+// to send it to.
+#[allow(clippy::result_large_err)]
+pub fn admit(queue: &WorkerQueue, pid: Pid, operation: Operation) -> Result<(), (Pid, Operation)> {
+    match client_request(pid, &operation) {
+        Some((client_pid, seq_no)) => match queue.push_client(client_pid, (pid, operation)) {
+            true => Ok(()),
+            false => Err((client_pid, Operation::Busy(seq_no, BUSY_RETRY_AFTER_MS))),
+        },
+        None => {
+            queue.push_control((pid, operation));
+            Ok(())
+        }
+    }
+}
@@ -1,10 +1,16 @@
-use kv_store::kvs::{get_shard_id_from_key, KVSResult, Operation, Pid, ShardId, ShardInfo};
-use kv_store::network::{create_network_context, recv, send, NetworkContext}; // Assuming network.rs is in the same crate
+use kv_store::cli::{
+    parse_pid_list, take_faults, take_flag, take_membership, take_option, take_read_preference, take_transport,
+    take_wire_format, take_wire_version,
+};
+use kv_store::client::{KvClient, KvError, ReadPreference};
+use kv_store::kvs::{KVSResult, Operation, Pid};
+use kv_store::lease::{Clock, SystemClock};
+use kv_store::network::{set_allow_fault_control, set_default_faults, set_default_membership, set_default_transport, set_default_wire_format, set_default_wire_version}; // Assuming network.rs is in the same crate
+use kv_store::shell::Shell;
 use std::env;
 use std::fs::File;
-use std::io::{self, BufRead, Error, ErrorKind, Write};
+use std::io::{self, BufRead, Error, IsTerminal, Write};
 use std::path::Path;
-use std::time::{SystemTime, SystemTimeError};
 
 fn write_result_to_file(result_filename: &str, results: Vec<KVSResult>) -> Result<(), Error> {
     let mut file = File::create(result_filename)?;
@@ -25,141 +31,100 @@ fn write_result_to_file(result_filename: &str, results: Vec<KVSResult>) -> Resul
     Ok(())
 }
 
-fn get_shard_info(
-    ctx: &mut NetworkContext,
-    controller_pid: &Pid,
-    shard_id: Option<ShardId>,
-) -> Result<ShardInfo, Error> {
-    // TODO: This function should send a GetShardInfo to the controller
-    // wait for its reply, and returns the contained GetShardInfoRes
-
-    match send(ctx, controller_pid, &Operation::GetShardInfo()) {
-        Ok(_) => {},
-        Err(x) => {
-            return Err(x);
-        },
-    };
+// Replays the operations through a KvClient, with up to window of them in
+// flight at once.
+#[allow(clippy::too_many_arguments)]
+fn run_client(
+    self_pid: Pid,
+    controller_pids: Vec<Pid>,
+    _server_pids: Vec<Pid>,
+    operations: Vec<Operation>,
+    read_preference: ReadPreference,
+    max_staleness_ms: Option<u64>,
+    hot_key_reads: bool,
+    window: usize,
+) -> Result<Vec<KVSResult>, KvError> {
+    KvClient::new(self_pid, controller_pids)?
+        .with_read_preference(read_preference, max_staleness_ms)
+        .with_hot_key_reads(hot_key_reads)
+        .with_window(window)
+        // a failover takes as long as it takes, the trace waits for it
+        .with_timeout(None)
+        .replay(operations)
+}
 
-    match recv(ctx, 10000) {
-        Ok(msg) => {
-            for (_id, operation) in msg.clone() {
-                match operation {
-                    Operation::GetShardInfoRes(shard_info) => {
-                        return Ok(shard_info);
-                    }
-                    _ => {},
-                }
+// Reads commands from stdin instead of replaying a trace (see shell.rs), with
+// a prompt when stdin is a terminal
+fn run_shell(
+    args: &[String],
+    read_preference: ReadPreference,
+    max_staleness_ms: Option<u64>,
+    hot_key_reads: bool,
+    window: usize,
+    history_file: Option<String>,
+) {
+    let (self_pid, controller_pids) = match (args.get(1).map(|pid| pid.parse()), args.get(2).map(|pids| parse_pid_list(pids))) {
+        (Some(Ok(self_pid)), Some(Ok(controller_pids))) => (self_pid, controller_pids),
+        _ => {
+            eprintln!("Usage: client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
+            return;
+        }
+    };
+    let client = match KvClient::new(self_pid, controller_pids.clone()) {
+        Ok(client) => client
+            .with_read_preference(read_preference, max_staleness_ms)
+            .with_hot_key_reads(hot_key_reads)
+            .with_window(window),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let mut shell = Shell::new(client, controller_pids);
+    if let Some(path) = history_file {
+        shell = match shell.with_history_file(&path) {
+            Ok(shell) => shell,
+            Err(e) => {
+                eprintln!("Failed to open history file {}: {}", path, e);
+                return;
             }
-            
-            return Err(Error::new(ErrorKind::Other, 
-                format!("Unexpected messages came through! msg={:?}", msg.clone())
-            ));
-        },
-        Err(x) => {
-            return Err(x);
-        },
+        };
+    }
+    let stdin = io::stdin();
+    let interactive = stdin.is_terminal();
+    if let Err(e) = shell.run(stdin.lock(), &mut io::stdout(), interactive) {
+        eprintln!("{}", e);
     }
 }
 
-fn get_timestamp() -> Result<u128, SystemTimeError> {
-    let now = SystemTime::now();
-    match now.duration_since(SystemTime::UNIX_EPOCH) {
-        Ok(duration) => Ok(duration.as_nanos()),
-        Err(e) => Err(e),
+// A value of a trace line, - for none
+fn parse_optional(word: &str) -> Option<String> {
+    match word {
+        "-" => None,
+        word => Some(word.to_string()),
     }
 }
 
-fn run_client(
-    self_pid: Pid,
-    controller_pid: Pid,
-    _server_pids: Vec<Pid>,
-    operations: Vec<Operation>,
-) -> Result<Vec<KVSResult>, Error> {
-    let mut ctx = create_network_context(&self_pid).unwrap();
-    let mut shard_info = get_shard_info(&mut ctx, &controller_pid, None).unwrap();
-
-    let mut operation_index = 0;
-    let num_operations = operations.len();
-    let mut results = Vec::new();
-    while operation_index < num_operations {
-        let operation = &operations[operation_index];
-        let key;
-        let mut put_value = "";
-        let shard_id = match operation {
-            Operation::Get(key_, _) => {
-                key = key_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
-            }
-            Operation::Put(key_, value_, _) => {
-                key = key_;
-                put_value = value_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
+fn parse_txn_writes(mut words: &[&str]) -> Option<Vec<(String, Option<String>)>> {
+    let mut writes = Vec::new();
+    while !words.is_empty() {
+        words = match words {
+            ["put", key, value, rest @ ..] => {
+                writes.push((key.to_string(), Some(value.to_string())));
+                rest
             }
-            Operation::Delete(key_, _) => {
-                key = key_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
+            ["delete", key, rest @ ..] => {
+                writes.push((key.to_string(), None));
+                rest
             }
-            _ => todo!(),
+            _ => return None,
         };
-        let primary_server_pid = shard_info.locations[&shard_id].primary;
-        let begin_time = get_timestamp().unwrap();
-        if let Err(e) = send(&mut ctx, &primary_server_pid, operation) {
-            println!("Client failed to send: {:?}", e);
-        } else {
-            match recv(&mut ctx, 1000) {
-                Ok(messages) => {
-                    let end_time = get_timestamp().unwrap();
-                    let (_, result_operation) = &messages[0];
-                    match result_operation {
-                        Operation::GetRes(observed_value, _) => results.push(KVSResult {
-                            operation: "get".to_string(),
-                            key: key.clone(),
-                            observed_value: match observed_value {
-                                Some(val) => val.clone(),
-                                None => "".to_string(),
-                            },
-                            new_value: "".to_string(),
-                            begin_time: begin_time,
-                            end_time: end_time,
-                        }),
-                        Operation::PutRes(observed_value, _) => results.push(KVSResult {
-                            operation: "put".to_string(),
-                            key: key.clone(),
-                            observed_value: match observed_value {
-                                Some(val) => val.clone(),
-                                None => "".to_string(),
-                            },
-                            new_value: put_value.to_string(),
-                            begin_time: begin_time,
-                            end_time: end_time,
-                        }),
-                        Operation::DeleteRes(observed_value, _) => results.push(KVSResult {
-                            operation: "delete".to_string(),
-                            key: key.clone(),
-                            observed_value: match observed_value {
-                                Some(val) => val.clone(),
-                                None => "".to_string(),
-                            },
-                            new_value: "".to_string(),
-                            begin_time: begin_time,
-                            end_time: end_time,
-                        }),
-                        _ => todo!(),
-                    }
-                    operation_index += 1;
-                }
-                Err(_) => {
-                    println!("Client recv timeout, retry...");
-                    // the server might have failed, so we update the shard info
-                    shard_info = get_shard_info(&mut ctx, &controller_pid, None).unwrap();
-                }
-            }
-        }
     }
-    Ok(results)
+    Some(writes)
 }
 
-fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error> {
+// The requests of the file, numbered from first_seq_no on in file order
+fn parse_operations_from_file(file_path: &str, first_seq_no: usize) -> Result<Vec<Operation>, Error> {
     let path = Path::new(file_path);
     let file = File::open(&path)?;
     let reader = io::BufReader::new(file);
@@ -167,17 +132,46 @@ fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error>
 
     for (index, line) in reader.lines().enumerate() {
         let line = line?;
+        let seq_no = first_seq_no + index;
         let parts: Vec<&str> = line.split_whitespace().collect();
         match parts.as_slice() {
             ["get", key] => {
-                operations.push(Operation::Get(key.to_string(), index));
+                operations.push(Operation::Get(key.to_string(), seq_no));
             }
             ["put", key, value] => {
-                operations.push(Operation::Put(key.to_string(), value.to_string(), index));
+                operations.push(Operation::Put(key.to_string(), value.to_string(), seq_no));
             }
             ["delete", key] => {
-                operations.push(Operation::Delete(key.to_string(), index));
+                operations.push(Operation::Delete(key.to_string(), seq_no));
+            }
+            // batches: mget <key>..., mput <key> <value>... and mdelete <key>...
+            ["mget", keys @ ..] if !keys.is_empty() => {
+                operations.push(Operation::MultiGet(keys.iter().map(|key| key.to_string()).collect(), seq_no));
+            }
+            ["mput", entries @ ..] if !entries.is_empty() && entries.len() % 2 == 0 => {
+                let entries = entries.chunks(2).map(|entry| (entry[0].to_string(), entry[1].to_string())).collect();
+                operations.push(Operation::MultiPut(entries, seq_no));
             }
+            ["mdelete", keys @ ..] if !keys.is_empty() => {
+                operations.push(Operation::MultiDelete(keys.iter().map(|key| key.to_string()).collect(), seq_no));
+            }
+            // conditional writes: cas <key> <expected> <new> (- for no value),
+            // putifabsent <key> <value> and incr <key> <delta>
+            ["cas", key, expected, new] => {
+                operations.push(Operation::Cas(key.to_string(), parse_optional(expected), parse_optional(new), seq_no));
+            }
+            ["putifabsent", key, value] => {
+                operations.push(Operation::PutIfAbsent(key.to_string(), value.to_string(), seq_no));
+            }
+            ["incr", key, delta] if delta.parse::<i64>().is_ok() => {
+                operations.push(Operation::Incr(key.to_string(), delta.parse().unwrap(), seq_no));
+            }
+            // transactions: txn put <key> <value> delete <key> ..., the writes
+            // are applied together or not at all
+            ["txn", steps @ ..] if !steps.is_empty() => match parse_txn_writes(steps) {
+                Some(writes) => operations.push(Operation::TxnCommit(Vec::new(), writes, seq_no)),
+                None => eprintln!("Skipping invalid line {}: {:?}", index + 1, parts),
+            },
             _ => {
                 eprintln!("Skipping invalid line {}: {:?}", index + 1, parts);
             }
@@ -188,25 +182,105 @@ fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error>
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let transport = take_transport(&mut args);
+    let wire_format = take_wire_format(&mut args);
+    let wire_version = take_wire_version(&mut args);
+    let membership = take_membership(&mut args);
+    let faults = take_faults(&mut args);
+    let allow_fault_control = take_flag(&mut args, "--allow-fault-control");
+    let read_preference = take_read_preference(&mut args);
+    let max_staleness_ms = take_option(&mut args, "--max-staleness-ms");
+    let hot_key_reads = take_flag(&mut args, "--hot-key-reads");
+    let window = take_option(&mut args, "--window");
+    let shell = take_flag(&mut args, "--shell");
+    let history_file = take_option(&mut args, "--history");
+
+    match transport {
+        Ok(kind) => set_default_transport(kind),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match wire_format {
+        Ok(format) => set_default_wire_format(format),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match wire_version {
+        Ok(version) => set_default_wire_version(version),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match membership {
+        Ok(membership) => set_default_membership(membership),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match faults {
+        Ok(Some(faults)) => set_default_faults(faults),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    set_allow_fault_control(allow_fault_control);
+
+    let read_preference = match read_preference {
+        Ok(read_preference) => read_preference,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+    let max_staleness_ms: Option<u64> = match max_staleness_ms.map(|ms| ms.parse()) {
+        None => None,
+        Some(Ok(ms)) => Some(ms),
+        Some(Err(_)) => {
+            eprintln!("--max-staleness-ms must be a number of milliseconds");
+            return;
+        }
+    };
+    let window: usize = match window.map(|n| n.parse()) {
+        None => 1,
+        Some(Ok(n)) if n > 0 => n,
+        _ => {
+            eprintln!("--window must be a positive number of requests");
+            return;
+        }
+    };
+
+    if shell {
+        run_shell(&args, read_preference, max_staleness_ms, hot_key_reads, window, history_file);
+        return;
+    }
+
 
     if args.len() < 6 {
-        eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+        eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
         return;
     }
 
     let self_pid: Pid = match args[1].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
             return;
         }
     };
 
-    let controller_pid: Pid = match args[2].parse() {
+    let controller_pids: Vec<Pid> = match parse_pid_list(&args[2]) {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
             return;
         }
     };
@@ -214,7 +288,7 @@ fn main() {
     let operation_file_dir: String = match args[3].parse() {
         Ok(path) => path,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
             return;
         }
     };
@@ -222,7 +296,7 @@ fn main() {
     let result_dir: String = match args[4].parse() {
         Ok(path) => path,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
             return;
         }
     };
@@ -232,13 +306,18 @@ fn main() {
     let server_pids = match server_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--read-preference <primary|nearest|any>] [--max-staleness-ms <ms>] [--hot-key-reads] [--window <n>] <self_pid> <controller_pid_list> <operation_file_dir> <result_dir> <server_pid_list>\n       client [options] --shell [--history <file>] <self_pid> <controller_pid_list>");
             return;
         }
     };
 
     let operation_filename = operation_file_dir + &self_pid.to_string() + ".txt";
-    let operations = match parse_operations_from_file(&operation_filename) {
+    // Numbered from the time in microseconds on, like KvClient does, so a
+    // second run under the same pid mostly does not look like late copies of
+    // the first run's writes to the servers, which answer those Stale (see
+    // server.rs)
+    let first_seq_no = (SystemClock::new().timestamp_ns() / 1000) as usize;
+    let operations = match parse_operations_from_file(&operation_filename, first_seq_no) {
         Ok(ops) => ops,
         Err(e) => {
             println!("Failed to parse operations: {:?}", e);
@@ -246,7 +325,17 @@ fn main() {
         }
     };
 
-    let results = run_client(self_pid, controller_pid, server_pids, operations).unwrap();
+    let results = run_client(
+        self_pid,
+        controller_pids,
+        server_pids,
+        operations,
+        read_preference,
+        max_staleness_ms,
+        hot_key_reads,
+        window,
+    )
+    .unwrap();
     let result_filename = result_dir + &self_pid.to_string() + ".txt";
     write_result_to_file(&result_filename, results).unwrap();
 }
@@ -1,162 +1,171 @@
-use kv_store::kvs::{Operation, Pid, ShardId, ShardInfo, ShardLoc};
-use kv_store::network::{create_network_context, recv, send}; // Assuming network.rs is in the same crate
-use std::collections::HashSet;
+use kv_store::cli::{parse_pid_list, take_flag, take_option, take_faults, take_membership, take_transport, take_wire_format, take_wire_version};
+use kv_store::controller::{
+    assign_shards_to_servers, ControllerNode, CONTROLLER_TICK_MS, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR,
+};
+use kv_store::kvs::{Pid, ReplicationMode, ShardInfo};
+use kv_store::lease::SystemClock;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::network::{create_network_context, recv, send, set_allow_fault_control, set_default_faults, set_default_membership, set_default_transport, set_default_wire_format, set_default_wire_version, NetworkContext}; // Assuming network.rs is in the same crate
 use std::env;
 use std::io::{Error, ErrorKind};
 use std::sync::{Arc, Mutex};
-use std::thread::{self, sleep};
-use std::time::Duration;
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn assign_shards_to_servers(
-    shard_info: &Arc<Mutex<ShardInfo>>,
-    num_shards: usize,
-    server_pids: &Vec<Pid>,
+// Sends what the node queued, each message from the pid it is meant to come from
+fn send_messages(
+    node: &Arc<Mutex<ControllerNode<SystemClock>>>,
+    ctx_clients: &mut NetworkContext,
+    ctx_servers: &mut NetworkContext,
+    controller_pid_for_clients: Pid,
 ) {
-    // TODO: assign one primary for each shard in round-robin manner
-    // for each shard, assign all remaining servers as secondaries
-    // you should update the shard_info argument in place
-    let mut info = shard_info.lock().unwrap();
-    info.locations.clear();
-    
-    for i in 0..num_shards {
-        let primary = server_pids[i];
-
-        let mut secondaries = server_pids.clone();
-        secondaries.remove(i);
-
-        info.locations.insert(
-            i as u32, ShardLoc { 
-                primary, 
-                secondaries
-            }
-        );
+    let messages = node.lock().unwrap().take_messages();
+    for (src_pid, dst_pid, operation) in messages {
+        let ctx = if src_pid == controller_pid_for_clients { &mut *ctx_clients } else { &mut *ctx_servers };
+        if send(ctx, &dst_pid, &operation).is_err() {
+            println!("Failed to send {:?} from controller to id={}", operation, dst_pid);
+        }
     }
-
-    println!("Shard Assignments: {:?}", info.locations);
 }
 
+// The controller's logic lives in ControllerNode (controller.rs). One thread
+// listens on each of its two pids and feeds what arrives to the node, the
+// first one also ticks it.
+#[allow(clippy::too_many_arguments)]
 fn run_controller(
     controller_pid_for_clients: Pid,
     controller_pid_for_servers: Pid,
     _client_pids: Vec<Pid>,
     server_pids: Vec<Pid>,
+    replication: ReplicationMode,
+    num_shards: usize,
+    replication_factor: usize,
+    vnodes: usize,
+    hot_key_rps: u64,
+    peers: Vec<Pid>,
+    raft_dir: String,
 ) {
-    const PERIOD: Duration = Duration::new(5, 0);
-
-    let shard_info: Arc<Mutex<ShardInfo>> = Arc::new(Mutex::new(ShardInfo::new()));
-    assign_shards_to_servers(&shard_info, server_pids.len(), &server_pids);
-
-    // Create references for shard_info to be used by threads
-    let shard_info_1: Arc<Mutex<ShardInfo>> = Arc::clone(&shard_info);
-    let shard_info_2 = Arc::clone(&shard_info);
-
-    // TODO: implement the shardinfo_server thread.
-    // it should first create a network context with create_network_context using controller_pid_for_client
-    // it should then use recv to poll GetShardInfo from clients, and reply to clients using send
-    // with a GetShardInfoRes, which contains the up-to-date shard info
-    let shardinfo_server = thread::spawn(move || {
-        let mut ctx = create_network_context(&controller_pid_for_clients).unwrap();
-
-        loop {
-            match recv(&mut ctx, 10000) {
-                Ok(msg) => {
-                    for (id, operation) in msg {
-                        match operation {
-                            Operation::GetShardInfo() => {
-                                let info = shard_info_1.lock().unwrap().clone();
-                                let message = Operation::GetShardInfoRes(info);
-                                
-                                let res = send(&mut ctx, &id, &message);
-                                match res {
-                                    Ok(_) => {},
-                                    Err(_) => {
-                                        println!("Failed to send GetShardInfoRes from controller to id={}", id);
-                                    },
-                                }
-                            },
-                            _ => {
-                                println!("(shardinfo_server) Unexpected operation received at controller, id={}, op={:?}", 
-                                                id, operation);
-                            }
-                        }
+    let mut info = ShardInfo::new();
+    info.replication = replication;
+    assign_shards_to_servers(&mut info, &server_pids, num_shards, replication_factor, vnodes);
+    let node = Arc::new(Mutex::new(ControllerNode::new(
+        controller_pid_for_clients,
+        controller_pid_for_servers,
+        server_pids,
+        info,
+        peers,
+        SystemClock::new(),
+        rand::random(),
+        Some(raft_dir),
+    )
+    .with_hot_key_threshold(hot_key_rps)));
+    let ctx_clients = create_network_context(&controller_pid_for_clients).unwrap();
+    let ctx_servers = create_network_context(&controller_pid_for_servers).unwrap();
+
+    let mut threads = Vec::new();
+    for listen_pid in [controller_pid_for_clients, controller_pid_for_servers] {
+        let node = Arc::clone(&node);
+        let mut ctx_clients = ctx_clients.clone();
+        let mut ctx_servers = ctx_servers.clone();
+        threads.push(thread::spawn(move || {
+            let ticks = listen_pid == controller_pid_for_clients;
+            let tick = Duration::from_millis(CONTROLLER_TICK_MS);
+            let mut last_tick = Instant::now();
+            loop {
+                if ticks && last_tick.elapsed() >= tick {
+                    last_tick = Instant::now();
+                    node.lock().unwrap().tick();
+                }
+                let ctx = if ticks { &mut ctx_clients } else { &mut ctx_servers };
+                if let Ok(messages) = recv(ctx, CONTROLLER_TICK_MS) {
+                    let mut node = node.lock().unwrap();
+                    for (id, operation) in messages {
+                        node.step(id, operation);
                     }
-                },
-                Err(_) => {},
-            }
-        }
-    });
-
-    // TODO: implement server_monitor thread.
-    // it should first create a network context with create_network_context using controller_pid_for_server
-    // it should then periodically send a PutShardInfo to each server and use recv to wait for their response (PutShardInfoRes)
-    // if you want to do part C, this also serves as a heartbeat to detect server failures
-    let server_monitor = thread::spawn(move || {
-        let mut ctx = create_network_context(&controller_pid_for_servers).unwrap();
-
-        loop {
-            // Phase 1: Sending PutShardInfo
-            for id in &server_pids {
-                let info = shard_info_2.clone().lock().unwrap().clone();
-                let message = Operation::PutShardInfo(info);
-
-                let res = send(&mut ctx, id, &message);
-                match res {
-                    Ok(_) => {},
-                    Err(_) => {
-                        println!("Failed to send PutShardInfo from controller to id={}", id);
-                    },
                 }
+                send_messages(&node, &mut ctx_clients, &mut ctx_servers, controller_pid_for_clients);
             }
+        }));
+    }
+    for handle in threads {
+        handle.join().unwrap();
+    }
+}
 
-            // Phase 2: Collecting PutShardInfoRes
-            let mut servers_pending = server_pids.clone();
-            servers_pending.sort();
-
-            while !servers_pending.is_empty() {
-                match recv(&mut ctx, 2000) {
-                    Ok(msg) => {
-                        for (id, operation) in msg {
-                            match operation {
-                                Operation::PutShardInfoRes() => {
-                                    let index = servers_pending.binary_search(&id).unwrap();
-                                    let removed = servers_pending.remove(index);
-                                    assert_eq!(removed, id);
-                                },
-                                _ => {
-                                    println!("(server_monitor) Unexpected operation received at controller, id={}, op={:?}", 
-                                                id, operation);
-                                },
-                            }
-                        }
-                    },
-                    Err(_) => {
-                       println!("Timeout! Pending IDs: {:?}", servers_pending); 
-                       break;
-                    },
-                }
-            }
+// Picks the replication mode from the optional flags. `--raft` runs every
+// shard as a raft group; giving either quorum flag switches the cluster to
+// quorum replication, the other one defaults to a majority of the replicas.
+fn parse_replication_mode(
+    raft: bool,
+    write_quorum: Option<String>,
+    read_quorum: Option<String>,
+    num_replicas: usize,
+) -> Result<ReplicationMode, Error> {
+    if raft {
+        if write_quorum.is_some() || read_quorum.is_some() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "--raft can not be combined with quorum sizes",
+            ));
+        }
+        return Ok(ReplicationMode::Raft);
+    }
+    if write_quorum.is_none() && read_quorum.is_none() {
+        return Ok(ReplicationMode::PrimaryBackup);
+    }
 
-            // Phase 3: Wait for next iteration
-            sleep(PERIOD);
+    let majority = num_replicas / 2 + 1;
+    let parse = |value: Option<String>| -> Result<usize, Error> {
+        match value {
+            None => Ok(majority),
+            Some(v) => match v.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= num_replicas => Ok(n),
+                _ => Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("quorum size must be between 1 and {}, got {}", num_replicas, v),
+                )),
+            },
         }
-    });
+    };
+    let write = parse(write_quorum)?;
+    let read = parse(read_quorum)?;
 
-    shardinfo_server.join().unwrap();
-    server_monitor.join().unwrap();
+    if write + read <= num_replicas {
+        println!(
+            "Warning: W={} + R={} <= N={}, reads may return stale values",
+            write, read, num_replicas
+        );
+    }
+    Ok(ReplicationMode::Quorum { write, read })
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let raft = take_flag(&mut args, "--raft");
+    let write_quorum = take_option(&mut args, "--write-quorum");
+    let read_quorum = take_option(&mut args, "--read-quorum");
+    let peers = take_option(&mut args, "--peers");
+    let vnodes = take_option(&mut args, "--vnodes");
+    let num_shards = take_option(&mut args, "--shards");
+    let replication_factor = take_option(&mut args, "--replication-factor");
+    let hot_key_rps = take_option(&mut args, "--hot-key-rps");
+    let raft_dir = take_option(&mut args, "--raft-dir").unwrap_or("./data/raft".to_string());
+    let transport = take_transport(&mut args);
+    let wire_format = take_wire_format(&mut args);
+    let wire_version = take_wire_version(&mut args);
+    let membership = take_membership(&mut args);
+    let faults = take_faults(&mut args);
+    let allow_fault_control = take_flag(&mut args, "--allow-fault-control");
 
     if args.len() < 6 {
-        eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+        eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
         return;
     }
 
     let controller_pid_for_clients: Pid = match args[1].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -164,7 +173,7 @@ fn main() {
     let controller_pid_for_servers: Pid = match args[2].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -172,7 +181,7 @@ fn main() {
     let num_clients: usize = match args[3].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -180,7 +189,7 @@ fn main() {
     let _num_servers: usize = match args[4].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -193,7 +202,7 @@ fn main() {
     let client_pids = match client_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -206,7 +215,110 @@ fn main() {
     let server_pids = match server_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            return;
+        }
+    };
+
+    match transport {
+        Ok(kind) => set_default_transport(kind),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match wire_format {
+        Ok(format) => set_default_wire_format(format),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match wire_version {
+        Ok(version) => set_default_wire_version(version),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match membership {
+        Ok(membership) => set_default_membership(membership),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match faults {
+        Ok(Some(faults)) => set_default_faults(faults),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    set_allow_fault_control(allow_fault_control);
+
+    // logical shards, fixed for as long as the cluster runs
+    let num_shards: usize = match num_shards.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_NUM_SHARDS,
+        _ => {
+            eprintln!("--shards must be a positive number");
+            return;
+        }
+    };
+
+    // copies of every shard, the primary included
+    let replication_factor: usize = match replication_factor.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_REPLICATION_FACTOR,
+        _ => {
+            eprintln!("--replication-factor must be a positive number");
+            return;
+        }
+    };
+    let replication_factor = replication_factor.min(server_pids.len());
+
+    let replication = match parse_replication_mode(raft, write_quorum, read_quorum, replication_factor) {
+        Ok(mode) => mode,
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    };
+
+    // the client-facing pids of every replica of the controller group, this one included
+    let peers = match peers {
+        Some(list) => match parse_pid_list(&list) {
+            Ok(v) => v,
+            Err(_) => {
+                eprintln!("Usage: controller [--transport <udp|tcp>] [--wire <binary|json>] [--wire-version <n>] [--members <file>] [--base-port <port>] [--faults <file>] [--allow-fault-control] [--raft | --write-quorum <w> --read-quorum <r>] [--peers <controller_pid_for_clients_list> --raft-dir <dir>] [--shards <n>] [--replication-factor <r>] [--vnodes <n>] [--hot-key-rps <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+                return;
+            }
+        },
+        None => vec![controller_pid_for_clients],
+    };
+    if !peers.contains(&controller_pid_for_clients) {
+        eprintln!("--peers must include controller_pid_for_clients={}", controller_pid_for_clients);
+        return;
+    }
+
+    // virtual nodes per server on the hash ring the shards are placed with
+    let vnodes: usize = match vnodes.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_VNODES,
+        _ => {
+            eprintln!("--vnodes must be a positive number");
+            return;
+        }
+    };
+
+    // requests per second over the servers that make a key hot, 0 for none
+    let hot_key_rps: u64 = match hot_key_rps.map(|n| n.parse()) {
+        Some(Ok(n)) => n,
+        None => 0,
+        Some(Err(_)) => {
+            eprintln!("--hot-key-rps must be a number of requests per second");
             return;
         }
     };
@@ -216,5 +328,12 @@ fn main() {
         controller_pid_for_servers,
         client_pids,
         server_pids,
+        replication,
+        num_shards,
+        replication_factor,
+        vnodes,
+        hot_key_rps,
+        peers,
+        raft_dir,
     );
 }
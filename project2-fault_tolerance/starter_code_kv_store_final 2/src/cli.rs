@@ -0,0 +1,109 @@
+// Helpers shared by the binaries to parse optional `--name <value>` flags
+// before the positional pid lists.
+
+use crate::client::{parse_read_preference, ReadPreference};
+use crate::faults::FaultInjector;
+use crate::kvs::Pid;
+use crate::membership::{Membership, DEFAULT_BASE_PORT};
+use crate::network::{parse_transport, TransportKind};
+use crate::wire::{check_version, parse_wire_format, WireFormat, WIRE_VERSION};
+use std::io::{Error, ErrorKind};
+use std::num::ParseIntError;
+
+// Removes `--name <value>` from the argument list and returns the value, so the
+// remaining positional arguments can be parsed as before.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() {
+        args.remove(index);
+        return None;
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+// Removes a `--name` switch from the argument list, returns whether it was there.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+// Parses a comma separated pid list such as `0,2,4`, used where a single pid
+// argument may name a whole group (e.g. the replicas of the controller). A
+// plain pid parses as a list of one.
+pub fn parse_pid_list(value: &str) -> Result<Vec<Pid>, ParseIntError> {
+    value.split(',').map(|pid| pid.trim().parse()).collect()
+}
+
+// Removes `--transport udp|tcp` from the argument list, udp when it is not there.
+pub fn take_transport(args: &mut Vec<String>) -> std::io::Result<TransportKind> {
+    match take_option(args, "--transport") {
+        Some(name) => parse_transport(&name),
+        None => Ok(TransportKind::Udp),
+    }
+}
+
+// Removes `--read-preference primary|nearest|any` from the argument list,
+// primary when it is not there.
+pub fn take_read_preference(args: &mut Vec<String>) -> std::io::Result<ReadPreference> {
+    match take_option(args, "--read-preference") {
+        Some(name) => parse_read_preference(&name),
+        None => Ok(ReadPreference::Primary),
+    }
+}
+
+// Removes `--wire binary|json` from the argument list, binary when it is not
+// there. JSON is for talking to processes that predate the binary format.
+pub fn take_wire_format(args: &mut Vec<String>) -> std::io::Result<WireFormat> {
+    match take_option(args, "--wire") {
+        Some(name) => parse_wire_format(&name),
+        None => Ok(WireFormat::Binary),
+    }
+}
+
+// Removes `--wire-version <n>` from the argument list, the current binary
+// version when it is not there. An older one is for peers not upgraded yet.
+pub fn take_wire_version(args: &mut Vec<String>) -> std::io::Result<u8> {
+    match take_option(args, "--wire-version") {
+        Some(version) => match version.parse() {
+            Ok(version) => check_version(version).map(|_| version),
+            Err(_) => Err(Error::new(ErrorKind::InvalidInput, format!("bad --wire-version {}", version))),
+        },
+        None => Ok(WIRE_VERSION),
+    }
+}
+
+// Removes `--members <file>` and `--base-port <port>` from the argument list and
+// builds the cluster membership from them. The base port overrides the one
+// in the file; with neither, every pid is 127.0.0.1:8000+pid.
+pub fn take_membership(args: &mut Vec<String>) -> std::io::Result<Membership> {
+    let members = take_option(args, "--members");
+    let base_port = take_option(args, "--base-port");
+    let mut membership = match members {
+        Some(path) => Membership::load(&path)?,
+        None => Membership::loopback(DEFAULT_BASE_PORT),
+    };
+    if let Some(base_port) = base_port {
+        match base_port.parse() {
+            Ok(base_port) => membership.set_base_port(base_port),
+            Err(_) => return Err(Error::new(ErrorKind::InvalidInput, format!("bad --base-port {}", base_port))),
+        }
+    }
+    Ok(membership)
+}
+
+// Removes `--faults <file>` from the argument list and loads the fault
+// injection config from it, or from KV_STORE_FAULTS without the flag. None
+// when neither is given.
+pub fn take_faults(args: &mut Vec<String>) -> std::io::Result<Option<FaultInjector>> {
+    match take_option(args, "--faults") {
+        Some(path) => Ok(Some(FaultInjector::load(&path)?)),
+        None => FaultInjector::from_env(),
+    }
+}
@@ -0,0 +1,1179 @@
+use crate::kvs::{KVSResult, Operation, Pid, ReadBounds, ReplicationMode, ShardId, ShardInfo};
+use crate::lease::{Clock, SystemClock};
+use crate::network::{create_network_context, operation_name, recv, send, NetworkContext};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+
+// A client running through its list of operations as a state machine driven
+// like ServerNode. It asks the controller for the shard assignment, sends each
+// operation to the server of its key's shard and records a KVSResult for it.
+// Up to a window of requests are in flight at once (see with_window), matched
+// to their answers by seq_no, so answers can come in any order and late
+// duplicates are dropped. An operation waits for the earlier ones on its key.
+// A request without an answer after REQUEST_TIMEOUT_MS is sent again after
+// fetching a fresh assignment. A request the server was too busy to queue
+// (see admission.rs) is sent again after a backoff, which doubles with every
+// Busy the request got and is jittered so that the clients the server
+// refused do not all come back at once. A write a server answers Stale did
+// not run (see server.rs): the server ran later requests of an earlier
+// client under our pid, so ours not answered yet go again numbered above
+// those.
+//
+// A MultiGet, MultiPut or MultiDelete is split into one request per shard of
+// its keys, all sent at once under the batch's seq_no. Each part is answered
+// and retried on its own, and records a KVSResult per key when it is.
+//
+// A TxnCommit goes whole to the primary of its first key's shard, which
+// coordinates it (see server.rs), and records one "txn" KVSResult.
+//
+// Usage:
+// let mut node = ClientNode::new(self_pid, controller_pids, operations, clock).with_window(window);
+// node.tick();                            // every CLIENT_TICK_MS
+// node.step(src_pid, operation);          // on every received operation
+// for (dst, op) in node.take_messages() { send(...) }
+// if node.is_done() { node.results() }
+//
+// In PrimaryBackup mode a client can read from the secondaries too (see
+// ReadPreference and server.rs). It then keeps a session token per shard,
+// the highest version it has seen of it, which a secondary has to have
+// caught up with before it answers. A secondary that is too stale refuses
+// and the Get goes to the primary.
+// let node = ClientNode::new(...).with_read_preference(ReadPreference::Nearest, Some(max_staleness_ms));
+//
+// A client can also read only the hot keys the controller publishes (see
+// hotkeys.rs) from every replica, in turns, and the rest from the primary.
+// It asks for the ShardInfo every HOT_KEYS_REFRESH_MS to learn which keys
+// are hot.
+// let node = ClientNode::new(...).with_read_preference(ReadPreference::Primary, Some(max_staleness_ms)).with_hot_key_reads(true);
+//
+// Programs that talk to the store use KvClient, which drives a ClientNode
+// over the network one call at a time (see below).
+
+pub const CLIENT_TICK_MS: u64 = 10;
+// Each controller gets this long to answer before we ask the next one
+pub const CONTROLLER_TIMEOUT_MS: u64 = 1000;
+pub const CONTROLLER_ROUNDS: usize = 10;
+pub const REQUEST_TIMEOUT_MS: u64 = 1000;
+// How long a KvClient call retries before it gives up, enough to wait out a
+// failover of the primary
+pub const DEFAULT_CALL_TIMEOUT_MS: u64 = 30000;
+// How often a client reading hot keys from every replica asks for a fresh ShardInfo
+pub const HOT_KEYS_REFRESH_MS: u64 = 1000;
+// The longest a client backs off from a busy server
+pub const MAX_BACKOFF_MS: u64 = 500;
+
+// Where Gets go. Nearest is the secondary that answered fastest so far, Any
+// takes turns over every replica of the shard, the primary included.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReadPreference {
+    Primary,
+    Nearest,
+    Any,
+}
+
+pub fn parse_read_preference(name: &str) -> Result<ReadPreference> {
+    match name {
+        "primary" => Ok(ReadPreference::Primary),
+        "nearest" => Ok(ReadPreference::Nearest),
+        "any" => Ok(ReadPreference::Any),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown read preference {}, expected primary, nearest or any", name),
+        )),
+    }
+}
+
+enum State {
+    // asked controller_pids[asked % controller_pids.len()] at sent_ms
+    FetchingShardInfo { asked: usize, sent_ms: u64 },
+    // has a ShardInfo, operations go out as the window has room
+    Ready,
+    // no controller answered, the client gives up
+    Failed,
+}
+
+// seq_no of a request, and for the part of a batch the shard it went to
+type RequestId = (usize, Option<ShardId>);
+
+// A request sent to server_pid at sent_ms, not answered yet
+struct InFlight {
+    operation: Operation,
+    shard_id: ShardId,
+    begin_time: u128,
+    sent_ms: u64,
+    server_pid: Pid,
+    from_secondary: bool,
+    // timed out, goes out again once a fresh ShardInfo is in
+    retry: bool,
+    // refused with Busy, goes out again at this time
+    resend_ms: Option<u64>,
+    busy: u32, // Busy answers to the request so far
+}
+
+pub struct ClientNode<C: Clock> {
+    self_pid: Pid,
+    controller_pids: Vec<Pid>,
+    queued: VecDeque<Operation>, // not sent yet, in order
+    in_flight: BTreeMap<RequestId, InFlight>,
+    window: usize,
+    shard_info: ShardInfo,
+    attempts: HashMap<ShardId, usize>,
+    results: Vec<KVSResult>,
+    responses: Vec<Operation>,
+    state: State,
+    clock: C,
+    outbox: Vec<(Pid, Operation)>,
+
+    read_preference: ReadPreference,
+    max_staleness_ms: Option<u64>,
+    tokens: HashMap<ShardId, u64>, // the session token of every shard
+    latency_ms: HashMap<Pid, u64>, // smoothed response time of every server we sent to
+    next_replica: usize,
+    replica_reads: usize,
+    refused_reads: usize,
+    hot_key_reads: bool,
+    refreshed_ms: u64, // when we last asked for the ShardInfo
+    busy_answers: usize,
+    rng: StdRng,         // the jitter of backoffs
+    last_seq_no: usize, // the highest seq_no a Stale made us give a request
+}
+
+// The seq_no of a response, and the first key of a batch's
+fn get_response_id(operation: &Operation) -> Option<(usize, Option<&String>)> {
+    match operation {
+        Operation::GetRes(_, seq_no) => Some((*seq_no, None)),
+        Operation::PutRes(_, seq_no) => Some((*seq_no, None)),
+        Operation::DeleteRes(_, seq_no) => Some((*seq_no, None)),
+        Operation::CasRes(.., seq_no) | Operation::PutIfAbsentRes(.., seq_no) | Operation::IncrRes(.., seq_no) => {
+            Some((*seq_no, None))
+        }
+        Operation::TxnCommitRes(_, seq_no) => Some((*seq_no, None)),
+        Operation::MultiGetRes(values, seq_no)
+        | Operation::MultiPutRes(values, seq_no)
+        | Operation::MultiDeleteRes(values, seq_no) => values.first().map(|(key, _)| (*seq_no, Some(key))),
+        Operation::SessionRes(response, _) => get_response_id(response),
+        _ => None,
+    }
+}
+
+fn get_keys(operation: &Operation) -> Vec<&String> {
+    match operation {
+        Operation::Get(key, _) | Operation::Put(key, _, _) | Operation::Delete(key, _) => vec![key],
+        Operation::Cas(key, ..) | Operation::PutIfAbsent(key, ..) | Operation::Incr(key, ..) => vec![key],
+        Operation::MultiGet(keys, _) | Operation::MultiDelete(keys, _) => keys.iter().collect(),
+        Operation::MultiPut(entries, _) => entries.iter().map(|(key, _)| key).collect(),
+        // the writes first, the first key picks the coordinator
+        Operation::TxnCommit(reads, writes, _) => writes.iter().chain(reads.iter()).map(|(key, _)| key).collect(),
+        _ => unreachable!("{} is not a request a client sends", operation_name(operation)),
+    }
+}
+
+fn get_seq_no(operation: &Operation) -> usize {
+    match operation {
+        Operation::Get(_, seq_no)
+        | Operation::Put(_, _, seq_no)
+        | Operation::Delete(_, seq_no)
+        | Operation::MultiGet(_, seq_no)
+        | Operation::MultiPut(_, seq_no)
+        | Operation::MultiDelete(_, seq_no)
+        | Operation::Cas(.., seq_no)
+        | Operation::PutIfAbsent(.., seq_no)
+        | Operation::Incr(.., seq_no)
+        | Operation::TxnCommit(.., seq_no) => *seq_no,
+        _ => unreachable!("{} is not a request a client sends", operation_name(operation)),
+    }
+}
+
+// The request with seq_no instead of its own
+fn with_seq_no(operation: Operation, seq_no: usize) -> Operation {
+    match operation {
+        Operation::Get(key, _) => Operation::Get(key, seq_no),
+        Operation::Put(key, value, _) => Operation::Put(key, value, seq_no),
+        Operation::Delete(key, _) => Operation::Delete(key, seq_no),
+        Operation::MultiGet(keys, _) => Operation::MultiGet(keys, seq_no),
+        Operation::MultiPut(entries, _) => Operation::MultiPut(entries, seq_no),
+        Operation::MultiDelete(keys, _) => Operation::MultiDelete(keys, seq_no),
+        Operation::Cas(key, expected, new, _) => Operation::Cas(key, expected, new, seq_no),
+        Operation::PutIfAbsent(key, value, _) => Operation::PutIfAbsent(key, value, seq_no),
+        Operation::Incr(key, delta, _) => Operation::Incr(key, delta, seq_no),
+        Operation::TxnCommit(reads, writes, _) => Operation::TxnCommit(reads, writes, seq_no),
+        operation => operation,
+    }
+}
+
+fn is_batch(operation: &Operation) -> bool {
+    matches!(operation, Operation::MultiGet(..) | Operation::MultiPut(..) | Operation::MultiDelete(..))
+}
+
+// One batch per shard with the keys of operation on it, in the order they
+// come in operation
+fn split_batch(operation: Operation, shard_info: &ShardInfo) -> Vec<(ShardId, Operation)> {
+    match operation {
+        Operation::MultiGet(keys, seq_no) => {
+            let mut parts: BTreeMap<ShardId, Vec<String>> = BTreeMap::new();
+            for key in keys {
+                parts.entry(shard_info.shard_of(&key)).or_default().push(key);
+            }
+            parts.into_iter().map(|(shard_id, keys)| (shard_id, Operation::MultiGet(keys, seq_no))).collect()
+        }
+        Operation::MultiPut(entries, seq_no) => {
+            let mut parts: BTreeMap<ShardId, Vec<(String, String)>> = BTreeMap::new();
+            for (key, value) in entries {
+                parts.entry(shard_info.shard_of(&key)).or_default().push((key, value));
+            }
+            parts.into_iter().map(|(shard_id, entries)| (shard_id, Operation::MultiPut(entries, seq_no))).collect()
+        }
+        Operation::MultiDelete(keys, seq_no) => {
+            let mut parts: BTreeMap<ShardId, Vec<String>> = BTreeMap::new();
+            for key in keys {
+                parts.entry(shard_info.shard_of(&key)).or_default().push(key);
+            }
+            parts.into_iter().map(|(shard_id, keys)| (shard_id, Operation::MultiDelete(keys, seq_no))).collect()
+        }
+        // a transaction without keys goes anywhere, it commits right away
+        operation => {
+            let shard_id = get_keys(&operation).first().map_or(0, |key| shard_info.shard_of(key));
+            vec![(shard_id, operation)]
+        }
+    }
+}
+
+// The value a GetRes carries, or the old one a write's response does
+fn response_value(response: &Operation) -> Option<String> {
+    match response {
+        Operation::GetRes(value, _) | Operation::PutRes(value, _) | Operation::DeleteRes(value, _) => value.clone(),
+        Operation::CasRes(_, value, _) | Operation::PutIfAbsentRes(value, _) | Operation::IncrRes(value, _, _) => {
+            value.clone()
+        }
+        _ => None,
+    }
+}
+
+// The value a conditional write left the key with, whether it wrote or not
+fn conditional_value(request: &Operation, response: &Operation) -> Option<String> {
+    match (request, response) {
+        (Operation::Cas(_, _, new, _), Operation::CasRes(true, _, _)) => new.clone(),
+        (Operation::PutIfAbsent(_, value, _), Operation::PutIfAbsentRes(None, _)) => Some(value.clone()),
+        (_, Operation::IncrRes(_, Some(new), _)) => Some(new.to_string()),
+        (_, response) => response_value(response),
+    }
+}
+
+// The value of every key a batch's response carries, or the old one of a
+// MultiPut or MultiDelete
+fn response_values(response: &Operation) -> Option<HashMap<String, Option<String>>> {
+    match response {
+        Operation::MultiGetRes(values, _) | Operation::MultiPutRes(values, _) | Operation::MultiDeleteRes(values, _) => {
+            Some(values.iter().cloned().collect())
+        }
+        _ => None,
+    }
+}
+
+// The primary serves every request, except in Raft mode where the primary
+// may have failed: after a timeout we go around the other members of the
+// group, any of which forwards the request to the current leader. The member
+// that answered keeps getting that shard's requests.
+fn pick_server(shard_info: &ShardInfo, shard_id: ShardId, attempt: usize) -> Pid {
+    let location = &shard_info.locations[&shard_id];
+    if shard_info.replication != ReplicationMode::Raft {
+        return location.primary;
+    }
+    let mut replicas = vec![location.primary];
+    replicas.extend(location.secondaries.iter().cloned());
+    replicas[attempt % replicas.len()]
+}
+
+impl<C: Clock> ClientNode<C> {
+    pub fn new(self_pid: Pid, controller_pids: Vec<Pid>, operations: Vec<Operation>, clock: C) -> Self {
+        let mut node = ClientNode {
+            self_pid,
+            controller_pids,
+            queued: operations.into(),
+            in_flight: BTreeMap::new(),
+            window: 1,
+            shard_info: ShardInfo::new(),
+            attempts: HashMap::new(),
+            results: Vec::new(),
+            responses: Vec::new(),
+            state: State::Ready,
+            clock,
+            outbox: Vec::new(),
+            read_preference: ReadPreference::Primary,
+            max_staleness_ms: None,
+            tokens: HashMap::new(),
+            latency_ms: HashMap::new(),
+            next_replica: 0,
+            replica_reads: 0,
+            refused_reads: 0,
+            hot_key_reads: false,
+            refreshed_ms: 0,
+            busy_answers: 0,
+            rng: StdRng::seed_from_u64(self_pid as u64),
+            last_seq_no: 0,
+        };
+        node.fetch_shard_info(0);
+        node
+    }
+
+    // Up to window requests are in flight at once, one by default
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.window = window.max(1);
+        self
+    }
+
+    // Gets go where preference says; a secondary only answers if it was in
+    // sync with the primary at most max_staleness_ms ago (none for any age)
+    pub fn with_read_preference(mut self, preference: ReadPreference, max_staleness_ms: Option<u64>) -> Self {
+        self.read_preference = preference;
+        self.max_staleness_ms = max_staleness_ms;
+        self
+    }
+
+    // Gets of hot keys go to every replica in turns, whatever the read
+    // preference, within its max_staleness_ms
+    pub fn with_hot_key_reads(mut self, enabled: bool) -> Self {
+        self.hot_key_reads = enabled;
+        self
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.self_pid
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(Pid, Operation)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Whether every operation got its answer
+    pub fn is_done(&self) -> bool {
+        matches!(self.state, State::Ready) && self.queued.is_empty() && self.in_flight.is_empty()
+    }
+
+    pub fn has_failed(&self) -> bool {
+        matches!(self.state, State::Failed)
+    }
+
+    // Requests sent and not answered yet
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    pub fn results(&self) -> &Vec<KVSResult> {
+        &self.results
+    }
+
+    pub fn take_results(&mut self) -> Vec<KVSResult> {
+        std::mem::take(&mut self.results)
+    }
+
+    // The answers that came in since the last call, unwrapped from SessionRes,
+    // one per part of a batch
+    pub fn take_responses(&mut self) -> Vec<Operation> {
+        std::mem::take(&mut self.responses)
+    }
+
+    // Queues one more operation after the ones not answered yet
+    pub fn push(&mut self, operation: Operation) {
+        self.queued.push_back(operation);
+        match self.state {
+            State::Ready => self.fill_window(),
+            // try the controllers again, they may be back
+            State::Failed => self.fetch_shard_info(0),
+            _ => {}
+        }
+    }
+
+    // Gives up on every operation not answered yet. A late answer to one of
+    // them is dropped like any other.
+    pub fn cancel(&mut self) {
+        self.queued.clear();
+        self.in_flight.clear();
+    }
+
+    // Gets a secondary answered
+    pub fn replica_reads(&self) -> usize {
+        self.replica_reads
+    }
+
+    // Gets a secondary refused, which the primary answered instead
+    pub fn refused_reads(&self) -> usize {
+        self.refused_reads
+    }
+
+    // Requests a server refused with Busy, each one counted as often as it was
+    pub fn busy_answers(&self) -> usize {
+        self.busy_answers
+    }
+
+    // The highest seq_no the node renumbered a request to, 0 if none. The
+    // ones up to it are taken.
+    pub fn last_seq_no(&self) -> usize {
+        self.last_seq_no
+    }
+
+    pub fn tick(&mut self) {
+        let now_ms = self.clock.now_ms();
+        if let State::FetchingShardInfo { asked, sent_ms } = self.state {
+            if now_ms >= sent_ms + CONTROLLER_TIMEOUT_MS {
+                if asked + 1 >= CONTROLLER_ROUNDS * self.controller_pids.len() {
+                    println!("No controller answered, controllers={:?}", self.controller_pids);
+                    self.state = State::Failed;
+                } else {
+                    self.fetch_shard_info(asked + 1);
+                }
+            }
+        }
+        if self.hot_key_reads && matches!(self.state, State::Ready) && now_ms >= self.refreshed_ms + HOT_KEYS_REFRESH_MS {
+            // the controllers in turns, nothing waits for the answer
+            let controller_pid = self.controller_pids[(now_ms / HOT_KEYS_REFRESH_MS) as usize % self.controller_pids.len()];
+            self.outbox.push((controller_pid, Operation::GetShardInfo()));
+            self.refreshed_ms = now_ms;
+        }
+        if matches!(self.state, State::Ready) {
+            self.resend_backed_off(now_ms);
+        }
+        let mut timed_out = Vec::new();
+        for request in self.in_flight.values_mut() {
+            if !request.retry && request.resend_ms.is_none() && now_ms >= request.sent_ms + REQUEST_TIMEOUT_MS {
+                request.retry = true;
+                timed_out.push((request.shard_id, request.server_pid));
+            }
+        }
+        if timed_out.is_empty() {
+            return;
+        }
+        println!("Client recv timeout, retry...");
+        let mut shards = HashSet::new();
+        for (shard_id, server_pid) in timed_out {
+            // requests to one shard time out together when its server is
+            // down, that is one more attempt and not one per request
+            if shards.insert(shard_id) {
+                *self.attempts.entry(shard_id).or_insert(0) += 1;
+            }
+            // the nearest secondary is not that near if it does not answer
+            self.note_latency(server_pid, REQUEST_TIMEOUT_MS);
+        }
+        // the server might have failed, so we update the shard info
+        if matches!(self.state, State::Ready) {
+            self.fetch_shard_info(0);
+        }
+    }
+
+    pub fn step(&mut self, pid: Pid, operation: Operation) {
+        match operation {
+            // the controller may be a replicated group, the first replica to
+            // answer wins and late responses to earlier requests are dropped
+            Operation::GetShardInfoRes(shard_info) => {
+                if let State::FetchingShardInfo { .. } = self.state {
+                    self.shard_info = shard_info;
+                    self.state = State::Ready;
+                    self.resend_timed_out();
+                    self.fill_window();
+                } else if shard_info.epoch > self.shard_info.epoch {
+                    // a refresh, or an answer to an earlier request that is newer still
+                    self.shard_info = shard_info;
+                }
+            }
+            Operation::ReadRefused(seq_no) => {
+                if self.in_flight.get(&(seq_no, None)).is_some_and(|request| request.from_secondary) {
+                    self.refused_reads += 1;
+                    self.read_from_primary(seq_no);
+                }
+            }
+            Operation::Busy(seq_no, retry_after_ms) => self.back_off(pid, seq_no, retry_after_ms),
+            Operation::Stale(seq_no, newest) => self.renumber(pid, seq_no, newest),
+            // responses come in any order; ones to requests that were already
+            // answered, e.g. before a retry, are dropped
+            response => {
+                let request = match self.request_id(&response).and_then(|id| self.in_flight.remove(&id)) {
+                    Some(request) => request,
+                    None => return,
+                };
+                if request.from_secondary {
+                    self.replica_reads += 1;
+                }
+                self.note_latency(request.server_pid, self.clock.now_ms().saturating_sub(request.sent_ms));
+                let response = match response {
+                    Operation::SessionRes(response, version) => {
+                        let token = self.tokens.entry(request.shard_id).or_insert(0);
+                        *token = (*token).max(version);
+                        *response
+                    }
+                    response => response,
+                };
+                self.record(&request, &response);
+                self.responses.push(response);
+                if let State::Ready = self.state {
+                    self.fill_window();
+                }
+            }
+        }
+    }
+
+    // The in-flight request a response answers, the part of a batch by the
+    // shard of its keys
+    fn request_id(&self, response: &Operation) -> Option<RequestId> {
+        match get_response_id(response)? {
+            (seq_no, None) => Some((seq_no, None)),
+            (_, Some(_)) if self.shard_info.num_shards == 0 => None,
+            (seq_no, Some(key)) => Some((seq_no, Some(self.shard_info.shard_of(key)))),
+        }
+    }
+
+    fn fetch_shard_info(&mut self, asked: usize) {
+        let controller_pid = self.controller_pids[asked % self.controller_pids.len()];
+        self.outbox.push((controller_pid, Operation::GetShardInfo()));
+        self.refreshed_ms = self.clock.now_ms();
+        self.state = State::FetchingShardInfo {
+            asked,
+            sent_ms: self.clock.now_ms(),
+        };
+    }
+
+    // Sends queued operations while the window has room. An operation waits
+    // for the ones before it on the same key, so that writes to a key are
+    // applied in order and a Get sees the client's earlier writes. A batch
+    // waits for every one of its keys, and the operations after it on any of
+    // them wait for the batch.
+    fn fill_window(&mut self) {
+        let mut busy: HashSet<String> = self
+            .in_flight
+            .values()
+            .flat_map(|request| get_keys(&request.operation))
+            .cloned()
+            .collect();
+        let mut index = 0;
+        while self.in_flight.len() < self.window && index < self.queued.len() {
+            let keys = get_keys(&self.queued[index]);
+            if keys.iter().any(|key| busy.contains(*key)) {
+                busy.extend(keys.into_iter().cloned());
+                index += 1;
+                continue;
+            }
+            let operation = self.queued.remove(index).unwrap();
+            busy.extend(get_keys(&operation).into_iter().cloned());
+            let begin_time = self.clock.timestamp_ns();
+            for (shard_id, request) in split_batch(operation, &self.shard_info) {
+                self.send(request, shard_id, begin_time);
+            }
+        }
+    }
+
+    fn resend_timed_out(&mut self) {
+        let timed_out: Vec<RequestId> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.retry)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in timed_out {
+            let request = self.in_flight.remove(&id).unwrap();
+            self.send(request.operation, request.shard_id, request.begin_time);
+        }
+    }
+
+    // Sends a single key operation, or the part of a batch with the keys on shard_id
+    fn send(&mut self, operation: Operation, shard_id: ShardId, begin_time: u128) {
+        let (server_pid, request) = self.route(operation.clone(), shard_id);
+        let from_secondary = matches!(request, Operation::ReadReplica(..))
+            && self.shard_info.locations[&shard_id].secondaries.contains(&server_pid);
+        self.outbox.push((server_pid, request));
+        let part = match is_batch(&operation) {
+            true => Some(shard_id),
+            false => None,
+        };
+        self.in_flight.insert(
+            (get_seq_no(&operation), part),
+            InFlight {
+                operation,
+                shard_id,
+                begin_time,
+                sent_ms: self.clock.now_ms(),
+                server_pid,
+                from_secondary,
+                retry: false,
+                resend_ms: None,
+                busy: 0,
+            },
+        );
+    }
+
+    // server_pid had no room for the request with seq_no, every part of it
+    // that went there waits for a backoff. A Busy from a server no part went
+    // to comes from one a part was forwarded to, then every part waits. The
+    // backoff starts at the server's retry_after_ms, doubles with every Busy
+    // the part got up to MAX_BACKOFF_MS, and ends at a random time in its
+    // second half.
+    fn back_off(&mut self, server_pid: Pid, seq_no: usize, retry_after_ms: u64) {
+        let sent_there = self
+            .in_flight
+            .iter()
+            .any(|(id, request)| id.0 == seq_no && request.server_pid == server_pid);
+        let refused: Vec<RequestId> = self
+            .in_flight
+            .iter()
+            .filter(|(id, request)| {
+                id.0 == seq_no
+                    && (request.server_pid == server_pid || !sent_there)
+                    && !request.retry
+                    && request.resend_ms.is_none()
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        if refused.is_empty() {
+            return;
+        }
+        self.busy_answers += refused.len();
+        let now_ms = self.clock.now_ms();
+        for id in refused {
+            let request = self.in_flight.get_mut(&id).unwrap();
+            let backoff_ms = retry_after_ms.max(1).saturating_mul(1 << request.busy.min(16)).min(MAX_BACKOFF_MS);
+            request.busy += 1;
+            request.resend_ms = Some(now_ms + self.rng.gen_range(backoff_ms / 2..=backoff_ms));
+        }
+    }
+
+    // server_pid did not run the write with seq_no, it answered requests of
+    // our pid up to newest. Unless it is a late copy of one answered already,
+    // we are a new client under the pid of an old one: the parts of the
+    // write that went there (or every part, as for a Busy) and the requests
+    // not sent yet numbered up to newest get numbers above it.
+    fn renumber(&mut self, server_pid: Pid, seq_no: usize, newest: usize) {
+        let sent_there = self
+            .in_flight
+            .iter()
+            .any(|(id, request)| id.0 == seq_no && request.server_pid == server_pid);
+        let stale: Vec<RequestId> = self
+            .in_flight
+            .iter()
+            .filter(|(id, request)| id.0 == seq_no && (request.server_pid == server_pid || !sent_there))
+            .map(|(id, _)| *id)
+            .collect();
+        if stale.is_empty() {
+            return;
+        }
+        let highest = self.in_flight.keys().map(|id| id.0).chain(self.queued.iter().map(get_seq_no)).max();
+        self.last_seq_no = self.last_seq_no.max(newest).max(highest.unwrap_or(0)) + 1;
+        for id in stale {
+            let request = self.in_flight.remove(&id).unwrap();
+            self.send(with_seq_no(request.operation, self.last_seq_no), request.shard_id, request.begin_time);
+        }
+        for operation in self.queued.iter_mut() {
+            if get_seq_no(operation) <= newest {
+                self.last_seq_no += 1;
+                *operation = with_seq_no(operation.clone(), self.last_seq_no);
+            }
+        }
+    }
+
+    // Sends the requests whose backoff is over again, routed afresh
+    fn resend_backed_off(&mut self, now_ms: u64) {
+        let due: Vec<RequestId> = self
+            .in_flight
+            .iter()
+            .filter(|(_, request)| request.resend_ms.is_some_and(|resend_ms| now_ms >= resend_ms))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in due {
+            let request = self.in_flight.remove(&id).unwrap();
+            self.send(request.operation, request.shard_id, request.begin_time);
+            self.in_flight.get_mut(&id).unwrap().busy = request.busy;
+        }
+    }
+
+    // The server an operation goes to, and the request to send it. Reading
+    // from secondaries, Gets go as ReadReplica to the replica the read
+    // preference picks, or any replica for a hot key, and writes as Session
+    // to the primary, so that every answer carries a version for the session
+    // token.
+    fn route(&mut self, operation: Operation, shard_id: ShardId) -> (Pid, Operation) {
+        let server_pid = pick_server(&self.shard_info, shard_id, *self.attempts.get(&shard_id).unwrap_or(&0));
+        if (self.read_preference == ReadPreference::Primary && !self.hot_key_reads)
+            || self.shard_info.replication != ReplicationMode::PrimaryBackup
+            || matches!(operation, Operation::TxnCommit(..))
+        {
+            return (server_pid, operation);
+        }
+        let replica = match &operation {
+            Operation::Get(key, _) if self.hot_key_reads && self.shard_info.is_hot(key) => Some(self.next_replica(shard_id)),
+            Operation::Get(..) => self.pick_replica(shard_id),
+            _ => None,
+        };
+        match (operation, replica) {
+            (Operation::Get(key, seq_no), Some(replica)) => {
+                let bounds = ReadBounds {
+                    max_staleness_ms: self.max_staleness_ms,
+                    min_version: self.tokens.get(&shard_id).cloned().unwrap_or(0),
+                };
+                (replica, Operation::ReadReplica(key, seq_no, bounds))
+            }
+            (operation, _) => (server_pid, Operation::Session(Box::new(operation))),
+        }
+    }
+
+    fn pick_replica(&mut self, shard_id: ShardId) -> Option<Pid> {
+        match self.read_preference {
+            ReadPreference::Primary => None,
+            // a secondary we never heard from counts as the nearest, so every
+            // one gets tried
+            ReadPreference::Nearest => self.shard_info.locations[&shard_id]
+                .secondaries
+                .iter()
+                .min_by_key(|pid| (self.latency_ms.get(pid).cloned().unwrap_or(0), **pid))
+                .cloned(),
+            ReadPreference::Any => Some(self.next_replica(shard_id)),
+        }
+    }
+
+    // Every replica of the shard in turns, the primary included
+    fn next_replica(&mut self, shard_id: ShardId) -> Pid {
+        let replicas = self.shard_info.replicas(shard_id);
+        self.next_replica += 1;
+        replicas[(self.self_pid as usize + self.next_replica) % replicas.len()]
+    }
+
+    // A secondary refused the Get, the primary answers it instead
+    fn read_from_primary(&mut self, seq_no: usize) {
+        let request = match self.in_flight.get_mut(&(seq_no, None)) {
+            Some(request) => request,
+            None => return,
+        };
+        let key = match &request.operation {
+            Operation::Get(key, _) => key.clone(),
+            _ => return,
+        };
+        let primary = self.shard_info.locations[&request.shard_id].primary;
+        self.outbox.push((primary, Operation::Session(Box::new(Operation::Get(key, seq_no)))));
+        request.sent_ms = self.clock.now_ms();
+        request.server_pid = primary;
+        request.from_secondary = false;
+    }
+
+    fn note_latency(&mut self, server_pid: Pid, sample_ms: u64) {
+        let latency_ms = self.latency_ms.entry(server_pid).or_insert(sample_ms);
+        *latency_ms = (*latency_ms * 3 + sample_ms) / 4;
+    }
+
+    // Results go in the order the answers came in, those of a batch's part in
+    // the order of its keys
+    fn record(&mut self, request: &InFlight, response: &Operation) {
+        let end_time = self.clock.timestamp_ns();
+        let answered: Vec<(&str, String, String)> = match &request.operation {
+            Operation::Get(key, _) => vec![("get", key.clone(), "".to_string())],
+            Operation::Put(key, value, _) => vec![("put", key.clone(), value.clone())],
+            Operation::Delete(key, _) => vec![("delete", key.clone(), "".to_string())],
+            Operation::MultiGet(keys, _) => keys.iter().map(|key| ("get", key.clone(), "".to_string())).collect(),
+            Operation::MultiPut(entries, _) => {
+                entries.iter().map(|(key, value)| ("put", key.clone(), value.clone())).collect()
+            }
+            Operation::MultiDelete(keys, _) => keys.iter().map(|key| ("delete", key.clone(), "".to_string())).collect(),
+            // the new value is what the key holds after the write, or kept
+            // if the condition did not hold
+            Operation::Cas(key, ..) => vec![("cas", key.clone(), conditional_value(&request.operation, response).unwrap_or_default())],
+            Operation::PutIfAbsent(key, ..) => {
+                vec![("putifabsent", key.clone(), conditional_value(&request.operation, response).unwrap_or_default())]
+            }
+            Operation::Incr(key, ..) => vec![("incr", key.clone(), conditional_value(&request.operation, response).unwrap_or_default())],
+            Operation::TxnCommit(..) => {
+                let key = get_keys(&request.operation).first().cloned().cloned().unwrap_or_default();
+                let outcome = match response {
+                    Operation::TxnCommitRes(true, _) => "commit",
+                    _ => "abort",
+                };
+                self.results.push(KVSResult {
+                    operation: "txn".to_string(),
+                    key,
+                    observed_value: outcome.to_string(),
+                    new_value: "".to_string(),
+                    begin_time: request.begin_time,
+                    end_time,
+                });
+                return;
+            }
+            operation => {
+                println!("Not recording the answer to {}: {:?}", operation_name(operation), response);
+                return;
+            }
+        };
+        let values = response_values(response);
+        for (operation, key, new_value) in answered {
+            let observed_value = match &values {
+                Some(values) => values.get(&key).cloned().flatten(),
+                None => response_value(response),
+            };
+            self.results.push(KVSResult {
+                operation: operation.to_string(),
+                key,
+                observed_value: observed_value.unwrap_or_default(),
+                new_value,
+                begin_time: request.begin_time,
+                end_time,
+            });
+        }
+    }
+}
+
+// Why a KvClient call failed
+#[derive(Debug)]
+pub enum KvError {
+    // no controller told us where the key's shard is
+    NoShardInfo,
+    // no answer within the client's timeout. A write may still have been
+    // applied, or be applied later.
+    Timeout,
+    Io(Error),
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::NoShardInfo => write!(f, "no controller answered"),
+            KvError::Timeout => write!(f, "request timed out"),
+            KvError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
+
+impl From<Error> for KvError {
+    fn from(e: Error) -> Self {
+        KvError::Io(e)
+    }
+}
+
+// A blocking client for programs that link against the store. Every call
+// queues one operation on a ClientNode and drives it over the network until
+// the answer is in, so the node's ShardInfo is kept from call to call and a
+// request that goes unanswered is retried after a ShardInfo refresh, for up
+// to the client's timeout.
+//
+// Usage:
+// let mut client = KvClient::new(self_pid, controller_pids)?;
+// client.put("key", "value")?;  // the old value
+// client.get("key")?;           // Some("value")
+// client.delete("key")?;        // the old value
+// client.multi_put(&[("a", "1"), ("b", "2")])?;  // the old values
+// client.multi_get(&["a", "b"])?;                 // [Some("1"), Some("2")]
+//
+// A transaction reads as it goes and writes all of its keys or none:
+// let mut txn = client.begin();
+// let balance = txn.get("a")?;
+// txn.put("a", "0");
+// txn.put("b", &balance.unwrap_or_default());
+// txn.commit()?;                // false if "a" changed meanwhile
+//
+// A batch of operations can go through with several in flight at once:
+// let results = KvClient::new(self_pid, controller_pids)?.with_window(8).replay(operations)?;
+pub struct KvClient {
+    ctx: NetworkContext,
+    node: ClientNode<SystemClock>,
+    clock: SystemClock,
+    next_seq_no: usize,
+    timeout_ms: Option<u64>,
+}
+
+impl KvClient {
+    pub fn new(self_pid: Pid, controller_pids: Vec<Pid>) -> std::result::Result<Self, KvError> {
+        let ctx = create_network_context(&self_pid)?;
+        Ok(KvClient::with_context(ctx, controller_pids))
+    }
+
+    // A client on a context the caller set up, e.g. with a membership of its own
+    pub fn with_context(ctx: NetworkContext, controller_pids: Vec<Pid>) -> Self {
+        let clock = SystemClock::new();
+        let node = ClientNode::new(ctx.pid(), controller_pids, Vec::new(), clock.clone());
+        // Servers take a write whose seq_no is below that of a later write of
+        // the pid to the same key for a late copy (see server.rs) and answer
+        // Stale, then the node numbers it on from there. Starting at the time
+        // in microseconds spares a client that restarts under the same pid
+        // most of those round trips.
+        let next_seq_no = (clock.timestamp_ns() / 1000) as usize;
+        KvClient {
+            ctx,
+            node,
+            clock,
+            next_seq_no,
+            timeout_ms: Some(DEFAULT_CALL_TIMEOUT_MS),
+        }
+    }
+
+    // See ClientNode::with_read_preference
+    pub fn with_read_preference(mut self, preference: ReadPreference, max_staleness_ms: Option<u64>) -> Self {
+        self.node = self.node.with_read_preference(preference, max_staleness_ms);
+        self
+    }
+
+    // See ClientNode::with_hot_key_reads
+    pub fn with_hot_key_reads(mut self, enabled: bool) -> Self {
+        self.node = self.node.with_hot_key_reads(enabled);
+        self
+    }
+
+    // See ClientNode::with_window
+    pub fn with_window(mut self, window: usize) -> Self {
+        self.node = self.node.with_window(window);
+        self
+    }
+
+    // How long a call retries before it fails with KvError::Timeout, none to
+    // retry for as long as it takes
+    pub fn with_timeout(mut self, timeout_ms: Option<u64>) -> Self {
+        self.timeout_ms = timeout_ms;
+        self
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.node.pid()
+    }
+
+    pub fn get(&mut self, key: &str) -> std::result::Result<Option<String>, KvError> {
+        let seq_no = self.take_seq_no();
+        self.call(Operation::Get(key.to_string(), seq_no))
+    }
+
+    // Returns the value the write replaced
+    pub fn put(&mut self, key: &str, value: &str) -> std::result::Result<Option<String>, KvError> {
+        let seq_no = self.take_seq_no();
+        self.call(Operation::Put(key.to_string(), value.to_string(), seq_no))
+    }
+
+    // Returns the value the delete removed
+    pub fn delete(&mut self, key: &str) -> std::result::Result<Option<String>, KvError> {
+        let seq_no = self.take_seq_no();
+        self.call(Operation::Delete(key.to_string(), seq_no))
+    }
+
+    // Reads every key with one request per shard, sent at once. The values
+    // are in the order of keys.
+    pub fn multi_get(&mut self, keys: &[&str]) -> std::result::Result<Vec<Option<String>>, KvError> {
+        let seq_no = self.take_seq_no();
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        self.call_batch(Operation::MultiGet(keys.clone(), seq_no), &keys)
+    }
+
+    // Returns the values the writes replaced. The writes to each shard are
+    // applied together, those to different shards are not.
+    pub fn multi_put(&mut self, entries: &[(&str, &str)]) -> std::result::Result<Vec<Option<String>>, KvError> {
+        let seq_no = self.take_seq_no();
+        let entries: Vec<(String, String)> =
+            entries.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        let keys: Vec<String> = entries.iter().map(|(key, _)| key.clone()).collect();
+        self.call_batch(Operation::MultiPut(entries, seq_no), &keys)
+    }
+
+    // Returns the values the deletes removed
+    pub fn multi_delete(&mut self, keys: &[&str]) -> std::result::Result<Vec<Option<String>>, KvError> {
+        let seq_no = self.take_seq_no();
+        let keys: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+        self.call_batch(Operation::MultiDelete(keys.clone(), seq_no), &keys)
+    }
+
+    // Sets key to new if it holds expected, a none new deletes it and a none
+    // expected stands for an absent key. Returns whether it did and the value
+    // the key had.
+    pub fn cas(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<&str>,
+    ) -> std::result::Result<(bool, Option<String>), KvError> {
+        let seq_no = self.take_seq_no();
+        let expected = expected.map(|value| value.to_string());
+        let new = new.map(|value| value.to_string());
+        match self.call_for_response(Operation::Cas(key.to_string(), expected, new, seq_no))? {
+            Some(Operation::CasRes(swapped, old_value, _)) => Ok((swapped, old_value)),
+            _ => Ok((false, None)),
+        }
+    }
+
+    // Sets key only if it has no value. Returns the value it already had,
+    // none if the put happened.
+    pub fn put_if_absent(&mut self, key: &str, value: &str) -> std::result::Result<Option<String>, KvError> {
+        let seq_no = self.take_seq_no();
+        self.call(Operation::PutIfAbsent(key.to_string(), value.to_string(), seq_no))
+    }
+
+    // Adds delta to the number key holds, an absent key counts as 0. Returns
+    // the new number, none if the key holds something else (or it would
+    // overflow), which is left as it is.
+    pub fn incr(&mut self, key: &str, delta: i64) -> std::result::Result<Option<i64>, KvError> {
+        let seq_no = self.take_seq_no();
+        match self.call_for_response(Operation::Incr(key.to_string(), delta, seq_no))? {
+            Some(Operation::IncrRes(_, new_value, _)) => Ok(new_value),
+            _ => Ok(None),
+        }
+    }
+
+    // A transaction on this client, see Transaction
+    pub fn begin(&mut self) -> Transaction<'_> {
+        Transaction {
+            client: self,
+            reads: Vec::new(),
+            writes: Vec::new(),
+        }
+    }
+
+    fn commit(
+        &mut self,
+        reads: Vec<(String, Option<String>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) -> std::result::Result<bool, KvError> {
+        if reads.is_empty() && writes.is_empty() {
+            return Ok(true);
+        }
+        let seq_no = self.take_seq_no();
+        match self.call_for_response(Operation::TxnCommit(reads, writes, seq_no))? {
+            Some(Operation::TxnCommitRes(committed, _)) => Ok(committed),
+            _ => Ok(false),
+        }
+    }
+
+    // Above every seq_no the node renumbered a request to as well
+    fn take_seq_no(&mut self) -> usize {
+        self.next_seq_no = self.next_seq_no.max(self.node.last_seq_no()) + 1;
+        self.next_seq_no
+    }
+
+    // Sends operation to dst_pid and returns the first message dst_pid sends
+    // back, e.g. the SnapshotRes of a server. Whatever else comes in
+    // meanwhile goes to the node as usual.
+    pub fn ask(&mut self, dst_pid: Pid, operation: &Operation, timeout_ms: u64) -> std::result::Result<Operation, KvError> {
+        send(&mut self.ctx, &dst_pid, operation)?;
+        let start_ms = self.clock.now_ms();
+        while self.clock.now_ms() < start_ms + timeout_ms {
+            let messages = match recv(&mut self.ctx, CLIENT_TICK_MS) {
+                Ok(messages) => messages,
+                Err(_) => continue,
+            };
+            let mut answer = None;
+            for (pid, message) in messages {
+                if pid == dst_pid && answer.is_none() {
+                    answer = Some(message);
+                } else {
+                    self.node.step(pid, message);
+                }
+            }
+            if let Some(answer) = answer {
+                return Ok(answer);
+            }
+        }
+        Err(KvError::Timeout)
+    }
+
+    // Runs operations with up to the client's window of them in flight and
+    // returns their results in the order they were answered. The operations
+    // are given seq_nos of the client's own.
+    pub fn replay(&mut self, operations: Vec<Operation>) -> std::result::Result<Vec<KVSResult>, KvError> {
+        for operation in operations {
+            let seq_no = self.take_seq_no();
+            self.node.push(with_seq_no(operation, seq_no));
+        }
+        self.drive()?;
+        Ok(self.node.take_results())
+    }
+
+    fn call(&mut self, operation: Operation) -> std::result::Result<Option<String>, KvError> {
+        Ok(self.call_for_response(operation)?.and_then(|response| response_value(&response)))
+    }
+
+    // The whole response to a single key operation
+    fn call_for_response(&mut self, operation: Operation) -> std::result::Result<Option<Operation>, KvError> {
+        self.node.push(operation);
+        self.drive()?;
+        // the caller has the answer, the node need not keep it
+        self.node.take_results();
+        Ok(self.node.take_responses().pop())
+    }
+
+    // Like call, with the answers of every part of the batch put together
+    fn call_batch(&mut self, operation: Operation, keys: &[String]) -> std::result::Result<Vec<Option<String>>, KvError> {
+        self.node.push(operation);
+        self.drive()?;
+        self.node.take_results();
+        let mut values = HashMap::new();
+        for response in self.node.take_responses() {
+            values.extend(response_values(&response).unwrap_or_default());
+        }
+        Ok(keys.iter().map(|key| values.get(key).cloned().flatten()).collect())
+    }
+
+    // Drives the node over the network until every operation pushed to it is
+    // answered. Fails when no answer comes in for the client's timeout.
+    fn drive(&mut self) -> std::result::Result<(), KvError> {
+        let mut answered = self.node.results().len();
+        let mut answered_ms = self.clock.now_ms();
+        loop {
+            for (dst_pid, operation) in self.node.take_messages() {
+                if let Err(e) = send(&mut self.ctx, &dst_pid, &operation) {
+                    println!("Client failed to send: {:?}", e);
+                }
+            }
+            if self.node.is_done() {
+                return Ok(());
+            }
+            if self.node.has_failed() {
+                self.node.cancel();
+                return Err(KvError::NoShardInfo);
+            }
+            let now_ms = self.clock.now_ms();
+            if self.node.results().len() > answered {
+                answered = self.node.results().len();
+                answered_ms = now_ms;
+            }
+            if self.timeout_ms.is_some_and(|timeout_ms| now_ms >= answered_ms + timeout_ms) {
+                self.node.cancel();
+                return Err(KvError::Timeout);
+            }
+            if let Ok(messages) = recv(&mut self.ctx, CLIENT_TICK_MS) {
+                for (pid, operation) in messages {
+                    self.node.step(pid, operation);
+                }
+            }
+            self.node.tick();
+        }
+    }
+}
+
+// A transaction of a KvClient. Reads go to the store right away and the value
+// seen is kept, writes wait for commit, which applies all of them or none: it
+// fails if a value read changed meanwhile or one of the keys was busy, e.g. in
+// another transaction. Dropped without a commit it leaves no trace.
+pub struct Transaction<'a> {
+    client: &'a mut KvClient,
+    reads: Vec<(String, Option<String>)>,
+    writes: Vec<(String, Option<String>)>,
+}
+
+impl Transaction<'_> {
+    // The value the transaction wrote to key, or the one it first read
+    pub fn get(&mut self, key: &str) -> std::result::Result<Option<String>, KvError> {
+        if let Some((_, value)) = self.writes.iter().rev().find(|(written, _)| written == key) {
+            return Ok(value.clone());
+        }
+        if let Some((_, value)) = self.reads.iter().find(|(read, _)| read == key) {
+            return Ok(value.clone());
+        }
+        let value = self.client.get(key)?;
+        self.reads.push((key.to_string(), value.clone()));
+        Ok(value)
+    }
+
+    pub fn put(&mut self, key: &str, value: &str) {
+        self.writes.push((key.to_string(), Some(value.to_string())));
+    }
+
+    pub fn delete(&mut self, key: &str) {
+        self.writes.push((key.to_string(), None));
+    }
+
+    // Whether the transaction committed. After a KvError::Timeout it may
+    // have or not.
+    pub fn commit(self) -> std::result::Result<bool, KvError> {
+        self.client.commit(self.reads, self.writes)
+    }
+}
@@ -0,0 +1,658 @@
+use crate::hotkeys::pick_hot_keys;
+use crate::kvs::{Operation, Pid, ReplicationMode, ShardId, ShardInfo, ShardLoc};
+use crate::lease::{Clock, LeaseTable, LEASE_MS};
+use crate::raft::RaftNode;
+use crate::ring::{shard_point, vnode_point, HashRing};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+
+// The controller as a state machine, driven like ServerNode. It listens on two
+// pids: clients ask controller_pid_for_clients for the shard assignment, and
+// controller_pid_for_servers sends the servers their PutShardInfo heartbeats.
+// take_messages returns which of the two every message goes out from.
+//
+// With more than one pid in `peers` the controller runs as one replica of a
+// controller group. The replicas agree on the shard assignment through a raft
+// log kept over their client-facing pids, any replica that knows the
+// assignment answers clients and only the leader updates the servers.
+//
+// Usage:
+// let mut info = ShardInfo::new();
+// info.replication = replication;
+// assign_shards_to_servers(&mut info, &server_pids, num_shards, replication_factor, vnodes);
+// let mut node = ControllerNode::new(pid_for_clients, pid_for_servers, server_pids,
+//                                    info, peers, clock, seed, Some(raft_dir));
+// node.tick();                            // every CONTROLLER_TICK_MS
+// node.step(src_pid, operation);          // on every operation either pid received
+// for (src, dst, op) in node.take_messages() { send(...) }
+//
+// Shards move between servers while the cluster runs (see server.rs for the
+// server side). An admin asks for a move with MoveShard, or sends AddServer
+// for a new server and the controller moves shards to it until no live
+// server is the primary of more than one shard above any other. The leader
+// runs one move at a time and switches the shard over with a new ShardInfo
+// once the new primary has every key, or gives up after MOVE_TIMEOUT_MS.
+//
+// With a hot key threshold (see with_hot_key_threshold) the leader adds up
+// the HotKeys the servers report with their heartbeat acks and publishes the
+// keys requested more often than that in ShardInfo.hot_keys, for clients to
+// spread their reads over the replicas (see hotkeys.rs).
+
+// Controller replicas tick their raft node every CONTROLLER_TICK_MS
+pub const CONTROLLER_TICK_MS: u64 = 50;
+// A server that misses this many heartbeats in a row is considered failed
+pub const MAX_MISSED_HEARTBEATS: usize = 2;
+// Time between the end of a heartbeat round and the start of the next one
+pub const HEARTBEAT_PERIOD_MS: u64 = 5000;
+// A round ends when every server answered or after this long
+pub const HEARTBEAT_TIMEOUT_MS: u64 = 2000;
+// A shard move that did not finish in this long is given up
+pub const MOVE_TIMEOUT_MS: u64 = 10000;
+// Logical shards of a cluster and copies of each, unless the controller is told otherwise
+pub const DEFAULT_NUM_SHARDS: usize = 64;
+pub const DEFAULT_REPLICATION_FACTOR: usize = 3;
+
+// Fair shares of total primaries over the servers, where at most total % N
+// servers hold one more than the rest
+struct Shares {
+    counts: HashMap<Pid, usize>,
+    base: usize,
+    extra: usize, // servers that may still take base + 1
+}
+
+impl Shares {
+    fn new(total: usize, num_servers: usize) -> Self {
+        Shares { counts: HashMap::new(), base: total / num_servers, extra: total % num_servers }
+    }
+
+    fn has_room(&self, pid: &Pid) -> bool {
+        let count = self.counts.get(pid).cloned().unwrap_or(0);
+        count < self.base || (count == self.base && self.extra > 0)
+    }
+
+    fn take(&mut self, pid: Pid) {
+        let count = self.counts.entry(pid).or_insert(0);
+        *count += 1;
+        if *count == self.base + 1 && self.extra > 0 {
+            self.extra -= 1;
+        }
+    }
+}
+
+// Places num_shards logical shards on the servers, replication_factor
+// replicas each (capped at the number of servers). Every server gets vnodes
+// points on the hash ring (see ring.rs) and a shard's replicas are the first
+// servers that follow the shard's own point. A server is skipped as primary
+// once it holds its fair share, and secondaries go to whoever holds the fewest,
+// so both stay balanced however the points fall, while most primaries stay
+// where they were when a server joins or leaves.
+pub fn assign_shards_to_servers(
+    info: &mut ShardInfo,
+    server_pids: &[Pid],
+    num_shards: usize,
+    replication_factor: usize,
+    vnodes: usize,
+) {
+    info.locations.clear();
+    info.num_shards = num_shards as u32;
+
+    let mut ring = HashRing::new();
+    for server_pid in server_pids {
+        for vnode in 0..vnodes {
+            ring.insert(vnode_point(*server_pid, vnode), *server_pid);
+        }
+    }
+    let num_secondaries = replication_factor.clamp(1, server_pids.len()) - 1;
+    let candidates: Vec<Vec<Pid>> = (0..num_shards).map(|shard_id| ring.walk(shard_point(shard_id as ShardId))).collect();
+
+    let mut primaries = Shares::new(num_shards, server_pids.len());
+    for (shard_id, servers) in candidates.iter().enumerate() {
+        let primary = *servers.iter().find(|pid| primaries.has_room(pid)).unwrap();
+        primaries.take(primary);
+        info.locations.insert(shard_id as ShardId, ShardLoc { primary, secondaries: Vec::new() });
+    }
+
+    // the secondaries are the servers holding the fewest secondaries so far,
+    // in ring order among equals
+    let mut secondaries: HashMap<Pid, usize> = HashMap::new();
+    for (shard_id, servers) in candidates.iter().enumerate() {
+        let location = info.locations.get_mut(&(shard_id as ShardId)).unwrap();
+        let mut choices: Vec<(usize, usize, Pid)> = servers
+            .iter()
+            .enumerate()
+            .filter(|(_, pid)| **pid != location.primary)
+            .map(|(position, pid)| (secondaries.get(pid).cloned().unwrap_or(0), position, *pid))
+            .collect();
+        choices.sort();
+        for (_, _, pid) in choices.into_iter().take(num_secondaries) {
+            location.secondaries.push(pid);
+            *secondaries.entry(pid).or_insert(0) += 1;
+        }
+    }
+    // a shard's primary can not be its secondary, which may leave the
+    // counts two apart at the end: hand secondaries over until they are not
+    loop {
+        let count = |pid: &Pid| secondaries.get(pid).cloned().unwrap_or(0);
+        let most = *server_pids.iter().max_by_key(|pid| (count(pid), std::cmp::Reverse(**pid))).unwrap();
+        let fewest = *server_pids.iter().min_by_key(|pid| (count(pid), **pid)).unwrap();
+        if count(&most) <= count(&fewest) + 1 {
+            break;
+        }
+        let shard_id = (0..num_shards as ShardId).find(|shard_id| {
+            let location = &info.locations[shard_id];
+            location.secondaries.contains(&most) && location.primary != fewest && !location.secondaries.contains(&fewest)
+        });
+        let location = match shard_id {
+            Some(shard_id) => info.locations.get_mut(&shard_id).unwrap(),
+            None => break,
+        };
+        let index = location.secondaries.iter().position(|pid| *pid == most).unwrap();
+        location.secondaries[index] = fewest;
+        *secondaries.get_mut(&most).unwrap() -= 1;
+        *secondaries.entry(fewest).or_insert(0) += 1;
+    }
+
+    println!("Shard Assignments: {:?}", info.locations);
+}
+
+// Drops a failed server from every shard. A shard it was primary of gets its
+// first secondary as the new primary, which in primary-backup mode has every
+// write that was acknowledged to a client. Returns whether anything changed.
+pub fn remove_failed_server(info: &mut ShardInfo, failed_pid: Pid) -> bool {
+    let mut changed = false;
+    for location in info.locations.values_mut() {
+        if let Some(index) = location.secondaries.iter().position(|pid| *pid == failed_pid) {
+            location.secondaries.remove(index);
+            changed = true;
+        }
+        if location.primary == failed_pid && !location.secondaries.is_empty() {
+            location.primary = location.secondaries.remove(0);
+            changed = true;
+        }
+    }
+    changed
+}
+
+// Whether the log already holds a shard assignment, so a new leader does not
+// propose the initial one again.
+fn has_shard_info(node: &RaftNode) -> bool {
+    (1..=node.last_index()).any(|index| match node.entry(index) {
+        Some(entry) => matches!(entry.command, Some(Operation::PutShardInfo(_))),
+        None => false,
+    })
+}
+
+// A shard move in flight
+struct Move {
+    shard_id: ShardId,
+    from: Pid,
+    to: Pid,
+    epoch: u64,
+    started_ms: u64,
+}
+
+// One round of heartbeats
+struct Round {
+    info: ShardInfo,   // what the servers were sent
+    pending: Vec<Pid>, // sorted, servers that have not answered yet
+    started_ms: u64,
+}
+
+pub struct ControllerNode<C: Clock + Clone> {
+    pid_for_clients: Pid,
+    pid_for_servers: Pid,
+    server_pids: Vec<Pid>,
+    initial_info: ShardInfo,
+    shard_info: ShardInfo,
+    node: Option<RaftNode>,
+    clock: C,
+
+    leases: LeaseTable<C>,
+    missed_heartbeats: HashMap<Pid, usize>,
+    failed_servers: BTreeSet<Pid>,
+    was_leader: bool,
+    round: Option<Round>,
+    next_round_ms: u64,
+    resend: bool, // the shard info changed, heartbeat right away
+    proposed_epoch: u64,
+
+    requested_moves: VecDeque<(ShardId, Pid)>,
+    balancing: bool,
+    moving: Option<Move>,
+
+    hot_key_rps: u64, // requests per second that make a key hot, 0 for no hot keys
+    hot_key_rates: Option<BTreeMap<String, u64>>, // requests per second of every key reported since the last round ended
+
+    outbox: Vec<(Pid, Pid, Operation)>,
+}
+
+impl<C: Clock + Clone> ControllerNode<C> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        pid_for_clients: Pid,
+        pid_for_servers: Pid,
+        server_pids: Vec<Pid>,
+        initial_info: ShardInfo,
+        peers: Vec<Pid>,
+        clock: C,
+        seed: u64,
+        raft_dir: Option<String>,
+    ) -> Self {
+        let mut initial_info = initial_info;
+        if initial_info.replication != ReplicationMode::Raft {
+            // raft groups elect their own leaders, every other mode relies on leases
+            initial_info.lease_ms = LEASE_MS;
+        }
+
+        let mut node = None;
+        let mut shard_info = ShardInfo::new();
+        if peers.len() > 1 {
+            let path = raft_dir.map(|raft_dir| format!("{}/controller_{}", raft_dir, pid_for_clients));
+            node = Some(RaftNode::new(pid_for_clients, peers, seed, path));
+        } else {
+            shard_info = initial_info.clone();
+        }
+
+        ControllerNode {
+            pid_for_clients,
+            pid_for_servers,
+            server_pids,
+            initial_info,
+            shard_info,
+            node,
+            leases: LeaseTable::new(clock.clone()),
+            clock,
+            missed_heartbeats: HashMap::new(),
+            failed_servers: BTreeSet::new(),
+            was_leader: false,
+            round: None,
+            next_round_ms: 0,
+            resend: false,
+            proposed_epoch: 0,
+            requested_moves: VecDeque::new(),
+            balancing: false,
+            moving: None,
+            hot_key_rps: 0,
+            hot_key_rates: None,
+            outbox: Vec::new(),
+        }
+    }
+
+    // Publishes the keys requested more than rps times per second over the
+    // servers, none with 0
+    pub fn with_hot_key_threshold(mut self, rps: u64) -> Self {
+        self.hot_key_rps = rps;
+        self
+    }
+
+    pub fn pid_for_clients(&self) -> Pid {
+        self.pid_for_clients
+    }
+
+    pub fn pid_for_servers(&self) -> Pid {
+        self.pid_for_servers
+    }
+
+    pub fn shard_info(&self) -> &ShardInfo {
+        &self.shard_info
+    }
+
+    pub fn is_leader(&self) -> bool {
+        match &self.node {
+            Some(node) => node.is_leader(),
+            None => true,
+        }
+    }
+
+    // (src, dst, operation), src being one of the controller's two pids
+    pub fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    pub fn tick(&mut self) {
+        if let Some(node) = self.node.as_mut() {
+            node.tick();
+            self.drive_raft();
+        }
+        self.monitor();
+    }
+
+    pub fn step(&mut self, pid: Pid, operation: Operation) {
+        match operation {
+            Operation::GetShardInfo() => {
+                if self.shard_info.locations.is_empty() {
+                    // the group has not agreed on an assignment yet,
+                    // the client tries another replica
+                    return;
+                }
+                let message = Operation::GetShardInfoRes(self.shard_info.clone());
+                self.outbox.push((self.pid_for_clients, pid, message));
+            }
+            Operation::ControllerRaft(raft_msg) => {
+                if let Some(node) = self.node.as_mut() {
+                    node.step(pid, raft_msg);
+                    self.drive_raft();
+                }
+            }
+            Operation::PutShardInfoRes() => {
+                // late acks of an earlier round are not pending anymore
+                if let Some(round) = self.round.as_mut() {
+                    if let Ok(index) = round.pending.binary_search(&pid) {
+                        round.pending.remove(index);
+                    }
+                }
+                self.missed_heartbeats.insert(pid, 0);
+                self.monitor();
+            }
+            // the workers of a server report on their own, a report that
+            // comes after the round ended counts for the next one
+            Operation::HotKeys(rates) => {
+                let total = self.hot_key_rates.get_or_insert_with(BTreeMap::new);
+                for (key, rate) in rates {
+                    *total.entry(key).or_insert(0) += rate;
+                }
+            }
+            Operation::AddServer(server_pid) => {
+                if !self.server_pids.contains(&server_pid) {
+                    self.server_pids.push(server_pid);
+                }
+                self.balancing = true;
+                // tell the new server the shard info right away
+                self.resend = true;
+            }
+            Operation::MoveShard(shard_id, to) => {
+                if !self.server_pids.contains(&to) {
+                    self.server_pids.push(to);
+                }
+                self.requested_moves.push_back((shard_id, to));
+                self.resend = true;
+            }
+            Operation::MigrateShardRes(shard_id, epoch) => self.finish_move(shard_id, epoch),
+            _ => {
+                println!("Unexpected operation received at controller, id={}, op={:?}", pid, operation);
+            }
+        }
+    }
+
+    // Sends what the controller's raft node has queued and installs every shard
+    // assignment the group committed. The leader proposes the initial assignment
+    // if nobody did so far.
+    fn drive_raft(&mut self) {
+        let node = self.node.as_mut().unwrap();
+        if node.is_leader() && !has_shard_info(node) {
+            node.propose(self.pid_for_clients, Operation::PutShardInfo(self.initial_info.clone()));
+        }
+        for (dst_pid, msg) in node.take_messages() {
+            self.outbox.push((self.pid_for_clients, dst_pid, Operation::ControllerRaft(msg)));
+        }
+        for (_index, entry) in node.take_committed() {
+            if let Some(Operation::PutShardInfo(info)) = entry.command {
+                self.shard_info = info;
+                self.resend = true;
+            }
+        }
+    }
+
+    // Heartbeats the servers every HEARTBEAT_PERIOD_MS with a PutShardInfo.
+    fn monitor(&mut self) {
+        // Only the leader of a controller group talks to the servers, a
+        // follower checks every tick so it takes over soon after an election
+        if !self.is_leader() || self.shard_info.locations.is_empty() {
+            self.was_leader = false;
+            self.round = None;
+            return;
+        }
+        let now_ms = self.clock.now_ms();
+        match &self.round {
+            Some(round) if round.pending.is_empty() || now_ms >= round.started_ms + HEARTBEAT_TIMEOUT_MS => {
+                self.finish_round();
+                self.next_round_ms = now_ms + HEARTBEAT_PERIOD_MS;
+            }
+            Some(_) => {}
+            None if now_ms >= self.next_round_ms || !self.was_leader || self.resend => self.start_round(),
+            None => {}
+        }
+        self.advance_moves();
+    }
+
+    // Every server we heartbeat: the ones we started with or were told about,
+    // and any the shard info names
+    fn servers(&self) -> Vec<Pid> {
+        let mut servers: BTreeSet<Pid> = self.server_pids.iter().cloned().collect();
+        for location in self.shard_info.locations.values() {
+            servers.insert(location.primary);
+            servers.extend(location.secondaries.iter().cloned());
+        }
+        servers.into_iter().collect()
+    }
+
+    // Installs a new shard assignment, through the log of a controller group
+    fn change_shard_info(&mut self, mut info: ShardInfo) {
+        info.epoch = self.shard_info.epoch + 1;
+        self.proposed_epoch = info.epoch;
+        println!("Shard Assignments: {:?}", info.locations);
+        match self.node.as_mut() {
+            Some(node) => {
+                if node.is_leader() {
+                    node.propose(self.pid_for_clients, Operation::PutShardInfo(info));
+                    self.drive_raft();
+                }
+            }
+            None => {
+                self.shard_info = info;
+                self.resend = true;
+            }
+        }
+    }
+
+    // Starts the next move once the last one is over
+    fn advance_moves(&mut self) {
+        if self.shard_info.replication == ReplicationMode::Raft {
+            if self.balancing || !self.requested_moves.is_empty() {
+                println!("Moving shards is not supported in Raft mode");
+            }
+            self.balancing = false;
+            self.requested_moves.clear();
+            return;
+        }
+        if let Some(shard_move) = &self.moving {
+            let failed = self.failed_servers.contains(&shard_move.from) || self.failed_servers.contains(&shard_move.to);
+            if failed || self.clock.now_ms() >= shard_move.started_ms + MOVE_TIMEOUT_MS {
+                println!("Giving up moving shard {} to {}", shard_move.shard_id, shard_move.to);
+                self.moving = None;
+                // a new epoch unfreezes the shard at its primary
+                self.change_shard_info(self.shard_info.clone());
+            }
+            return;
+        }
+        if self.shard_info.epoch < self.proposed_epoch {
+            // the group has not committed the last change yet
+            return;
+        }
+
+        let next = match self.requested_moves.pop_front() {
+            Some(next) => Some(next),
+            None if self.balancing && !self.every_server_answered() => {
+                // balance once the new server has answered, not around it
+                return;
+            }
+            None if self.balancing => self.balancing_move(),
+            None => None,
+        };
+        let (shard_id, to) = match next {
+            Some(next) => next,
+            None => {
+                self.balancing = false;
+                return;
+            }
+        };
+        let from = match self.shard_info.locations.get(&shard_id) {
+            Some(location) => location.primary,
+            None => {
+                println!("No shard {} to move", shard_id);
+                return;
+            }
+        };
+        if from == to || self.missed_heartbeats.get(&to) != Some(&0) {
+            // nothing to do, or the new primary has not answered a heartbeat yet
+            if from != to {
+                self.requested_moves.push_front((shard_id, to));
+            }
+            return;
+        }
+        println!("Moving shard {} from {} to {}", shard_id, from, to);
+        let epoch = self.shard_info.epoch;
+        self.outbox.push((self.pid_for_servers, from, Operation::MigrateShard(shard_id, to, epoch)));
+        self.moving = Some(Move {
+            shard_id,
+            from,
+            to,
+            epoch,
+            started_ms: self.clock.now_ms(),
+        });
+    }
+
+    fn every_server_answered(&self) -> bool {
+        self.servers()
+            .iter()
+            .all(|pid| self.failed_servers.contains(pid) || self.missed_heartbeats.get(pid) == Some(&0))
+    }
+
+    // A shard of the live server with the most primaries for the one with the
+    // fewest, none once they differ by at most one
+    fn balancing_move(&self) -> Option<(ShardId, Pid)> {
+        let mut primaries: BTreeMap<Pid, Vec<ShardId>> = BTreeMap::new();
+        for server_pid in self.servers() {
+            if !self.failed_servers.contains(&server_pid) && self.missed_heartbeats.get(&server_pid) == Some(&0) {
+                primaries.insert(server_pid, Vec::new());
+            }
+        }
+        for (shard_id, location) in &self.shard_info.locations {
+            if let Some(shards) = primaries.get_mut(&location.primary) {
+                shards.push(*shard_id);
+            }
+        }
+        let (_, shards) = primaries.iter().max_by_key(|(pid, shards)| (shards.len(), std::cmp::Reverse(**pid)))?;
+        let (idlest, fewest) = primaries.iter().min_by_key(|(pid, shards)| (shards.len(), **pid))?;
+        if shards.len() <= fewest.len() + 1 {
+            return None;
+        }
+        Some((*shards.iter().min().unwrap(), *idlest))
+    }
+
+    // The new primary has every key of the shard and the old one froze it:
+    // switch the shard over. The old primary stays a secondary.
+    fn finish_move(&mut self, shard_id: ShardId, epoch: u64) {
+        let shard_move = match self.moving.take() {
+            Some(shard_move) if shard_move.shard_id == shard_id && shard_move.epoch == epoch => shard_move,
+            other => {
+                self.moving = other;
+                return;
+            }
+        };
+        let mut new_info = self.shard_info.clone();
+        let location = new_info.locations.get_mut(&shard_id).unwrap();
+        // the shard keeps its number of replicas, a secondary that was not
+        // the new primary makes room for the old one
+        let num_secondaries = location.secondaries.len();
+        location.secondaries.retain(|pid| *pid != shard_move.to);
+        location.secondaries.insert(0, shard_move.from);
+        location.secondaries.truncate(num_secondaries);
+        location.primary = shard_move.to;
+        println!("Moved shard {} from {} to {}", shard_id, shard_move.from, shard_move.to);
+        self.change_shard_info(new_info);
+    }
+
+    fn start_round(&mut self) {
+        let info = self.shard_info.clone();
+        let servers = self.servers();
+        self.resend = false;
+        if !self.was_leader {
+            // leases granted by the previous leader may still be running
+            for id in &servers {
+                self.leases.grant(*id, info.lease_ms);
+            }
+            self.was_leader = true;
+            if self.node.is_some() {
+                // the previous leader may have left a shard frozen for a move
+                // we know nothing about, a new epoch ends it
+                self.change_shard_info(info.clone());
+            }
+        }
+
+        // Phase 1: Sending PutShardInfo
+        let mut pending: Vec<Pid> = Vec::new();
+        for id in &servers {
+            if self.failed_servers.contains(id) {
+                continue;
+            }
+            self.leases.grant(*id, info.lease_ms);
+            self.outbox.push((self.pid_for_servers, *id, Operation::PutShardInfo(info.clone())));
+            pending.push(*id);
+        }
+
+        // Phase 2: Collecting PutShardInfoRes in step
+        pending.sort();
+        self.round = Some(Round {
+            info,
+            pending,
+            started_ms: self.clock.now_ms(),
+        });
+    }
+
+    // Phase 3: Replace failed servers once their leases are over, so a primary
+    // that is only cut off from us has stopped serving reads
+    fn finish_round(&mut self) {
+        let round = self.round.take().unwrap();
+        let rates = self.hot_key_rates.take();
+        if !round.pending.is_empty() {
+            println!("Timeout! Pending IDs: {:?}", round.pending);
+        }
+        if round.info.lease_ms == 0 {
+            return;
+        }
+
+        for id in &round.pending {
+            let missed = self.missed_heartbeats.entry(*id).or_insert(0);
+            *missed += 1;
+            if *missed >= MAX_MISSED_HEARTBEATS && self.failed_servers.insert(*id) {
+                println!("Server {} failed", id);
+            }
+        }
+
+        let mut new_info = self.shard_info.clone();
+        let mut changed = false;
+        for id in &self.failed_servers {
+            if self.leases.expired(*id) {
+                changed |= remove_failed_server(&mut new_info, *id);
+            }
+        }
+        if changed {
+            self.change_shard_info(new_info);
+        }
+        if let Some(rates) = rates {
+            self.update_hot_keys(&rates);
+        }
+    }
+
+    // Publishes the keys the last round's reports found hot. Only the
+    // secondaries of PrimaryBackup shards serve reads. A new epoch ends a
+    // shard move, so the hot keys wait for it like for a change the group
+    // did not commit yet.
+    fn update_hot_keys(&mut self, rates: &BTreeMap<String, u64>) {
+        if self.hot_key_rps == 0
+            || self.shard_info.replication != ReplicationMode::PrimaryBackup
+            || self.moving.is_some()
+            || self.shard_info.epoch < self.proposed_epoch
+        {
+            return;
+        }
+        let hot_keys = pick_hot_keys(&self.shard_info.hot_keys, rates, self.hot_key_rps);
+        if hot_keys == self.shard_info.hot_keys {
+            return;
+        }
+        println!("Hot keys: {:?}", hot_keys);
+        let mut new_info = self.shard_info.clone();
+        new_info.hot_keys = hot_keys;
+        self.change_shard_info(new_info);
+    }
+}
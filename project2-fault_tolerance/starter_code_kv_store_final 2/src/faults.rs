@@ -0,0 +1,207 @@
+use crate::kvs::Pid;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::env;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+
+// Fault injection for tests. send and recv in network.rs ask the process'
+// FaultInjector what to do with every message. It is configured with one
+// command per line (or separated by ';', handy in an environment variable):
+//
+// seed 42                             # RNG seed, failures replay with the same one
+// drop prob=0.1 src=3 dst=4 op=Put    # every key is optional, prob defaults to 1
+// delay ms=200 op=PutShardInfo
+// duplicate prob=0.05
+// reorder prob=0.2 dst=5              # held back until the next message to dst
+// partition 2,3 4,5                   # nothing gets between the two groups
+// heal                                # removes every partition
+// clear                               # removes every rule and partition
+//
+// The binaries read the configuration from `--faults <file>` or the
+// KV_STORE_FAULTS variable, and a process started with --allow-fault-control
+// also applies the text of an Operation::FaultControl a cluster member sends
+// it, which is how a test partitions and heals a running cluster. Rules act where a message is sent; the first
+// matching rule whose coin flip succeeds decides. Partitions act on both
+// sides, so it is enough for one of the two processes to know about them.
+
+pub const FAULTS_ENV: &str = "KV_STORE_FAULTS";
+pub const DEFAULT_FAULT_SEED: u64 = 0;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FaultAction {
+    Drop,
+    Delay(u64),
+    Duplicate,
+    Reorder,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FaultRule {
+    pub action: FaultAction,
+    pub probability: f64,
+    pub src: Option<Pid>,
+    pub dst: Option<Pid>,
+    pub operation: Option<String>, // Operation variant name, e.g. Put
+}
+
+impl FaultRule {
+    fn matches(&self, src: Pid, dst: Pid, operation: &str) -> bool {
+        self.src.is_none_or(|pid| pid == src)
+            && self.dst.is_none_or(|pid| pid == dst)
+            && self.operation.as_ref().is_none_or(|name| name == operation)
+    }
+}
+
+// What happens to one outgoing message
+#[derive(Clone, Debug, PartialEq)]
+pub enum Verdict {
+    Deliver,
+    Drop,
+    Delay(u64),
+    Duplicate,
+    Reorder,
+}
+
+pub struct FaultInjector {
+    rng: StdRng,
+    rules: Vec<FaultRule>,
+    partitions: Vec<(Vec<Pid>, Vec<Pid>)>,
+}
+
+impl FaultInjector {
+    pub fn new(seed: u64) -> FaultInjector {
+        FaultInjector {
+            rng: StdRng::seed_from_u64(seed),
+            rules: Vec::new(),
+            partitions: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<FaultInjector> {
+        let mut injector = FaultInjector::new(DEFAULT_FAULT_SEED);
+        injector.apply(text)?;
+        Ok(injector)
+    }
+
+    pub fn load(path: &str) -> Result<FaultInjector> {
+        let text = fs::read_to_string(path)?;
+        FaultInjector::parse(&text)
+    }
+
+    // The configuration in KV_STORE_FAULTS, if it is set
+    pub fn from_env() -> Result<Option<FaultInjector>> {
+        match env::var(FAULTS_ENV) {
+            Ok(text) => Ok(Some(FaultInjector::parse(&text)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    // Whether no fault can happen, so callers can skip the bookkeeping
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty() && self.partitions.is_empty()
+    }
+
+    // Runs configuration commands against the current state. Nothing is
+    // changed when a line is invalid.
+    pub fn apply(&mut self, text: &str) -> Result<()> {
+        let mut rng = None;
+        let mut rules = self.rules.clone();
+        let mut partitions = self.partitions.clone();
+        for line in text.split(['\n', ';']) {
+            let line = match line.find('#') {
+                Some(index) => &line[..index],
+                None => line,
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.is_empty() {
+                continue;
+            }
+            let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("faults: {}: {}", what, line.trim()));
+            match fields[0] {
+                "seed" if fields.len() == 2 => {
+                    let seed = fields[1].parse().map_err(|_| invalid("bad seed"))?;
+                    rng = Some(StdRng::seed_from_u64(seed));
+                }
+                "partition" if fields.len() == 3 => {
+                    let side_a = parse_pids(fields[1]).ok_or_else(|| invalid("bad pid list"))?;
+                    let side_b = parse_pids(fields[2]).ok_or_else(|| invalid("bad pid list"))?;
+                    partitions.push((side_a, side_b));
+                }
+                "heal" if fields.len() == 1 => partitions.clear(),
+                "clear" if fields.len() == 1 => {
+                    rules.clear();
+                    partitions.clear();
+                }
+                "drop" | "delay" | "duplicate" | "reorder" => {
+                    rules.push(parse_rule(&fields).ok_or_else(|| invalid("bad rule"))?);
+                }
+                _ => return Err(invalid("unknown command")),
+            }
+        }
+        if let Some(rng) = rng {
+            self.rng = rng;
+        }
+        self.rules = rules;
+        self.partitions = partitions;
+        Ok(())
+    }
+
+    pub fn is_partitioned(&self, a: Pid, b: Pid) -> bool {
+        self.partitions.iter().any(|(side_a, side_b)| {
+            (side_a.contains(&a) && side_b.contains(&b)) || (side_a.contains(&b) && side_b.contains(&a))
+        })
+    }
+
+    pub fn decide(&mut self, src: Pid, dst: Pid, operation: &str) -> Verdict {
+        if self.is_partitioned(src, dst) {
+            return Verdict::Drop;
+        }
+        for rule in self.rules.iter() {
+            if !rule.matches(src, dst, operation) || !self.rng.gen_bool(rule.probability) {
+                continue;
+            }
+            return match rule.action {
+                FaultAction::Drop => Verdict::Drop,
+                FaultAction::Delay(ms) => Verdict::Delay(ms),
+                FaultAction::Duplicate => Verdict::Duplicate,
+                FaultAction::Reorder => Verdict::Reorder,
+            };
+        }
+        Verdict::Deliver
+    }
+}
+
+fn parse_pids(list: &str) -> Option<Vec<Pid>> {
+    list.split(',').map(|pid| pid.trim().parse().ok()).collect()
+}
+
+fn parse_rule(fields: &[&str]) -> Option<FaultRule> {
+    let mut rule = FaultRule {
+        action: FaultAction::Drop,
+        probability: 1.0,
+        src: None,
+        dst: None,
+        operation: None,
+    };
+    let mut delay_ms = None;
+    for field in &fields[1..] {
+        let (key, value) = field.split_once('=')?;
+        match key {
+            "prob" => rule.probability = value.parse().ok().filter(|p| (0.0..=1.0).contains(p))?,
+            "src" => rule.src = Some(value.parse().ok()?),
+            "dst" => rule.dst = Some(value.parse().ok()?),
+            "op" => rule.operation = Some(value.to_string()),
+            "ms" => delay_ms = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    rule.action = match (fields[0], delay_ms) {
+        ("drop", None) => FaultAction::Drop,
+        ("delay", Some(ms)) => FaultAction::Delay(ms),
+        ("duplicate", None) => FaultAction::Duplicate,
+        ("reorder", None) => FaultAction::Reorder,
+        _ => return None,
+    };
+    Some(rule)
+}
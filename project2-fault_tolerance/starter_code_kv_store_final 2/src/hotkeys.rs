@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, HashMap};
+
+// Finding the keys that get most of the requests, and spreading their reads.
+//
+// Every worker of a server counts the keys it serves with a space-saving
+// counter (Metwally et al.): at most HOT_KEY_CAPACITY keys are counted, and a
+// key that is not one of them takes over the slot of the least counted one,
+// starting from that one's count. A key that gets more than 1/capacity of the
+// requests is always counted, and its count is never too low. With the ack of
+// a heartbeat (see server.rs) a worker reports the HOT_KEYS_REPORTED keys it
+// counted most, in requests per second, and starts counting afresh.
+//
+// The controller adds up the reports of a round and publishes the keys above
+// its threshold in ShardInfo.hot_keys (see controller.rs). A client reading
+// hot keys from every replica (see client.rs) sends their Gets to the
+// replicas of the shard in turns instead of all to the primary.
+//
+// Usage:
+// let mut tracker = HotKeyTracker::new(now_ms);
+// tracker.record(&key);                     // on every request served
+// if let Some(rates) = tracker.report(now_ms) { send(HotKeys(rates)) }
+
+// Keys a worker counts at once
+pub const HOT_KEY_CAPACITY: usize = 64;
+// Keys a worker reports with a heartbeat
+pub const HOT_KEYS_REPORTED: usize = 8;
+// A report covers at least this long, heartbeats that come sooner (after a
+// change of the ShardInfo) go without one
+pub const HOT_KEY_MIN_WINDOW_MS: u64 = 1000;
+// Keys the controller publishes as hot at most
+pub const MAX_HOT_KEYS: usize = 16;
+
+// Approximate request counts of the most requested keys in fixed space
+pub struct SpaceSaving {
+    capacity: usize,
+    counts: HashMap<String, u64>,
+}
+
+impl SpaceSaving {
+    pub fn new(capacity: usize) -> Self {
+        SpaceSaving {
+            capacity: capacity.max(1),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, key: &str) {
+        if let Some(count) = self.counts.get_mut(key) {
+            *count += 1;
+            return;
+        }
+        if self.counts.len() < self.capacity {
+            self.counts.insert(key.to_string(), 1);
+            return;
+        }
+        // the new key inherits the count of the one it replaces, the most it
+        // could have had while it was not counted
+        let (least, count) = self
+            .counts
+            .iter()
+            .min_by(|a, b| a.1.cmp(b.1).then_with(|| a.0.cmp(b.0)))
+            .map(|(key, count)| (key.clone(), *count))
+            .unwrap();
+        self.counts.remove(&least);
+        self.counts.insert(key.to_string(), count + 1);
+    }
+
+    // The n most counted keys, the most counted first
+    pub fn top(&self, n: usize) -> Vec<(String, u64)> {
+        let mut top: Vec<(String, u64)> = self.counts.iter().map(|(key, count)| (key.clone(), *count)).collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(n);
+        top
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.clear();
+    }
+}
+
+// The requests a worker served since its last report
+pub struct HotKeyTracker {
+    counter: SpaceSaving,
+    since_ms: u64,
+}
+
+impl HotKeyTracker {
+    pub fn new(now_ms: u64) -> Self {
+        HotKeyTracker {
+            counter: SpaceSaving::new(HOT_KEY_CAPACITY),
+            since_ms: now_ms,
+        }
+    }
+
+    pub fn record(&mut self, key: &str) {
+        self.counter.record(key);
+    }
+
+    // (key, requests per second) of the most requested keys since the last
+    // report, none if that was less than HOT_KEY_MIN_WINDOW_MS ago
+    pub fn report(&mut self, now_ms: u64) -> Option<Vec<(String, u64)>> {
+        let window_ms = now_ms.saturating_sub(self.since_ms);
+        if window_ms < HOT_KEY_MIN_WINDOW_MS {
+            return None;
+        }
+        let rates = self
+            .counter
+            .top(HOT_KEYS_REPORTED)
+            .into_iter()
+            .map(|(key, count)| (key, count * 1000 / window_ms))
+            .collect();
+        self.counter.clear();
+        self.since_ms = now_ms;
+        Some(rates)
+    }
+}
+
+// The hot keys after a round of reports, given the requests per second of
+// every key reported (summed over the servers). A key becomes hot at
+// threshold_rps and stays hot down to half of it, so a key right at the
+// threshold does not flip with every round. The hottest MAX_HOT_KEYS, sorted.
+pub fn pick_hot_keys(current: &[String], rates: &BTreeMap<String, u64>, threshold_rps: u64) -> Vec<String> {
+    let mut hot: Vec<(u64, &String)> = rates
+        .iter()
+        .filter(|(key, rate)| **rate >= threshold_rps || (current.contains(key) && **rate >= threshold_rps / 2))
+        .map(|(key, rate)| (*rate, key))
+        .collect();
+    hot.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    let mut hot: Vec<String> = hot.into_iter().take(MAX_HOT_KEYS).map(|(_, key)| key.clone()).collect();
+    hot.sort();
+    hot
+}
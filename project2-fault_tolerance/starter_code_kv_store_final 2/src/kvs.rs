@@ -1,14 +1,16 @@
 extern crate serde_json;
 
+use crate::raft::RaftMessage;
+use crate::ring::stable_hash;
+use crate::wire::{ServerMetricsWire, ShardInfoWire, TxnRecordWire};
 use serde::{Deserialize, Serialize};
-use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-use std::convert::TryInto;
-use std::hash::{Hash, Hasher};
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 
 pub type Pid = u32;
 pub type ShardId = u32;
+// (client_pid, seq_no) of the TxnCommit that started a transaction
+pub type TxnId = (Pid, usize);
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShardLoc {
@@ -16,9 +18,30 @@ pub struct ShardLoc {
     pub secondaries: Vec<Pid>,
 }
 
+// How the primary of a shard replicates writes to its secondaries.
+// PrimaryBackup waits for every secondary before answering the client,
+// Quorum only waits for `write` of the N replicas (the primary included) and
+// serves reads from the newest of `read` replicas. Raft runs every shard's
+// replicas as a Raft group (see raft.rs): requests go through the log of the
+// group's elected leader, which may not be the primary listed here.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReplicationMode {
+    PrimaryBackup,
+    Quorum { write: usize, read: usize },
+    Raft,
+}
+
+// Where every shard lives, as the controller tells clients and servers. Its
+// shape on the wire depends on the wire version (see wire.rs).
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "ShardInfoWire", into = "ShardInfoWire")]
 pub struct ShardInfo {
     pub locations: HashMap<ShardId, ShardLoc>,
+    pub replication: ReplicationMode,
+    pub lease_ms: u64, // primary lease granted by each PutShardInfo, 0 disables leases (see lease.rs)
+    pub num_shards: u32, // keys are hashed onto this many logical shards, fixed for the cluster's life
+    pub epoch: u64,     // bumped by the controller on every change, servers ignore older ones
+    pub hot_keys: Vec<String>, // sorted, keys most requests go to, whose reads clients may spread over the replicas (see hotkeys.rs)
 }
 
 impl ShardInfo {
@@ -26,8 +49,50 @@ impl ShardInfo {
     pub fn new() -> Self {
         ShardInfo {
             locations: HashMap::new(),
+            replication: ReplicationMode::PrimaryBackup,
+            lease_ms: 0,
+            num_shards: 0,
+            epoch: 0,
+            hot_keys: Vec::new(),
         }
     }
+
+    // Whether the controller found the key hot
+    pub fn is_hot(&self, key: &str) -> bool {
+        self.hot_keys.binary_search_by(|hot_key| hot_key.as_str().cmp(key)).is_ok()
+    }
+
+    // The shard a key belongs to
+    pub fn shard_of(&self, key: &str) -> ShardId {
+        assert!(self.num_shards > 0, "shard info has no shards");
+        (stable_hash(key.as_bytes()) % self.num_shards as u64) as ShardId
+    }
+
+    // Every server holding a copy of the shard, the primary first
+    pub fn replicas(&self, shard_id: ShardId) -> Vec<Pid> {
+        let location = &self.locations[&shard_id];
+        let mut replicas = vec![location.primary];
+        replicas.extend(location.secondaries.iter().cloned());
+        replicas
+    }
+}
+
+// What a client accepts from a secondary it reads from (see ReadReplica)
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct ReadBounds {
+    pub max_staleness_ms: Option<u64>, // how long ago the copy may last have been in sync with the primary, none for any age
+    pub min_version: u64,              // the client's session token for the shard, 0 for none
+}
+
+// What a primary tells its secondaries about a transaction (see server.rs),
+// so a secondary that takes over knows as much as the primary did. Its shape
+// on the wire depends on the wire version (see wire.rs).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(from = "TxnRecordWire", into = "TxnRecordWire")]
+pub enum TxnRecord {
+    Prepared(ShardId, Vec<(String, Option<String>)>, Vec<String>), // (coordinator shard, writes, keys locked); the participant voted to commit
+    Decided(bool, Vec<ShardId>), // (commit, parts); the coordinator's decision, true to commit, and the shards involved
+    Applied(Vec<(String, Option<String>)>, u64), // (writes, version); the participant is done, no writes if it aborted
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -54,13 +119,105 @@ pub enum Operation {
     // server -> controller
     PutShardInfoRes(),
 
+    // controller -> controller
+    ControllerRaft(RaftMessage), // raft message between the replicas of a controller group
+
     // server -> server
-    Replicate(String, Option<String>), // (key, value); none value indicate delete
-    ReplicateRes(String, Option<String>), // (key, old_value);
+    Replicate(String, Option<String>, u64), // (key, value, version); none value indicate delete
+    ReplicateRes(String, Option<String>, u64), // (key, old_value, version);
+    ReadVersion(String, usize), // (key, read_id); quorum read of a secondary's copy
+    ReadVersionRes(String, Option<String>, u64, usize), // (key, value, version, read_id)
+    Raft(ShardId, RaftMessage), // (shard_id, message) between members of a shard's raft group
+    RaftForward(Pid, Box<Operation>), // (client_pid, operation); a follower hands a client request to its leader
 
     // test -> server
     Snapshot(),
     SnapshotRes(KVSSnapshot),
+
+    // admin -> controller
+    AddServer(Pid), // a new server joins, the controller moves shards to it until primaries are balanced
+    MoveShard(ShardId, Pid), // (shard_id, new_primary)
+
+    // controller -> server, shard migration (see server.rs)
+    MigrateShard(ShardId, Pid, u64), // (shard_id, new_primary, epoch); the primary starts handing the shard over
+    MigrateShardRes(ShardId, u64), // (shard_id, epoch); the new primary has every key, the shard is frozen
+
+    // server -> server, shard migration
+    ShardData(ShardId, u64, Vec<(String, Option<String>, u64)>), // (shard_id, epoch, [(key, value, version)]); none value is a deleted key
+    ShardDataDone(ShardId, u64, usize), // (shard_id, epoch, entries sent so far)
+    ShardDataRes(ShardId, u64, usize), // (shard_id, epoch, entries applied)
+    Forward(Pid, Box<Operation>), // (client_pid, operation); a server that is not the primary hands a client request to it
+
+    // test -> any process
+    FaultControl(String), // fault injection commands, applied by recv and never handed to the caller
+
+    // test -> server
+    Metrics(),
+    MetricsRes(ServerMetrics),
+
+    // client -> server, reads from secondaries (see server.rs)
+    Session(Box<Operation>), // a Get, Put or Delete whose response should carry the key's version
+    ReadReplica(String, usize, ReadBounds), // (key, seq_no, bounds); a Get for any replica of the shard
+
+    // server -> client
+    SessionRes(Box<Operation>, u64), // (response, version); version is the client's new session token for the shard
+    ReadRefused(usize), // (seq_no); the secondary's copy is out of bounds, the client asks the primary
+
+    // server -> server
+    ShardVersions(Vec<(ShardId, u64)>), // [(shard_id, version)]; the primary tells a secondary it has every write of the shard up to version
+
+    // client -> server, batches of keys that are all on one shard (see client.rs)
+    MultiGet(Vec<String>, usize), // (keys, seq_no)
+    MultiPut(Vec<(String, String)>, usize), // ([(key, value)], seq_no)
+    MultiDelete(Vec<String>, usize), // (keys, seq_no)
+
+    // server -> client
+    MultiGetRes(Vec<(String, Option<String>)>, usize), // ([(key, value)], seq_no)
+    MultiPutRes(Vec<(String, Option<String>)>, usize), // ([(key, old_value)], seq_no)
+    MultiDeleteRes(Vec<(String, Option<String>)>, usize), // ([(key, old_value)], seq_no)
+
+    // server -> server, batches
+    ReplicateBatch(ShardId, Vec<(String, Option<String>)>, u64), // (shard_id, [(key, value)], version); every write of a batch, applied together
+    ReplicateBatchRes(ShardId, u64), // (shard_id, version)
+    ReadVersions(ShardId, Vec<String>, usize), // (shard_id, keys, read_id); quorum read of a MultiGet
+    ReadVersionsRes(ShardId, Vec<(String, Option<String>, u64)>, usize), // (shard_id, [(key, value, version)], read_id)
+
+    // client -> server, conditional writes run on the primary (see server.rs)
+    Cas(String, Option<String>, Option<String>, usize), // (key, expected, new, seq_no); none expected for an absent key, none new deletes it
+    PutIfAbsent(String, String, usize), // (key, value, seq_no)
+    Incr(String, i64, usize), // (key, delta, seq_no); an absent key counts as 0
+
+    // server -> client
+    CasRes(bool, Option<String>, usize), // (swapped, old_value, seq_no); old_value is the value the key had
+    PutIfAbsentRes(Option<String>, usize), // (old_value, seq_no); the put happened only if old_value is none
+    IncrRes(Option<String>, Option<i64>, usize), // (old_value, new_value, seq_no); none new_value if old_value is not a number
+
+    // client -> server, transactions over any number of shards (see server.rs)
+    TxnCommit(Vec<(String, Option<String>)>, Vec<(String, Option<String>)>, usize), // (reads, writes, seq_no); reads with the value the client saw, a none write deletes
+
+    // server -> client
+    TxnCommitRes(bool, usize), // (committed, seq_no); false if a read changed or a key was busy
+
+    // server -> server, two-phase commit between the coordinator and the primary of every shard involved
+    TxnPrepare(TxnId, ShardId, ShardId, Vec<(String, Option<String>)>, Vec<(String, Option<String>)>), // (txn_id, coordinator shard, participant shard, reads, writes)
+    TxnPrepareRes(TxnId, ShardId, ShardId, bool), // (txn_id, coordinator shard, participant shard, vote)
+    TxnDecide(TxnId, ShardId, ShardId, bool), // (txn_id, coordinator shard, participant shard, commit)
+    TxnDecideRes(TxnId, ShardId, ShardId), // (txn_id, coordinator shard, participant shard)
+    TxnStatus(TxnId, ShardId, ShardId), // (txn_id, coordinator shard, participant shard); an in-doubt participant asks for the decision
+    TxnReplicate(ShardId, TxnId, TxnRecord, usize), // (shard_id, txn_id, record, ack_id); primary to secondaries
+    TxnReplicateRes(ShardId, usize), // (shard_id, ack_id)
+
+    // server -> controller, with the ack of a heartbeat (see hotkeys.rs)
+    HotKeys(Vec<(String, u64)>), // [(key, requests per second)]; the keys a worker of the server was asked for most since its last report
+
+    // server -> client, admission control (see admission.rs)
+    Busy(usize, u64), // (seq_no, retry_after_ms); the server's queues are full, the client sends the request again later
+
+    // server -> server, coordinator to every replica of the shards of a transaction
+    TxnForget(TxnId, ShardId), // (txn_id, shard_id); every part applied the decision, the shard's records of it can go
+
+    // server -> client, writes the primary ran before (see server.rs)
+    Stale(usize, usize), // (seq_no, newest); the write did not run, the client's later requests go up to newest
 }
 
 #[derive(Clone)]
@@ -89,46 +246,113 @@ impl KVSSnapshot {
     }
 }
 
-pub fn get_shard_id_from_key(key: &str, num_shards: usize) -> ShardId {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    let hash_value = hasher.finish();
-    ((hash_value as usize) % num_shards).try_into().unwrap()
+// Depth of a server's per-key request queues. A request for a key with a
+// write or read in flight waits in the key's queue until that one is done.
+// Before that it waits in the queue of its worker (see admission.rs). Its
+// shape on the wire depends on the wire version (see wire.rs).
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(from = "ServerMetricsWire", into = "ServerMetricsWire")]
+pub struct ServerMetrics {
+    pub parked_requests: usize, // requests waiting right now, over every key
+    pub parked_keys: usize,     // keys with at least one request waiting
+    pub max_key_depth: usize,   // longest queue right now
+    pub peak_key_depth: usize,  // longest any queue has been since the server started
+    pub total_parked: u64,      // requests that ever had to wait
+    pub queued_requests: usize, // client requests waiting for a worker or a key right now
+    pub busy_requests: u64,     // client requests refused with Busy since the server started
 }
 
+impl ServerMetrics {
+    // The metrics of a server from those of its workers. A key only ever
+    // waits at the worker owning its shard.
+    pub fn merge(workers: &[ServerMetrics]) -> ServerMetrics {
+        ServerMetrics {
+            parked_requests: workers.iter().map(|metrics| metrics.parked_requests).sum(),
+            parked_keys: workers.iter().map(|metrics| metrics.parked_keys).sum(),
+            max_key_depth: workers.iter().map(|metrics| metrics.max_key_depth).max().unwrap_or(0),
+            peak_key_depth: workers.iter().map(|metrics| metrics.peak_key_depth).max().unwrap_or(0),
+            total_parked: workers.iter().map(|metrics| metrics.total_parked).sum(),
+            queued_requests: workers.iter().map(|metrics| metrics.queued_requests).sum(),
+            busy_requests: workers.iter().map(|metrics| metrics.busy_requests).sum(),
+        }
+    }
+}
+
+// The storage of a server. Values live in the concurrent KVS of project 1
+// (kvs_engine, the repository root), which locks one bucket at a time, so the
+// workers of a server (see bin/server.rs) do not wait for each other. The
+// engine only stores strings, so the versions are a second one holding every
+// key's version in decimal. A key is only ever written by the worker owning
+// its shard, which keeps a value and its version in step.
 #[derive(Clone)]
-pub struct KVS<K, V> {
-    table: Arc<RwLock<HashMap<K, V>>>,
+pub struct KVS {
+    table: Arc<kvs_engine::KVS>,
+    versions: Arc<kvs_engine::KVS>, // per-key version, kept for deleted keys too
 }
 
-impl<K, V> KVS<K, V>
-where
-    K: std::hash::Hash + Eq + Clone,
-    V: std::hash::Hash + Clone,
-{
+impl KVS {
     pub fn new() -> Self {
         KVS {
-            table: Arc::new(RwLock::new(HashMap::new())),
+            table: Arc::new(kvs_engine::KVS::new()),
+            versions: Arc::new(kvs_engine::KVS::new()),
         }
     }
 
-    pub fn put(&self, key: K, value: V) -> Option<V> {
-        let mut table = self.table.write().unwrap();
-        table.insert(key, value)
+    pub fn put(&self, key: String, value: String) -> Option<String> {
+        let old_value = self.table.get(&key);
+        self.table.put(key, value);
+        old_value
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.table.get(key)
     }
 
-    pub fn get(&self, key: &K) -> Option<V> {
-        let table = self.table.read().unwrap();
-        table.get(key).cloned()
+    pub fn delete(&self, key: &str) -> Option<String> {
+        let old_value = self.table.get(key);
+        self.table.delete(key);
+        old_value
+    }
+
+    pub fn version(&self, key: &str) -> u64 {
+        match self.versions.get(key) {
+            Some(version) => version.parse().unwrap(),
+            None => 0,
+        }
+    }
+
+    pub fn get_versioned(&self, key: &str) -> (Option<String>, u64) {
+        (self.table.get(key), self.version(key))
+    }
+
+    // Applies a write only if it is newer than what we have, so replicated
+    // writes arriving out of order (or repairs of an already fresh copy) are
+    // harmless. A none value deletes the key but keeps its version as a tombstone.
+    pub fn apply_versioned(&self, key: String, value: Option<String>, version: u64) -> Option<String> {
+        if self.version(&key) >= version {
+            return self.table.get(&key);
+        }
+        self.versions.put(key.clone(), version.to_string());
+        match value {
+            Some(value) => self.put(key, value),
+            None => self.delete(&key),
+        }
     }
 
-    pub fn delete(&self, key: &K) -> Option<V> {
-        let mut table = self.table.write().unwrap();
-        table.remove(key)
+    // (key, value, version) of every key that was ever written, a none value
+    // for the deleted ones
+    pub fn versioned_entries(&self) -> Vec<(String, Option<String>, u64)> {
+        self.versions
+            .inner_table()
+            .into_iter()
+            .map(|(key, version)| {
+                let value = self.table.get(&key);
+                (key, value, version.parse().unwrap())
+            })
+            .collect()
     }
 
-    pub fn inner_table(&self) -> HashMap<K, V> {
-        let locked_table = self.table.read().unwrap();
-        locked_table.clone()
+    pub fn inner_table(&self) -> HashMap<String, String> {
+        self.table.inner_table()
     }
 }
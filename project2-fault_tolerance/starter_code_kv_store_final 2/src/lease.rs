@@ -0,0 +1,144 @@
+use crate::kvs::Pid;
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime};
+
+// Primary leases. Every PutShardInfo heartbeat grants each server a lease of
+// ShardInfo::lease_ms; a primary only serves Get while its lease runs, and the
+// controller only promotes a secondary once the old primary's lease is over.
+// The two sides never compare timestamps, each one measures the lease on its
+// own clock from its own side of the heartbeat: the server starts counting
+// when the heartbeat arrives and gives the lease up CLOCK_SKEW_MARGIN_MS
+// early, the controller starts counting when it sends the heartbeat and waits
+// CLOCK_SKEW_MARGIN_MS longer. Reads stay safe as long as the heartbeat's
+// delivery delay plus the drift between the two clocks over one lease stay
+// under twice the margin. The transport gives up on a heartbeat granting a
+// lease once it is CLOCK_SKEW_MARGIN_MS old rather than deliver it late (see
+// udp.rs).
+//
+// Usage:
+// // server, on every PutShardInfo
+// lease.grant(shard_info.lease_ms);
+// // server, before serving a Get as primary
+// if !lease.is_valid() { refuse }
+// // controller, when sending a heartbeat to pid
+// leases.grant(pid, lease_ms);
+// // controller, before promoting a secondary of pid's shards
+// if leases.expired(pid) { promote }
+
+// Long enough to span two heartbeats, so a live primary never lapses
+pub const LEASE_MS: u64 = 12000;
+pub const CLOCK_SKEW_MARGIN_MS: u64 = 1000;
+
+// Lease times are in milliseconds of a monotonic clock. Tests inject a clock
+// they move by hand.
+pub trait Clock {
+    fn now_ms(&self) -> u64;
+
+    // Timestamps of the results a client records, compared across clients
+    fn timestamp_ns(&self) -> u128 {
+        self.now_ms() as u128 * 1_000_000
+    }
+}
+
+#[derive(Clone)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        self.start.elapsed().as_millis() as u64
+    }
+
+    // wall clock time, so clients in different processes agree
+    fn timestamp_ns(&self) -> u128 {
+        match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+            Ok(duration) => duration.as_nanos(),
+            Err(_) => 0,
+        }
+    }
+}
+
+// The lease a server holds. It starts out expired.
+pub struct Lease<C: Clock> {
+    clock: C,
+    expires_at_ms: Option<u64>,
+}
+
+impl<C: Clock> Lease<C> {
+    pub fn new(clock: C) -> Self {
+        Lease {
+            clock,
+            expires_at_ms: None,
+        }
+    }
+
+    // A heartbeat arrived: hold the lease for duration_ms minus the margin.
+    // An earlier grant that would run longer is kept.
+    pub fn grant(&mut self, duration_ms: u64) {
+        let expires_at_ms = self.clock.now_ms() + duration_ms.saturating_sub(CLOCK_SKEW_MARGIN_MS);
+        if self.expires_at_ms.is_none_or(|current| expires_at_ms > current) {
+            self.expires_at_ms = Some(expires_at_ms);
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        match self.expires_at_ms {
+            Some(expires_at_ms) => self.clock.now_ms() < expires_at_ms,
+            None => false,
+        }
+    }
+
+    // The time on the clock the lease is measured with
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+}
+
+// The leases the controller handed out, one per server.
+pub struct LeaseTable<C: Clock> {
+    clock: C,
+    expires_at_ms: HashMap<Pid, u64>,
+}
+
+impl<C: Clock> LeaseTable<C> {
+    pub fn new(clock: C) -> Self {
+        LeaseTable {
+            clock,
+            expires_at_ms: HashMap::new(),
+        }
+    }
+
+    // About to send pid a heartbeat granting duration_ms: until proven
+    // otherwise pid holds the lease for that long plus the margin.
+    pub fn grant(&mut self, pid: Pid, duration_ms: u64) {
+        let expires_at_ms = self.clock.now_ms() + duration_ms + CLOCK_SKEW_MARGIN_MS;
+        let current = self.expires_at_ms.entry(pid).or_insert(expires_at_ms);
+        if expires_at_ms > *current {
+            *current = expires_at_ms;
+        }
+    }
+
+    // Whether pid can no longer think it is primary. A server that was never
+    // granted a lease holds none.
+    pub fn expired(&self, pid: Pid) -> bool {
+        match self.expires_at_ms.get(&pid) {
+            Some(expires_at_ms) => self.clock.now_ms() >= *expires_at_ms,
+            None => true,
+        }
+    }
+}
@@ -1,2 +1,19 @@
+pub mod admission;
+pub mod cli;
+pub mod client;
+pub mod controller;
+pub mod faults;
+pub mod hotkeys;
 pub mod kvs;
+pub mod lease;
+pub mod membership;
 pub mod network;
+pub mod raft;
+pub mod ring;
+pub mod server;
+pub mod shell;
+pub mod sim;
+pub mod tcp;
+pub mod txnlog;
+pub mod udp;
+pub mod wire;
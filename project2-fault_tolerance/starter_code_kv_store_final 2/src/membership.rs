@@ -0,0 +1,121 @@
+use crate::kvs::Pid;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+// Maps the pids of a cluster to socket addresses. A membership file has one
+// entry per line:
+//
+// # every pid not listed below is 127.0.0.1:<base_port + pid>
+// base_port 9000
+// # pid 3 runs on another host
+// 3 10.0.0.5:8003
+//
+// Without a base_port line only the listed pids are members. Without any
+// file, every pid is 127.0.0.1:<8000 + pid>. A packet or connection from an
+// address that maps to no member is rejected, not guessed at.
+
+pub const DEFAULT_BASE_PORT: u16 = 8000;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Membership {
+    base_port: Option<u16>,
+    addrs: HashMap<Pid, SocketAddr>,
+    pids: HashMap<SocketAddr, Pid>,
+}
+
+impl Membership {
+    // Every pid on loopback at base_port + pid
+    pub fn loopback(base_port: u16) -> Membership {
+        Membership {
+            base_port: Some(base_port),
+            addrs: HashMap::new(),
+            pids: HashMap::new(),
+        }
+    }
+
+    pub fn load(path: &str) -> Result<Membership> {
+        let text = fs::read_to_string(path)?;
+        Membership::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Membership> {
+        let mut membership = Membership {
+            base_port: None,
+            addrs: HashMap::new(),
+            pids: HashMap::new(),
+        };
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let invalid = |what: &str| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("membership line {}: {}: {}", line_no + 1, what, line),
+                )
+            };
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(invalid("expected two fields"));
+            }
+            if fields[0] == "base_port" {
+                let base_port = fields[1].parse().map_err(|_| invalid("bad port"))?;
+                membership.base_port = Some(base_port);
+                continue;
+            }
+            let pid: Pid = fields[0].parse().map_err(|_| invalid("bad pid"))?;
+            let addr: SocketAddr = fields[1].parse().map_err(|_| invalid("bad address"))?;
+            if membership.addrs.contains_key(&pid) {
+                return Err(invalid("pid listed twice"));
+            }
+            if membership.pids.contains_key(&addr) {
+                return Err(invalid("address listed twice"));
+            }
+            membership.addrs.insert(pid, addr);
+            membership.pids.insert(addr, pid);
+        }
+        Ok(membership)
+    }
+
+    pub fn set_base_port(&mut self, base_port: u16) {
+        self.base_port = Some(base_port);
+    }
+
+    pub fn addr_of(&self, pid: &Pid) -> Option<SocketAddr> {
+        if let Some(addr) = self.addrs.get(pid) {
+            return Some(*addr);
+        }
+        let port = (self.base_port? as Pid).checked_add(*pid)?;
+        let port = u16::try_from(port).ok()?;
+        Some(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port))
+    }
+
+    pub fn pid_of(&self, addr: &SocketAddr) -> Option<Pid> {
+        if let Some(pid) = self.pids.get(addr) {
+            return Some(*pid);
+        }
+        let base_port = self.base_port?;
+        if !addr.ip().is_loopback() || addr.port() < base_port {
+            return None;
+        }
+        let pid = (addr.port() - base_port) as Pid;
+        // a listed pid only lives at its listed address
+        if self.addrs.contains_key(&pid) {
+            return None;
+        }
+        Some(pid)
+    }
+
+    // Whether pid may connect from ip (the port of an outgoing connection is
+    // not known in advance)
+    pub fn is_member_at(&self, pid: &Pid, ip: &IpAddr) -> bool {
+        match self.addr_of(pid) {
+            Some(addr) => addr.ip() == *ip || (addr.ip().is_loopback() && ip.is_loopback()),
+            None => false,
+        }
+    }
+}
@@ -1,100 +1,371 @@
 extern crate serde_json;
 
+use crate::faults::{FaultInjector, Verdict, DEFAULT_FAULT_SEED};
 use crate::kvs::{Operation, Pid};
-use std::io::Result;
-use std::net::{SocketAddr, UdpSocket};
+use crate::lease::CLOCK_SKEW_MARGIN_MS;
+use crate::membership::{Membership, DEFAULT_BASE_PORT};
+use crate::tcp::TcpTransport;
+use crate::udp::UdpTransport;
+use crate::wire::{check_version, decode, encode, encode_version, WireFormat, WIRE_VERSION};
+use lazy_static::lazy_static;
+use std::io::{Error, ErrorKind, Result};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use std::time::{Duration, Instant};
 
 // Usage:
+// // pick the transport and the cluster's addresses once, before any context
+// // is created (udp on 127.0.0.1:8000+pid by default)
+// set_default_transport(TransportKind::Tcp);
+// set_default_membership(Membership::load("cluster.txt").unwrap());
 // // create network context
 // let ctx = create_network_context(&self_pid).unwrap();
 // // send to dst_pid an operation
 // send(&mut ctx, &dst_pid, Operation::Get("x", 0));
 // // recv from any sources, returns the data and src addr
 // recv_from(&mut ctx, 1000);
+//
+// send and recv encode operations (wire.rs) and hand the bytes to a Transport, which
+// delivers the messages of one sender reliably and in order:
+// UdpTransport (udp.rs) runs its own acks and retransmissions over one
+// UdpSocket, TcpTransport (tcp.rs) keeps a connection per peer.
+//
+// Between the two sits the process' FaultInjector (faults.rs), which tests
+// configure to drop, delay, duplicate or reorder messages and to partition
+// the cluster. It does nothing unless configured. FaultControl messages
+// reconfigure it at runtime, recv only applies them in processes started
+// with --allow-fault-control and only from cluster members.
+
+// Moves whole messages between pids. Clones of a transport share its state,
+// so every thread of a component can send and recv on its own clone.
+pub trait Transport: Send {
+    fn send(&mut self, dst_pid: &Pid, data: &[u8]) -> Result<()>;
+    // A message that is useless once late: the transport stops trying after
+    // deadline and may deliver it ahead of earlier ones
+    fn send_expiring(&mut self, dst_pid: &Pid, data: &[u8], _deadline: Duration) -> Result<()> {
+        self.send(dst_pid, data)
+    }
+    // Waits up to timeout for messages, returns every one that is ready
+    fn recv(&mut self, timeout: Duration) -> Result<Vec<(Pid, Vec<u8>)>>;
+    fn try_clone(&self) -> Result<Box<dyn Transport>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    Udp,
+    Tcp,
+}
+
+static DEFAULT_TRANSPORT: AtomicU8 = AtomicU8::new(TransportKind::Udp as u8);
+
+pub fn set_default_transport(kind: TransportKind) {
+    DEFAULT_TRANSPORT.store(kind as u8, Ordering::SeqCst);
+}
+
+pub fn default_transport() -> TransportKind {
+    if DEFAULT_TRANSPORT.load(Ordering::SeqCst) == TransportKind::Tcp as u8 {
+        TransportKind::Tcp
+    } else {
+        TransportKind::Udp
+    }
+}
+
+static DEFAULT_WIRE_FORMAT: AtomicU8 = AtomicU8::new(WireFormat::Binary as u8);
+
+// The format send uses, recv accepts both
+pub fn set_default_wire_format(format: WireFormat) {
+    DEFAULT_WIRE_FORMAT.store(format as u8, Ordering::SeqCst);
+}
+
+pub fn default_wire_format() -> WireFormat {
+    if DEFAULT_WIRE_FORMAT.load(Ordering::SeqCst) == WireFormat::Json as u8 {
+        WireFormat::Json
+    } else {
+        WireFormat::Binary
+    }
+}
+
+static DEFAULT_WIRE_VERSION: AtomicU8 = AtomicU8::new(WIRE_VERSION);
+
+// The binary version send writes, recv accepts every supported one
+pub fn set_default_wire_version(version: u8) {
+    DEFAULT_WIRE_VERSION.store(version, Ordering::SeqCst);
+}
+
+lazy_static! {
+    static ref DEFAULT_MEMBERSHIP: RwLock<Arc<Membership>> =
+        RwLock::new(Arc::new(Membership::loopback(DEFAULT_BASE_PORT)));
+}
+
+pub fn set_default_membership(membership: Membership) {
+    *DEFAULT_MEMBERSHIP.write().unwrap() = Arc::new(membership);
+}
+
+pub fn default_membership() -> Arc<Membership> {
+    Arc::clone(&DEFAULT_MEMBERSHIP.read().unwrap())
+}
+
+lazy_static! {
+    // one per process, shared by every context so a FaultControl received on
+    // any of them applies to all
+    static ref DEFAULT_FAULTS: Arc<Mutex<FaultInjector>> = Arc::new(Mutex::new(FaultInjector::new(DEFAULT_FAULT_SEED)));
+}
+
+pub fn set_default_faults(faults: FaultInjector) {
+    *DEFAULT_FAULTS.lock().unwrap() = faults;
+}
+
+// Off by default: anyone who can reach a port could otherwise partition the
+// cluster with a single FaultControl.
+static DEFAULT_ALLOW_FAULT_CONTROL: AtomicBool = AtomicBool::new(false);
+
+pub fn set_allow_fault_control(allow: bool) {
+    DEFAULT_ALLOW_FAULT_CONTROL.store(allow, Ordering::SeqCst);
+}
+
+// How often recv wakes up to release delayed messages
+const FAULT_TICK_MS: u64 = 10;
+// A reordered message goes out at the latest after this long
+const REORDER_HOLD_MS: u64 = 20;
+
+pub fn parse_transport(name: &str) -> Result<TransportKind> {
+    match name {
+        "udp" => Ok(TransportKind::Udp),
+        "tcp" => Ok(TransportKind::Tcp),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown transport {}, expected udp or tcp", name),
+        )),
+    }
+}
 
 pub struct NetworkContext {
-    socket: UdpSocket,
+    transport: Box<dyn Transport>,
     self_pid: Pid,
+    faults: Arc<Mutex<FaultInjector>>,
+    held: Arc<Mutex<Vec<HeldMessage>>>, // delayed or reordered, shared by every clone
+    wire_format: WireFormat,
+    wire_version: u8,
+    dropped: Arc<AtomicU64>, // undecodable messages recv threw away, counted over every clone
+    membership: Arc<Membership>,
+    allow_fault_control: bool,
+}
+
+struct HeldMessage {
+    dst_pid: Pid,
+    data: Vec<u8>,
+    release_at: Instant,
+    reordered: bool, // also released by the next message to dst_pid
 }
 
 impl NetworkContext {
-    pub fn new(self_pid: &Pid) -> Result<NetworkContext> {
-        let socket = UdpSocket::bind(&get_ip_port_from_pid(&self_pid))?;
-        println!("create socket: {}", &get_ip_port_from_pid(&self_pid));
+    pub fn new(self_pid: &Pid, kind: TransportKind) -> Result<NetworkContext> {
+        NetworkContext::with_membership(self_pid, kind, default_membership())
+    }
+
+    pub fn with_membership(
+        self_pid: &Pid,
+        kind: TransportKind,
+        membership: Arc<Membership>,
+    ) -> Result<NetworkContext> {
+        let addr = match membership.addr_of(self_pid) {
+            Some(addr) => addr,
+            None => {
+                return Err(Error::new(
+                    ErrorKind::NotFound,
+                    format!("pid {} is not a cluster member", self_pid),
+                ))
+            }
+        };
+        let transport: Box<dyn Transport> = match kind {
+            TransportKind::Udp => Box::new(UdpTransport::bind(addr, Arc::clone(&membership))?),
+            TransportKind::Tcp => Box::new(TcpTransport::bind(self_pid, addr, Arc::clone(&membership))?),
+        };
+        println!("create socket: {} ({:?})", addr, kind);
         Ok(NetworkContext {
-            socket: socket,
+            transport,
             self_pid: self_pid.clone(),
+            faults: Arc::clone(&DEFAULT_FAULTS),
+            held: Arc::new(Mutex::new(Vec::new())),
+            wire_format: default_wire_format(),
+            wire_version: DEFAULT_WIRE_VERSION.load(Ordering::SeqCst),
+            dropped: Arc::new(AtomicU64::new(0)),
+            membership,
+            allow_fault_control: DEFAULT_ALLOW_FAULT_CONTROL.load(Ordering::SeqCst),
         })
     }
+
+    pub fn pid(&self) -> Pid {
+        self.self_pid
+    }
+
+    pub fn set_wire_format(&mut self, format: WireFormat) {
+        self.wire_format = format;
+    }
+
+    pub fn set_wire_version(&mut self, version: u8) -> Result<()> {
+        check_version(version)?;
+        self.wire_version = version;
+        Ok(())
+    }
+
+    pub fn dropped_messages(&self) -> u64 {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    // Gives this context (and its later clones) an injector of its own
+    // instead of the process-wide one, for tests that run several pids in
+    // one process.
+    pub fn set_faults(&mut self, faults: FaultInjector) {
+        self.faults = Arc::new(Mutex::new(faults));
+    }
+
+    // Whether recv applies FaultControl messages, for contexts a test creates
+    // without going through the binaries' --allow-fault-control.
+    pub fn set_allow_fault_control(&mut self, allow: bool) {
+        self.allow_fault_control = allow;
+    }
+
+    // Sends the held messages that are due, or every one to dst_pid that was
+    // only held to be reordered. One that fails to go out is lost.
+    fn release_held(&mut self, next_to: Option<Pid>) {
+        let now = Instant::now();
+        let mut released = Vec::new();
+        self.held.lock().unwrap().retain(|message| {
+            let due = message.release_at <= now || (message.reordered && Some(message.dst_pid) == next_to);
+            if due {
+                released.push((message.dst_pid, message.data.clone()));
+            }
+            !due
+        });
+        for (dst_pid, data) in released {
+            let _ = self.transport.send(&dst_pid, &data);
+        }
+    }
+
+    fn hold(&mut self, dst_pid: &Pid, data: &[u8], hold_ms: u64, reordered: bool) {
+        self.held.lock().unwrap().push(HeldMessage {
+            dst_pid: *dst_pid,
+            data: data.to_vec(),
+            release_at: Instant::now() + Duration::from_millis(hold_ms),
+            reordered,
+        });
+    }
 }
 
 impl Clone for NetworkContext {
     fn clone(&self) -> Self {
-        let new_socket = self.socket.try_clone().expect("Failed to clone UdpSocket");
+        let new_transport = self.transport.try_clone().expect("Failed to clone transport");
         NetworkContext {
-            socket: new_socket,
+            transport: new_transport,
             self_pid: self.self_pid,
+            faults: Arc::clone(&self.faults),
+            held: Arc::clone(&self.held),
+            wire_format: self.wire_format,
+            wire_version: self.wire_version,
+            dropped: Arc::clone(&self.dropped),
+            membership: Arc::clone(&self.membership),
+            allow_fault_control: self.allow_fault_control,
         }
     }
 }
 
-fn get_ip_port_from_pid(pid: &Pid) -> String {
-    format!("127.0.0.1:{}", 8000 + pid).to_string()
-}
-
-fn get_pid_from_ip_port(ip_port: &SocketAddr) -> Pid {
-    (ip_port.port() - 8000) as Pid
+pub fn create_network_context(self_pid: &Pid) -> Result<NetworkContext> {
+    NetworkContext::new(self_pid, default_transport())
 }
 
-pub fn create_network_context(self_pid: &Pid) -> Result<NetworkContext> {
-    let ctx = NetworkContext::new(self_pid)?;
-    ctx.socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-    Ok(ctx)
+// The variant name fault rules match on, e.g. Put
+pub(crate) fn operation_name(operation: &Operation) -> String {
+    match serde_json::to_value(operation) {
+        Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
 }
 
 pub fn send(ctx: &mut NetworkContext, dst_pid: &Pid, operation: &Operation) -> Result<usize> {
-    let data = serde_json::to_string(operation).unwrap();
-    // println!("send: {} -> {} data:{}", &ctx.self_pid, &dst_pid, &data);
-    match ctx
-        .socket
-        .send_to(data.as_bytes(), &get_ip_port_from_pid(&dst_pid))
-    {
-        Ok(_) => Ok(data.len()),
-        Err(e) => Err(e),
+    let data = match ctx.wire_format {
+        WireFormat::Binary => encode_version(operation, ctx.wire_version)?,
+        WireFormat::Json => encode(operation, WireFormat::Json),
+    };
+    // println!("send: {} -> {} data:{:?}", &ctx.self_pid, &dst_pid, &data);
+    ctx.release_held(None);
+    let verdict = {
+        let mut faults = ctx.faults.lock().unwrap();
+        match operation {
+            Operation::FaultControl(_) => Verdict::Deliver,
+            _ if faults.is_empty() => Verdict::Deliver,
+            _ => faults.decide(ctx.self_pid, *dst_pid, &operation_name(operation)),
+        }
+    };
+    match verdict {
+        // a heartbeat arriving late would grant a lease the controller
+        // already counts as over
+        Verdict::Deliver if matches!(operation, Operation::PutShardInfo(info) if info.lease_ms > 0) => {
+            ctx.transport.send_expiring(dst_pid, &data, Duration::from_millis(CLOCK_SKEW_MARGIN_MS))?;
+            ctx.release_held(Some(*dst_pid));
+        }
+        Verdict::Deliver => {
+            ctx.transport.send(dst_pid, &data)?;
+            ctx.release_held(Some(*dst_pid));
+        }
+        Verdict::Drop => {}
+        Verdict::Delay(ms) => ctx.hold(dst_pid, &data, ms, false),
+        Verdict::Duplicate => {
+            ctx.transport.send(dst_pid, &data)?;
+            ctx.transport.send(dst_pid, &data)?;
+            ctx.release_held(Some(*dst_pid));
+        }
+        Verdict::Reorder => ctx.hold(dst_pid, &data, REORDER_HOLD_MS, true),
     }
+    Ok(data.len())
 }
 
 pub fn recv(ctx: &mut NetworkContext, timeout_ms: u64) -> Result<Vec<(Pid, Operation)>> {
-    ctx.socket
-        .set_read_timeout(Some(Duration::from_millis(timeout_ms)))?;
-
-    let mut results = Vec::new();
     let start_time = Instant::now();
     let timeout = Duration::from_millis(timeout_ms as u64);
 
-    const MAX_BUF_SIZE: usize = 1000000;
-    let mut buf = [0; MAX_BUF_SIZE];
     loop {
-        match ctx.socket.recv_from(&mut buf) {
-            Ok((bytes_read, ip_port)) => {
-                if bytes_read > 0 {
-                    let data = String::from_utf8_lossy(&buf[0..bytes_read]);
-                    let operation = serde_json::from_str(&data).unwrap();
-                    let src_pid = get_pid_from_ip_port(&ip_port);
-                    // println!("recv: {} -> {} data:{}", &src_pid, &(ctx.self_pid), &data);
-                    results.push((src_pid, operation));
+        ctx.release_held(None);
+        let remaining = timeout.saturating_sub(start_time.elapsed());
+        // wake up in time for held messages, which another clone may hold
+        // back while this one waits
+        let wait = if ctx.faults.lock().unwrap().is_empty() && ctx.held.lock().unwrap().is_empty() {
+            remaining
+        } else {
+            remaining.min(Duration::from_millis(FAULT_TICK_MS))
+        };
+        let messages = match ctx.transport.recv(wait) {
+            Ok(messages) => messages,
+            Err(e) if start_time.elapsed() >= timeout => return Err(e),
+            Err(_) => continue,
+        };
+        let mut operations = Vec::new();
+        for (src_pid, data) in messages {
+            match decode(&data) {
+                Ok(Operation::FaultControl(commands)) => {
+                    if !ctx.allow_fault_control {
+                        println!("Ignoring fault control from {}: not allowed in this process", src_pid);
+                    } else if ctx.membership.addr_of(&src_pid).is_none() {
+                        println!("Ignoring fault control from {}: not a cluster member", src_pid);
+                    } else if let Err(e) = ctx.faults.lock().unwrap().apply(&commands) {
+                        println!("Ignoring fault control from {}: {}", src_pid, e);
+                    }
+                }
+                Ok(operation) => {
+                    if ctx.faults.lock().unwrap().is_partitioned(src_pid, ctx.self_pid) {
+                        continue;
+                    }
+                    operations.push((src_pid, operation))
+                }
+                Err(e) => {
+                    ctx.dropped.fetch_add(1, Ordering::SeqCst);
+                    println!("Dropping malformed message from {}: {}", src_pid, e);
                 }
             }
-            Err(_) => {}
-        }
-
-        if !results.is_empty() {
-            return Ok(results);
         }
-
-        if start_time.elapsed() >= timeout {
-            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"));
+        if !operations.is_empty() {
+            return Ok(operations);
         }
     }
 }
@@ -0,0 +1,596 @@
+extern crate serde_json;
+
+use crate::kvs::{Operation, Pid};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+// Raft consensus for one replica group. The node does no I/O by itself: the
+// owner feeds it ticks and messages, sends whatever take_messages returns and
+// applies whatever take_committed returns to its state machine (the KVS).
+// This keeps it usable from the server worker as well as from tests that
+// drive a whole group deterministically in one process.
+//
+// Usage:
+// let mut node = RaftNode::new(self_pid, members, seed, Some(path));
+// node.tick();                            // every RAFT_TICK_MS
+// node.step(src_pid, message);            // on every Operation::Raft
+// node.propose(client_pid, operation);    // on the leader
+// for (dst, msg) in node.take_messages() { send(...) }
+// for (index, entry) in node.take_committed() { apply(...) }
+
+pub type Term = u64;
+pub type LogIndex = u64;
+
+// All timeouts are in ticks
+pub const ELECTION_TIMEOUT_MIN: u64 = 10;
+pub const ELECTION_TIMEOUT_MAX: u64 = 20;
+pub const HEARTBEAT_INTERVAL: u64 = 3;
+pub const MAX_ENTRIES_PER_APPEND: usize = 64;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LogEntry {
+    pub term: Term,
+    pub client: Pid,                // who to answer once the entry is applied
+    pub command: Option<Operation>, // none is the no-op a new leader appends
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum RaftMessage {
+    RequestVote {
+        term: Term,
+        last_log_index: LogIndex,
+        last_log_term: Term,
+    },
+    RequestVoteRes {
+        term: Term,
+        granted: bool,
+    },
+    AppendEntries {
+        term: Term,
+        prev_log_index: LogIndex,
+        prev_log_term: Term,
+        entries: Vec<LogEntry>,
+        leader_commit: LogIndex,
+    },
+    AppendEntriesRes {
+        term: Term,
+        success: bool,
+        match_index: LogIndex, // on failure, a hint of where to retry from
+    },
+}
+
+impl RaftMessage {
+    pub fn term(&self) -> Term {
+        match self {
+            RaftMessage::RequestVote { term, .. } => *term,
+            RaftMessage::RequestVoteRes { term, .. } => *term,
+            RaftMessage::AppendEntries { term, .. } => *term,
+            RaftMessage::AppendEntriesRes { term, .. } => *term,
+        }
+    }
+}
+
+// What has to survive a crash: written to disk before any message that
+// depends on it is handed out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersistentState {
+    pub current_term: Term,
+    pub voted_for: Option<Pid>,
+    pub log: Vec<LogEntry>, // log[i] holds index i + 1
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+pub struct RaftNode {
+    pub id: Pid,
+    peers: Vec<Pid>,
+    state: PersistentState,
+    storage_path: Option<String>,
+    persisted_meta: (Term, Option<Pid>),
+    persisted_len: usize, // log entries already in the log file
+    log_rewrite: bool,    // the log file no longer is a prefix of the log
+
+    role: Role,
+    leader: Option<Pid>,
+    commit_index: LogIndex,
+    last_applied: LogIndex,
+    next_index: HashMap<Pid, LogIndex>,
+    match_index: HashMap<Pid, LogIndex>,
+    votes: HashSet<Pid>,
+
+    election_elapsed: u64,
+    election_timeout: u64,
+    heartbeat_elapsed: u64,
+    rng: StdRng,
+
+    outbox: Vec<(Pid, RaftMessage)>,
+}
+
+impl RaftNode {
+    // members includes id itself. If storage_path names an existing file the
+    // term, vote and log are restored from it.
+    pub fn new(id: Pid, members: Vec<Pid>, seed: u64, storage_path: Option<String>) -> Self {
+        let state = match &storage_path {
+            Some(path) => load_state(path),
+            None => None,
+        };
+        let restored = state.is_some();
+        let mut node = RaftNode::restore(id, members, seed, state.unwrap_or_default(), storage_path);
+        // drop whatever a half written earlier run left in the log file
+        node.log_rewrite = !restored;
+        node
+    }
+
+    // Rebuilds a node from an already loaded persistent state, as after a crash.
+    pub fn restore(
+        id: Pid,
+        members: Vec<Pid>,
+        seed: u64,
+        state: PersistentState,
+        storage_path: Option<String>,
+    ) -> Self {
+        let mut peers: Vec<Pid> = members.into_iter().filter(|&pid| pid != id).collect();
+        peers.sort();
+        peers.dedup();
+        let persisted_meta = (state.current_term, state.voted_for);
+        let persisted_len = state.log.len();
+        let mut node = RaftNode {
+            id,
+            peers,
+            state,
+            storage_path,
+            persisted_meta,
+            persisted_len,
+            log_rewrite: false,
+            role: Role::Follower,
+            leader: None,
+            commit_index: 0,
+            last_applied: 0,
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            votes: HashSet::new(),
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            rng: StdRng::seed_from_u64(seed),
+            outbox: Vec::new(),
+        };
+        node.reset_election_timer();
+        node
+    }
+
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.role == Role::Leader
+    }
+
+    pub fn leader(&self) -> Option<Pid> {
+        self.leader
+    }
+
+    pub fn term(&self) -> Term {
+        self.state.current_term
+    }
+
+    pub fn commit_index(&self) -> LogIndex {
+        self.commit_index
+    }
+
+    pub fn last_index(&self) -> LogIndex {
+        self.state.log.len() as LogIndex
+    }
+
+    pub fn persistent_state(&self) -> &PersistentState {
+        &self.state
+    }
+
+    pub fn entry(&self, index: LogIndex) -> Option<&LogEntry> {
+        if index == 0 {
+            return None;
+        }
+        self.state.log.get((index - 1) as usize)
+    }
+
+    fn term_at(&self, index: LogIndex) -> Term {
+        match self.entry(index) {
+            Some(entry) => entry.term,
+            None => 0,
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        let members = self.peers.len() + 1;
+        members / 2 + 1
+    }
+
+    fn reset_election_timer(&mut self) {
+        self.election_elapsed = 0;
+        self.election_timeout = self
+            .rng
+            .gen_range(ELECTION_TIMEOUT_MIN..=ELECTION_TIMEOUT_MAX);
+    }
+
+    fn persist(&mut self) {
+        if let Err(e) = self.try_persist() {
+            println!("Raft node {} failed to persist state: {:?}", self.id, e);
+        }
+    }
+
+    // The term and vote go to a small file rewritten on change, log entries
+    // are appended to <path>.log and the log file is only rewritten when a
+    // conflicting suffix was truncated.
+    fn try_persist(&mut self) -> std::io::Result<()> {
+        let path = match &self.storage_path {
+            Some(path) => path.clone(),
+            None => return Ok(()),
+        };
+
+        let meta = (self.state.current_term, self.state.voted_for);
+        if meta != self.persisted_meta {
+            save_meta(&path, meta.0, meta.1)?;
+            self.persisted_meta = meta;
+        }
+
+        let log_path = format!("{}.log", path);
+        if self.log_rewrite || self.persisted_len > self.state.log.len() {
+            save_log(&log_path, &self.state.log)?;
+            self.log_rewrite = false;
+        } else if self.persisted_len < self.state.log.len() {
+            append_log(&log_path, &self.state.log[self.persisted_len..])?;
+        }
+        self.persisted_len = self.state.log.len();
+        Ok(())
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(Pid, RaftMessage)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    // Entries that became committed since the last call, in log order.
+    pub fn take_committed(&mut self) -> Vec<(LogIndex, LogEntry)> {
+        let mut committed = Vec::new();
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = self.entry(self.last_applied).unwrap().clone();
+            committed.push((self.last_applied, entry));
+        }
+        committed
+    }
+
+    pub fn tick(&mut self) {
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= HEARTBEAT_INTERVAL {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append();
+            }
+        } else {
+            self.election_elapsed += 1;
+            if self.election_elapsed >= self.election_timeout {
+                self.start_election();
+            }
+        }
+    }
+
+    // Appends a client command to the log. Returns its index, or none if this
+    // node is not the leader and the command has to go elsewhere.
+    pub fn propose(&mut self, client: Pid, command: Operation) -> Option<LogIndex> {
+        if self.role != Role::Leader {
+            return None;
+        }
+        self.append_local(client, Some(command));
+        self.broadcast_append();
+        Some(self.last_index())
+    }
+
+    fn append_local(&mut self, client: Pid, command: Option<Operation>) {
+        let term = self.state.current_term;
+        self.state.log.push(LogEntry {
+            term,
+            client,
+            command,
+        });
+        self.persist();
+        self.advance_commit_index();
+    }
+
+    fn start_election(&mut self) {
+        self.state.current_term += 1;
+        self.state.voted_for = Some(self.id);
+        self.persist();
+
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes.clear();
+        self.votes.insert(self.id);
+        self.reset_election_timer();
+
+        if self.votes.len() >= self.quorum() {
+            self.become_leader();
+            return;
+        }
+
+        let msg = RaftMessage::RequestVote {
+            term: self.state.current_term,
+            last_log_index: self.last_index(),
+            last_log_term: self.term_at(self.last_index()),
+        };
+        for peer in self.peers.clone() {
+            self.outbox.push((peer, msg.clone()));
+        }
+    }
+
+    fn become_follower(&mut self, term: Term) {
+        if term > self.state.current_term {
+            self.state.current_term = term;
+            self.state.voted_for = None;
+            self.persist();
+        }
+        self.role = Role::Follower;
+        self.votes.clear();
+    }
+
+    fn become_leader(&mut self) {
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.heartbeat_elapsed = 0;
+        let next = self.last_index() + 1;
+        for peer in &self.peers {
+            self.next_index.insert(*peer, next);
+            self.match_index.insert(*peer, 0);
+        }
+        // entries of earlier terms only commit once one of our own term does
+        self.append_local(self.id, None);
+        self.broadcast_append();
+    }
+
+    fn broadcast_append(&mut self) {
+        for peer in self.peers.clone() {
+            self.send_append(peer);
+        }
+    }
+
+    fn send_append(&mut self, peer: Pid) {
+        let next = *self.next_index.get(&peer).unwrap_or(&1);
+        let prev_log_index = next - 1;
+        let start = prev_log_index as usize;
+        let end = usize::min(self.state.log.len(), start + MAX_ENTRIES_PER_APPEND);
+        let msg = RaftMessage::AppendEntries {
+            term: self.state.current_term,
+            prev_log_index,
+            prev_log_term: self.term_at(prev_log_index),
+            entries: self.state.log[start..end].to_vec(),
+            leader_commit: self.commit_index,
+        };
+        self.outbox.push((peer, msg));
+    }
+
+    fn advance_commit_index(&mut self) {
+        if self.role != Role::Leader {
+            return;
+        }
+        let mut n = self.last_index();
+        while n > self.commit_index {
+            // only entries of the current term are committed by counting replicas
+            if self.term_at(n) == self.state.current_term {
+                let replicas = 1 + self.match_index.values().filter(|&&m| m >= n).count();
+                if replicas >= self.quorum() {
+                    self.commit_index = n;
+                    return;
+                }
+            }
+            n -= 1;
+        }
+    }
+
+    pub fn step(&mut self, from: Pid, msg: RaftMessage) {
+        if msg.term() > self.state.current_term {
+            self.become_follower(msg.term());
+            self.leader = None;
+        }
+
+        match msg {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                let my_last_term = self.term_at(self.last_index());
+                let up_to_date = last_log_term > my_last_term
+                    || (last_log_term == my_last_term && last_log_index >= self.last_index());
+                let can_vote = match self.state.voted_for {
+                    None => true,
+                    Some(pid) => pid == from,
+                };
+                let granted = term == self.state.current_term && can_vote && up_to_date;
+                if granted {
+                    self.state.voted_for = Some(from);
+                    self.persist();
+                    self.reset_election_timer();
+                }
+                let res = RaftMessage::RequestVoteRes {
+                    term: self.state.current_term,
+                    granted,
+                };
+                self.outbox.push((from, res));
+            }
+            RaftMessage::RequestVoteRes { term, granted } => {
+                if self.role == Role::Candidate && term == self.state.current_term && granted {
+                    self.votes.insert(from);
+                    if self.votes.len() >= self.quorum() {
+                        self.become_leader();
+                    }
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                prev_log_index,
+                prev_log_term,
+                entries,
+                leader_commit,
+            } => {
+                if term < self.state.current_term {
+                    let res = RaftMessage::AppendEntriesRes {
+                        term: self.state.current_term,
+                        success: false,
+                        match_index: 0,
+                    };
+                    self.outbox.push((from, res));
+                    return;
+                }
+
+                // a valid leader for our term
+                self.role = Role::Follower;
+                self.leader = Some(from);
+                self.reset_election_timer();
+
+                if prev_log_index > self.last_index()
+                    || self.term_at(prev_log_index) != prev_log_term
+                {
+                    let hint = LogIndex::min(self.last_index(), prev_log_index.saturating_sub(1));
+                    let res = RaftMessage::AppendEntriesRes {
+                        term: self.state.current_term,
+                        success: false,
+                        match_index: hint,
+                    };
+                    self.outbox.push((from, res));
+                    return;
+                }
+
+                let mut changed = false;
+                let mut index = prev_log_index;
+                for entry in entries {
+                    index += 1;
+                    if index <= self.last_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // conflicting suffix, never committed, drop it
+                        self.state.log.truncate((index - 1) as usize);
+                        self.log_rewrite = true;
+                    }
+                    self.state.log.push(entry);
+                    changed = true;
+                }
+                if changed {
+                    self.persist();
+                }
+
+                if leader_commit > self.commit_index {
+                    self.commit_index = LogIndex::min(leader_commit, index);
+                }
+                let res = RaftMessage::AppendEntriesRes {
+                    term: self.state.current_term,
+                    success: true,
+                    match_index: index,
+                };
+                self.outbox.push((from, res));
+            }
+            RaftMessage::AppendEntriesRes {
+                term,
+                success,
+                match_index,
+            } => {
+                if self.role != Role::Leader || term != self.state.current_term {
+                    return;
+                }
+                if success {
+                    let matched = self.match_index.entry(from).or_insert(0);
+                    if match_index > *matched {
+                        *matched = match_index;
+                    }
+                    self.next_index.insert(from, match_index + 1);
+                    self.advance_commit_index();
+                    if match_index < self.last_index() {
+                        self.send_append(from);
+                    }
+                } else {
+                    let next = self.next_index.entry(from).or_insert(1);
+                    *next = LogIndex::max(1, LogIndex::min(*next - 1, match_index + 1));
+                    self.send_append(from);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Meta {
+    current_term: Term,
+    voted_for: Option<Pid>,
+}
+
+pub fn load_state(path: &str) -> Option<PersistentState> {
+    let data = fs::read_to_string(path).ok()?;
+    let meta: Meta = match serde_json::from_str(&data) {
+        Ok(meta) => meta,
+        Err(e) => {
+            println!("Ignoring unreadable raft state {}: {:?}", path, e);
+            return None;
+        }
+    };
+
+    let mut log = Vec::new();
+    if let Ok(file) = File::open(format!("{}.log", path)) {
+        for line in BufReader::new(file).lines() {
+            let line = line.ok()?;
+            match serde_json::from_str(&line) {
+                Ok(entry) => log.push(entry),
+                // a torn last line from a crash mid-append
+                Err(_) => break,
+            }
+        }
+    }
+
+    Some(PersistentState {
+        current_term: meta.current_term,
+        voted_for: meta.voted_for,
+        log,
+    })
+}
+
+// Writes to a temporary file first so a crash mid-write never leaves a torn state behind.
+fn save_meta(path: &str, current_term: Term, voted_for: Option<Pid>) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let meta = Meta {
+        current_term,
+        voted_for,
+    };
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_string(&meta).unwrap())?;
+    fs::rename(&tmp_path, path)
+}
+
+fn save_log(log_path: &str, log: &[LogEntry]) -> std::io::Result<()> {
+    let tmp_path = format!("{}.tmp", log_path);
+    let mut data = String::new();
+    for entry in log {
+        data.push_str(&serde_json::to_string(entry).unwrap());
+        data.push('\n');
+    }
+    fs::write(&tmp_path, data)?;
+    fs::rename(&tmp_path, log_path)
+}
+
+fn append_log(log_path: &str, entries: &[LogEntry]) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(log_path)?;
+    for entry in entries {
+        writeln!(file, "{}", serde_json::to_string(entry).unwrap())?;
+    }
+    file.sync_data()
+}
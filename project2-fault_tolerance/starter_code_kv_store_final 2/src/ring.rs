@@ -0,0 +1,111 @@
+use crate::kvs::{Pid, ShardId};
+use serde::{Deserialize, Serialize};
+
+// Consistent hashing. Every server owns `vnodes` points on a ring of u64
+// hashes, and every logical shard sits at a point of its own: the shard goes
+// to the first server point at or after it, wrapping around at the end, and
+// its other replicas to the next distinct servers. A new server's points each
+// take over the shards between the previous point and itself, so adding or
+// removing one of N servers only moves about 1/N of the shards. More virtual
+// nodes spread the shards more evenly.
+//
+// Points and key hashes use stable_hash, which (unlike DefaultHasher) gives
+// the same value in every process and with every Rust version.
+//
+// Usage:
+// let mut ring = HashRing::new();
+// ring.insert(vnode_point(server_pid, 0), server_pid);
+// let servers = ring.walk(shard_point(shard_id));   // the shard's server first
+
+pub const DEFAULT_VNODES: usize = 16;
+
+// 64 bit FNV-1a, finished with the murmur3 mixer so that similar inputs (e.g.
+// the points of one server) land far apart on the ring
+pub fn stable_hash(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// Where the vnode-th virtual node of a server sits on the ring
+pub fn vnode_point(server_pid: Pid, vnode: usize) -> u64 {
+    stable_hash(format!("server-{}-vnode-{}", server_pid, vnode).as_bytes())
+}
+
+// Where a logical shard sits on the ring
+pub fn shard_point(shard_id: ShardId) -> u64 {
+    stable_hash(format!("shard-{}", shard_id).as_bytes())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HashRing {
+    points: Vec<(u64, Pid)>, // sorted
+}
+
+impl HashRing {
+    pub fn new() -> Self {
+        HashRing::default()
+    }
+
+    pub fn insert(&mut self, point: u64, server_pid: Pid) {
+        let index = match self.points.binary_search(&(point, server_pid)) {
+            Ok(_) => return,
+            Err(index) => index,
+        };
+        self.points.insert(index, (point, server_pid));
+    }
+
+    // Removes every point of the server
+    pub fn remove(&mut self, server_pid: Pid) {
+        self.points.retain(|(_, pid)| *pid != server_pid);
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    // (point, server_pid) in ring order
+    pub fn points(&self) -> &Vec<(u64, Pid)> {
+        &self.points
+    }
+
+    // Index of the first point at or after hash, wrapping around
+    fn successor(&self, hash: u64) -> usize {
+        let index = self.points.partition_point(|(point, _)| *point < hash);
+        index % self.points.len()
+    }
+
+    pub fn lookup_hash(&self, hash: u64) -> Option<Pid> {
+        if self.points.is_empty() {
+            return None;
+        }
+        Some(self.points[self.successor(hash)].1)
+    }
+
+    // Every server once, in ring order starting with the one that owns hash
+    pub fn walk(&self, hash: u64) -> Vec<Pid> {
+        let mut servers: Vec<Pid> = Vec::new();
+        if self.points.is_empty() {
+            return servers;
+        }
+        let start = self.successor(hash);
+        for offset in 0..self.points.len() {
+            let server_pid = self.points[(start + offset) % self.points.len()].1;
+            if !servers.contains(&server_pid) {
+                servers.push(server_pid);
+            }
+        }
+        servers
+    }
+}
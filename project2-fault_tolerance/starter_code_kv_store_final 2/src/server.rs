@@ -0,0 +1,2266 @@
+use crate::admission::{client_seq_no, BUSY_RETRY_AFTER_MS, CLIENT_QUEUE_CAPACITY, QUEUE_CAPACITY};
+use crate::hotkeys::HotKeyTracker;
+use crate::kvs::KVS;
+use crate::kvs::{KVSSnapshot, Operation, Pid, ReadBounds, ReplicationMode, ServerMetrics, ShardId, ShardInfo, TxnId, TxnRecord};
+use crate::lease::{Clock, Lease};
+use crate::raft::{LogEntry, LogIndex, RaftNode, Term};
+use crate::txnlog::TxnLog;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::io::Error;
+
+// The request handling of a server. Like RaftNode it does no I/O by itself:
+// the owner feeds it operations and ticks and sends whatever take_messages
+// returns. bin/server.rs drives it over the network, sim.rs over a simulated
+// one with a virtual clock.
+//
+// Usage:
+// let mut node = ServerNode::new(self_pid, clock, seed, Some(raft_dir));
+// node.tick();                            // every RAFT_TICK_MS
+// node.step(src_pid, operation);          // on every received operation
+// for (dst, op) in node.take_messages() { send(...) }
+//
+// In Raft mode a client request is a log entry every replica applies. A
+// client that lost the answer sends the request again, which commits a second
+// time, so the answers of every client's latest ANSWERS_PER_CLIENT
+// entries in a shard's log are part of the replicated state and the retry
+// gets the first answer back instead of running again. The parts of a batch
+// share the seq_no but go to different shards.
+//
+// Moving a shard to a new primary (PrimaryBackup and Quorum modes):
+// 1. the controller sends MigrateShard to the shard's primary, which streams
+//    every key of the shard (with versions and tombstones) to the new primary
+//    in ShardData chunks while it keeps serving the shard
+// 2. once the new primary applied them all, the old one freezes the shard:
+//    requests for it are held back. When the writes in flight are done it
+//    sends the keys that changed since the first copy
+// 3. once those are applied too it answers the controller, which switches
+//    the shard over with a new ShardInfo. The old primary forwards the held
+//    requests, and later ones from clients with an old ShardInfo, to the new
+//    primary. A ShardInfo of a later epoch that still lists us as the
+//    primary means the controller gave up on the move, and we serve the held
+//    requests ourselves.
+//
+// Reads from secondaries (PrimaryBackup mode): versions grow per shard, a
+// write gets a version above every one its shard has seen. Every
+// SYNC_INTERVAL_MS the primary tells each secondary the version up to which
+// every write of its shards was acked by all secondaries (ShardVersions), so
+// the secondary knows it has all of them, and since when. A ReadReplica is
+// served by a secondary only if that was at most the client's
+// max_staleness_ms ago and the version reaches the client's session token,
+// the highest version the client has seen of the shard. Requests sent as a
+// Session get the key's version along with the response to keep the token
+// up to date.
+//
+// Batches (MultiGet, MultiPut, MultiDelete) hold keys of one shard, the
+// client splits its batches per shard. The primary applies the writes of a
+// batch under one version and sends them to each secondary in a single
+// ReplicateBatch, so no replica holds part of a batch; the batch's keys wait
+// for its acks like those of a single write. In Raft mode a batch is one
+// log entry.
+//
+// Conditional writes (Cas, PutIfAbsent, Incr) check their condition against
+// the primary's copy of the key, which no other request touches while the
+// key has a write in flight, and replicate whatever they write like a Put or
+// Delete. A retry must not run again, a second Incr would count twice (see
+// below). In Raft mode every replica applies the entry and keeps the answer,
+// a client's request committed twice only runs once.
+//
+// Writes the primary ran before: a client sends a write again when it lost
+// the answer, and the network may deliver a copy of one late, e.g. after a
+// shard move held it back. Running it again would undo the client's later
+// writes of the key, or count an Incr twice. So the primary keeps the answers
+// of every client's latest ANSWERS_PER_CLIENT writes per shard and sends a
+// write it finds there the first answer back. One it has no answer for does
+// not run if the client wrote one of its keys later (the client waits for
+// the answer before it writes the key again, it already has it) or if it is
+// older than every answer kept, nor does one under the seq_no of a kept
+// answer for other keys. It is answered Stale(seq_no, newest) instead: a late
+// copy is no longer in flight and the client drops that, but a client that
+// restarted under the pid of an old one numbers its requests on from newest
+// and sends them again (see client.rs).
+//
+// Transactions (PrimaryBackup and Quorum modes) commit with two-phase
+// commit. The client sends TxnCommit, its reads with the values it saw and
+// its writes, to the primary of its first key's shard, which coordinates:
+// it asks the primary of every shard involved to prepare that shard's part.
+// A participant votes to commit if none of its keys is busy and every read
+// still holds, and locks the keys; requests for them wait like those for a
+// key with a write in flight. Once every part voted yes the coordinator
+// decides to commit, otherwise to abort, and tells the client and the
+// participants, which apply the writes and unlock. A prepared part and a
+// decision go to every secondary of the shard, whatever the replication
+// mode, before anyone acts on them, so a secondary that takes over after a
+// crash goes on where the primary stopped. A participant left waiting
+// TXN_TIMEOUT_MS for the decision asks the coordinator for it; one that has
+// no record of the transaction decides to abort, nobody can have been told
+// to commit. Every replica also writes the decisions and the parts it
+// applied to a file under raft_dir before acting on them (see txnlog.rs), so
+// the coordinator's shard still knows its decisions after all of its
+// replicas restarted; its new primary sends them again. Once every part
+// applied the decision the coordinator tells the replicas of every shard
+// involved to forget the transaction, and keeps the answer to the client
+// like that of a write (see above).
+//
+// Hot keys: every node counts the requests it serves per key and reports the
+// most requested ones to the controller with its heartbeat acks (see
+// hotkeys.rs), a secondary counting the reads it answered.
+//
+// A server can run several ServerNodes over one KVS, each the worker of a
+// partition of the shards (see worker_of). The Dispatcher hands every
+// operation to the worker owning its shard, so a shard's requests and
+// replication acks always reach the same one; a new ShardInfo goes to all.
+//
+// let mut dispatcher = Dispatcher::new(num_workers);
+// let node = ServerNode::with_partition(self_pid, clock, seed, raft_dir, kvs.clone(), worker, num_workers);
+// match dispatcher.route(&operation) {
+//     Route::Worker(worker) => ...,   // only that worker's node steps it
+//     Route::Every => ...,            // every worker's node steps it
+//     Route::Dispatcher => ...,       // answered from every worker's metrics
+// }
+
+// Raft timeouts in raft.rs are counted in ticks of this length
+pub const RAFT_TICK_MS: u64 = 50;
+// Answers kept per client and shard to answer retries with, enough for every
+// request of its window
+pub const ANSWERS_PER_CLIENT: usize = 1024;
+// Keys per ShardData message of a migration
+pub const MIGRATION_CHUNK_KEYS: usize = 100;
+// How often a primary sends its secondaries ShardVersions
+pub const SYNC_INTERVAL_MS: u64 = 100;
+// How long a coordinator waits for votes before it aborts, and how long the
+// decision may take before it is sent again or a participant asks for it
+pub const TXN_TIMEOUT_MS: u64 = 1000;
+
+struct KeyInfo {
+    client_pid: Pid,
+    pending_secondaries: Vec<Pid>,
+    acks_remaining: usize,
+    version: u64,
+    response: Operation,
+}
+
+struct ReadInfo {
+    client_pid: Pid,
+    seq_no: usize,
+    read_id: usize,
+    replies_remaining: usize,
+    replied: Vec<(Pid, u64)>, // (pid, version) of every copy consulted so far
+    newest: (Option<String>, u64),
+}
+
+// A MultiPut or MultiDelete waiting for acks, by (shard_id, version): every
+// write of the batch went out under one version in one ReplicateBatch
+struct BatchInfo {
+    client_pid: Pid,
+    keys: Vec<String>,
+    pending_secondaries: Vec<Pid>,
+    acks_remaining: usize,
+    response: Operation,
+}
+
+// A MultiGet in Quorum mode waiting for copies, by read_id
+struct BatchReadInfo {
+    client_pid: Pid,
+    shard_id: ShardId,
+    seq_no: usize,
+    replies_remaining: usize,
+    replied: Vec<(Pid, Vec<u64>)>, // (pid, version of every key) of every copy consulted so far
+    newest: Vec<(String, Option<String>, u64)>, // the newest copy of every key so far
+}
+
+// The reads, with the values seen, and the writes of a transaction on one shard
+type TxnPart = (Vec<(String, Option<String>)>, Vec<(String, Option<String>)>);
+
+// The answers of a client's latest requests by seq_no, with the keys each one
+// wrote
+type WriteAnswers = BTreeMap<usize, (Vec<String>, Operation)>;
+
+// A transaction we coordinate, until every part applied the decision
+struct Coordinating {
+    shard_id: ShardId, // ours, whose secondaries keep the decision
+    parts: BTreeMap<ShardId, TxnPart>, // every shard involved
+    votes: BTreeSet<ShardId>, // parts that voted to commit
+    decision: Option<bool>,
+    durable: bool, // every secondary has the decision
+    done: BTreeSet<ShardId>, // parts that applied it
+    sent_ms: u64, // when the prepares or the decisions last went out
+}
+
+// A part of a transaction we prepared, by (txn_id, shard_id). Its keys stay
+// locked until the decision is applied. The secondaries keep one too, for
+// when one of them takes over.
+struct Prepared {
+    coordinator: ShardId,
+    writes: Vec<(String, Option<String>)>,
+    keys: Vec<String>,
+    voted: bool,
+    applying: bool,
+    heard_ms: u64, // when we last heard of the decision, or asked for it
+}
+
+// What to do once every secondary has a TxnRecord
+enum TxnStep {
+    Vote(Pid),    // tell the coordinator at pid we are prepared
+    Decided,      // tell the client and the participants
+    Release(Pid), // unlock the keys and tell the coordinator at pid
+}
+
+// A TxnRecord sent to the secondaries, by ack_id
+struct TxnAcks {
+    shard_id: ShardId,
+    txn_id: TxnId,
+    pending_secondaries: Vec<Pid>,
+    version: u64, // of the writes the record applies, 0 for none
+    then: TxnStep,
+}
+
+// A shard we are handing over to a new primary
+struct MigrationOut {
+    to: Pid,
+    epoch: u64, // of the ShardInfo the move was started under
+    controller_pid: Pid,
+    sent: HashMap<String, u64>, // version of every key sent so far
+    num_sent: usize,
+    first_copy: usize, // entries of the first copy
+    frozen: bool,
+    delta_sent: bool,
+    held: Vec<(Pid, Operation)>, // (client_pid, request) that came in while frozen
+}
+
+// A shard we are taking over
+struct MigrationIn {
+    from: Pid,
+    received: usize,
+    expected: Option<usize>, // entries the old primary sent so far
+}
+
+pub struct ServerNode<C: Clock> {
+    self_pid: Pid,
+    worker: usize, // the partition of the shards this node serves
+    num_workers: usize,
+    kvs: KVS,
+    shard_info: ShardInfo,
+    lease: Lease<C>,
+    raft_dir: Option<String>, // none keeps raft and transaction state in memory only
+    rng: StdRng,
+
+    pending_keys: HashMap<String, KeyInfo>,
+    pending_reads: HashMap<String, ReadInfo>,
+    pending_batches: BTreeMap<(ShardId, u64), BatchInfo>,
+    pending_batch_reads: BTreeMap<usize, BatchReadInfo>,
+    next_read_id: usize,
+    // requests for a key with a write or read in flight, in arrival order
+    parked: HashMap<String, VecDeque<(Pid, Operation)>>,
+    peak_key_depth: usize,
+    total_parked: u64,
+    busy_requests: u64, // requests answered Busy instead of parked
+    raft_groups: BTreeMap<ShardId, RaftNode>,
+    raft_pending: HashMap<(ShardId, LogIndex), Term>,
+    raft_answers: HashMap<(ShardId, Pid), WriteAnswers>, // (shard, client_pid) to the keys written and answers of its latest applied raft entries
+    migrations_out: BTreeMap<ShardId, MigrationOut>,
+    migrations_in: HashMap<(ShardId, u64), MigrationIn>,
+    write_answers: HashMap<(ShardId, Pid), WriteAnswers>, // (shard, client_pid) to the answers of its latest writes we ran as the primary
+    shard_versions: HashMap<ShardId, u64>, // highest version of any key of the shard we applied
+    replica_sync: HashMap<ShardId, (u64, u64)>, // (version, ms) of the primary's last ShardVersions
+    last_sync_ms: u64,
+    sessions: HashMap<(Pid, usize), Vec<String>>, // (client_pid, seq_no) of Session requests in flight, to their keys
+    txns: BTreeMap<TxnId, Coordinating>,
+    txn_logs: HashMap<ShardId, TxnLog>, // decisions and applied parts of our shards' transactions, see txn_log
+    prepared: BTreeMap<(TxnId, ShardId), Prepared>,
+    txn_locks: HashMap<String, TxnId>, // keys of prepared transactions
+    pending_txn_acks: BTreeMap<usize, TxnAcks>,
+    next_txn_ack: usize,
+    hot_keys: HotKeyTracker, // requests served per key since the last report to the controller
+
+    outbox: Vec<(Pid, Operation)>,
+}
+
+// The key of a request, the first one of a batch
+fn request_key(operation: &Operation) -> Option<&String> {
+    match operation {
+        Operation::Get(key, _) | Operation::Put(key, _, _) | Operation::Delete(key, _) => Some(key),
+        Operation::Cas(key, ..) | Operation::PutIfAbsent(key, ..) | Operation::Incr(key, ..) => Some(key),
+        Operation::MultiGet(keys, _) | Operation::MultiDelete(keys, _) => keys.first(),
+        Operation::MultiPut(entries, _) => entries.first().map(|(key, _)| key),
+        Operation::Session(request) => request_key(request),
+        // the coordinator's, see handle_txn_commit
+        Operation::TxnCommit(reads, writes, _) => writes.first().or(reads.first()).map(|(key, _)| key),
+        _ => None,
+    }
+}
+
+// Every key of a request
+fn request_keys(operation: &Operation) -> Vec<String> {
+    match operation {
+        Operation::MultiGet(keys, _) | Operation::MultiDelete(keys, _) => keys.clone(),
+        Operation::MultiPut(entries, _) => entries.iter().map(|(key, _)| key.clone()).collect(),
+        Operation::Session(request) => request_keys(request),
+        operation => request_key(operation).into_iter().cloned().collect(),
+    }
+}
+
+fn request_seq_no(operation: &Operation) -> Option<usize> {
+    match operation {
+        Operation::Get(_, seq_no)
+        | Operation::Put(_, _, seq_no)
+        | Operation::Delete(_, seq_no)
+        | Operation::MultiGet(_, seq_no)
+        | Operation::MultiPut(_, seq_no)
+        | Operation::MultiDelete(_, seq_no) => Some(*seq_no),
+        Operation::Cas(.., seq_no) | Operation::PutIfAbsent(.., seq_no) | Operation::Incr(.., seq_no) => Some(*seq_no),
+        _ => None,
+    }
+}
+
+fn write_seq_no(operation: &Operation) -> Option<usize> {
+    match operation {
+        Operation::Put(_, _, seq_no)
+        | Operation::Delete(_, seq_no)
+        | Operation::MultiPut(_, seq_no)
+        | Operation::MultiDelete(_, seq_no) => Some(*seq_no),
+        Operation::Cas(.., seq_no) | Operation::PutIfAbsent(.., seq_no) | Operation::Incr(.., seq_no) => Some(*seq_no),
+        Operation::Session(request) => write_seq_no(request),
+        _ => None,
+    }
+}
+
+fn response_seq_no(operation: &Operation) -> Option<usize> {
+    match operation {
+        Operation::GetRes(_, seq_no)
+        | Operation::PutRes(_, seq_no)
+        | Operation::DeleteRes(_, seq_no)
+        | Operation::MultiGetRes(_, seq_no)
+        | Operation::MultiPutRes(_, seq_no)
+        | Operation::MultiDeleteRes(_, seq_no) => Some(*seq_no),
+        Operation::CasRes(.., seq_no) | Operation::PutIfAbsentRes(.., seq_no) | Operation::IncrRes(.., seq_no) => Some(*seq_no),
+        Operation::TxnCommitRes(_, seq_no) => Some(*seq_no),
+        _ => None,
+    }
+}
+
+// The worker that owns a shard when a server runs num_workers of them
+pub fn worker_of(shard_id: ShardId, num_workers: usize) -> usize {
+    shard_id as usize % num_workers
+}
+
+pub enum Route {
+    Worker(usize), // the worker owning the operation's shard
+    Every,         // every worker
+    Dispatcher,    // answered by the dispatcher itself
+}
+
+// Picks the worker for every operation a server receives. It keeps the
+// latest ShardInfo to map keys to shards.
+pub struct Dispatcher {
+    num_workers: usize,
+    shard_info: ShardInfo,
+}
+
+impl Dispatcher {
+    pub fn new(num_workers: usize) -> Self {
+        Dispatcher {
+            num_workers,
+            shard_info: ShardInfo::new(),
+        }
+    }
+
+    fn worker_of_key(&self, key: &str) -> Route {
+        if self.shard_info.num_shards == 0 {
+            // the worker drops it, there is no shard info yet
+            return Route::Worker(0);
+        }
+        Route::Worker(worker_of(self.shard_info.shard_of(key), self.num_workers))
+    }
+
+    pub fn route(&mut self, operation: &Operation) -> Route {
+        match operation {
+            Operation::Get(key, _) | Operation::Put(key, _, _) | Operation::Delete(key, _) => self.worker_of_key(key),
+            Operation::Cas(key, ..) | Operation::PutIfAbsent(key, ..) | Operation::Incr(key, ..) => self.worker_of_key(key),
+            // the keys of a batch are all on one shard
+            Operation::MultiGet(..) | Operation::MultiPut(..) | Operation::MultiDelete(..) => match request_key(operation) {
+                Some(key) => self.worker_of_key(key),
+                None => Route::Worker(0),
+            },
+            Operation::TxnCommit(..) => match request_key(operation) {
+                Some(key) => self.worker_of_key(key),
+                None => Route::Worker(0),
+            },
+            Operation::Forward(_, request) | Operation::RaftForward(_, request) | Operation::Session(request) => {
+                match request_key(request) {
+                    Some(key) => self.worker_of_key(key),
+                    None => Route::Worker(0),
+                }
+            }
+            Operation::ReadReplica(key, ..)
+            | Operation::Replicate(key, ..)
+            | Operation::ReplicateRes(key, ..)
+            | Operation::ReadVersion(key, ..)
+            | Operation::ReadVersionRes(key, ..) => self.worker_of_key(key),
+            Operation::Raft(shard_id, _)
+            | Operation::MigrateShard(shard_id, ..)
+            | Operation::ShardData(shard_id, ..)
+            | Operation::ShardDataDone(shard_id, ..)
+            | Operation::ShardDataRes(shard_id, ..)
+            | Operation::ReplicateBatch(shard_id, ..)
+            | Operation::ReplicateBatchRes(shard_id, ..)
+            | Operation::ReadVersions(shard_id, ..)
+            | Operation::ReadVersionsRes(shard_id, ..)
+            | Operation::TxnReplicate(shard_id, ..)
+            | Operation::TxnReplicateRes(shard_id, ..) => Route::Worker(worker_of(*shard_id, self.num_workers)),
+            // the participant's shard
+            Operation::TxnPrepare(_, _, shard_id, ..) | Operation::TxnDecide(_, _, shard_id, _) | Operation::TxnForget(_, shard_id) => {
+                Route::Worker(worker_of(*shard_id, self.num_workers))
+            }
+            // the coordinator's
+            Operation::TxnPrepareRes(_, shard_id, ..) | Operation::TxnDecideRes(_, shard_id, _) | Operation::TxnStatus(_, shard_id, _) => {
+                Route::Worker(worker_of(*shard_id, self.num_workers))
+            }
+            Operation::PutShardInfo(shard_info) => {
+                if shard_info.epoch >= self.shard_info.epoch {
+                    self.shard_info = shard_info.clone();
+                }
+                Route::Every
+            }
+            Operation::ShardVersions(..) => Route::Every,
+            Operation::Metrics() => Route::Dispatcher,
+            // a snapshot reads the KVS every worker shares
+            _ => Route::Worker(0),
+        }
+    }
+}
+
+// The writes of a batch with one per key: a key the batch writes more than
+// once ends with its last value. All of them go under one version, and the
+// KVS ignores a second write of a key under the version it already has.
+fn last_writes(writes: Vec<(String, Option<String>)>) -> Vec<(String, Option<String>)> {
+    let mut last: Vec<(String, Option<String>)> = Vec::new();
+    for (key, value) in writes {
+        match last.iter_mut().find(|(written, _)| *written == key) {
+            Some(write) => write.1 = value,
+            None => last.push((key, value)),
+        }
+    }
+    last
+}
+
+// Applies every write of a batch under version and returns the old value of
+// every key
+fn apply_batch(kvs: &KVS, writes: &[(String, Option<String>)], version: u64) -> Vec<(String, Option<String>)> {
+    writes
+        .iter()
+        .map(|(key, value)| (key.clone(), kvs.apply_versioned(key.clone(), value.clone(), version)))
+        .collect()
+}
+
+// Runs a conditional write against the key's current value. Returns the
+// write to make, if any (a none value deletes the key), and the answer.
+fn run_conditional(operation: &Operation, current: Option<String>) -> (Option<Option<String>>, Operation) {
+    match operation {
+        Operation::Cas(_, expected, new, seq_no) => {
+            if current == *expected {
+                (Some(new.clone()), Operation::CasRes(true, current, *seq_no))
+            } else {
+                (None, Operation::CasRes(false, current, *seq_no))
+            }
+        }
+        Operation::PutIfAbsent(_, value, seq_no) => match current {
+            None => (Some(Some(value.clone())), Operation::PutIfAbsentRes(None, *seq_no)),
+            Some(_) => (None, Operation::PutIfAbsentRes(current, *seq_no)),
+        },
+        Operation::Incr(_, delta, seq_no) => {
+            let number = match &current {
+                Some(value) => value.parse::<i64>().ok(),
+                None => Some(0),
+            };
+            match number.and_then(|number| number.checked_add(*delta)) {
+                Some(new) => (Some(Some(new.to_string())), Operation::IncrRes(current, Some(new), *seq_no)),
+                None => (None, Operation::IncrRes(current, None, *seq_no)),
+            }
+        }
+        _ => panic!("not a conditional write: {:?}", operation),
+    }
+}
+
+// Applies a committed log entry to the KVS, using the log index as the key's
+// version, and returns the response the client is waiting for. A request of
+// the client that was applied before only gets its first answer again, a late
+// copy of a conditional write whose key the client wrote since Stale.
+fn apply_raft_entry(
+    kvs: &KVS,
+    raft_answers: &mut HashMap<(ShardId, Pid), WriteAnswers>,
+    shard_id: ShardId,
+    index: LogIndex,
+    entry: &LogEntry,
+) -> Option<Operation> {
+    let command = entry.command.as_ref()?;
+    let seq_no = match client_seq_no(command) {
+        Some(seq_no) => seq_no,
+        None => return run_raft_entry(kvs, index, entry),
+    };
+    let answers = raft_answers.entry((shard_id, entry.client)).or_default();
+    if let Some((_, response)) = answers.get(&seq_no) {
+        return Some(response.clone());
+    }
+    if let Operation::Cas(key, ..) | Operation::PutIfAbsent(key, ..) | Operation::Incr(key, ..) = command {
+        if answers.range(seq_no..).any(|(_, (written, _))| written.contains(key)) {
+            let newest = answers.keys().next_back().cloned().unwrap_or(seq_no);
+            return Some(Operation::Stale(seq_no, newest));
+        }
+    }
+    let response = run_raft_entry(kvs, index, entry)?;
+    let written = match write_seq_no(command) {
+        Some(_) => request_keys(command),
+        None => Vec::new(),
+    };
+    answers.insert(seq_no, (written, response.clone()));
+    if answers.len() > ANSWERS_PER_CLIENT {
+        answers.pop_first();
+    }
+    Some(response)
+}
+
+fn run_raft_entry(kvs: &KVS, index: LogIndex, entry: &LogEntry) -> Option<Operation> {
+    match &entry.command {
+        Some(operation @ (Operation::Cas(key, ..) | Operation::PutIfAbsent(key, ..) | Operation::Incr(key, ..))) => {
+            let (write, response) = run_conditional(operation, kvs.get(key));
+            if let Some(value) = write {
+                kvs.apply_versioned(key.clone(), value, index);
+            }
+            Some(response)
+        }
+        Some(Operation::Put(key, value, seq_no)) => {
+            let old_value = kvs.apply_versioned(key.clone(), Some(value.clone()), index);
+            Some(Operation::PutRes(old_value, *seq_no))
+        }
+        Some(Operation::Delete(key, seq_no)) => {
+            let old_value = kvs.apply_versioned(key.clone(), None, index);
+            Some(Operation::DeleteRes(old_value, *seq_no))
+        }
+        Some(Operation::Get(key, seq_no)) => Some(Operation::GetRes(kvs.get(key), *seq_no)),
+        // a batch is one entry, so every replica applies all of it at once
+        Some(Operation::MultiPut(entries, seq_no)) => {
+            let writes = entries.iter().map(|(key, value)| (key.clone(), Some(value.clone()))).collect();
+            Some(Operation::MultiPutRes(apply_batch(kvs, &last_writes(writes), index), *seq_no))
+        }
+        Some(Operation::MultiDelete(keys, seq_no)) => {
+            let writes = keys.iter().map(|key| (key.clone(), None)).collect();
+            Some(Operation::MultiDeleteRes(apply_batch(kvs, &last_writes(writes), index), *seq_no))
+        }
+        Some(Operation::MultiGet(keys, seq_no)) => {
+            let values = keys.iter().map(|key| (key.clone(), kvs.get(key))).collect();
+            Some(Operation::MultiGetRes(values, *seq_no))
+        }
+        _ => None,
+    }
+}
+
+impl<C: Clock> ServerNode<C> {
+    pub fn new(self_pid: Pid, clock: C, seed: u64, raft_dir: Option<String>) -> Self {
+        ServerNode::with_partition(self_pid, clock, seed, raft_dir, KVS::new(), 0, 1)
+    }
+
+    // One of num_workers nodes of a server, serving the shards worker_of
+    // gives to `worker` over the KVS they share
+    pub fn with_partition(
+        self_pid: Pid,
+        clock: C,
+        seed: u64,
+        raft_dir: Option<String>,
+        kvs: KVS,
+        worker: usize,
+        num_workers: usize,
+    ) -> Self {
+        let now_ms = clock.now_ms();
+        ServerNode {
+            self_pid,
+            worker,
+            num_workers,
+            kvs,
+            shard_info: ShardInfo::new(),
+            lease: Lease::new(clock),
+            raft_dir,
+            rng: StdRng::seed_from_u64(seed),
+            pending_keys: HashMap::new(),
+            pending_reads: HashMap::new(),
+            pending_batches: BTreeMap::new(),
+            pending_batch_reads: BTreeMap::new(),
+            next_read_id: 0,
+            parked: HashMap::new(),
+            peak_key_depth: 0,
+            total_parked: 0,
+            busy_requests: 0,
+            raft_groups: BTreeMap::new(),
+            raft_pending: HashMap::new(),
+            raft_answers: HashMap::new(),
+            migrations_out: BTreeMap::new(),
+            migrations_in: HashMap::new(),
+            write_answers: HashMap::new(),
+            shard_versions: HashMap::new(),
+            replica_sync: HashMap::new(),
+            last_sync_ms: 0,
+            sessions: HashMap::new(),
+            txns: BTreeMap::new(),
+            txn_logs: HashMap::new(),
+            prepared: BTreeMap::new(),
+            txn_locks: HashMap::new(),
+            pending_txn_acks: BTreeMap::new(),
+            next_txn_ack: 0,
+            hot_keys: HotKeyTracker::new(now_ms),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.self_pid
+    }
+
+    pub fn kvs(&self) -> &KVS {
+        &self.kvs
+    }
+
+    pub fn shard_info(&self) -> &ShardInfo {
+        &self.shard_info
+    }
+
+    pub fn metrics(&self) -> ServerMetrics {
+        let parked_requests = self.parked.values().map(|queue| queue.len()).sum();
+        ServerMetrics {
+            parked_requests,
+            parked_keys: self.parked.len(),
+            max_key_depth: self.parked.values().map(|queue| queue.len()).max().unwrap_or(0),
+            peak_key_depth: self.peak_key_depth,
+            total_parked: self.total_parked,
+            // bin/server.rs adds those of the network thread's queues
+            queued_requests: parked_requests,
+            busy_requests: self.busy_requests,
+        }
+    }
+
+    pub fn take_messages(&mut self) -> Vec<(Pid, Operation)> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn send(&mut self, dst_pid: Pid, operation: Operation) {
+        self.outbox.push((dst_pid, operation));
+    }
+
+    // Answers a client, with the version of its keys if the request came as a
+    // Session
+    fn respond(&mut self, client_pid: Pid, response: Operation) {
+        let session = response_seq_no(&response).and_then(|seq_no| self.sessions.remove(&(client_pid, seq_no)));
+        match session {
+            Some(keys) => {
+                let version = keys.iter().map(|key| self.kvs.version(key)).max().unwrap_or(0);
+                self.send(client_pid, Operation::SessionRes(Box::new(response), version));
+            }
+            None => self.send(client_pid, response),
+        }
+    }
+
+    fn owns(&self, shard_id: ShardId) -> bool {
+        worker_of(shard_id, self.num_workers) == self.worker
+    }
+
+    // Every worker gets the ShardInfo, one of them answers for the server.
+    // Each reports the hottest keys of its own shards.
+    fn ack_shard_info(&mut self, controller_pid: Pid) {
+        if let Some(rates) = self.hot_keys.report(self.lease.now_ms()) {
+            self.send(controller_pid, Operation::HotKeys(rates));
+        }
+        if self.worker == 0 {
+            self.send(controller_pid, Operation::PutShardInfoRes());
+        }
+    }
+
+    pub fn tick(&mut self) {
+        let shard_ids: Vec<ShardId> = self.raft_groups.keys().cloned().collect();
+        for shard_id in shard_ids {
+            self.raft_groups.get_mut(&shard_id).unwrap().tick();
+            self.drive_raft(shard_id);
+        }
+        self.send_shard_versions();
+        self.check_txns();
+    }
+
+    pub fn step(&mut self, pid: Pid, operation: Operation) {
+        // println!("Server {} Received: pid={}, op={:?}", self_pid, pid, operation);
+
+        let raft_mode = self.shard_info.replication == ReplicationMode::Raft;
+        let no_shard_info = self.shard_info.locations.is_empty();
+        match operation {
+            Operation::Get(..)
+            | Operation::Put(..)
+            | Operation::Delete(..)
+            | Operation::MultiGet(..)
+            | Operation::MultiPut(..)
+            | Operation::MultiDelete(..)
+            | Operation::Cas(..)
+            | Operation::PutIfAbsent(..)
+            | Operation::Incr(..)
+            | Operation::Forward(..)
+            | Operation::Session(..)
+            | Operation::ReadReplica(..)
+            | Operation::TxnCommit(..)
+                if no_shard_info =>
+            {
+                // the controller has not told us the shard assignment yet,
+                // the client retries after its timeout
+                println!("No shard info yet, dropping request from {}", pid);
+            }
+            Operation::Get(..)
+            | Operation::Put(..)
+            | Operation::Delete(..)
+            | Operation::MultiGet(..)
+            | Operation::MultiPut(..)
+            | Operation::MultiDelete(..)
+            | Operation::Cas(..)
+            | Operation::PutIfAbsent(..)
+            | Operation::Incr(..)
+                if raft_mode =>
+            {
+                self.handle_raft_request(pid, operation);
+            }
+            // the client only sends these in PrimaryBackup mode, the response
+            // goes without a version
+            Operation::Session(request) if raft_mode => self.handle_raft_request(pid, *request),
+            Operation::ReadReplica(_, seq_no, _) if raft_mode => self.send(pid, Operation::ReadRefused(seq_no)),
+            Operation::RaftForward(client_pid, forwarded) => {
+                self.handle_raft_request(client_pid, *forwarded);
+            }
+            Operation::Raft(shard_id, msg) => {
+                if let Some(node) = self.raft_groups.get_mut(&shard_id) {
+                    node.step(pid, msg);
+                    self.drive_raft(shard_id);
+                }
+            }
+
+            Operation::Get(..)
+            | Operation::Put(..)
+            | Operation::Delete(..)
+            | Operation::MultiGet(..)
+            | Operation::MultiPut(..)
+            | Operation::MultiDelete(..)
+            | Operation::Cas(..)
+            | Operation::PutIfAbsent(..)
+            | Operation::Incr(..)
+            | Operation::Session(..) => {
+                self.handle_request(pid, operation, false);
+            }
+            Operation::ReadReplica(key, seq_no, bounds) => self.handle_read_replica(pid, key, seq_no, bounds),
+            Operation::TxnCommit(..) => self.handle_txn_commit(pid, operation, false),
+            Operation::Forward(client_pid, forwarded) if matches!(*forwarded, Operation::TxnCommit(..)) => {
+                self.handle_txn_commit(client_pid, *forwarded, true);
+            }
+            Operation::Forward(client_pid, forwarded) => {
+                self.handle_request(client_pid, *forwarded, true);
+            }
+
+            Operation::PutShardInfo(rcvd_shard_info) => {
+                if rcvd_shard_info.epoch < self.shard_info.epoch {
+                    // a heartbeat sent before the latest change, which may
+                    // have replaced this primary
+                    self.ack_shard_info(pid);
+                    return;
+                }
+                // Only renew the lease while still primary of every shard
+                // served as one, a demoted primary lets it run out
+                let still_primary = self
+                    .shard_info
+                    .locations
+                    .iter()
+                    .filter(|(shard_id, location)| location.primary == self.self_pid && self.owns(**shard_id))
+                    .all(|(shard_id, _)| {
+                        rcvd_shard_info.locations.get(shard_id).is_some_and(|location| location.primary == self.self_pid)
+                    });
+                if still_primary {
+                    self.lease.grant(rcvd_shard_info.lease_ms);
+                }
+                self.shard_info = rcvd_shard_info;
+                self.end_migrations();
+
+                // A secondary the controller dropped will never ack, stop waiting for it
+                let mut keys: Vec<String> = self.pending_keys.keys().cloned().collect();
+                keys.sort();
+                for key in keys {
+                    let secondaries = self.get_secondaries_by_key(&key);
+                    let key_info = self.pending_keys.get_mut(&key).unwrap();
+                    key_info.pending_secondaries.retain(|secondary| secondaries.contains(secondary));
+                    key_info.acks_remaining = key_info.acks_remaining.min(key_info.pending_secondaries.len());
+                    if key_info.acks_remaining == 0 {
+                        self.finish_write(&key);
+                    }
+                }
+                let batches: Vec<(ShardId, u64)> = self.pending_batches.keys().cloned().collect();
+                for batch_id in batches {
+                    let secondaries = self.get_secondaries_by_key(&self.pending_batches[&batch_id].keys[0]);
+                    let batch = self.pending_batches.get_mut(&batch_id).unwrap();
+                    batch.pending_secondaries.retain(|secondary| secondaries.contains(secondary));
+                    batch.acks_remaining = batch.acks_remaining.min(batch.pending_secondaries.len());
+                    if batch.acks_remaining == 0 {
+                        self.finish_batch(batch_id);
+                    }
+                }
+                let txn_acks: Vec<usize> = self.pending_txn_acks.keys().cloned().collect();
+                for ack_id in txn_acks {
+                    let secondaries = self.get_secondaries(self.pending_txn_acks[&ack_id].shard_id);
+                    let acks = self.pending_txn_acks.get_mut(&ack_id).unwrap();
+                    acks.pending_secondaries.retain(|secondary| secondaries.contains(secondary));
+                    if acks.pending_secondaries.is_empty() {
+                        let acks = self.pending_txn_acks.remove(&ack_id).unwrap();
+                        self.run_txn_step(acks.shard_id, acks.txn_id, acks.then);
+                    }
+                }
+
+                self.ensure_raft_groups();
+                self.adopt_decisions();
+                self.ack_shard_info(pid);
+            }
+
+            Operation::Replicate(key, value, version) => {
+                let old_value = self.kvs.apply_versioned(key.clone(), value, version);
+                self.note_version(&key, version);
+                self.send(pid, Operation::ReplicateRes(key, old_value, version));
+            }
+            Operation::ReplicateRes(key, _old_value, version) => {
+                // Acks of an older version are late quorum acks or read repairs
+                let key_info = match self.pending_keys.get_mut(&key) {
+                    Some(key_info) if key_info.version == version => key_info,
+                    _ => return,
+                };
+
+                let found: Result<usize, usize> = key_info.pending_secondaries.binary_search(&pid);
+                if let Ok(id) = found {
+                    key_info.pending_secondaries.remove(id);
+                    key_info.acks_remaining -= 1;
+                } else {
+                    println!("Unexpected behaviour... secondary pid not found in pending list");
+                }
+
+                if key_info.acks_remaining == 0 {
+                    self.finish_write(&key);
+                }
+            }
+
+            Operation::ReplicateBatch(shard_id, writes, version) => {
+                for (key, _) in &writes {
+                    self.note_version(key, version);
+                }
+                apply_batch(&self.kvs, &writes, version);
+                self.send(pid, Operation::ReplicateBatchRes(shard_id, version));
+            }
+            Operation::ReplicateBatchRes(shard_id, version) => {
+                let batch = match self.pending_batches.get_mut(&(shard_id, version)) {
+                    Some(batch) => batch,
+                    None => return,
+                };
+                if let Ok(id) = batch.pending_secondaries.binary_search(&pid) {
+                    batch.pending_secondaries.remove(id);
+                    batch.acks_remaining -= 1;
+                }
+                if batch.acks_remaining == 0 {
+                    self.finish_batch((shard_id, version));
+                }
+            }
+
+            Operation::ReadVersion(key, read_id) => {
+                let (value, version) = self.kvs.get_versioned(&key);
+                self.send(pid, Operation::ReadVersionRes(key, value, version, read_id));
+            }
+            Operation::ReadVersionRes(key, value, version, read_id) => {
+                self.handle_read_version_res(pid, key, value, version, read_id);
+            }
+            Operation::ReadVersions(shard_id, keys, read_id) => {
+                let copies = keys
+                    .into_iter()
+                    .map(|key| {
+                        let (value, version) = self.kvs.get_versioned(&key);
+                        (key, value, version)
+                    })
+                    .collect();
+                self.send(pid, Operation::ReadVersionsRes(shard_id, copies, read_id));
+            }
+            Operation::ReadVersionsRes(_, copies, read_id) => self.handle_read_versions_res(pid, copies, read_id),
+            Operation::ShardVersions(versions) => {
+                let now_ms = self.lease.now_ms();
+                for (shard_id, version) in versions {
+                    // only the primary our ShardInfo names knows what we have,
+                    // and an older ShardVersions overtaken by a newer one says less
+                    if self.shard_info.locations.get(&shard_id).is_none_or(|location| location.primary != pid) {
+                        continue;
+                    }
+                    let sync = self.replica_sync.entry(shard_id).or_insert((version, now_ms));
+                    if version >= sync.0 {
+                        *sync = (version, now_ms);
+                    }
+                }
+            }
+
+            Operation::Snapshot() => {
+                let op = self.handle_snapshot();
+                self.send(pid, op);
+            }
+            Operation::Metrics() => {
+                let metrics = self.metrics();
+                self.send(pid, Operation::MetricsRes(metrics));
+            }
+
+            Operation::MigrateShard(shard_id, to, epoch) => self.start_migration(pid, shard_id, to, epoch),
+            Operation::ShardDataRes(shard_id, epoch, applied) => {
+                self.handle_shard_data_res(shard_id, epoch, applied);
+            }
+            Operation::ShardData(shard_id, epoch, entries) => {
+                for (key, value, version) in entries.iter().cloned() {
+                    self.note_version(&key, version);
+                    self.kvs.apply_versioned(key, value, version);
+                }
+                let migration = self.migrations_in.entry((shard_id, epoch)).or_insert(MigrationIn {
+                    from: pid,
+                    received: 0,
+                    expected: None,
+                });
+                migration.received += entries.len();
+                self.check_migration_in(shard_id, epoch);
+            }
+            Operation::ShardDataDone(shard_id, epoch, total) => {
+                let migration = self.migrations_in.entry((shard_id, epoch)).or_insert(MigrationIn {
+                    from: pid,
+                    received: 0,
+                    expected: None,
+                });
+                migration.expected = migration.expected.max(Some(total));
+                self.check_migration_in(shard_id, epoch);
+            }
+
+            Operation::TxnPrepare(txn_id, coordinator, shard_id, reads, writes) => {
+                self.handle_txn_prepare(pid, txn_id, coordinator, shard_id, reads, writes);
+            }
+            Operation::TxnPrepareRes(txn_id, _, shard_id, vote) => {
+                let txn = match self.txns.get_mut(&txn_id) {
+                    Some(txn) if txn.decision.is_none() => txn,
+                    _ => return,
+                };
+                if vote {
+                    txn.votes.insert(shard_id);
+                }
+                if !vote || txn.votes.len() == txn.parts.len() {
+                    self.decide(txn_id, vote);
+                }
+            }
+            Operation::TxnDecide(txn_id, coordinator, shard_id, commit) => {
+                self.handle_txn_decide(pid, txn_id, coordinator, shard_id, commit);
+            }
+            Operation::TxnDecideRes(txn_id, _, shard_id) => {
+                let txn = match self.txns.get_mut(&txn_id) {
+                    Some(txn) => txn,
+                    None => return,
+                };
+                txn.done.insert(shard_id);
+                if txn.done.len() == txn.parts.len() {
+                    let txn = self.txns.remove(&txn_id).unwrap();
+                    self.forget_txn(txn_id, txn.shard_id, txn.parts.keys().cloned().collect());
+                }
+            }
+            Operation::TxnStatus(txn_id, coordinator, shard_id) => {
+                self.handle_txn_status(pid, txn_id, coordinator, shard_id);
+            }
+            Operation::TxnForget(txn_id, shard_id) => self.txn_log(shard_id).forget(txn_id),
+            Operation::TxnReplicate(shard_id, txn_id, record, ack_id) => {
+                if self.apply_txn_record(shard_id, txn_id, record) {
+                    self.send(pid, Operation::TxnReplicateRes(shard_id, ack_id));
+                }
+            }
+            Operation::TxnReplicateRes(_, ack_id) => {
+                let acks = match self.pending_txn_acks.get_mut(&ack_id) {
+                    Some(acks) => acks,
+                    None => return,
+                };
+                acks.pending_secondaries.retain(|secondary| *secondary != pid);
+                if acks.pending_secondaries.is_empty() {
+                    let acks = self.pending_txn_acks.remove(&ack_id).unwrap();
+                    self.run_txn_step(acks.shard_id, acks.txn_id, acks.then);
+                }
+            }
+
+            // Operation::SnapshotRes(snapshot) => {},
+
+            _op => {
+                let error = format!("Unexpected operation received at server, op={:?}", _op);
+                println!("{}", error);
+            }
+        }
+    }
+
+    fn is_pending(&self, key: &String) -> bool {
+        self.pending_keys.contains_key(key)
+            || self.pending_reads.contains_key(key)
+            || self.pending_batches.values().any(|batch| batch.keys.contains(key))
+            || self.pending_batch_reads.values().any(|read| read.newest.iter().any(|(read_key, ..)| read_key == key))
+            || self.txn_locks.contains_key(key)
+    }
+
+    // Serves the requests that waited for key, until one of them has to wait
+    // again.
+    fn resume(&mut self, key: &String) {
+        while !self.is_pending(key) {
+            let (pid, operation) = match self.parked.get_mut(key).and_then(|queue| queue.pop_front()) {
+                Some(request) => request,
+                None => {
+                    self.parked.remove(key);
+                    return;
+                }
+            };
+            self.step(pid, operation);
+        }
+    }
+
+    // The other replicas of the key's shard. A request forwarded to us before
+    // our ShardInfo names us the primary still goes to every other replica.
+    fn get_secondaries_by_key(&self, key: &str) -> Vec<Pid> {
+        self.get_secondaries(self.shard_info.shard_of(key))
+    }
+
+    fn get_secondaries(&self, shard_id: ShardId) -> Vec<Pid> {
+        let mut secondaries = self.shard_info.replicas(shard_id);
+        secondaries.retain(|pid| *pid != self.self_pid);
+        secondaries.sort();
+        secondaries
+    }
+
+    // A client request, or one another server forwarded to us as the primary
+    fn handle_request(&mut self, client_pid: Pid, operation: Operation, forwarded: bool) {
+        let keys = request_keys(&operation);
+        let shard_id = match keys.first() {
+            Some(key) => self.shard_info.shard_of(key),
+            None => return,
+        };
+        if keys.iter().any(|key| self.shard_info.shard_of(key) != shard_id) {
+            println!("Dropping batch {:?} from {}, its keys are on more than one shard", keys, client_pid);
+            return;
+        }
+        if let Some(migration) = self.migrations_out.get_mut(&shard_id) {
+            if migration.frozen {
+                migration.held.push((client_pid, operation));
+                return;
+            }
+        }
+        let primary = self.shard_info.locations[&shard_id].primary;
+        if !forwarded && primary != self.self_pid {
+            // the client has an old ShardInfo, or the shard moved away from us
+            self.send(primary, Operation::Forward(client_pid, Box::new(operation)));
+            return;
+        }
+        if let Some(key) = keys.iter().find(|key| self.is_pending(key)).cloned() {
+            // served once the write or read in flight for the key is done, a
+            // batch may wait again for another of its keys then
+            // with as many requests waiting as a worker queues (see
+            // admission.rs), the client is told Busy instead
+            if let Some(seq_no) = client_seq_no(&operation) {
+                let client_parked = self.parked.values().flatten().filter(|(pid, _)| *pid == client_pid).count();
+                let parked: usize = self.parked.values().map(|queue| queue.len()).sum();
+                if client_parked >= CLIENT_QUEUE_CAPACITY || parked >= QUEUE_CAPACITY {
+                    self.busy_requests += 1;
+                    self.send(client_pid, Operation::Busy(seq_no, BUSY_RETRY_AFTER_MS));
+                    return;
+                }
+            }
+            let parked = match forwarded {
+                true => Operation::Forward(client_pid, Box::new(operation)),
+                false => operation,
+            };
+            let queue = self.parked.entry(key).or_default();
+            queue.push_back((client_pid, parked));
+            self.peak_key_depth = self.peak_key_depth.max(queue.len());
+            self.total_parked += 1;
+            return;
+        }
+        for key in &keys {
+            self.hot_keys.record(key);
+        }
+
+        let operation = match operation {
+            Operation::Session(request) => {
+                if let Some(seq_no) = request_seq_no(&request) {
+                    self.sessions.insert((client_pid, seq_no), keys.clone());
+                }
+                *request
+            }
+            operation => operation,
+        };
+        if let Some(seq_no) = write_seq_no(&operation) {
+            if self.ran_before(client_pid, shard_id, &keys, seq_no) {
+                return;
+            }
+        }
+        match operation {
+            Operation::Put(key, value, seq_no) => {
+                let op = self.handle_put(key.clone(), value, seq_no);
+                self.keep_answer(client_pid, shard_id, vec![key.clone()], &op);
+                self.wait_for_acks(client_pid, key, op);
+            }
+            Operation::Get(key, seq_no) => self.handle_get(client_pid, key, seq_no),
+            Operation::Delete(key, seq_no) => {
+                let op = self.handle_delete(key.clone(), seq_no);
+                self.keep_answer(client_pid, shard_id, vec![key.clone()], &op);
+                self.wait_for_acks(client_pid, key, op);
+            }
+            Operation::MultiGet(keys, seq_no) => self.handle_multi_get(client_pid, shard_id, keys, seq_no),
+            Operation::MultiPut(entries, seq_no) => {
+                let writes = entries.into_iter().map(|(key, value)| (key, Some(value))).collect();
+                self.handle_batch_write(client_pid, shard_id, writes, |old_values| Operation::MultiPutRes(old_values, seq_no));
+            }
+            Operation::MultiDelete(keys, seq_no) => {
+                let writes = keys.into_iter().map(|key| (key, None)).collect();
+                self.handle_batch_write(client_pid, shard_id, writes, |old_values| {
+                    Operation::MultiDeleteRes(old_values, seq_no)
+                });
+            }
+            operation @ (Operation::Cas(..) | Operation::PutIfAbsent(..) | Operation::Incr(..)) => {
+                self.handle_conditional(client_pid, operation);
+            }
+            _ => {}
+        }
+    }
+
+    // Whether the write with seq_no must not run: a retry, or a late copy of
+    // one (see the top of the file). One we still have the answer of gets it
+    // again, the others Stale.
+    fn ran_before(&mut self, client_pid: Pid, shard_id: ShardId, keys: &[String], seq_no: usize) -> bool {
+        let answers = match self.write_answers.get(&(shard_id, client_pid)) {
+            Some(answers) => answers,
+            None => return false,
+        };
+        let same_keys = |written: &Vec<String>| {
+            written.iter().all(|key| keys.contains(key)) && keys.iter().all(|key| written.contains(key))
+        };
+        if let Some((written, response)) = answers.get(&seq_no) {
+            if same_keys(written) {
+                let response = response.clone();
+                self.respond(client_pid, response);
+                return true;
+            }
+        }
+        let reused = answers.contains_key(&seq_no);
+        let wrote_later = answers.range(seq_no..).any(|(_, (written, _))| written.iter().any(|key| keys.contains(key)));
+        let too_old = answers.len() >= ANSWERS_PER_CLIENT && answers.keys().next().is_some_and(|oldest| seq_no < *oldest);
+        if reused || wrote_later || too_old {
+            let newest = answers.keys().next_back().cloned().unwrap_or(seq_no);
+            println!("Not running write {} of {:?} from {}, we answered up to {}", seq_no, keys, client_pid, newest);
+            self.sessions.remove(&(client_pid, seq_no));
+            self.send(client_pid, Operation::Stale(seq_no, newest));
+            return true;
+        }
+        false
+    }
+
+    // Remembers the answer of a write we ran as the primary, for ran_before
+    fn keep_answer(&mut self, client_pid: Pid, shard_id: ShardId, keys: Vec<String>, response: &Operation) {
+        let seq_no = match response_seq_no(response) {
+            Some(seq_no) => seq_no,
+            None => return,
+        };
+        let answers = self.write_answers.entry((shard_id, client_pid)).or_default();
+        answers.insert(seq_no, (keys, response.clone()));
+        if answers.len() > ANSWERS_PER_CLIENT {
+            answers.pop_first();
+        }
+    }
+
+    // Number of secondaries that have to answer a write and a read of the shard
+    // before the primary can respond to the client. Raft groups never get here.
+    fn quorum_sizes(&self, num_secondaries: usize) -> (usize, usize) {
+        match self.shard_info.replication {
+            ReplicationMode::PrimaryBackup | ReplicationMode::Raft => (num_secondaries, 0),
+            ReplicationMode::Quorum { write, read } => (
+                write.saturating_sub(1).min(num_secondaries),
+                read.saturating_sub(1).min(num_secondaries),
+            ),
+        }
+    }
+
+    fn handle_get(&mut self, pid: Pid, key: String, seq_no: usize) {
+        // Without a lease the controller may already have promoted a secondary,
+        // our copy could be stale, and so could those of a quorum we ask
+        if self.shard_info.lease_ms > 0 && !self.lease.is_valid() {
+            // the client retries with a fresh shard info after its timeout
+            let e = Error::other("primary lease expired");
+            println!("Refusing get of {}: {}", key, e);
+            return;
+        }
+        let secondaries = self.get_secondaries_by_key(&key);
+        let (_, replies_needed) = self.quorum_sizes(secondaries.len());
+        if replies_needed > 0 {
+            // Quorum read: ask every secondary and answer once enough copies came back
+            self.next_read_id += 1;
+            for secondary in &secondaries {
+                self.send(*secondary, Operation::ReadVersion(key.clone(), self.next_read_id));
+            }
+            let (value, version) = self.kvs.get_versioned(&key);
+            self.pending_reads.insert(
+                key,
+                ReadInfo {
+                    client_pid: pid,
+                    seq_no,
+                    read_id: self.next_read_id,
+                    replies_remaining: replies_needed,
+                    replied: vec![(self.self_pid, version)],
+                    newest: (value, version),
+                },
+            );
+            return;
+        }
+
+        let value = self.kvs.get(&key);
+        self.respond(pid, Operation::GetRes(value, seq_no));
+    }
+
+    // A Get any replica of the shard may answer. As the primary we serve it
+    // like any other, as a secondary only within the client's bounds.
+    fn handle_read_replica(&mut self, client_pid: Pid, key: String, seq_no: usize, bounds: ReadBounds) {
+        let shard_id = self.shard_info.shard_of(&key);
+        let location = &self.shard_info.locations[&shard_id];
+        if location.primary == self.self_pid {
+            let request = Operation::Session(Box::new(Operation::Get(key, seq_no)));
+            self.handle_request(client_pid, request, false);
+            return;
+        }
+        let is_secondary = location.secondaries.contains(&self.self_pid);
+        let sync = match self.replica_sync.get(&shard_id) {
+            Some(sync) if is_secondary && self.shard_info.replication == ReplicationMode::PrimaryBackup => *sync,
+            _ => {
+                self.send(client_pid, Operation::ReadRefused(seq_no));
+                return;
+            }
+        };
+        let (synced_version, synced_ms) = sync;
+        let staleness_ms = self.lease.now_ms().saturating_sub(synced_ms);
+        if synced_version < bounds.min_version || bounds.max_staleness_ms.is_some_and(|max| staleness_ms > max) {
+            self.send(client_pid, Operation::ReadRefused(seq_no));
+            return;
+        }
+        self.hot_keys.record(&key);
+        // the copy may hold a write still waiting for acks, its version goes
+        // into the token so no later read goes back before it
+        let (value, version) = self.kvs.get_versioned(&key);
+        let token = version.max(synced_version);
+        self.send(client_pid, Operation::SessionRes(Box::new(Operation::GetRes(value, seq_no)), token));
+    }
+
+    fn handle_put(&mut self, key: String, value: String, psn: usize) -> Operation {
+        let old_value = self.replicate_write(&key, Some(value));
+        Operation::PutRes(old_value, psn)
+    }
+
+    fn handle_delete(&mut self, key: String, psn: usize) -> Operation {
+        let old_value = self.replicate_write(&key, None);
+        Operation::DeleteRes(old_value, psn)
+    }
+
+    // Apply the write locally under a new version, then send Replicate to
+    // all secondary servers of the key; a none value marks a delete. The
+    // ReplicateRes are collected before sending the response to the client
+    fn replicate_write(&mut self, key: &str, value: Option<String>) -> Option<String> {
+        let version = self.next_version(key);
+        let old_value = self.kvs.apply_versioned(key.to_string(), value.clone(), version);
+        self.note_version(key, version);
+
+        for pid in self.get_secondaries_by_key(key) {
+            self.send(pid, Operation::Replicate(key.to_string(), value.clone(), version));
+        }
+
+        old_value
+    }
+
+    // A Cas, PutIfAbsent or Incr. The condition reads our copy, so like a Get
+    // it needs the lease; what it writes waits for acks like a Put.
+    fn handle_conditional(&mut self, client_pid: Pid, operation: Operation) {
+        let key = request_key(&operation).unwrap().clone();
+        if self.shard_info.lease_ms > 0 && !self.lease.is_valid() {
+            println!("Refusing {:?}: primary lease expired", operation);
+            return;
+        }
+
+        let (write, response) = run_conditional(&operation, self.kvs.get(&key));
+        let shard_id = self.shard_info.shard_of(&key);
+        self.keep_answer(client_pid, shard_id, vec![key.clone()], &response);
+        match write {
+            Some(value) => {
+                self.replicate_write(&key, value);
+                self.wait_for_acks(client_pid, key, response);
+            }
+            None => self.respond(client_pid, response),
+        }
+    }
+
+    // Applies every write of a batch under one version and sends them to each
+    // secondary in one ReplicateBatch, so no replica ever has only part of the
+    // batch. The client is answered once enough secondaries acked all of it.
+    fn handle_batch_write<F>(&mut self, client_pid: Pid, shard_id: ShardId, writes: Vec<(String, Option<String>)>, response: F)
+    where
+        F: FnOnce(Vec<(String, Option<String>)>) -> Operation,
+    {
+        let writes = last_writes(writes);
+        let version = writes.iter().map(|(key, _)| self.next_version(key)).max().unwrap();
+        let old_values = apply_batch(&self.kvs, &writes, version);
+        for (key, _) in &writes {
+            self.note_version(key, version);
+        }
+
+        let secondaries = self.get_secondaries_by_key(&writes[0].0);
+        for pid in &secondaries {
+            self.send(*pid, Operation::ReplicateBatch(shard_id, writes.clone(), version));
+        }
+        let (acks_needed, _) = self.quorum_sizes(secondaries.len());
+        let response = response(old_values);
+        let keys = writes.iter().map(|(key, _)| key.clone()).collect();
+        self.keep_answer(client_pid, shard_id, keys, &response);
+        if acks_needed == 0 {
+            self.respond(client_pid, response);
+            return;
+        }
+        self.pending_batches.insert(
+            (shard_id, version),
+            BatchInfo {
+                client_pid,
+                keys: writes.into_iter().map(|(key, _)| key).collect(),
+                pending_secondaries: secondaries,
+                acks_remaining: acks_needed,
+                response,
+            },
+        );
+    }
+
+    fn finish_batch(&mut self, batch_id: (ShardId, u64)) {
+        let batch = self.pending_batches.remove(&batch_id).unwrap();
+        self.respond(batch.client_pid, batch.response);
+        for key in &batch.keys {
+            self.resume(key);
+        }
+        self.continue_migration(batch_id.0);
+    }
+
+    // Like handle_get for every key of the batch, with one ReadVersions per
+    // secondary in Quorum mode
+    fn handle_multi_get(&mut self, client_pid: Pid, shard_id: ShardId, keys: Vec<String>, seq_no: usize) {
+        if self.shard_info.lease_ms > 0 && !self.lease.is_valid() {
+            println!("Refusing get of {:?}: primary lease expired", keys);
+            return;
+        }
+        let secondaries = self.get_secondaries_by_key(&keys[0]);
+        let (_, replies_needed) = self.quorum_sizes(secondaries.len());
+        if replies_needed > 0 {
+            self.next_read_id += 1;
+            for secondary in &secondaries {
+                self.send(*secondary, Operation::ReadVersions(shard_id, keys.clone(), self.next_read_id));
+            }
+            let newest: Vec<(String, Option<String>, u64)> = keys
+                .into_iter()
+                .map(|key| {
+                    let (value, version) = self.kvs.get_versioned(&key);
+                    (key, value, version)
+                })
+                .collect();
+            let versions = newest.iter().map(|(_, _, version)| *version).collect();
+            self.pending_batch_reads.insert(
+                self.next_read_id,
+                BatchReadInfo {
+                    client_pid,
+                    shard_id,
+                    seq_no,
+                    replies_remaining: replies_needed,
+                    replied: vec![(self.self_pid, versions)],
+                    newest,
+                },
+            );
+            return;
+        }
+
+        let values = keys
+            .into_iter()
+            .map(|key| {
+                let value = self.kvs.get(&key);
+                (key, value)
+            })
+            .collect();
+        self.respond(client_pid, Operation::MultiGetRes(values, seq_no));
+    }
+
+    fn handle_read_versions_res(&mut self, pid: Pid, copies: Vec<(String, Option<String>, u64)>, read_id: usize) {
+        let read_info = match self.pending_batch_reads.get_mut(&read_id) {
+            Some(read_info) => read_info,
+            None => {
+                // Late reply to a finished read, repair the copies that are behind ours
+                for (key, _, version) in copies {
+                    let (local_value, local_version) = self.kvs.get_versioned(&key);
+                    if version < local_version {
+                        self.send(pid, Operation::Replicate(key, local_value, local_version));
+                    }
+                }
+                return;
+            }
+        };
+
+        read_info.replied.push((pid, copies.iter().map(|(_, _, version)| *version).collect()));
+        for (newest, copy) in read_info.newest.iter_mut().zip(copies) {
+            if copy.0 == newest.0 && copy.2 > newest.2 {
+                *newest = copy;
+            }
+        }
+        read_info.replies_remaining -= 1;
+        if read_info.replies_remaining > 0 {
+            return;
+        }
+
+        // Read repair of every stale copy we consulted, as for a single key
+        let read_info = self.pending_batch_reads.remove(&read_id).unwrap();
+        for (replica, versions) in &read_info.replied {
+            for ((key, value, version), replica_version) in read_info.newest.iter().zip(versions) {
+                if replica_version >= version {
+                    continue;
+                }
+                if *replica == self.self_pid {
+                    self.kvs.apply_versioned(key.clone(), value.clone(), *version);
+                    self.note_version(key, *version);
+                } else {
+                    self.send(*replica, Operation::Replicate(key.clone(), value.clone(), *version));
+                }
+            }
+        }
+
+        let values = read_info.newest.iter().map(|(key, value, _)| (key.clone(), value.clone())).collect();
+        self.respond(read_info.client_pid, Operation::MultiGetRes(values, read_info.seq_no));
+        for (key, ..) in &read_info.newest {
+            self.resume(key);
+        }
+        self.continue_migration(read_info.shard_id);
+    }
+
+    // The version of a new write of key: above the key's and every other
+    // version of its shard
+    fn next_version(&self, key: &str) -> u64 {
+        let shard_id = self.shard_info.shard_of(key);
+        let shard_version = self.shard_versions.get(&shard_id).cloned().unwrap_or(0);
+        self.kvs.version(key).max(shard_version) + 1
+    }
+
+    fn note_version(&mut self, key: &str, version: u64) {
+        if self.shard_info.num_shards == 0 {
+            return;
+        }
+        let shard_version = self.shard_versions.entry(self.shard_info.shard_of(key)).or_insert(0);
+        *shard_version = (*shard_version).max(version);
+    }
+
+    // Answers the client right away, or once enough secondaries acked the write
+    fn wait_for_acks(&mut self, client_pid: Pid, key: String, response: Operation) {
+        let secondaries = self.get_secondaries_by_key(&key);
+        let (acks_needed, _) = self.quorum_sizes(secondaries.len());
+        if acks_needed == 0 {
+            self.respond(client_pid, response);
+            return;
+        }
+        let version = self.kvs.version(&key);
+        self.pending_keys.insert(
+            key,
+            KeyInfo {
+                client_pid,
+                pending_secondaries: secondaries,
+                acks_remaining: acks_needed,
+                version,
+                response,
+            },
+        );
+    }
+
+    fn finish_write(&mut self, key: &String) {
+        let key_info = self.pending_keys.remove(key).unwrap();
+        self.respond(key_info.client_pid, key_info.response);
+        self.resume(key);
+        self.continue_migration(self.shard_info.shard_of(key));
+    }
+
+    fn handle_read_version_res(&mut self, pid: Pid, key: String, value: Option<String>, version: u64, read_id: usize) {
+        let read_info = match self.pending_reads.get_mut(&key) {
+            Some(read_info) if read_info.read_id == read_id => read_info,
+            _ => {
+                // Late reply to a finished read, repair the copy if it is behind ours
+                let (local_value, local_version) = self.kvs.get_versioned(&key);
+                if version < local_version {
+                    self.send(pid, Operation::Replicate(key, local_value, local_version));
+                }
+                return;
+            }
+        };
+
+        read_info.replied.push((pid, version));
+        if version > read_info.newest.1 {
+            read_info.newest = (value, version);
+        }
+        read_info.replies_remaining -= 1;
+        if read_info.replies_remaining > 0 {
+            return;
+        }
+
+        let read_info = self.pending_reads.remove(&key).unwrap();
+        let (newest_value, newest_version) = read_info.newest;
+
+        // Read repair: bring every stale copy we consulted up to date
+        for (replica, replica_version) in &read_info.replied {
+            if *replica_version >= newest_version {
+                continue;
+            }
+            if *replica == self.self_pid {
+                self.kvs.apply_versioned(key.clone(), newest_value.clone(), newest_version);
+                self.note_version(&key, newest_version);
+            } else {
+                self.send(*replica, Operation::Replicate(key.clone(), newest_value.clone(), newest_version));
+            }
+        }
+
+        self.respond(read_info.client_pid, Operation::GetRes(newest_value, read_info.seq_no));
+        self.resume(&key);
+        self.continue_migration(self.shard_info.shard_of(&key));
+    }
+
+    // Tells every secondary of the shards we are the primary of up to which
+    // version it has all of their writes. In PrimaryBackup mode a write is
+    // only done once every secondary acked it, so that is the version just
+    // below the oldest write still waiting for acks, or the shard's newest.
+    fn send_shard_versions(&mut self) {
+        if self.shard_info.replication != ReplicationMode::PrimaryBackup || self.shard_info.locations.is_empty() {
+            return;
+        }
+        let now_ms = self.lease.now_ms();
+        if now_ms < self.last_sync_ms + SYNC_INTERVAL_MS {
+            return;
+        }
+        self.last_sync_ms = now_ms;
+        if self.shard_info.lease_ms > 0 && !self.lease.is_valid() {
+            // the controller may have promoted a secondary already
+            return;
+        }
+        let mut oldest_pending: HashMap<ShardId, u64> = HashMap::new();
+        for (key, key_info) in &self.pending_keys {
+            let oldest = oldest_pending.entry(self.shard_info.shard_of(key)).or_insert(key_info.version);
+            *oldest = (*oldest).min(key_info.version);
+        }
+        for (shard_id, version) in self.pending_batches.keys() {
+            let oldest = oldest_pending.entry(*shard_id).or_insert(*version);
+            *oldest = (*oldest).min(*version);
+        }
+        for acks in self.pending_txn_acks.values().filter(|acks| acks.version > 0) {
+            let oldest = oldest_pending.entry(acks.shard_id).or_insert(acks.version);
+            *oldest = (*oldest).min(acks.version);
+        }
+        let mut shard_ids: Vec<ShardId> = self.shard_info.locations.keys().cloned().collect();
+        shard_ids.sort();
+        let mut versions: BTreeMap<Pid, Vec<(ShardId, u64)>> = BTreeMap::new();
+        for shard_id in shard_ids {
+            let location = &self.shard_info.locations[&shard_id];
+            if location.primary != self.self_pid || !self.owns(shard_id) {
+                continue;
+            }
+            let version = match oldest_pending.get(&shard_id) {
+                Some(oldest) => oldest - 1,
+                None => self.shard_versions.get(&shard_id).cloned().unwrap_or(0),
+            };
+            for secondary in &location.secondaries {
+                versions.entry(*secondary).or_default().push((shard_id, version));
+            }
+        }
+        for (secondary, versions) in versions {
+            self.send(secondary, Operation::ShardVersions(versions));
+        }
+    }
+
+    // Step 1 of a move: the first copy of the shard, sent while we keep serving it
+    fn start_migration(&mut self, controller_pid: Pid, shard_id: ShardId, to: Pid, epoch: u64) {
+        if let Some(migration) = self.migrations_out.get(&shard_id) {
+            if migration.epoch != epoch {
+                println!("Shard {} is already moving to {}", shard_id, migration.to);
+            }
+            return;
+        }
+        let location = match self.shard_info.locations.get(&shard_id) {
+            Some(location) => location,
+            None => return,
+        };
+        if self.shard_info.replication == ReplicationMode::Raft || location.primary != self.self_pid {
+            println!("Not moving shard {}, we are not its primary", shard_id);
+            return;
+        }
+        self.migrations_out.insert(
+            shard_id,
+            MigrationOut {
+                to,
+                epoch,
+                controller_pid,
+                sent: HashMap::new(),
+                num_sent: 0,
+                first_copy: 0,
+                frozen: false,
+                delta_sent: false,
+                held: Vec::new(),
+            },
+        );
+        let entries = self.shard_entries(shard_id);
+        self.send_shard_data(shard_id, entries);
+        let migration = self.migrations_out.get_mut(&shard_id).unwrap();
+        migration.first_copy = migration.num_sent;
+    }
+
+    // (key, value, version) of every key of the shard we have not sent to the
+    // new primary in this version yet, sorted by key
+    fn shard_entries(&self, shard_id: ShardId) -> Vec<(String, Option<String>, u64)> {
+        let sent = &self.migrations_out[&shard_id].sent;
+        let mut entries: Vec<(String, Option<String>, u64)> = self
+            .kvs
+            .versioned_entries()
+            .into_iter()
+            .filter(|(key, _, version)| {
+                self.shard_info.shard_of(key) == shard_id && sent.get(key).is_none_or(|sent| sent < version)
+            })
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn send_shard_data(&mut self, shard_id: ShardId, entries: Vec<(String, Option<String>, u64)>) {
+        let migration = self.migrations_out.get_mut(&shard_id).unwrap();
+        let (to, epoch) = (migration.to, migration.epoch);
+        for (key, _, version) in &entries {
+            migration.sent.insert(key.clone(), *version);
+        }
+        migration.num_sent += entries.len();
+        let total = migration.num_sent;
+        for chunk in entries.chunks(MIGRATION_CHUNK_KEYS) {
+            self.send(to, Operation::ShardData(shard_id, epoch, chunk.to_vec()));
+        }
+        self.send(to, Operation::ShardDataDone(shard_id, epoch, total));
+    }
+
+    fn handle_shard_data_res(&mut self, shard_id: ShardId, epoch: u64, applied: usize) {
+        let migration = match self.migrations_out.get_mut(&shard_id) {
+            Some(migration) if migration.epoch == epoch => migration,
+            _ => return,
+        };
+        if !migration.frozen && applied >= migration.first_copy {
+            // Step 2: stop serving the shard and send what changed meanwhile
+            migration.frozen = true;
+            self.continue_migration(shard_id);
+        } else if migration.delta_sent && applied >= migration.num_sent {
+            // Step 3: the new primary has every key
+            let controller_pid = migration.controller_pid;
+            self.send(controller_pid, Operation::MigrateShardRes(shard_id, epoch));
+        }
+    }
+
+    // Sends the keys that changed since the first copy once the shard is
+    // frozen and no write or read of it is in flight anymore
+    fn continue_migration(&mut self, shard_id: ShardId) {
+        match self.migrations_out.get(&shard_id) {
+            Some(migration) if migration.frozen && !migration.delta_sent => {}
+            _ => return,
+        }
+        let busy = self
+            .pending_keys
+            .keys()
+            .chain(self.pending_reads.keys())
+            .any(|key| self.shard_info.shard_of(key) == shard_id)
+            || self.pending_batches.keys().any(|(batch_shard, _)| *batch_shard == shard_id)
+            || self.pending_batch_reads.values().any(|read| read.shard_id == shard_id)
+            || self.txn_locks.keys().any(|key| self.shard_info.shard_of(key) == shard_id)
+            || self.pending_txn_acks.values().any(|acks| acks.shard_id == shard_id);
+        if busy {
+            return;
+        }
+        let entries = self.shard_entries(shard_id);
+        self.migrations_out.get_mut(&shard_id).unwrap().delta_sent = true;
+        self.send_shard_data(shard_id, entries);
+    }
+
+    // A new ShardInfo ends the moves started under an older one: the held
+    // requests go to the shard's primary, which is us again if the controller
+    // gave up on the move
+    fn end_migrations(&mut self) {
+        let epoch = self.shard_info.epoch;
+        self.migrations_in.retain(|(_, started), _| *started >= epoch);
+        let ended: Vec<ShardId> = self
+            .migrations_out
+            .iter()
+            .filter(|(_, migration)| migration.epoch < epoch)
+            .map(|(shard_id, _)| *shard_id)
+            .collect();
+        for shard_id in ended {
+            let migration = self.migrations_out.remove(&shard_id).unwrap();
+            let primary = self.shard_info.locations[&shard_id].primary;
+            if primary != self.self_pid {
+                println!("Shard {} moved to {}", shard_id, primary);
+            }
+            for (client_pid, operation) in migration.held {
+                if primary == self.self_pid {
+                    self.handle_request(client_pid, operation, true);
+                } else {
+                    self.send(primary, Operation::Forward(client_pid, Box::new(operation)));
+                }
+            }
+        }
+    }
+
+    // Tells the old primary once we applied every entry it sent so far
+    fn check_migration_in(&mut self, shard_id: ShardId, epoch: u64) {
+        let migration = &self.migrations_in[&(shard_id, epoch)];
+        if migration.expected.is_some_and(|expected| migration.received >= expected) {
+            let (from, received) = (migration.from, migration.received);
+            self.send(from, Operation::ShardDataRes(shard_id, epoch, received));
+        }
+    }
+
+    fn primary_of(&self, shard_id: ShardId) -> Option<Pid> {
+        self.shard_info.locations.get(&shard_id).map(|location| location.primary)
+    }
+
+    // A transaction's commit. The primary of the shard of its first key
+    // coordinates it, every other server passes it on.
+    fn handle_txn_commit(&mut self, client_pid: Pid, operation: Operation, forwarded: bool) {
+        let shard_id = match request_key(&operation) {
+            Some(key) => self.shard_info.shard_of(key),
+            None => {
+                if let Operation::TxnCommit(_, _, seq_no) = operation {
+                    self.send(client_pid, Operation::TxnCommitRes(true, seq_no));
+                }
+                return;
+            }
+        };
+        let (reads, writes, seq_no) = match operation {
+            Operation::TxnCommit(reads, writes, seq_no) => (reads, writes, seq_no),
+            _ => return,
+        };
+        if self.shard_info.replication == ReplicationMode::Raft {
+            println!("Refusing transaction {} of {}, not supported in Raft mode", seq_no, client_pid);
+            self.send(client_pid, Operation::TxnCommitRes(false, seq_no));
+            return;
+        }
+        let txn_id = (client_pid, seq_no);
+        if let Some(commit) = self.txn_log(shard_id).decision(txn_id) {
+            // a retry, the first answer got lost
+            self.send(client_pid, Operation::TxnCommitRes(commit, seq_no));
+            return;
+        }
+        if self.txns.contains_key(&txn_id) {
+            // a retry, answered once decided
+            return;
+        }
+        let primary = self.shard_info.locations[&shard_id].primary;
+        if primary != self.self_pid {
+            if !forwarded {
+                self.send(primary, Operation::Forward(client_pid, Box::new(Operation::TxnCommit(reads, writes, seq_no))));
+            }
+            return;
+        }
+        // a retry of one whose records every part forgot already
+        let keys: Vec<String> = reads.iter().chain(writes.iter()).map(|(key, _)| key.clone()).collect();
+        if self.ran_before(client_pid, shard_id, &keys, seq_no) {
+            return;
+        }
+
+        let mut parts: BTreeMap<ShardId, TxnPart> = BTreeMap::new();
+        for read in reads {
+            parts.entry(self.shard_info.shard_of(&read.0)).or_default().0.push(read);
+        }
+        for write in last_writes(writes) {
+            parts.entry(self.shard_info.shard_of(&write.0)).or_default().1.push(write);
+        }
+        for (part, (reads, writes)) in &parts {
+            let primary = self.shard_info.locations[part].primary;
+            self.send(primary, Operation::TxnPrepare(txn_id, shard_id, *part, reads.clone(), writes.clone()));
+        }
+        self.txns.insert(
+            txn_id,
+            Coordinating {
+                shard_id,
+                parts,
+                votes: BTreeSet::new(),
+                decision: None,
+                durable: false,
+                done: BTreeSet::new(),
+                sent_ms: self.lease.now_ms(),
+            },
+        );
+    }
+
+    // Votes to commit the part if we are its shard's primary, none of its
+    // keys is busy and every value read still holds. The keys stay locked
+    // until the decision is applied.
+    fn handle_txn_prepare(
+        &mut self,
+        coordinator_pid: Pid,
+        txn_id: TxnId,
+        coordinator: ShardId,
+        shard_id: ShardId,
+        reads: Vec<(String, Option<String>)>,
+        writes: Vec<(String, Option<String>)>,
+    ) {
+        if self.txn_log(shard_id).is_applied(txn_id) {
+            return;
+        }
+        if let Some(prepared) = self.prepared.get(&(txn_id, shard_id)) {
+            if prepared.voted {
+                self.send(coordinator_pid, Operation::TxnPrepareRes(txn_id, coordinator, shard_id, true));
+            }
+            return;
+        }
+        let mut keys: Vec<String> = reads.iter().chain(writes.iter()).map(|(key, _)| key.clone()).collect();
+        keys.sort();
+        keys.dedup();
+        let leased = self.shard_info.lease_ms == 0 || self.lease.is_valid();
+        let vote = self.primary_of(shard_id) == Some(self.self_pid)
+            && leased
+            && !self.migrations_out.contains_key(&shard_id)
+            && !keys.iter().any(|key| self.is_pending(key))
+            && reads.iter().all(|(key, value)| self.kvs.get(key) == *value);
+        if !vote {
+            self.send(coordinator_pid, Operation::TxnPrepareRes(txn_id, coordinator, shard_id, false));
+            return;
+        }
+
+        for key in &keys {
+            self.txn_locks.insert(key.clone(), txn_id);
+        }
+        self.prepared.insert(
+            (txn_id, shard_id),
+            Prepared {
+                coordinator,
+                writes: writes.clone(),
+                keys: keys.clone(),
+                voted: false,
+                applying: false,
+                heard_ms: self.lease.now_ms(),
+            },
+        );
+        let record = TxnRecord::Prepared(coordinator, writes, keys);
+        self.replicate_txn_record(shard_id, txn_id, record, 0, TxnStep::Vote(coordinator_pid));
+    }
+
+    fn decide(&mut self, txn_id: TxnId, commit: bool) {
+        let txn = self.txns.get_mut(&txn_id).unwrap();
+        if txn.decision.is_some() {
+            return;
+        }
+        txn.decision = Some(commit);
+        let shard_id = txn.shard_id;
+        let parts = txn.parts.keys().cloned().collect();
+        self.replicate_txn_record(shard_id, txn_id, TxnRecord::Decided(commit, parts), 0, TxnStep::Decided);
+    }
+
+    // Tells the primary of every part that has not applied the decision yet
+    fn send_decisions(&mut self, txn_id: TxnId) {
+        let txn = &self.txns[&txn_id];
+        let (coordinator, commit) = (txn.shard_id, txn.decision.unwrap());
+        let parts: Vec<ShardId> = txn.parts.keys().filter(|part| !txn.done.contains(part)).cloned().collect();
+        for part in parts {
+            if let Some(primary) = self.primary_of(part) {
+                self.send(primary, Operation::TxnDecide(txn_id, coordinator, part, commit));
+            }
+        }
+        self.txns.get_mut(&txn_id).unwrap().sent_ms = self.lease.now_ms();
+    }
+
+    // Applies the decision to a part we prepared: the writes of a commit all
+    // get one version, like a batch
+    fn handle_txn_decide(&mut self, coordinator_pid: Pid, txn_id: TxnId, coordinator: ShardId, shard_id: ShardId, commit: bool) {
+        if self.primary_of(shard_id) != Some(self.self_pid) {
+            // the coordinator tries again with a newer ShardInfo
+            return;
+        }
+        if self.txn_log(shard_id).is_applied(txn_id) {
+            self.send(coordinator_pid, Operation::TxnDecideRes(txn_id, coordinator, shard_id));
+            return;
+        }
+        let now_ms = self.lease.now_ms();
+        let prepared = match self.prepared.get_mut(&(txn_id, shard_id)) {
+            Some(prepared) => prepared,
+            None => {
+                // we voted no, or never got the prepare
+                self.send(coordinator_pid, Operation::TxnDecideRes(txn_id, coordinator, shard_id));
+                return;
+            }
+        };
+        if prepared.applying {
+            return;
+        }
+        prepared.applying = true;
+        prepared.heard_ms = now_ms;
+        let writes = match commit {
+            true => prepared.writes.clone(),
+            false => Vec::new(),
+        };
+        let version = writes.iter().map(|(key, _)| self.next_version(key)).max().unwrap_or(0);
+        apply_batch(&self.kvs, &writes, version);
+        for (key, _) in &writes {
+            self.note_version(key, version);
+        }
+        let record = TxnRecord::Applied(writes, version);
+        self.replicate_txn_record(shard_id, txn_id, record, version, TxnStep::Release(coordinator_pid));
+    }
+
+    // A participant that waited too long for the decision asks for it
+    fn handle_txn_status(&mut self, pid: Pid, txn_id: TxnId, coordinator: ShardId, shard_id: ShardId) {
+        if self.primary_of(coordinator) != Some(self.self_pid) {
+            return;
+        }
+        if let Some(commit) = self.txn_log(coordinator).decision(txn_id) {
+            self.send(pid, Operation::TxnDecide(txn_id, coordinator, shard_id, commit));
+            return;
+        }
+        match self.txns.get(&txn_id) {
+            // the decision goes out once the secondaries have it
+            Some(txn) if txn.decision.is_some() => {}
+            Some(_) => self.decide(txn_id, false),
+            None => {
+                // never heard of, or its coordinator crashed before every
+                // secondary had the decision: nobody was told to commit. The
+                // abort goes out like any decision, until the part applied it.
+                let mut parts = BTreeMap::new();
+                parts.insert(shard_id, TxnPart::default());
+                self.txns.insert(
+                    txn_id,
+                    Coordinating {
+                        shard_id: coordinator,
+                        parts,
+                        votes: BTreeSet::new(),
+                        decision: None,
+                        durable: false,
+                        done: BTreeSet::new(),
+                        sent_ms: self.lease.now_ms(),
+                    },
+                );
+                self.decide(txn_id, false);
+            }
+        }
+    }
+
+    // Sends the record to every secondary of the shard, step runs once they
+    // all acked it
+    fn replicate_txn_record(&mut self, shard_id: ShardId, txn_id: TxnId, record: TxnRecord, version: u64, then: TxnStep) {
+        let secondaries = self.get_secondaries(shard_id);
+        if secondaries.is_empty() {
+            self.run_txn_step(shard_id, txn_id, then);
+            return;
+        }
+        self.next_txn_ack += 1;
+        let ack_id = self.next_txn_ack;
+        for pid in &secondaries {
+            self.send(*pid, Operation::TxnReplicate(shard_id, txn_id, record.clone(), ack_id));
+        }
+        self.pending_txn_acks.insert(
+            ack_id,
+            TxnAcks {
+                shard_id,
+                txn_id,
+                pending_secondaries: secondaries,
+                version,
+                then,
+            },
+        );
+    }
+
+    fn run_txn_step(&mut self, shard_id: ShardId, txn_id: TxnId, step: TxnStep) {
+        match step {
+            TxnStep::Vote(coordinator_pid) => {
+                // gone if an abort overtook the vote
+                if let Some(prepared) = self.prepared.get_mut(&(txn_id, shard_id)) {
+                    prepared.voted = true;
+                    let coordinator = prepared.coordinator;
+                    self.send(coordinator_pid, Operation::TxnPrepareRes(txn_id, coordinator, shard_id, true));
+                }
+            }
+            TxnStep::Decided => {
+                let txn = self.txns.get_mut(&txn_id).unwrap();
+                let commit = txn.decision.unwrap();
+                txn.durable = true;
+                let parts = txn.parts.keys().cloned().collect();
+                let keys = txn.parts.values().flat_map(|(reads, writes)| reads.iter().chain(writes.iter()));
+                let keys: Vec<String> = keys.map(|(key, _)| key.clone()).collect();
+                self.txn_log(shard_id).decide(txn_id, commit, parts);
+                let response = Operation::TxnCommitRes(commit, txn_id.1);
+                self.keep_answer(txn_id.0, shard_id, keys, &response);
+                self.send(txn_id.0, response);
+                self.send_decisions(txn_id);
+            }
+            TxnStep::Release(coordinator_pid) => {
+                let prepared = match self.prepared.remove(&(txn_id, shard_id)) {
+                    Some(prepared) => prepared,
+                    None => return,
+                };
+                self.unlock(txn_id, &prepared.keys);
+                self.txn_log(shard_id).set_applied(txn_id);
+                self.send(coordinator_pid, Operation::TxnDecideRes(txn_id, prepared.coordinator, shard_id));
+                for key in &prepared.keys {
+                    self.resume(key);
+                }
+                self.continue_migration(shard_id);
+            }
+        }
+    }
+
+    // The transaction records of a shard, read back from raft_dir the first
+    // time they are needed
+    fn txn_log(&mut self, shard_id: ShardId) -> &mut TxnLog {
+        if !self.txn_logs.contains_key(&shard_id) {
+            let path = self
+                .raft_dir
+                .as_ref()
+                .map(|raft_dir| format!("{}/{}_{}.txn", raft_dir, self.self_pid, shard_id));
+            self.txn_logs.insert(shard_id, TxnLog::open(path));
+        }
+        self.txn_logs.get_mut(&shard_id).unwrap()
+    }
+
+    // Every part applied the decision: the replicas of every shard involved
+    // drop their records of the transaction. A replica that misses the
+    // TxnForget keeps them, a late prepare for the part is still dropped.
+    fn forget_txn(&mut self, txn_id: TxnId, coordinator: ShardId, mut shard_ids: Vec<ShardId>) {
+        shard_ids.push(coordinator);
+        shard_ids.sort();
+        shard_ids.dedup();
+        for shard_id in shard_ids {
+            let location = match self.shard_info.locations.get(&shard_id) {
+                Some(location) => location,
+                None => continue,
+            };
+            let mut replicas = location.secondaries.clone();
+            replicas.push(location.primary);
+            for pid in replicas {
+                if pid == self.self_pid && self.owns(shard_id) {
+                    self.txn_log(shard_id).forget(txn_id);
+                } else {
+                    self.send(pid, Operation::TxnForget(txn_id, shard_id));
+                }
+            }
+        }
+    }
+
+    // The decisions of the shards we just became the primary of, e.g. after
+    // a failover or a restart, go out again until every part applied them.
+    // One an older primary replicated without its parts waits for the
+    // participants to ask for it.
+    fn adopt_decisions(&mut self) {
+        if self.shard_info.replication == ReplicationMode::Raft {
+            return;
+        }
+        let mut shard_ids: Vec<ShardId> = self
+            .shard_info
+            .locations
+            .iter()
+            .filter(|(shard_id, location)| location.primary == self.self_pid && self.owns(**shard_id))
+            .map(|(shard_id, _)| *shard_id)
+            .collect();
+        shard_ids.sort();
+        let now_ms = self.lease.now_ms();
+        for shard_id in shard_ids {
+            for (txn_id, commit, parts) in self.txn_log(shard_id).decisions() {
+                if parts.is_empty() || self.txns.contains_key(&txn_id) {
+                    continue;
+                }
+                self.txns.insert(
+                    txn_id,
+                    Coordinating {
+                        shard_id,
+                        parts: parts.into_iter().map(|part| (part, TxnPart::default())).collect(),
+                        votes: BTreeSet::new(),
+                        decision: Some(commit),
+                        durable: true,
+                        done: BTreeSet::new(),
+                        sent_ms: now_ms,
+                    },
+                );
+                self.send_decisions(txn_id);
+            }
+        }
+    }
+
+    fn unlock(&mut self, txn_id: TxnId, keys: &[String]) {
+        for key in keys {
+            if self.txn_locks.get(key) == Some(&txn_id) {
+                self.txn_locks.remove(key);
+            }
+        }
+    }
+
+    // A secondary's copy of a transaction record. A decision that differs
+    // from one we already have is not acked: we presumed an abort as the
+    // new primary while the old one was still deciding.
+    fn apply_txn_record(&mut self, shard_id: ShardId, txn_id: TxnId, record: TxnRecord) -> bool {
+        match record {
+            TxnRecord::Prepared(coordinator, writes, keys) => {
+                if self.txn_log(shard_id).is_applied(txn_id) || self.prepared.contains_key(&(txn_id, shard_id)) {
+                    return true;
+                }
+                for key in &keys {
+                    self.txn_locks.insert(key.clone(), txn_id);
+                }
+                let heard_ms = self.lease.now_ms();
+                self.prepared.insert(
+                    (txn_id, shard_id),
+                    Prepared {
+                        coordinator,
+                        writes,
+                        keys,
+                        voted: true,
+                        applying: false,
+                        heard_ms,
+                    },
+                );
+            }
+            TxnRecord::Decided(commit, parts) => return self.txn_log(shard_id).decide(txn_id, commit, parts) == commit,
+            TxnRecord::Applied(writes, version) => {
+                apply_batch(&self.kvs, &writes, version);
+                for (key, _) in &writes {
+                    self.note_version(key, version);
+                }
+                if let Some(prepared) = self.prepared.remove(&(txn_id, shard_id)) {
+                    self.unlock(txn_id, &prepared.keys);
+                }
+                self.txn_log(shard_id).set_applied(txn_id);
+            }
+        }
+        true
+    }
+
+    // Run on every tick: the coordinator aborts a transaction whose votes
+    // are late and sends a decision again until every part applied it, a
+    // primary with a prepared part asks the coordinator once the decision
+    // is late
+    fn check_txns(&mut self) {
+        let now_ms = self.lease.now_ms();
+        let late: Vec<TxnId> = self
+            .txns
+            .iter()
+            .filter(|(_, txn)| now_ms >= txn.sent_ms + TXN_TIMEOUT_MS)
+            .map(|(txn_id, _)| *txn_id)
+            .collect();
+        for txn_id in late {
+            let txn = &self.txns[&txn_id];
+            match (txn.decision, txn.durable) {
+                (None, _) => self.decide(txn_id, false),
+                (Some(_), true) => self.send_decisions(txn_id),
+                // waiting for the secondaries
+                (Some(_), false) => {}
+            }
+        }
+
+        let self_pid = self.self_pid;
+        let in_doubt: Vec<(TxnId, ShardId, ShardId)> = self
+            .prepared
+            .iter()
+            .filter(|((_, shard_id), prepared)| {
+                prepared.voted
+                    && !prepared.applying
+                    && now_ms >= prepared.heard_ms + TXN_TIMEOUT_MS
+                    && self.primary_of(*shard_id) == Some(self_pid)
+            })
+            .map(|((txn_id, shard_id), prepared)| (*txn_id, *shard_id, prepared.coordinator))
+            .collect();
+        for (txn_id, shard_id, coordinator) in in_doubt {
+            self.prepared.get_mut(&(txn_id, shard_id)).unwrap().heard_ms = now_ms;
+            if let Some(primary) = self.primary_of(coordinator) {
+                self.send(primary, Operation::TxnStatus(txn_id, coordinator, shard_id));
+            }
+        }
+    }
+
+    fn handle_snapshot(&self) -> Operation {
+        let mut snapshot = KVSSnapshot::new();
+        let shard_info = &self.shard_info;
+        for (k, v) in self.kvs.inner_table() {
+            let shard_id = shard_info.shard_of(&k);
+            if shard_info.locations[&shard_id].primary == self.self_pid {
+                snapshot.primary_shards.insert(k.clone(), v.clone());
+            } else if shard_info.locations[&shard_id].secondaries.contains(&self.self_pid) {
+                snapshot.secondary_shards.insert(k.clone(), v.clone());
+            }
+        }
+        Operation::SnapshotRes(snapshot)
+    }
+
+    // Creates the raft group of every shard this node replicates, once the
+    // controller told us the cluster runs in Raft mode. A restarted server picks up
+    // its term, vote and log from raft_dir and replays the log into the KVS.
+    fn ensure_raft_groups(&mut self) {
+        if self.shard_info.replication != ReplicationMode::Raft {
+            return;
+        }
+        let mut shard_ids: Vec<ShardId> = self.shard_info.locations.keys().cloned().collect();
+        shard_ids.sort();
+        for shard_id in shard_ids {
+            if self.raft_groups.contains_key(&shard_id) || !self.owns(shard_id) {
+                continue;
+            }
+            let location = &self.shard_info.locations[&shard_id];
+            let mut members = location.secondaries.clone();
+            members.push(location.primary);
+            if !members.contains(&self.self_pid) {
+                continue;
+            }
+            let path = self
+                .raft_dir
+                .as_ref()
+                .map(|raft_dir| format!("{}/{}_{}", raft_dir, self.self_pid, shard_id));
+            let node = RaftNode::new(self.self_pid, members, self.rng.gen(), path);
+            self.raft_groups.insert(shard_id, node);
+        }
+    }
+
+    // Sends what the raft node of shard_id has queued and applies what it
+    // committed. Only the node that accepted a proposal answers the client, and
+    // only if the committed entry is still the one it proposed.
+    fn drive_raft(&mut self, shard_id: ShardId) {
+        let node = self.raft_groups.get_mut(&shard_id).unwrap();
+        let messages = node.take_messages();
+        let committed = node.take_committed();
+        for (dst_pid, msg) in messages {
+            self.send(dst_pid, Operation::Raft(shard_id, msg));
+        }
+        for (index, entry) in committed {
+            let response = apply_raft_entry(&self.kvs, &mut self.raft_answers, shard_id, index, &entry);
+            if self.raft_pending.remove(&(shard_id, index)) == Some(entry.term) {
+                if let Some(op) = response {
+                    self.send(entry.client, op);
+                }
+            }
+        }
+    }
+
+    // In Raft mode a client request is proposed to the raft group of its key's
+    // shard. A follower forwards it to the leader it knows about; if there is no
+    // leader yet the request is dropped and the client retries after its timeout.
+    fn handle_raft_request(&mut self, client_pid: Pid, operation: Operation) {
+        let key = match request_key(&operation) {
+            Some(key) => key.clone(),
+            None => return,
+        };
+        let shard_id = self.shard_info.shard_of(&key);
+        let node = match self.raft_groups.get_mut(&shard_id) {
+            Some(node) => node,
+            None => {
+                println!("No raft group for shard {}, dropping request", shard_id);
+                return;
+            }
+        };
+
+        match node.propose(client_pid, operation.clone()) {
+            Some(index) => {
+                let term = node.term();
+                self.raft_pending.insert((shard_id, index), term);
+                self.drive_raft(shard_id);
+            }
+            None => {
+                if let Some(leader) = node.leader() {
+                    self.send(leader, Operation::RaftForward(client_pid, Box::new(operation)));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use crate::client::{KvClient, KvError};
+use crate::kvs::{Operation, Pid, ShardInfo};
+use rand::Rng;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result, Write};
+use std::time::Instant;
+
+// An interactive shell over a KvClient, for poking at a running cluster
+// without writing a trace file. It reads one command per line and prints
+// the answer and how long it took:
+//
+// kv> put a 1
+// (none)
+// (2.104 ms)
+// kv> where a
+// key a: shard 12, primary 5, secondaries [6, 7]
+//
+// Commands: get <key>, put <key> <value>, delete <key>, their batches mget,
+// mput and mdelete, the conditional writes cas, putifabsent and incr, txn
+// (a transaction of gets, puts and deletes), where <key>, shardinfo, snapshot <pid>, bench <num_ops> [<num_keys>], history, !<n>
+// (run history entry n again), help and quit. Lines starting with # are
+// comments, so a script can be piped in on stdin.
+//
+// Usage:
+// let mut shell = Shell::new(client, controller_pids).with_history_file(path)?;
+// shell.run(stdin.lock(), &mut stdout, interactive)?;
+
+// How long controllers and servers get to answer shardinfo, where and snapshot
+pub const ASK_TIMEOUT_MS: u64 = 1000;
+pub const DEFAULT_BENCH_KEYS: usize = 100;
+
+const HELP: &str = "\
+get <key>                    the value of key
+put <key> <value>            sets key, prints the value it replaced
+delete <key>                 removes key, prints the value it had
+mget <key>...                the value of every key
+mput <key> <value>...        sets every key, prints the values they replaced
+mdelete <key>...             removes every key, prints the values they had
+cas <key> <expected> <new>   sets key to new if it holds expected (- for no value), prints whether it did
+putifabsent <key> <value>    sets key if it has no value, prints the value it had
+incr <key> [<delta>]         adds delta (default 1) to the number key holds, prints the new number
+txn <step>...                gets, puts and deletes as one transaction, e.g. txn get a put b 1 delete c
+where <key>                  the shard of key and the servers holding it
+shardinfo                    the controller's shard assignment
+snapshot <pid>               every key the server holds
+bench <num_ops> [<num_keys>] random gets and puts over num_keys keys, through the client's window
+history                      the commands run so far
+!<n>                         runs history entry n again
+quit";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Get(String),
+    Put(String, String),
+    Delete(String),
+    MultiGet(Vec<String>),
+    MultiPut(Vec<(String, String)>),
+    MultiDelete(Vec<String>),
+    Cas(String, Option<String>, Option<String>),
+    PutIfAbsent(String, String),
+    Incr(String, i64),
+    Txn(Vec<Command>), // of Gets, Puts and Deletes
+    Where(String),
+    ShardInfo,
+    Snapshot(Pid),
+    Bench { num_ops: usize, num_keys: usize },
+    History,
+    Rerun(usize),
+    Help,
+    Quit,
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidInput, message)
+}
+
+// - stands for no value
+fn parse_optional(word: &str) -> Option<String> {
+    match word {
+        "-" => None,
+        word => Some(word.to_string()),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(word: &str) -> Result<T> {
+    word.parse().map_err(|_| invalid(format!("{} is not a number", word)))
+}
+
+// The steps of a txn command, each a get <key>, put <key> <value> or delete <key>
+fn parse_txn(mut words: &[&str]) -> Result<Command> {
+    let mut steps = Vec::new();
+    while !words.is_empty() {
+        let (step, rest) = match words {
+            ["get", key, rest @ ..] => (Command::Get(key.to_string()), rest),
+            ["put", key, value, rest @ ..] => (Command::Put(key.to_string(), value.to_string()), rest),
+            ["delete", key, rest @ ..] => (Command::Delete(key.to_string()), rest),
+            _ => return Err(invalid(format!("cannot parse transaction step {:?}", words.join(" ")))),
+        };
+        steps.push(step);
+        words = rest;
+    }
+    Ok(Command::Txn(steps))
+}
+
+pub fn parse_command(line: &str) -> Result<Command> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words.as_slice() {
+        ["get", key] => Ok(Command::Get(key.to_string())),
+        ["put", key, value] => Ok(Command::Put(key.to_string(), value.to_string())),
+        ["delete", key] => Ok(Command::Delete(key.to_string())),
+        ["mget", keys @ ..] if !keys.is_empty() => Ok(Command::MultiGet(keys.iter().map(|key| key.to_string()).collect())),
+        ["mput", entries @ ..] if !entries.is_empty() && entries.len() % 2 == 0 => Ok(Command::MultiPut(
+            entries.chunks(2).map(|entry| (entry[0].to_string(), entry[1].to_string())).collect(),
+        )),
+        ["mdelete", keys @ ..] if !keys.is_empty() => {
+            Ok(Command::MultiDelete(keys.iter().map(|key| key.to_string()).collect()))
+        }
+        ["cas", key, expected, new] => Ok(Command::Cas(key.to_string(), parse_optional(expected), parse_optional(new))),
+        ["putifabsent", key, value] => Ok(Command::PutIfAbsent(key.to_string(), value.to_string())),
+        ["incr", key] => Ok(Command::Incr(key.to_string(), 1)),
+        ["incr", key, delta] => Ok(Command::Incr(key.to_string(), parse_number(delta)?)),
+        ["txn", steps @ ..] if !steps.is_empty() => parse_txn(steps),
+        ["where", key] => Ok(Command::Where(key.to_string())),
+        ["shardinfo"] => Ok(Command::ShardInfo),
+        ["snapshot", pid] => Ok(Command::Snapshot(parse_number(pid)?)),
+        ["bench", num_ops] => Ok(Command::Bench {
+            num_ops: parse_number(num_ops)?,
+            num_keys: DEFAULT_BENCH_KEYS,
+        }),
+        ["bench", num_ops, num_keys] => Ok(Command::Bench {
+            num_ops: parse_number(num_ops)?,
+            num_keys: parse_number::<usize>(num_keys)?.max(1),
+        }),
+        ["history"] => Ok(Command::History),
+        [rerun] if rerun.starts_with('!') => Ok(Command::Rerun(parse_number(&rerun[1..])?)),
+        ["help"] => Ok(Command::Help),
+        ["quit"] | ["exit"] => Ok(Command::Quit),
+        _ => Err(invalid(format!("cannot parse {:?}, try help", line.trim()))),
+    }
+}
+
+fn show(value: Option<String>) -> String {
+    match value {
+        Some(value) => value,
+        None => "(none)".to_string(),
+    }
+}
+
+// One line per key of a batch
+fn show_all(keys: &[String], values: Vec<Option<String>>) -> Vec<String> {
+    keys.iter().zip(values).map(|(key, value)| format!("{} = {}", key, show(value))).collect()
+}
+
+pub struct Shell {
+    client: KvClient,
+    controller_pids: Vec<Pid>,
+    history: Vec<String>,
+    history_file: Option<File>,
+}
+
+impl Shell {
+    pub fn new(client: KvClient, controller_pids: Vec<Pid>) -> Self {
+        Shell {
+            client,
+            controller_pids,
+            history: Vec::new(),
+            history_file: None,
+        }
+    }
+
+    // Starts with the commands in path and adds every command run to it, so
+    // history carries over from one session to the next
+    pub fn with_history_file(mut self, path: &str) -> Result<Self> {
+        if let Ok(file) = File::open(path) {
+            for line in BufReader::new(file).lines() {
+                self.history.push(line?);
+            }
+        }
+        self.history_file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(self)
+    }
+
+    pub fn history(&self) -> &Vec<String> {
+        &self.history
+    }
+
+    // Runs every command of input until quit or the end of input. Without a
+    // terminal the commands are echoed, so the output reads like a session.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, output: &mut W, interactive: bool) -> Result<()> {
+        if interactive {
+            write!(output, "kv> ")?;
+            output.flush()?;
+        }
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if !interactive && !line.is_empty() {
+                writeln!(output, "kv> {}", line)?;
+            }
+            if !line.is_empty() && !line.starts_with('#') && !self.run_line(line, output)? {
+                return Ok(());
+            }
+            if interactive {
+                write!(output, "kv> ")?;
+                output.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    // Returns false on quit
+    fn run_line<W: Write>(&mut self, line: &str, output: &mut W) -> Result<bool> {
+        let mut line = line.to_string();
+        let mut command = parse_command(&line);
+        if let Ok(Command::Rerun(n)) = command {
+            match self.history.get(n.wrapping_sub(1)) {
+                Some(entry) => {
+                    line = entry.clone();
+                    writeln!(output, "{}", line)?;
+                    command = parse_command(&line);
+                }
+                None => command = Err(invalid(format!("no history entry {}", n))),
+            }
+        }
+        let command = match command {
+            Ok(command) => command,
+            Err(e) => {
+                writeln!(output, "error: {}", e)?;
+                return Ok(true);
+            }
+        };
+        match command {
+            Command::Quit => return Ok(false),
+            Command::Help => writeln!(output, "{}", HELP)?,
+            Command::History => {
+                for (index, entry) in self.history.iter().enumerate() {
+                    writeln!(output, "{:>4}  {}", index + 1, entry)?;
+                }
+            }
+            command => {
+                self.history.push(line.clone());
+                if let Some(file) = self.history_file.as_mut() {
+                    writeln!(file, "{}", line)?;
+                }
+                let start = Instant::now();
+                match self.execute(command) {
+                    Ok(lines) => {
+                        for line in lines {
+                            writeln!(output, "{}", line)?;
+                        }
+                    }
+                    Err(e) => writeln!(output, "error: {}", e)?,
+                }
+                writeln!(output, "({:.3} ms)", start.elapsed().as_secs_f64() * 1000.0)?;
+            }
+        }
+        Ok(true)
+    }
+
+    // The lines to print for a command
+    fn execute(&mut self, command: Command) -> std::result::Result<Vec<String>, KvError> {
+        match command {
+            Command::Get(key) => Ok(vec![show(self.client.get(&key)?)]),
+            Command::Put(key, value) => Ok(vec![show(self.client.put(&key, &value)?)]),
+            Command::Delete(key) => Ok(vec![show(self.client.delete(&key)?)]),
+            Command::MultiGet(keys) => {
+                let values = self.client.multi_get(&keys.iter().map(|key| key.as_str()).collect::<Vec<_>>())?;
+                Ok(show_all(&keys, values))
+            }
+            Command::MultiPut(entries) => {
+                let pairs: Vec<(&str, &str)> = entries.iter().map(|(key, value)| (key.as_str(), value.as_str())).collect();
+                let keys: Vec<String> = entries.iter().map(|(key, _)| key.clone()).collect();
+                Ok(show_all(&keys, self.client.multi_put(&pairs)?))
+            }
+            Command::MultiDelete(keys) => {
+                let values = self.client.multi_delete(&keys.iter().map(|key| key.as_str()).collect::<Vec<_>>())?;
+                Ok(show_all(&keys, values))
+            }
+            Command::Cas(key, expected, new) => {
+                let (swapped, old_value) = self.client.cas(&key, expected.as_deref(), new.as_deref())?;
+                match swapped {
+                    true => Ok(vec![format!("swapped, was {}", show(old_value))]),
+                    false => Ok(vec![format!("not swapped, is {}", show(old_value))]),
+                }
+            }
+            Command::PutIfAbsent(key, value) => Ok(vec![show(self.client.put_if_absent(&key, &value)?)]),
+            Command::Incr(key, delta) => match self.client.incr(&key, delta)? {
+                Some(new_value) => Ok(vec![new_value.to_string()]),
+                None => Ok(vec![format!("{} does not hold a number", key)]),
+            },
+            Command::Txn(steps) => {
+                let mut lines = Vec::new();
+                let mut txn = self.client.begin();
+                for step in steps {
+                    match step {
+                        Command::Get(key) => lines.push(format!("{} = {}", key, show(txn.get(&key)?))),
+                        Command::Put(key, value) => txn.put(&key, &value),
+                        Command::Delete(key) => txn.delete(&key),
+                        _ => {}
+                    }
+                }
+                match txn.commit()? {
+                    true => lines.push("committed".to_string()),
+                    false => lines.push("aborted".to_string()),
+                }
+                Ok(lines)
+            }
+            Command::Where(key) => {
+                let info = self.fetch_shard_info()?;
+                let shard_id = info.shard_of(&key);
+                let location = &info.locations[&shard_id];
+                Ok(vec![format!(
+                    "key {}: shard {}, primary {}, secondaries {:?}",
+                    key, shard_id, location.primary, location.secondaries
+                )])
+            }
+            Command::ShardInfo => {
+                let info = self.fetch_shard_info()?;
+                let mut lines = vec![format!(
+                    "epoch {}, {} shards, {:?}, lease {} ms",
+                    info.epoch, info.num_shards, info.replication, info.lease_ms
+                )];
+                let mut shard_ids: Vec<_> = info.locations.keys().cloned().collect();
+                shard_ids.sort();
+                for shard_id in shard_ids {
+                    let location = &info.locations[&shard_id];
+                    lines.push(format!(
+                        "shard {}: primary {}, secondaries {:?}",
+                        shard_id, location.primary, location.secondaries
+                    ));
+                }
+                Ok(lines)
+            }
+            Command::Snapshot(pid) => match self.client.ask(pid, &Operation::Snapshot(), ASK_TIMEOUT_MS)? {
+                Operation::SnapshotRes(snapshot) => {
+                    let mut lines = vec![format!(
+                        "server {}: {} primary keys, {} secondary keys",
+                        pid,
+                        snapshot.primary_shards.len(),
+                        snapshot.secondary_shards.len()
+                    )];
+                    for (role, entries) in [("primary", snapshot.primary_shards), ("secondary", snapshot.secondary_shards)] {
+                        let mut entries: Vec<_> = entries.into_iter().collect();
+                        entries.sort();
+                        for (key, value) in entries {
+                            lines.push(format!("{} {} = {}", role, key, value));
+                        }
+                    }
+                    Ok(lines)
+                }
+                other => Ok(vec![format!("unexpected answer {:?}", other)]),
+            },
+            Command::Bench { num_ops, num_keys } => Ok(vec![self.bench(num_ops, num_keys)?]),
+            Command::History | Command::Rerun(_) | Command::Help | Command::Quit => Ok(Vec::new()),
+        }
+    }
+
+    // Asks the controllers in turn for the assignment they hand out now
+    fn fetch_shard_info(&mut self) -> std::result::Result<ShardInfo, KvError> {
+        for controller_pid in self.controller_pids.clone() {
+            if let Ok(Operation::GetShardInfoRes(info)) =
+                self.client.ask(controller_pid, &Operation::GetShardInfo(), ASK_TIMEOUT_MS)
+            {
+                return Ok(info);
+            }
+        }
+        Err(KvError::NoShardInfo)
+    }
+
+    // Half gets and half puts over bench_0 .. bench_<num_keys - 1>
+    fn bench(&mut self, num_ops: usize, num_keys: usize) -> std::result::Result<String, KvError> {
+        let mut rng = rand::thread_rng();
+        let operations = (0..num_ops)
+            .map(|seq_no| {
+                let key = format!("bench_{}", rng.gen_range(0..num_keys));
+                match rng.gen_bool(0.5) {
+                    true => Operation::Get(key, seq_no),
+                    false => Operation::Put(key, seq_no.to_string(), seq_no),
+                }
+            })
+            .collect();
+        let start = Instant::now();
+        let results = self.client.replay(operations)?;
+        let elapsed = start.elapsed().as_secs_f64();
+        let mut latencies_us: Vec<u128> = results.iter().map(|result| (result.end_time - result.begin_time) / 1000).collect();
+        latencies_us.sort();
+        let percentile = |p: usize| match latencies_us.len() {
+            0 => 0,
+            len => latencies_us[(len - 1) * p / 100],
+        };
+        Ok(format!(
+            "{} ops in {:.3} s, {:.0} ops/s, latency median {} us, 99% {} us",
+            results.len(),
+            elapsed,
+            results.len() as f64 / elapsed,
+            percentile(50),
+            percentile(99)
+        ))
+    }
+}
@@ -0,0 +1,493 @@
+use crate::client::{ClientNode, CLIENT_TICK_MS};
+use crate::controller::{
+    assign_shards_to_servers, ControllerNode, CONTROLLER_TICK_MS, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR,
+};
+use crate::faults::{FaultInjector, Verdict};
+use crate::kvs::{KVSResult, Operation, Pid, ReplicationMode, ServerMetrics, ShardInfo, KVS};
+use crate::lease::Clock;
+use crate::network::operation_name;
+use crate::ring::DEFAULT_VNODES;
+use crate::server::{Dispatcher, Route, ServerNode, RAFT_TICK_MS};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::hash::Hasher;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+// Runs a whole cluster in one process: the ServerNode, ControllerNode and
+// ClientNode state machines exchange messages over a simulated network and
+// read a virtual clock. One seeded RNG picks every message's latency, so the
+// same seed replays the same execution exactly, in a fraction of the time the
+// processes in tests/ take.
+//
+// Usage:
+// let mut sim = Simulation::new(seed);
+// let (client_pids, server_pids) = sim.add_cluster(workloads, 3, ReplicationMode::PrimaryBackup);
+// sim.faults_mut().apply("duplicate prob=0.05").unwrap();
+// assert!(sim.run_until(60_000, |sim| sim.clients_done()));
+// let results = sim.results(&client_pids[0]);
+//
+// Faults come from a FaultInjector (faults.rs) consulted for every message:
+// a drop loses it, a delay or reorder adds to its latency, a duplicate
+// delivers it twice. crash(pid) stops a process for good, restart(pid, ...)
+// swaps it for a fresh one that only has what the old one wrote to disk.
+
+// Latency of every message, in milliseconds, unless set_latency changes it
+pub const DEFAULT_MIN_LATENCY_MS: u64 = 1;
+pub const DEFAULT_MAX_LATENCY_MS: u64 = 5;
+// A reordered message is late by up to this much
+pub const REORDER_SPREAD_MS: u64 = 20;
+
+// The clock every process of a simulation reads, moved only by the simulation
+#[derive(Clone, Default)]
+pub struct SimClock {
+    now_ms: Arc<AtomicU64>,
+}
+
+impl SimClock {
+    pub fn new() -> Self {
+        SimClock::default()
+    }
+
+    fn set(&self, now_ms: u64) {
+        self.now_ms.store(now_ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for SimClock {
+    fn now_ms(&self) -> u64 {
+        self.now_ms.load(Ordering::SeqCst)
+    }
+}
+
+// What the simulation needs from a state machine
+pub trait Process {
+    // every pid it receives on
+    fn pids(&self) -> Vec<Pid>;
+    // how often it wants tick() called
+    fn tick_ms(&self) -> u64;
+    fn tick(&mut self);
+    fn step(&mut self, src_pid: Pid, operation: Operation);
+    // (src, dst, operation) of everything it sent since the last call
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)>;
+    // whether it finished its work, servers and controllers have none
+    fn is_done(&self) -> bool {
+        true
+    }
+    fn results(&self) -> Vec<KVSResult> {
+        Vec::new()
+    }
+    // the shard assignment a controller works with
+    fn shard_info(&self) -> Option<ShardInfo> {
+        None
+    }
+    // the request queue depths of a server
+    fn metrics(&self) -> Option<ServerMetrics> {
+        None
+    }
+    // (answered, refused) Gets a client sent to secondaries
+    fn secondary_reads(&self) -> Option<(usize, usize)> {
+        None
+    }
+}
+
+impl Process for ServerNode<SimClock> {
+    fn pids(&self) -> Vec<Pid> {
+        vec![self.pid()]
+    }
+    fn tick_ms(&self) -> u64 {
+        RAFT_TICK_MS
+    }
+    fn tick(&mut self) {
+        ServerNode::tick(self)
+    }
+    fn step(&mut self, src_pid: Pid, operation: Operation) {
+        ServerNode::step(self, src_pid, operation)
+    }
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        let pid = self.pid();
+        ServerNode::take_messages(self).into_iter().map(|(dst, op)| (pid, dst, op)).collect()
+    }
+    fn metrics(&self) -> Option<ServerMetrics> {
+        Some(ServerNode::metrics(self))
+    }
+}
+
+// A server running one ServerNode per worker over a shared KVS, the way
+// bin/server.rs runs them on threads, with the dispatching done in step
+pub struct ServerPool {
+    dispatcher: Dispatcher,
+    workers: Vec<ServerNode<SimClock>>,
+    replies: Vec<(Pid, Operation)>, // answered by the dispatcher
+}
+
+impl ServerPool {
+    pub fn new(self_pid: Pid, clock: SimClock, seed: u64, num_workers: usize) -> Self {
+        let kvs = KVS::new();
+        let mut rng = StdRng::seed_from_u64(seed);
+        let workers = (0..num_workers)
+            .map(|worker| ServerNode::with_partition(self_pid, clock.clone(), rng.gen(), None, kvs.clone(), worker, num_workers))
+            .collect();
+        ServerPool {
+            dispatcher: Dispatcher::new(num_workers),
+            workers,
+            replies: Vec::new(),
+        }
+    }
+
+    pub fn metrics(&self) -> ServerMetrics {
+        let workers: Vec<ServerMetrics> = self.workers.iter().map(|worker| worker.metrics()).collect();
+        ServerMetrics::merge(&workers)
+    }
+}
+
+impl Process for ServerPool {
+    fn pids(&self) -> Vec<Pid> {
+        vec![self.workers[0].pid()]
+    }
+    fn tick_ms(&self) -> u64 {
+        RAFT_TICK_MS
+    }
+    fn tick(&mut self) {
+        for worker in self.workers.iter_mut() {
+            worker.tick();
+        }
+    }
+    fn step(&mut self, src_pid: Pid, operation: Operation) {
+        match self.dispatcher.route(&operation) {
+            Route::Worker(worker) => self.workers[worker].step(src_pid, operation),
+            Route::Every => {
+                for worker in self.workers.iter_mut() {
+                    worker.step(src_pid, operation.clone());
+                }
+            }
+            Route::Dispatcher => {
+                let metrics = ServerPool::metrics(self);
+                self.replies.push((src_pid, Operation::MetricsRes(metrics)));
+            }
+        }
+    }
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        let pid = self.workers[0].pid();
+        let mut messages = std::mem::take(&mut self.replies);
+        for worker in self.workers.iter_mut() {
+            messages.extend(worker.take_messages());
+        }
+        messages.into_iter().map(|(dst, op)| (pid, dst, op)).collect()
+    }
+    fn metrics(&self) -> Option<ServerMetrics> {
+        Some(ServerPool::metrics(self))
+    }
+}
+
+impl Process for ControllerNode<SimClock> {
+    fn pids(&self) -> Vec<Pid> {
+        vec![self.pid_for_clients(), self.pid_for_servers()]
+    }
+    fn tick_ms(&self) -> u64 {
+        CONTROLLER_TICK_MS
+    }
+    fn tick(&mut self) {
+        ControllerNode::tick(self)
+    }
+    fn step(&mut self, src_pid: Pid, operation: Operation) {
+        ControllerNode::step(self, src_pid, operation)
+    }
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        ControllerNode::take_messages(self)
+    }
+    fn shard_info(&self) -> Option<ShardInfo> {
+        Some(ControllerNode::shard_info(self).clone())
+    }
+}
+
+impl Process for ClientNode<SimClock> {
+    fn pids(&self) -> Vec<Pid> {
+        vec![self.pid()]
+    }
+    fn tick_ms(&self) -> u64 {
+        CLIENT_TICK_MS
+    }
+    fn tick(&mut self) {
+        ClientNode::tick(self)
+    }
+    fn step(&mut self, src_pid: Pid, operation: Operation) {
+        ClientNode::step(self, src_pid, operation)
+    }
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        let pid = self.pid();
+        ClientNode::take_messages(self).into_iter().map(|(dst, op)| (pid, dst, op)).collect()
+    }
+    fn is_done(&self) -> bool {
+        ClientNode::is_done(self) || self.has_failed()
+    }
+    fn results(&self) -> Vec<KVSResult> {
+        ClientNode::results(self).clone()
+    }
+    fn secondary_reads(&self) -> Option<(usize, usize)> {
+        Some((self.replica_reads(), self.refused_reads()))
+    }
+}
+
+enum Event {
+    Tick(usize),                   // index into processes
+    Deliver(Pid, Pid, Operation), // (src, dst, operation)
+}
+
+pub struct Simulation {
+    clock: SimClock,
+    rng: StdRng,
+    faults: FaultInjector,
+    latency_ms: (u64, u64),
+    processes: Vec<Box<dyn Process>>,
+    owners: HashMap<Pid, usize>, // pid -> index into processes
+    crashed: HashSet<usize>,
+    events: BTreeMap<(u64, u64), Event>, // (time, sequence number), ties go in order
+    next_event: u64,
+    trace: DefaultHasher, // every delivery so far, to compare executions
+    delivered: usize,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Self {
+        Simulation {
+            clock: SimClock::new(),
+            rng: StdRng::seed_from_u64(seed),
+            faults: FaultInjector::new(seed),
+            latency_ms: (DEFAULT_MIN_LATENCY_MS, DEFAULT_MAX_LATENCY_MS),
+            processes: Vec::new(),
+            owners: HashMap::new(),
+            crashed: HashSet::new(),
+            events: BTreeMap::new(),
+            next_event: 0,
+            trace: DefaultHasher::new(),
+            delivered: 0,
+        }
+    }
+
+    pub fn clock(&self) -> SimClock {
+        self.clock.clone()
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.clock.now_ms()
+    }
+
+    // A seed for a process' own RNG, drawn from the simulation's
+    pub fn next_seed(&mut self) -> u64 {
+        self.rng.gen()
+    }
+
+    pub fn set_latency(&mut self, min_ms: u64, max_ms: u64) {
+        self.latency_ms = (min_ms, max_ms.max(min_ms));
+    }
+
+    pub fn faults_mut(&mut self) -> &mut FaultInjector {
+        &mut self.faults
+    }
+
+    // Identifies the execution so far: two runs with the same seed agree
+    pub fn fingerprint(&self) -> u64 {
+        self.trace.finish()
+    }
+
+    pub fn delivered(&self) -> usize {
+        self.delivered
+    }
+
+    pub fn add(&mut self, process: Box<dyn Process>) {
+        let index = self.processes.len();
+        for pid in process.pids() {
+            self.owners.insert(pid, index);
+        }
+        // processes start out of step with each other
+        let first_tick = self.now_ms() + self.rng.gen_range(1..=process.tick_ms());
+        self.processes.push(process);
+        self.schedule(first_tick, Event::Tick(index));
+        self.flush(index);
+    }
+
+    // The layout the tests in tests/ use: controller pids 0 (for clients) and
+    // 1 (for servers), then one client per workload, then the servers.
+    // Returns the client and server pids.
+    pub fn add_cluster(
+        &mut self,
+        workloads: Vec<Vec<Operation>>,
+        num_servers: usize,
+        replication: ReplicationMode,
+    ) -> (Vec<Pid>, Vec<Pid>) {
+        self.add_cluster_with_workers(workloads, num_servers, replication, 1)
+    }
+
+    // add_cluster with servers of num_workers workers each (see ServerPool)
+    pub fn add_cluster_with_workers(
+        &mut self,
+        workloads: Vec<Vec<Operation>>,
+        num_servers: usize,
+        replication: ReplicationMode,
+        num_workers: usize,
+    ) -> (Vec<Pid>, Vec<Pid>) {
+        let num_clients = workloads.len() as Pid;
+        let client_pids: Vec<Pid> = (2..num_clients + 2).collect();
+        let server_pids: Vec<Pid> = (num_clients + 2..num_clients + 2 + num_servers as Pid).collect();
+
+        for server_pid in &server_pids {
+            let seed = self.next_seed();
+            match num_workers {
+                1 => self.add(Box::new(ServerNode::new(*server_pid, self.clock(), seed, None))),
+                _ => self.add(Box::new(ServerPool::new(*server_pid, self.clock(), seed, num_workers))),
+            }
+        }
+        let seed = self.next_seed();
+        let mut info = ShardInfo::new();
+        info.replication = replication;
+        assign_shards_to_servers(&mut info, &server_pids, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR, DEFAULT_VNODES);
+        let controller = ControllerNode::new(0, 1, server_pids.clone(), info, vec![0], self.clock(), seed, None);
+        self.add(Box::new(controller));
+        for (client_pid, operations) in client_pids.iter().zip(workloads) {
+            self.add(Box::new(ClientNode::new(*client_pid, vec![0], operations, self.clock())));
+        }
+        (client_pids, server_pids)
+    }
+
+    // The process stops: it gets no more messages or ticks and sends nothing
+    pub fn crash(&mut self, pid: &Pid) {
+        if let Some(index) = self.owners.get(pid) {
+            self.crashed.insert(*index);
+        }
+    }
+
+    // The process crashes and comes back at once as process, which takes the
+    // messages on their way to the old one. A crashed process stays down.
+    pub fn restart(&mut self, pid: &Pid, process: Box<dyn Process>) {
+        let index = self.owners[pid];
+        if self.crashed.contains(&index) {
+            return;
+        }
+        self.processes[index] = process;
+        self.flush(index);
+    }
+
+    // Whether every client that is still up is done
+    pub fn clients_done(&self) -> bool {
+        self.processes
+            .iter()
+            .enumerate()
+            .all(|(index, process)| self.crashed.contains(&index) || process.is_done())
+    }
+
+    // The shard assignment of the controller listening on pid
+    pub fn shard_info(&self, pid: &Pid) -> Option<ShardInfo> {
+        self.owners.get(pid).and_then(|index| self.processes[*index].shard_info())
+    }
+
+    // The queue depths of the server listening on pid
+    pub fn metrics(&self, pid: &Pid) -> Option<ServerMetrics> {
+        self.owners.get(pid).and_then(|index| self.processes[*index].metrics())
+    }
+
+    // (answered, refused) Gets the client listening on pid sent to secondaries
+    pub fn secondary_reads(&self, pid: &Pid) -> Option<(usize, usize)> {
+        self.owners.get(pid).and_then(|index| self.processes[*index].secondary_reads())
+    }
+
+    // Puts a message on the network as if src_pid had sent it, e.g. an admin
+    // request from a pid no process owns
+    pub fn send(&mut self, src_pid: Pid, dst_pid: Pid, operation: Operation) {
+        let latency_ms = self.rng.gen_range(self.latency_ms.0..=self.latency_ms.1);
+        self.schedule(self.now_ms() + latency_ms, Event::Deliver(src_pid, dst_pid, operation));
+    }
+
+    pub fn results(&self, pid: &Pid) -> Vec<KVSResult> {
+        match self.owners.get(pid) {
+            Some(index) => self.processes[*index].results(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn run_for(&mut self, duration_ms: u64) {
+        let deadline_ms = self.now_ms() + duration_ms;
+        self.run_until(deadline_ms, |_| false);
+    }
+
+    // Runs until done returns true or the clock reaches deadline_ms, returns
+    // whether done
+    pub fn run_until<F: Fn(&Simulation) -> bool>(&mut self, deadline_ms: u64, done: F) -> bool {
+        while !done(self) {
+            let (time_ms, id) = match self.events.keys().next() {
+                Some((time_ms, id)) if *time_ms <= deadline_ms => (*time_ms, *id),
+                _ => {
+                    self.clock.set(deadline_ms.max(self.now_ms()));
+                    return done(self);
+                }
+            };
+            let event = self.events.remove(&(time_ms, id)).unwrap();
+            self.clock.set(time_ms);
+            self.handle(event);
+        }
+        true
+    }
+
+    fn schedule(&mut self, time_ms: u64, event: Event) {
+        self.events.insert((time_ms, self.next_event), event);
+        self.next_event += 1;
+    }
+
+    fn handle(&mut self, event: Event) {
+        match event {
+            Event::Tick(index) => {
+                if self.crashed.contains(&index) {
+                    return;
+                }
+                self.processes[index].tick();
+                let next_tick = self.now_ms() + self.processes[index].tick_ms();
+                self.schedule(next_tick, Event::Tick(index));
+                self.flush(index);
+            }
+            Event::Deliver(src_pid, dst_pid, operation) => {
+                let index = self.owners[&dst_pid];
+                if self.crashed.contains(&index) {
+                    return;
+                }
+                self.trace.write_u64(self.now_ms());
+                self.trace.write_u32(src_pid);
+                self.trace.write_u32(dst_pid);
+                self.trace.write(operation_name(&operation).as_bytes());
+                self.delivered += 1;
+                self.processes[index].step(src_pid, operation);
+                self.flush(index);
+            }
+        }
+    }
+
+    // Puts what process index sent on the network
+    fn flush(&mut self, index: usize) {
+        for (src_pid, dst_pid, operation) in self.processes[index].take_messages() {
+            if !self.owners.contains_key(&dst_pid) {
+                continue;
+            }
+            let verdict = match self.faults.is_empty() {
+                true => Verdict::Deliver,
+                false => self.faults.decide(src_pid, dst_pid, &operation_name(&operation)),
+            };
+            let latency_ms = self.rng.gen_range(self.latency_ms.0..=self.latency_ms.1);
+            let arrival_ms = self.now_ms() + latency_ms;
+            match verdict {
+                Verdict::Deliver => self.schedule(arrival_ms, Event::Deliver(src_pid, dst_pid, operation)),
+                Verdict::Drop => {}
+                Verdict::Delay(ms) => self.schedule(arrival_ms + ms, Event::Deliver(src_pid, dst_pid, operation)),
+                Verdict::Duplicate => {
+                    let second_ms = self.now_ms() + self.rng.gen_range(self.latency_ms.0..=self.latency_ms.1);
+                    self.schedule(arrival_ms, Event::Deliver(src_pid, dst_pid, operation.clone()));
+                    self.schedule(second_ms, Event::Deliver(src_pid, dst_pid, operation));
+                }
+                Verdict::Reorder => {
+                    let late_ms = arrival_ms + self.rng.gen_range(1..=REORDER_SPREAD_MS);
+                    self.schedule(late_ms, Event::Deliver(src_pid, dst_pid, operation));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+extern crate serde_json;
+
+use crate::kvs::{ShardId, TxnId};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+// What a replica of a shard keeps of its transactions (see server.rs): the
+// decisions of the ones the shard coordinates, with the shards involved, and
+// the ones whose part on the shard applied its decision. A record is written
+// before anyone acts on it and read back by a server that restarted, so a
+// decision survives even a restart of every replica of the coordinator's
+// shard. It goes once every part applied the decision.
+//
+// Like the raft log (see raft.rs) records are appended to a file as JSON
+// lines, a torn last line from a crash is skipped. Once the file holds
+// mostly records that went, it is rewritten with the others.
+//
+// Usage:
+// let mut log = TxnLog::open(Some(path));  // reads back what is on disk
+// log.decide(txn_id, commit, parts);       // before telling anyone
+// log.set_applied(txn_id);                 // before acking the decision
+// log.forget(txn_id);                      // once every part applied it
+
+// The file is rewritten once it has this many lines more than twice the
+// records kept
+pub const REWRITE_SLACK: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+enum Entry {
+    Decided(TxnId, bool, Vec<ShardId>),
+    Applied(TxnId),
+    Forgotten(TxnId),
+}
+
+pub struct TxnLog {
+    path: Option<String>, // none keeps the records in memory only
+    decisions: BTreeMap<TxnId, (bool, Vec<ShardId>)>,
+    applied: BTreeSet<TxnId>,
+    lines: usize, // in the file
+}
+
+impl TxnLog {
+    pub fn open(path: Option<String>) -> Self {
+        let mut log = TxnLog {
+            path,
+            decisions: BTreeMap::new(),
+            applied: BTreeSet::new(),
+            lines: 0,
+        };
+        let file = match log.path.as_ref().map(File::open) {
+            Some(Ok(file)) => file,
+            _ => return log,
+        };
+        for line in BufReader::new(file).lines() {
+            let entry = match line.ok().and_then(|line| serde_json::from_str(&line).ok()) {
+                Some(entry) => entry,
+                // a torn last line from a crash mid-append
+                None => break,
+            };
+            log.lines += 1;
+            match entry {
+                Entry::Decided(txn_id, commit, parts) => {
+                    log.decisions.insert(txn_id, (commit, parts));
+                }
+                Entry::Applied(txn_id) => {
+                    log.applied.insert(txn_id);
+                }
+                Entry::Forgotten(txn_id) => {
+                    log.decisions.remove(&txn_id);
+                    log.applied.remove(&txn_id);
+                }
+            }
+        }
+        log
+    }
+
+    pub fn decision(&self, txn_id: TxnId) -> Option<bool> {
+        self.decisions.get(&txn_id).map(|(commit, _)| *commit)
+    }
+
+    // (txn_id, commit, parts) of every decision kept
+    pub fn decisions(&self) -> Vec<(TxnId, bool, Vec<ShardId>)> {
+        self.decisions.iter().map(|(txn_id, (commit, parts))| (*txn_id, *commit, parts.clone())).collect()
+    }
+
+    // Records the decision unless there is one already, returns the one on
+    // record
+    pub fn decide(&mut self, txn_id: TxnId, commit: bool, parts: Vec<ShardId>) -> bool {
+        if let Some((recorded, _)) = self.decisions.get(&txn_id) {
+            return *recorded;
+        }
+        self.append(&Entry::Decided(txn_id, commit, parts.clone()));
+        self.decisions.insert(txn_id, (commit, parts));
+        commit
+    }
+
+    pub fn is_applied(&self, txn_id: TxnId) -> bool {
+        self.applied.contains(&txn_id)
+    }
+
+    pub fn set_applied(&mut self, txn_id: TxnId) {
+        if self.applied.insert(txn_id) {
+            self.append(&Entry::Applied(txn_id));
+        }
+    }
+
+    pub fn forget(&mut self, txn_id: TxnId) {
+        let decided = self.decisions.remove(&txn_id).is_some();
+        let applied = self.applied.remove(&txn_id);
+        if !decided && !applied {
+            return;
+        }
+        if self.lines > 2 * self.len() + REWRITE_SLACK {
+            self.rewrite();
+        } else {
+            self.append(&Entry::Forgotten(txn_id));
+        }
+    }
+
+    // Records kept, decisions and applied parts
+    pub fn len(&self) -> usize {
+        self.decisions.len() + self.applied.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn append(&mut self, entry: &Entry) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        if let Err(e) = append_entry(&path, entry) {
+            println!("Txn log {} failed to persist: {:?}", path, e);
+        }
+        self.lines += 1;
+    }
+
+    // Writes to a temporary file first so a crash mid-write leaves the old
+    // file behind
+    fn rewrite(&mut self) {
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => return,
+        };
+        let mut entries: Vec<Entry> = self
+            .decisions
+            .iter()
+            .map(|(txn_id, (commit, parts))| Entry::Decided(*txn_id, *commit, parts.clone()))
+            .collect();
+        entries.extend(self.applied.iter().map(|txn_id| Entry::Applied(*txn_id)));
+        let mut data = String::new();
+        for entry in &entries {
+            data.push_str(&serde_json::to_string(entry).unwrap());
+            data.push('\n');
+        }
+        let tmp_path = format!("{}.tmp", path);
+        if let Err(e) = fs::write(&tmp_path, data).and_then(|_| fs::rename(&tmp_path, &path)) {
+            println!("Txn log {} failed to persist: {:?}", path, e);
+        }
+        self.lines = entries.len();
+    }
+}
+
+fn append_entry(path: &str, entry: &Entry) -> std::io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(entry).unwrap())?;
+    file.sync_data()
+}
@@ -0,0 +1,366 @@
+use crate::kvs::Pid;
+use crate::membership::Membership;
+use crate::network::Transport;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// The default transport: a small reliable protocol over one UdpSocket. Every
+// message gets a per-destination id and is split into fragments that fit a
+// datagram. The receiver acks every fragment, reassembles the message and
+// hands messages of one sender to recv in the order they were sent, dropping
+// duplicates. The sender retransmits unacked fragments with backoff and gives
+// up after MAX_ATTEMPTS, so a dead peer does not pile up messages forever.
+// Retransmissions happen inside recv, which every component calls in a loop
+// anyway.
+//
+// Each transport picks a random session id. A peer that sees a new session
+// from a pid knows that process restarted and starts its stream over; every
+// data packet also carries the oldest id the sender still waits on, so a
+// receiver that restarted, or a sender that gave up on a message, never
+// leaves the other side waiting for an id that will not come.
+//
+// A message sent with send_expiring is worth nothing once late, like a
+// heartbeat granting a lease (see lease.rs). Its fragments go as
+// EXPIRING_DATA_PACKETs, the sender retransmits them only until the deadline
+// and the receiver delivers the message as soon as it is complete, ahead of
+// earlier ones still missing, so it never arrives much later than the
+// deadline.
+
+const MAX_FRAGMENT_SIZE: usize = 32 * 1024;
+const MAX_DATAGRAM_SIZE: usize = 64 * 1024;
+const RETRANSMIT_MS: u64 = 100;
+const MAX_RETRANSMIT_MS: u64 = 1000;
+const MAX_ATTEMPTS: u32 = 8;
+
+const DATA_PACKET: u8 = 0;
+const ACK_PACKET: u8 = 1;
+const EXPIRING_DATA_PACKET: u8 = 2;
+// kind, session, msg_id, base, frag, num_frags
+const DATA_HEADER_SIZE: usize = 1 + 8 + 8 + 8 + 4 + 4;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    membership: Arc<Membership>,
+    state: Arc<Mutex<TransportState>>, // shared by every clone of the transport
+}
+
+struct TransportState {
+    session: u64,
+    outgoing: HashMap<Pid, OutStream>,
+    incoming: HashMap<Pid, InStream>,
+    ready: VecDeque<(Pid, Vec<u8>)>, // delivered in order, not yet returned by recv
+}
+
+struct OutStream {
+    addr: SocketAddr,
+    next_id: u64,
+    unacked: BTreeMap<u64, PendingMessage>,
+}
+
+struct PendingMessage {
+    packets: Vec<Option<Vec<u8>>>, // none once the fragment is acked
+    attempts: u32,
+    next_retry: Instant,
+    expires: Option<Instant>, // given up on from then on
+}
+
+struct InStream {
+    session: u64,
+    next_expected: u64,
+    partial: HashMap<u64, Vec<Option<Vec<u8>>>>,
+    complete: BTreeMap<u64, Option<Vec<u8>>>, // none once delivered ahead of its turn
+}
+
+impl UdpTransport {
+    pub fn bind(addr: SocketAddr, membership: Arc<Membership>) -> Result<UdpTransport> {
+        let socket = UdpSocket::bind(addr)?;
+        Ok(UdpTransport {
+            socket,
+            membership,
+            state: Arc::new(Mutex::new(TransportState {
+                session: rand::random(),
+                outgoing: HashMap::new(),
+                incoming: HashMap::new(),
+                ready: VecDeque::new(),
+            })),
+        })
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_be_bytes(bytes[at..at + 8].try_into().unwrap())
+}
+
+fn data_packet(
+    kind: u8,
+    session: u64,
+    msg_id: u64,
+    base: u64,
+    frag: u32,
+    num_frags: u32,
+    payload: &[u8],
+) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(DATA_HEADER_SIZE + payload.len());
+    packet.push(kind);
+    packet.extend_from_slice(&session.to_be_bytes());
+    packet.extend_from_slice(&msg_id.to_be_bytes());
+    packet.extend_from_slice(&base.to_be_bytes());
+    packet.extend_from_slice(&frag.to_be_bytes());
+    packet.extend_from_slice(&num_frags.to_be_bytes());
+    packet.extend_from_slice(payload);
+    packet
+}
+
+// The session is the one of the data packet's sender, so acks meant for an
+// earlier incarnation of it are ignored.
+fn ack_packet(session: u64, msg_id: u64, frag: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(1 + 8 + 8 + 4);
+    packet.push(ACK_PACKET);
+    packet.extend_from_slice(&session.to_be_bytes());
+    packet.extend_from_slice(&msg_id.to_be_bytes());
+    packet.extend_from_slice(&frag.to_be_bytes());
+    packet
+}
+
+fn retry_delay(attempts: u32) -> Duration {
+    Duration::from_millis((RETRANSMIT_MS << attempts.min(4)).min(MAX_RETRANSMIT_MS))
+}
+
+impl TransportState {
+    // Resends every fragment whose ack is overdue, drops messages that ran
+    // out of attempts or expired.
+    fn retransmit(&mut self, socket: &UdpSocket) {
+        let now = Instant::now();
+        for stream in self.outgoing.values_mut() {
+            stream.unacked.retain(|_, message| {
+                message.next_retry > now
+                    || (message.attempts + 1 < MAX_ATTEMPTS && message.expires.is_none_or(|expires| expires > now))
+            });
+            // the resent packets carry the oldest id still waited on, past
+            // the messages just given up
+            let base = match stream.unacked.keys().next() {
+                Some(oldest) => *oldest,
+                None => continue,
+            };
+            for message in stream.unacked.values_mut() {
+                if message.next_retry > now {
+                    continue;
+                }
+                message.attempts += 1;
+                for packet in message.packets.iter_mut().flatten() {
+                    packet[17..25].copy_from_slice(&base.to_be_bytes());
+                    let _ = socket.send_to(packet, stream.addr);
+                }
+                message.next_retry = now + retry_delay(message.attempts);
+            }
+        }
+    }
+
+    fn handle_ack(&mut self, src_pid: Pid, packet: &[u8]) {
+        if packet.len() < 1 + 8 + 8 + 4 || read_u64(packet, 1) != self.session {
+            return;
+        }
+        let msg_id = read_u64(packet, 9);
+        let frag = read_u32(packet, 17) as usize;
+        if let Some(stream) = self.outgoing.get_mut(&src_pid) {
+            if let Some(message) = stream.unacked.get_mut(&msg_id) {
+                if frag < message.packets.len() {
+                    message.packets[frag] = None;
+                }
+                if message.packets.iter().all(|packet| packet.is_none()) {
+                    stream.unacked.remove(&msg_id);
+                }
+            }
+        }
+    }
+
+    fn handle_data(&mut self, socket: &UdpSocket, src_pid: Pid, src_addr: SocketAddr, packet: &[u8]) {
+        if packet.len() < DATA_HEADER_SIZE {
+            return;
+        }
+        let session = read_u64(packet, 1);
+        let msg_id = read_u64(packet, 9);
+        let base = read_u64(packet, 17);
+        let frag = read_u32(packet, 25);
+        let num_frags = read_u32(packet, 29) as usize;
+        if frag as usize >= num_frags {
+            return;
+        }
+        let _ = socket.send_to(&ack_packet(session, msg_id, frag), src_addr);
+
+        let stream = self.incoming.entry(src_pid).or_insert(InStream {
+            session,
+            next_expected: base,
+            partial: HashMap::new(),
+            complete: BTreeMap::new(),
+        });
+        if stream.session != session {
+            // the sender restarted, its old stream is gone
+            *stream = InStream {
+                session,
+                next_expected: base,
+                partial: HashMap::new(),
+                complete: BTreeMap::new(),
+            };
+        }
+
+        let mut delivered = Vec::new();
+        if base > stream.next_expected {
+            // the sender gave up on the messages before base
+            let skipped: Vec<u64> = stream.complete.range(..base).map(|(msg_id, _)| *msg_id).collect();
+            for msg_id in skipped {
+                delivered.extend(stream.complete.remove(&msg_id).unwrap());
+            }
+            stream.partial.retain(|msg_id, _| *msg_id >= base);
+            stream.next_expected = base;
+        }
+
+        if msg_id >= stream.next_expected && !stream.complete.contains_key(&msg_id) {
+            let fragments = stream.partial.entry(msg_id).or_insert(vec![None; num_frags]);
+            if fragments.len() == num_frags {
+                fragments[frag as usize] = Some(packet[DATA_HEADER_SIZE..].to_vec());
+                if fragments.iter().all(|fragment| fragment.is_some()) {
+                    let fragments = stream.partial.remove(&msg_id).unwrap();
+                    let data: Vec<u8> = fragments.into_iter().flatten().flatten().collect();
+                    if packet[0] == EXPIRING_DATA_PACKET {
+                        delivered.push(data);
+                        stream.complete.insert(msg_id, None);
+                    } else {
+                        stream.complete.insert(msg_id, Some(data));
+                    }
+                }
+            }
+        }
+
+        while let Some(data) = stream.complete.remove(&stream.next_expected) {
+            delivered.extend(data);
+            stream.next_expected += 1;
+        }
+
+        for data in delivered {
+            self.ready.push_back((src_pid, data));
+        }
+    }
+}
+
+impl UdpTransport {
+    fn send_packets(&mut self, dst_pid: &Pid, data: &[u8], expires: Option<Instant>) -> Result<()> {
+        let addr = match self.membership.addr_of(dst_pid) {
+            Some(addr) => addr,
+            None => return Err(Error::new(ErrorKind::NotFound, format!("pid {} is not a cluster member", dst_pid))),
+        };
+        let kind = match expires {
+            Some(_) => EXPIRING_DATA_PACKET,
+            None => DATA_PACKET,
+        };
+        let mut state = self.state.lock().unwrap();
+        let session = state.session;
+        let stream = state.outgoing.entry(*dst_pid).or_insert(OutStream {
+            addr,
+            next_id: 0,
+            unacked: BTreeMap::new(),
+        });
+        let msg_id = stream.next_id;
+        stream.next_id += 1;
+        let base = match stream.unacked.keys().next() {
+            Some(oldest) => *oldest,
+            None => msg_id,
+        };
+
+        let chunks: Vec<&[u8]> = if data.is_empty() {
+            vec![&[]]
+        } else {
+            data.chunks(MAX_FRAGMENT_SIZE).collect()
+        };
+        let num_frags = chunks.len() as u32;
+        let mut packets = Vec::new();
+        for (frag, chunk) in chunks.iter().enumerate() {
+            let packet = data_packet(kind, session, msg_id, base, frag as u32, num_frags, chunk);
+            match self.socket.send_to(&packet, addr) {
+                Ok(_) => {}
+                // retransmitted later like a lost packet
+                Err(e) => println!("send: -> {} failed: {:?}", &dst_pid, e),
+            }
+            packets.push(Some(packet));
+        }
+        stream.unacked.insert(
+            msg_id,
+            PendingMessage {
+                packets,
+                attempts: 0,
+                next_retry: Instant::now() + retry_delay(0),
+                expires,
+            },
+        );
+        Ok(())
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, dst_pid: &Pid, data: &[u8]) -> Result<()> {
+        self.send_packets(dst_pid, data, None)
+    }
+
+    fn send_expiring(&mut self, dst_pid: &Pid, data: &[u8], deadline: Duration) -> Result<()> {
+        self.send_packets(dst_pid, data, Some(Instant::now() + deadline))
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Vec<(Pid, Vec<u8>)>> {
+        let start_time = Instant::now();
+
+        let mut buf = vec![0; MAX_DATAGRAM_SIZE];
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                state.retransmit(&self.socket);
+                if !state.ready.is_empty() {
+                    return Ok(state.ready.drain(..).collect());
+                }
+            }
+
+            let elapsed = start_time.elapsed();
+            if elapsed >= timeout {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"));
+            }
+            // wake up in time for the next retransmission
+            let wait = (timeout - elapsed).min(Duration::from_millis(RETRANSMIT_MS));
+            self.socket.set_read_timeout(Some(wait.max(Duration::from_millis(1))))?;
+
+            if let Ok((bytes_read, ip_port)) = self.socket.recv_from(&mut buf) {
+                if bytes_read > 0 {
+                    let src_pid = match self.membership.pid_of(&ip_port) {
+                        Some(pid) => pid,
+                        None => {
+                            println!("Dropping packet from unknown sender {}", ip_port);
+                            continue;
+                        }
+                    };
+                    let mut state = self.state.lock().unwrap();
+                    match buf[0] {
+                        DATA_PACKET | EXPIRING_DATA_PACKET => {
+                            state.handle_data(&self.socket, src_pid, ip_port, &buf[0..bytes_read])
+                        }
+                        ACK_PACKET => state.handle_ack(src_pid, &buf[0..bytes_read]),
+                        _ => println!("Dropping unknown packet from {}", src_pid),
+                    }
+                }
+            }
+        }
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(UdpTransport {
+            socket: self.socket.try_clone()?,
+            membership: Arc::clone(&self.membership),
+            state: Arc::clone(&self.state),
+        }))
+    }
+}
@@ -0,0 +1,489 @@
+extern crate serde_json;
+
+use crate::kvs::{Operation, ReplicationMode, ServerMetrics, ShardId, ShardInfo, ShardLoc, TxnRecord};
+use bincode::Options;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Result};
+
+// How an Operation looks on the wire. send encodes in the process' format and
+// recv takes either, so a cluster can move from JSON to binary one process at
+// a time: the new processes keep sending JSON (`--wire json`) until every peer
+// is upgraded.
+//
+// Binary messages start with a 6 byte header:
+//
+// +---------+-----+------------------+----------------------------+
+// | version | tag | length (u32, BE) | payload (bincode, varints) |
+// +---------+-----+------------------+----------------------------+
+//
+// The tag is the operation's variant (see message_tag), so a reader can tell
+// what a message is without decoding it. JSON messages are the plain
+// serde_json text, which always starts with '{' or '"' and so never looks
+// like a version byte.
+//
+// The version goes up whenever a message older processes already send
+// changes shape:
+//
+// 1  the first binary format
+// 2  ShardInfo gains the hash ring
+// 3  ShardInfo gains epoch
+// 4  ShardInfo replaces the ring with num_shards
+// 5  ShardInfo gains hot_keys
+// 6  ServerMetrics gains queued_requests and busy_requests
+// 7  TxnRecord::Decided gains the parts of the transaction
+//
+// A new Operation variant needs no new version: a process that does not know
+// it fails to decode it like any other bad message. decode reads every
+// version back to MIN_WIRE_VERSION and fills in what an older sender did not
+// know with defaults; encode_version writes an older one and leaves out what
+// it cannot carry. So binary clusters upgrade one process at a time too, the
+// new processes send the old version (`--wire-version 5`) until every peer is
+// upgraded. Before version 4 keys were placed by a different hash, those
+// processes cannot share a cluster with these ones. JSON has no version, new
+// fields take their defaults when an older peer leaves them out and records
+// of either shape decode.
+//
+// Usage:
+// let data = encode(&operation, WireFormat::Binary);
+// let data = encode_version(&operation, 5)?;   // for peers that only read 5
+// let operation = decode(&data)?;   // Err for anything undecodable
+
+pub const WIRE_VERSION: u8 = 7;
+pub const MIN_WIRE_VERSION: u8 = 4;
+pub const HEADER_LEN: usize = 6;
+// Larger payloads are refused before they are decoded
+pub const MAX_PAYLOAD_LEN: u64 = 256 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WireFormat {
+    Binary,
+    Json,
+}
+
+pub fn parse_wire_format(name: &str) -> Result<WireFormat> {
+    match name {
+        "binary" => Ok(WireFormat::Binary),
+        "json" => Ok(WireFormat::Json),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown wire format {}, expected binary or json", name),
+        )),
+    }
+}
+
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new().with_limit(MAX_PAYLOAD_LEN)
+}
+
+// One tag per Operation variant. Tags are part of the wire format: a new
+// variant gets the next free number and existing ones never change.
+pub fn message_tag(operation: &Operation) -> u8 {
+    match operation {
+        Operation::Get(..) => 0,
+        Operation::Put(..) => 1,
+        Operation::Delete(..) => 2,
+        Operation::GetRes(..) => 3,
+        Operation::PutRes(..) => 4,
+        Operation::DeleteRes(..) => 5,
+        Operation::GetShardInfo() => 6,
+        Operation::GetShardInfoRes(..) => 7,
+        Operation::PutShardInfo(..) => 8,
+        Operation::PutShardInfoRes() => 9,
+        Operation::ControllerRaft(..) => 10,
+        Operation::Replicate(..) => 11,
+        Operation::ReplicateRes(..) => 12,
+        Operation::ReadVersion(..) => 13,
+        Operation::ReadVersionRes(..) => 14,
+        Operation::Raft(..) => 15,
+        Operation::RaftForward(..) => 16,
+        Operation::Snapshot() => 17,
+        Operation::SnapshotRes(..) => 18,
+        Operation::FaultControl(..) => 19,
+        Operation::AddServer(..) => 20,
+        Operation::MoveShard(..) => 21,
+        Operation::MigrateShard(..) => 22,
+        Operation::MigrateShardRes(..) => 23,
+        Operation::ShardData(..) => 24,
+        Operation::ShardDataDone(..) => 25,
+        Operation::ShardDataRes(..) => 26,
+        Operation::Forward(..) => 27,
+        Operation::Metrics() => 28,
+        Operation::MetricsRes(..) => 29,
+        Operation::Session(..) => 30,
+        Operation::ReadReplica(..) => 31,
+        Operation::SessionRes(..) => 32,
+        Operation::ReadRefused(..) => 33,
+        Operation::ShardVersions(..) => 34,
+        Operation::MultiGet(..) => 35,
+        Operation::MultiPut(..) => 36,
+        Operation::MultiDelete(..) => 37,
+        Operation::MultiGetRes(..) => 38,
+        Operation::MultiPutRes(..) => 39,
+        Operation::MultiDeleteRes(..) => 40,
+        Operation::ReplicateBatch(..) => 41,
+        Operation::ReplicateBatchRes(..) => 42,
+        Operation::ReadVersions(..) => 43,
+        Operation::ReadVersionsRes(..) => 44,
+        Operation::Cas(..) => 45,
+        Operation::PutIfAbsent(..) => 46,
+        Operation::Incr(..) => 47,
+        Operation::CasRes(..) => 48,
+        Operation::PutIfAbsentRes(..) => 49,
+        Operation::IncrRes(..) => 50,
+        Operation::TxnCommit(..) => 51,
+        Operation::TxnCommitRes(..) => 52,
+        Operation::TxnPrepare(..) => 53,
+        Operation::TxnPrepareRes(..) => 54,
+        Operation::TxnDecide(..) => 55,
+        Operation::TxnDecideRes(..) => 56,
+        Operation::TxnStatus(..) => 57,
+        Operation::TxnReplicate(..) => 58,
+        Operation::TxnReplicateRes(..) => 59,
+        Operation::HotKeys(..) => 60,
+        Operation::Busy(..) => 61,
+        Operation::TxnForget(..) => 62,
+        Operation::Stale(..) => 63,
+    }
+}
+
+pub fn encode(operation: &Operation, format: WireFormat) -> Vec<u8> {
+    match format {
+        WireFormat::Json => serde_json::to_vec(operation).unwrap(),
+        WireFormat::Binary => encode_binary(operation, WIRE_VERSION),
+    }
+}
+
+// Encodes in binary as the given version, for peers that do not read the
+// current one yet
+pub fn encode_version(operation: &Operation, version: u8) -> Result<Vec<u8>> {
+    check_version(version)?;
+    Ok(encode_binary(operation, version))
+}
+
+pub fn check_version(version: u8) -> Result<()> {
+    if (MIN_WIRE_VERSION..=WIRE_VERSION).contains(&version) {
+        Ok(())
+    } else {
+        Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unsupported wire version {}, expected {} to {}", version, MIN_WIRE_VERSION, WIRE_VERSION),
+        ))
+    }
+}
+
+fn encode_binary(operation: &Operation, version: u8) -> Vec<u8> {
+    let payload = with_codec_version(version, || bincode_options().serialize(operation).unwrap());
+    let mut data = Vec::with_capacity(HEADER_LEN + payload.len());
+    data.push(version);
+    data.push(message_tag(operation));
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(&payload);
+    data
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+// Decodes a message in either format
+pub fn decode(data: &[u8]) -> Result<Operation> {
+    match data.first() {
+        None => Err(invalid("empty message".to_string())),
+        Some(b'{') | Some(b'"') => serde_json::from_slice(data).map_err(|e| invalid(format!("bad json: {}", e))),
+        Some(version) if (MIN_WIRE_VERSION..=WIRE_VERSION).contains(version) => decode_binary(data),
+        Some(version) => Err(invalid(format!("unsupported wire version {}", version))),
+    }
+}
+
+fn decode_binary(data: &[u8]) -> Result<Operation> {
+    if data.len() < HEADER_LEN {
+        return Err(invalid(format!("truncated header, {} bytes", data.len())));
+    }
+    let tag = data[1];
+    let length = u32::from_be_bytes(data[2..HEADER_LEN].try_into().unwrap()) as usize;
+    let payload = &data[HEADER_LEN..];
+    if payload.len() != length {
+        return Err(invalid(format!("payload is {} bytes, header says {}", payload.len(), length)));
+    }
+    let version = data[0];
+    let operation: Operation = match with_codec_version(version, || bincode_options().deserialize(payload)) {
+        Ok(operation) => operation,
+        Err(e) => return Err(invalid(format!("bad payload: {}", e))),
+    };
+    if message_tag(&operation) != tag {
+        return Err(invalid(format!("tag {} does not match the payload", tag)));
+    }
+    Ok(operation)
+}
+
+thread_local! {
+    // The binary version being encoded or decoded on this thread, read by the
+    // types whose shape changed between versions. JSON always uses the
+    // current shapes.
+    static CODEC_VERSION: Cell<u8> = const { Cell::new(WIRE_VERSION) };
+}
+
+fn with_codec_version<R>(version: u8, f: impl FnOnce() -> R) -> R {
+    let previous = CODEC_VERSION.with(|cell| cell.replace(version));
+    let result = f();
+    CODEC_VERSION.with(|cell| cell.set(previous));
+    result
+}
+
+fn codec_version() -> u8 {
+    CODEC_VERSION.with(|cell| cell.get())
+}
+
+// ShardInfo as versions 4 and later send it. serde converts through these, so
+// ShardInfo itself always has every field.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ShardInfoWire {
+    V4(ShardInfoV4),
+    V5(ShardInfoV5),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ShardInfoV4 {
+    locations: HashMap<ShardId, ShardLoc>,
+    replication: ReplicationMode,
+    lease_ms: u64,
+    num_shards: u32,
+    epoch: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ShardInfoV5 {
+    locations: HashMap<ShardId, ShardLoc>,
+    replication: ReplicationMode,
+    lease_ms: u64,
+    num_shards: u32,
+    epoch: u64,
+    #[serde(default)]
+    hot_keys: Vec<String>,
+}
+
+impl<'de> Deserialize<'de> for ShardInfoWire {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if codec_version() < 5 {
+            ShardInfoV4::deserialize(deserializer).map(ShardInfoWire::V4)
+        } else {
+            ShardInfoV5::deserialize(deserializer).map(ShardInfoWire::V5)
+        }
+    }
+}
+
+impl From<ShardInfo> for ShardInfoWire {
+    fn from(info: ShardInfo) -> Self {
+        if codec_version() < 5 {
+            ShardInfoWire::V4(ShardInfoV4 {
+                locations: info.locations,
+                replication: info.replication,
+                lease_ms: info.lease_ms,
+                num_shards: info.num_shards,
+                epoch: info.epoch,
+            })
+        } else {
+            ShardInfoWire::V5(ShardInfoV5 {
+                locations: info.locations,
+                replication: info.replication,
+                lease_ms: info.lease_ms,
+                num_shards: info.num_shards,
+                epoch: info.epoch,
+                hot_keys: info.hot_keys,
+            })
+        }
+    }
+}
+
+impl From<ShardInfoWire> for ShardInfo {
+    fn from(wire: ShardInfoWire) -> Self {
+        match wire {
+            ShardInfoWire::V4(info) => ShardInfo {
+                locations: info.locations,
+                replication: info.replication,
+                lease_ms: info.lease_ms,
+                num_shards: info.num_shards,
+                epoch: info.epoch,
+                hot_keys: Vec::new(),
+            },
+            ShardInfoWire::V5(info) => ShardInfo {
+                locations: info.locations,
+                replication: info.replication,
+                lease_ms: info.lease_ms,
+                num_shards: info.num_shards,
+                epoch: info.epoch,
+                hot_keys: info.hot_keys,
+            },
+        }
+    }
+}
+
+// ServerMetrics as versions 4 and later send it
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum ServerMetricsWire {
+    V4(ServerMetricsV4),
+    V6(ServerMetricsV6),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ServerMetricsV4 {
+    parked_requests: usize,
+    parked_keys: usize,
+    max_key_depth: usize,
+    peak_key_depth: usize,
+    total_parked: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct ServerMetricsV6 {
+    parked_requests: usize,
+    parked_keys: usize,
+    max_key_depth: usize,
+    peak_key_depth: usize,
+    total_parked: u64,
+    #[serde(default)]
+    queued_requests: usize,
+    #[serde(default)]
+    busy_requests: u64,
+}
+
+impl<'de> Deserialize<'de> for ServerMetricsWire {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if codec_version() < 6 {
+            ServerMetricsV4::deserialize(deserializer).map(ServerMetricsWire::V4)
+        } else {
+            ServerMetricsV6::deserialize(deserializer).map(ServerMetricsWire::V6)
+        }
+    }
+}
+
+impl From<ServerMetrics> for ServerMetricsWire {
+    fn from(metrics: ServerMetrics) -> Self {
+        if codec_version() < 6 {
+            ServerMetricsWire::V4(ServerMetricsV4 {
+                parked_requests: metrics.parked_requests,
+                parked_keys: metrics.parked_keys,
+                max_key_depth: metrics.max_key_depth,
+                peak_key_depth: metrics.peak_key_depth,
+                total_parked: metrics.total_parked,
+            })
+        } else {
+            ServerMetricsWire::V6(ServerMetricsV6 {
+                parked_requests: metrics.parked_requests,
+                parked_keys: metrics.parked_keys,
+                max_key_depth: metrics.max_key_depth,
+                peak_key_depth: metrics.peak_key_depth,
+                total_parked: metrics.total_parked,
+                queued_requests: metrics.queued_requests,
+                busy_requests: metrics.busy_requests,
+            })
+        }
+    }
+}
+
+impl From<ServerMetricsWire> for ServerMetrics {
+    fn from(wire: ServerMetricsWire) -> Self {
+        match wire {
+            ServerMetricsWire::V4(metrics) => ServerMetrics {
+                parked_requests: metrics.parked_requests,
+                parked_keys: metrics.parked_keys,
+                max_key_depth: metrics.max_key_depth,
+                peak_key_depth: metrics.peak_key_depth,
+                total_parked: metrics.total_parked,
+                ..ServerMetrics::default()
+            },
+            ServerMetricsWire::V6(metrics) => ServerMetrics {
+                parked_requests: metrics.parked_requests,
+                parked_keys: metrics.parked_keys,
+                max_key_depth: metrics.max_key_depth,
+                peak_key_depth: metrics.peak_key_depth,
+                total_parked: metrics.total_parked,
+                queued_requests: metrics.queued_requests,
+                busy_requests: metrics.busy_requests,
+            },
+        }
+    }
+}
+
+// TxnRecord as versions 4 and later send it
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum TxnRecordWire {
+    V4(TxnRecordV4),
+    V7(TxnRecordV7),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum TxnRecordV4 {
+    Prepared(ShardId, Vec<(String, Option<String>)>, Vec<String>),
+    Decided(bool),
+    Applied(Vec<(String, Option<String>)>, u64),
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum TxnRecordV7 {
+    Prepared(ShardId, Vec<(String, Option<String>)>, Vec<String>),
+    Decided(bool, Vec<ShardId>),
+    Applied(Vec<(String, Option<String>)>, u64),
+}
+
+// JSON tells the two shapes apart by itself
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxnRecordJson {
+    V7(TxnRecordV7),
+    V4(TxnRecordV4),
+}
+
+impl<'de> Deserialize<'de> for TxnRecordWire {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            return TxnRecordJson::deserialize(deserializer).map(|record| match record {
+                TxnRecordJson::V7(record) => TxnRecordWire::V7(record),
+                TxnRecordJson::V4(record) => TxnRecordWire::V4(record),
+            });
+        }
+        if codec_version() < 7 {
+            TxnRecordV4::deserialize(deserializer).map(TxnRecordWire::V4)
+        } else {
+            TxnRecordV7::deserialize(deserializer).map(TxnRecordWire::V7)
+        }
+    }
+}
+
+impl From<TxnRecord> for TxnRecordWire {
+    fn from(record: TxnRecord) -> Self {
+        if codec_version() < 7 {
+            TxnRecordWire::V4(match record {
+                TxnRecord::Prepared(coordinator, writes, keys) => TxnRecordV4::Prepared(coordinator, writes, keys),
+                TxnRecord::Decided(commit, _) => TxnRecordV4::Decided(commit),
+                TxnRecord::Applied(writes, version) => TxnRecordV4::Applied(writes, version),
+            })
+        } else {
+            TxnRecordWire::V7(match record {
+                TxnRecord::Prepared(coordinator, writes, keys) => TxnRecordV7::Prepared(coordinator, writes, keys),
+                TxnRecord::Decided(commit, parts) => TxnRecordV7::Decided(commit, parts),
+                TxnRecord::Applied(writes, version) => TxnRecordV7::Applied(writes, version),
+            })
+        }
+    }
+}
+
+impl From<TxnRecordWire> for TxnRecord {
+    fn from(wire: TxnRecordWire) -> Self {
+        match wire {
+            TxnRecordWire::V4(TxnRecordV4::Prepared(coordinator, writes, keys))
+            | TxnRecordWire::V7(TxnRecordV7::Prepared(coordinator, writes, keys)) => TxnRecord::Prepared(coordinator, writes, keys),
+            // an older primary does not say which shards are involved
+            TxnRecordWire::V4(TxnRecordV4::Decided(commit)) => TxnRecord::Decided(commit, Vec::new()),
+            TxnRecordWire::V7(TxnRecordV7::Decided(commit, parts)) => TxnRecord::Decided(commit, parts),
+            TxnRecordWire::V4(TxnRecordV4::Applied(writes, version))
+            | TxnRecordWire::V7(TxnRecordV7::Applied(writes, version)) => TxnRecord::Applied(writes, version),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+mod utils;
+
+use kv_store::admission::{admit, client_seq_no, FairQueue, WorkerQueue, BUSY_RETRY_AFTER_MS};
+use kv_store::client::{ClientNode, MAX_BACKOFF_MS, REQUEST_TIMEOUT_MS};
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ShardInfo};
+use kv_store::lease::Clock;
+use kv_store::ring::DEFAULT_VNODES;
+use std::cell::Cell;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    get_server_metrics, group_result_by_key, isolate_ports, launch_client_with_options, launch_controller,
+    launch_server_with_options, read_result,
+};
+
+// A clock the test moves by hand
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+#[test]
+fn test_clients_are_served_in_turns() {
+    let mut queue = FairQueue::new(100, 100);
+    for i in 0..5 {
+        queue.push_client(10, format!("a{}", i)).unwrap();
+    }
+    queue.push_client(11, "b0".to_string()).unwrap();
+    queue.push_client(11, "b1".to_string()).unwrap();
+    queue.push_control("control".to_string());
+    assert_eq!(queue.queued(), 7);
+
+    // control first, then one request of every client in turn
+    let mut served = Vec::new();
+    while let Some(item) = queue.pop() {
+        served.push(item);
+    }
+    assert_eq!(served, vec!["control", "a0", "b0", "a1", "b1", "a2", "a3", "a4"]);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn test_full_queues_refuse_clients_only() {
+    let mut queue = FairQueue::new(3, 2);
+    assert!(queue.push_client(10, 0).is_ok());
+    assert!(queue.push_client(10, 1).is_ok());
+    // a client over its share, while others still get in
+    assert_eq!(queue.push_client(10, 2), Err(2));
+    assert!(queue.push_client(11, 3).is_ok());
+    // every client over the queue's capacity
+    assert_eq!(queue.push_client(12, 4), Err(4));
+    for i in 0..100 {
+        queue.push_control(100 + i);
+    }
+    assert_eq!(queue.queued(), 3);
+
+    // room again once the worker took a request
+    for _ in 0..101 {
+        queue.pop();
+    }
+    assert!(queue.push_client(12, 4).is_ok());
+}
+
+#[test]
+fn test_admit_answers_busy() {
+    let queue = WorkerQueue::new(1, 1);
+    let get = |seq_no| Operation::Get("key".to_string(), seq_no);
+    assert!(admit(&queue, 10, get(0)).is_ok());
+    assert!(matches!(admit(&queue, 11, get(1)), Err((11, Operation::Busy(1, BUSY_RETRY_AFTER_MS)))));
+    let session = Operation::Session(Box::new(Operation::Put("key".to_string(), "v".to_string(), 2)));
+    assert_eq!(client_seq_no(&session), Some(2));
+    assert!(matches!(admit(&queue, 11, session), Err((11, Operation::Busy(2, _)))));
+    // a server's messages always get in
+    assert!(admit(&queue, 4, Operation::ReplicateRes("key".to_string(), None, 1)).is_ok());
+    assert_eq!(queue.busy(), 2);
+    assert_eq!(queue.queued(), 1);
+
+    let timeout = Duration::from_millis(10);
+    assert!(matches!(queue.pop(timeout), Some((4, Operation::ReplicateRes(..)))));
+    assert!(matches!(queue.pop(timeout), Some((10, Operation::Get(_, 0)))));
+    assert!(queue.pop(timeout).is_none());
+}
+
+// A request another server passes on queues as one of its client, and the
+// client is the one told Busy
+#[test]
+fn test_admit_forwarded_requests_as_their_client() {
+    let queue = WorkerQueue::new(3, 1);
+    let put = |seq_no| Box::new(Operation::Put("key".to_string(), "v".to_string(), seq_no));
+    assert!(admit(&queue, 4, Operation::Forward(10, put(0))).is_ok());
+    assert!(matches!(
+        admit(&queue, 4, Operation::Forward(10, put(1))),
+        Err((10, Operation::Busy(1, BUSY_RETRY_AFTER_MS)))
+    ));
+    assert!(matches!(admit(&queue, 10, *put(2)), Err((10, Operation::Busy(2, _)))));
+    assert!(matches!(
+        admit(&queue, 5, Operation::RaftForward(10, put(3))),
+        Err((10, Operation::Busy(3, _)))
+    ));
+    // other clients still get in
+    assert!(admit(&queue, 4, Operation::Forward(11, put(4))).is_ok());
+    assert_eq!(queue.busy(), 3);
+    assert_eq!(queue.queued(), 2);
+
+    let timeout = Duration::from_millis(10);
+    assert!(matches!(queue.pop(timeout), Some((4, Operation::Forward(10, _)))));
+    assert!(matches!(queue.pop(timeout), Some((4, Operation::Forward(11, _)))));
+    assert!(queue.pop(timeout).is_none());
+}
+
+// The seq_no of every request the node sent to pid
+fn sent_to(node: &mut ClientNode<ManualClock>, pid: Pid) -> Vec<usize> {
+    node.take_messages()
+        .into_iter()
+        .filter(|(dst, _)| *dst == pid)
+        .map(|(_, operation)| client_seq_no(&operation).unwrap())
+        .collect()
+}
+
+// A refused request goes again after a jittered backoff that doubles with
+// every Busy it got, and does not count as timed out meanwhile
+#[test]
+fn test_client_backs_off_from_a_busy_server() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4], 4, 1, DEFAULT_VNODES);
+    let operations = vec![Operation::Get("a".to_string(), 0), Operation::Get("b".to_string(), 1)];
+    let mut node = ClientNode::new(2, vec![0], operations, ManualClock(now.clone())).with_window(2);
+    node.take_messages();
+    node.step(0, Operation::GetShardInfoRes(info));
+    assert_eq!(sent_to(&mut node, 4), vec![0, 1]);
+
+    // the first backoff is within [retry_after_ms / 2, retry_after_ms]
+    node.step(4, Operation::Busy(0, 100));
+    now.set(49);
+    node.tick();
+    assert!(sent_to(&mut node, 4).is_empty());
+    now.set(100);
+    node.tick();
+    assert_eq!(sent_to(&mut node, 4), vec![0]);
+
+    // the second one twice that, a duplicate Busy does not count
+    node.step(4, Operation::Busy(0, 100));
+    node.step(4, Operation::Busy(0, 100));
+    assert_eq!(node.busy_answers(), 2);
+    now.set(199);
+    node.tick();
+    assert!(sent_to(&mut node, 4).is_empty());
+    now.set(300);
+    node.tick();
+    assert_eq!(sent_to(&mut node, 4), vec![0]);
+
+    // every request backs off on its own
+    node.step(4, Operation::Busy(1, 100));
+    now.set(400);
+    node.tick();
+    assert_eq!(sent_to(&mut node, 4), vec![1]);
+    node.step(4, Operation::GetRes(None, 1));
+
+    // it never grows past MAX_BACKOFF_MS
+    for _ in 0..10 {
+        node.step(4, Operation::Busy(0, 100));
+        now.set(now.get() + MAX_BACKOFF_MS);
+        node.tick();
+        assert_eq!(sent_to(&mut node, 4), vec![0]);
+    }
+    now.set(now.get() + REQUEST_TIMEOUT_MS - 1);
+    node.tick();
+    assert!(node.take_messages().is_empty());
+    node.step(4, Operation::GetRes(None, 0));
+    assert!(node.is_done());
+}
+
+// The server a request was forwarded to answers Busy itself
+#[test]
+fn test_client_backs_off_from_the_server_a_request_was_forwarded_to() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4], 4, 1, DEFAULT_VNODES);
+    let operations = vec![Operation::Get("a".to_string(), 0), Operation::Get("b".to_string(), 1)];
+    let mut node = ClientNode::new(2, vec![0], operations, ManualClock(now.clone())).with_window(2);
+    node.take_messages();
+    node.step(0, Operation::GetShardInfoRes(info));
+    assert_eq!(sent_to(&mut node, 4), vec![0, 1]);
+
+    node.step(5, Operation::Busy(0, 100));
+    assert_eq!(node.busy_answers(), 1);
+    now.set(100);
+    node.tick();
+    assert_eq!(sent_to(&mut node, 4), vec![0]);
+    node.step(4, Operation::GetRes(None, 0));
+    node.step(4, Operation::GetRes(None, 1));
+    assert!(node.is_done());
+}
+
+fn verify_read_your_write_per_key(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Read your write against servers that queue four requests per client, so
+// that clients sending 16 at once are refused most of the time
+#[test]
+fn test_read_your_write_with_busy_servers() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_busy";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            bin_dir,
+            &["--workers", "1", "--queue-capacity", "16", "--client-queue-capacity", "4"],
+            server_pid,
+            &vec![controller_pid_for_servers],
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            &["--window", "16"],
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    let metrics = get_server_metrics(&server_pids.iter().map(|pid| *pid as Pid).collect());
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    assert_eq!(metrics.len(), num_servers, "not every server answered: {:?}", metrics);
+    let busy: u64 = metrics.iter().map(|(_, metrics)| metrics.busy_requests).sum();
+    assert!(busy > 0, "no server was busy: {:?}", metrics);
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write_per_key(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
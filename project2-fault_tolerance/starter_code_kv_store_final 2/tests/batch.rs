@@ -0,0 +1,389 @@
+mod utils;
+
+use kv_store::client::{ClientNode, ReadPreference, REQUEST_TIMEOUT_MS};
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardId, ShardInfo};
+use kv_store::lease::Clock;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use kv_store::sim::{SimClock, Simulation};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{group_result_by_key, isolate_ports, launch_client, launch_controller, launch_server, read_result};
+
+const DEADLINE_MS: u64 = 300_000;
+
+// A clock the test moves by hand
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+// The keys of every batch the node sent, by the shard of the first one
+fn sent_batches(node: &mut ClientNode<ManualClock>, info: &ShardInfo) -> HashMap<ShardId, (Pid, Vec<String>)> {
+    node.take_messages()
+        .into_iter()
+        .map(|(dst, operation)| {
+            let keys = match operation {
+                Operation::MultiGet(keys, _) | Operation::MultiDelete(keys, _) => keys,
+                Operation::MultiPut(entries, _) => entries.into_iter().map(|(key, _)| key).collect(),
+                other => panic!("unexpected request {:?}", other),
+            };
+            (info.shard_of(&keys[0]), (dst, keys))
+        })
+        .collect()
+}
+
+#[test]
+fn test_client_splits_batches_per_shard() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let names: Vec<String> = (0..8).map(|key| format!("k{}", key)).collect();
+    let entries: Vec<(String, String)> = names.iter().map(|key| (key.clone(), format!("{}_v", key))).collect();
+    let operations = vec![Operation::MultiPut(entries, 0), Operation::MultiGet(names.clone(), 1)];
+    let mut node = ClientNode::new(2, vec![0], operations, ManualClock(now.clone())).with_window(8);
+    node.take_messages();
+    node.step(0, Operation::GetShardInfoRes(info.clone()));
+
+    // one MultiPut per shard to its primary, the MultiGet waits for all of them
+    let mut by_shard: HashMap<ShardId, Vec<String>> = HashMap::new();
+    for key in &names {
+        by_shard.entry(info.shard_of(key)).or_default().push(key.clone());
+    }
+    assert!(by_shard.len() > 1);
+    let sent = sent_batches(&mut node, &info);
+    assert_eq!(sent.len(), by_shard.len());
+    for (shard_id, (dst, keys)) in &sent {
+        assert_eq!(*dst, info.locations[shard_id].primary);
+        assert_eq!(*keys, by_shard[shard_id]);
+    }
+    assert_eq!(node.in_flight(), by_shard.len());
+
+    // every part is answered on its own, the one left over is retried alone
+    let mut shard_ids: Vec<ShardId> = by_shard.keys().cloned().collect();
+    shard_ids.sort();
+    let (last, answered) = shard_ids.split_last().unwrap();
+    for shard_id in answered {
+        let old_values = by_shard[shard_id].iter().map(|key| (key.clone(), None)).collect();
+        node.step(info.locations[shard_id].primary, Operation::MultiPutRes(old_values, 0));
+    }
+    assert!(node.take_messages().is_empty());
+    assert_eq!(node.results().len(), names.len() - by_shard[last].len());
+    now.set(REQUEST_TIMEOUT_MS);
+    node.tick();
+    assert!(matches!(node.take_messages().as_slice(), [(0, Operation::GetShardInfo())]));
+    node.step(0, Operation::GetShardInfoRes(info.clone()));
+    let resent = sent_batches(&mut node, &info);
+    assert_eq!(resent.keys().collect::<Vec<_>>(), vec![last]);
+
+    let old_values = by_shard[last].iter().map(|key| (key.clone(), None)).collect();
+    node.step(info.locations[last].primary, Operation::MultiPutRes(old_values, 0));
+    let sent = sent_batches(&mut node, &info);
+    assert_eq!(sent.len(), by_shard.len());
+    for (shard_id, (_, keys)) in sent {
+        let values = keys.iter().map(|key| (key.clone(), Some(format!("{}_v", key)))).collect();
+        node.step(info.locations[&shard_id].primary, Operation::MultiGetRes(values, 1));
+    }
+    assert!(node.is_done());
+
+    // a result per key, the get of every key after its put
+    let results = node.results();
+    assert_eq!(results.len(), names.len() * 2);
+    for key in &names {
+        let ops: Vec<(&str, &str)> = results
+            .iter()
+            .filter(|result| result.key == *key)
+            .map(|result| (result.operation.as_str(), result.observed_value.as_str()))
+            .collect();
+        assert_eq!(ops, vec![("put", ""), ("get", format!("{}_v", key).as_str())]);
+    }
+}
+
+// The writes of a batch go to the secondaries in one message under one
+// version, and the keys of the batch wait until all of it is acked
+#[test]
+fn test_primary_applies_batch_as_one_write() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let shard_id = info.shard_of("k0");
+    let names: Vec<String> = (0..100)
+        .map(|key| format!("k{}", key))
+        .filter(|key| info.shard_of(key) == shard_id)
+        .take(3)
+        .collect();
+    let location = info.locations[&shard_id].clone();
+    let mut primary = ServerNode::new(location.primary, SimClock::new(), 0, None);
+    primary.step(1, Operation::PutShardInfo(info.clone()));
+    primary.take_messages();
+
+    // the first key twice, the last value wins
+    let mut entries: Vec<(String, String)> = names.iter().map(|key| (key.clone(), format!("{}_v", key))).collect();
+    entries.push((names[0].clone(), "again".to_string()));
+    primary.step(10, Operation::MultiPut(entries, 0));
+    primary.step(11, Operation::Get(names[1].clone(), 0));
+    let sent = primary.take_messages();
+    assert_eq!(sent.len(), location.secondaries.len());
+    let mut version = None;
+    for (dst, operation) in &sent {
+        assert!(location.secondaries.contains(dst));
+        match operation {
+            Operation::ReplicateBatch(batch_shard, writes, batch_version) => {
+                assert_eq!(*batch_shard, shard_id);
+                assert_eq!(writes.len(), names.len());
+                assert_eq!(writes[0], (names[0].clone(), Some("again".to_string())));
+                assert!(version.is_none_or(|version| version == *batch_version));
+                version = Some(*batch_version);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+    let version = version.unwrap();
+    for key in &names {
+        assert_eq!(primary.kvs().version(key), version);
+    }
+
+    // a secondary applies the whole batch from the one message
+    let mut secondary = ServerNode::new(location.secondaries[0], SimClock::new(), 0, None);
+    secondary.step(1, Operation::PutShardInfo(info.clone()));
+    secondary.take_messages();
+    secondary.step(location.primary, sent[0].1.clone());
+    assert!(matches!(secondary.take_messages()[..], [(_, Operation::ReplicateBatchRes(_, acked))] if acked == version));
+    assert_eq!(secondary.kvs().get_versioned(&names[0]), (Some("again".to_string()), version));
+    assert_eq!(secondary.kvs().get(&names[2]), Some(format!("{}_v", names[2])));
+
+    // the client and the parked Get are answered after the last ack
+    primary.step(location.secondaries[0], Operation::ReplicateBatchRes(shard_id, version));
+    assert!(primary.take_messages().is_empty());
+    primary.step(location.secondaries[1], Operation::ReplicateBatchRes(shard_id, version));
+    let sent = primary.take_messages();
+    match &sent[..] {
+        [(10, Operation::MultiPutRes(old_values, 0)), (11, Operation::GetRes(Some(value), 0))] => {
+            assert_eq!(*old_values, names.iter().map(|key| (key.clone(), None)).collect::<Vec<_>>());
+            assert_eq!(*value, format!("{}_v", names[1]));
+        }
+        other => panic!("unexpected messages {:?}", other),
+    }
+}
+
+// num_ops random operations of one client over its own keys, a third of them
+// batches of up to 5 keys
+fn workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..20));
+            let batch: Vec<String> = (0..rng.gen_range(2..=5))
+                .map(|_| format!("c{}_k{}", client, rng.gen_range(0..20)))
+                .collect();
+            match rng.gen_range(0..15) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=7 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                8 => Operation::Delete(key, seq_no),
+                9..=10 => Operation::MultiGet(batch, seq_no),
+                11..=13 => {
+                    let entries = batch
+                        .into_iter()
+                        .enumerate()
+                        .map(|(index, key)| (key, format!("v{}_{}_{}", client, seq_no, index)))
+                        .collect();
+                    Operation::MultiPut(entries, seq_no)
+                }
+                _ => Operation::MultiDelete(batch, seq_no),
+            }
+        })
+        .collect()
+}
+
+// Checks read your write and records the value every key was left with.
+// Results are in the order they were answered, which is the order they were
+// sent for the operations of one key.
+fn verify_read_your_write(results: &[KVSResult], last_written: &mut HashMap<String, String>) {
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            assert!(op_result.operation == "get");
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// Reads every key back in one MultiGet once the workload is over
+fn check_no_write_lost(sim: &mut Simulation, client_pids: &[Pid], reader_pid: Pid) {
+    let mut last_written = HashMap::new();
+    for client_pid in client_pids {
+        verify_read_your_write(&sim.results(client_pid), &mut last_written);
+    }
+    let keys: Vec<String> = last_written.keys().cloned().collect();
+    let reads = vec![Operation::MultiGet(keys.clone(), 0)];
+    sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock())));
+    assert!(sim.run_until(DEADLINE_MS * 2, |sim| sim.clients_done()));
+    let results = sim.results(&reader_pid);
+    assert_eq!(results.len(), keys.len());
+    for result in results {
+        assert_eq!(result.observed_value, last_written[&result.key], "lost write of {}", result.key);
+    }
+}
+
+fn add_batch_cluster(sim: &mut Simulation, seed: u64, window: usize, replication: ReplicationMode) -> Vec<Pid> {
+    let (_, server_pids) = sim.add_cluster(vec![], 3, replication);
+    let client_pids: Vec<Pid> = (5..9).collect();
+    for (client, client_pid) in client_pids.iter().enumerate() {
+        let operations = workload(seed, client, 60);
+        sim.add(Box::new(ClientNode::new(*client_pid, vec![0], operations, sim.clock()).with_window(window)));
+    }
+    assert_eq!(server_pids, vec![2, 3, 4]);
+    client_pids
+}
+
+#[test]
+fn test_sim_batches() {
+    // requests and answers of two of the clients get lost, the parts of their
+    // batches are retried on their own
+    let faults = "drop src=5 prob=0.05; drop dst=6 prob=0.05; duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    let modes = [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }, ReplicationMode::Raft];
+    for seed in 0..3 {
+        for replication in modes {
+            for window in [1, 8] {
+                for faults in ["", faults] {
+                    let mut sim = Simulation::new(seed);
+                    sim.faults_mut().apply(faults).unwrap();
+                    let client_pids = add_batch_cluster(&mut sim, seed, window, replication);
+                    assert!(
+                        sim.run_until(DEADLINE_MS, |sim| sim.clients_done()),
+                        "seed {} window {} {:?} faults {:?} did not finish",
+                        seed,
+                        window,
+                        replication,
+                        faults
+                    );
+                    check_no_write_lost(&mut sim, &client_pids, 9);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_batches_primary_crash() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        let client_pids = add_batch_cluster(&mut sim, seed, 4, ReplicationMode::PrimaryBackup);
+        sim.run_for(100 + seed * 10);
+        sim.crash(&2);
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, 9);
+    }
+}
+
+// Batches carry the session token like single writes, so reads from the
+// secondaries still see the client's writes
+#[test]
+fn test_sim_batches_with_secondary_reads() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new(seed);
+        sim.add_cluster(vec![], 3, ReplicationMode::PrimaryBackup);
+        let client_pids: Vec<Pid> = (5..9).collect();
+        for (client, client_pid) in client_pids.iter().enumerate() {
+            let node = ClientNode::new(*client_pid, vec![0], workload(seed, client, 60), sim.clock())
+                .with_window(4)
+                .with_read_preference(ReadPreference::Nearest, None);
+            sim.add(Box::new(node));
+        }
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, 9);
+    }
+}
+
+fn verify_read_your_write_per_key(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Traces with mget, mput and mdelete lines (see data/input/generate_batch_input.py)
+#[test]
+fn test_read_your_write_with_batches() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/batch";
+    let result_dir = "./data/result/batch";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(bin_dir, client_pid, &controller_pid_for_clients, input_dir, &server_pids, result_dir)
+            .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            assert!(grouped_result.len() > 1);
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write_per_key(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
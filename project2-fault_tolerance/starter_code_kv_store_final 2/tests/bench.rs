@@ -4,7 +4,7 @@ use kv_store::kvs::KVSResult;
 use std::process::Child;
 use std::thread::sleep;
 use std::time::Duration;
-use utils::{launch_client, launch_controller, launch_server, read_result};
+use utils::{isolate_ports, launch_client_with_options, launch_controller_with_options, launch_server_with_options, read_result};
 
 fn compute_average(data: &[f64]) -> f64 {
     let sum: f64 = data.iter().sum();
@@ -22,7 +22,8 @@ fn compute_percentile(data: &[f64], percentile: f64) -> f64 {
     data[index]
 }
 
-fn print_performance(result: &Vec<Vec<KVSResult>>) {
+// Prints the latencies and returns the throughput in Mops
+fn print_performance(result: &Vec<Vec<KVSResult>>) -> f64 {
     // calculate latency
     let mut latencies = Vec::new();
     for client_result in result.iter() {
@@ -73,14 +74,28 @@ fn print_performance(result: &Vec<Vec<KVSResult>>) {
         "Latency(us): average:{}, median:{}, 95%:{}, 99%:{}",
         average, median, p95, p99
     );
+    throughput_mops
 }
 
-pub fn bench_common(num_clients: usize, num_servers: usize, workload: &str) {
+// server_options go to every server, controller_options to the controller
+// and client_options to every client, results go to ./data/result/<run_name>.
+// Returns the throughput in Mops
+pub fn bench_common(
+    num_clients: usize,
+    num_servers: usize,
+    workload: &str,
+    server_options: &[&str],
+    controller_options: &[&str],
+    client_options: &[&str],
+    run_name: &str,
+) -> f64 {
+    // the benches run side by side, each on its own ports
+    isolate_ports();
     // binary location. binaries include controller and worker
     let bin_dir = "./target/debug/";
     // number pf worker processes to run
     let input_dir = format!("./data/input/{}", workload);
-    let result_dir = format!("./data/result/{}", workload);
+    let result_dir = format!("./data/result/{}", run_name);
 
     let controller_pid_for_clients: usize = 0;
     let controller_pid_for_servers: usize = 1;
@@ -91,10 +106,11 @@ pub fn bench_common(num_clients: usize, num_servers: usize, workload: &str) {
     println!("launching servers");
     let mut servers: Vec<Child> = Vec::new();
     for server_pid in &server_pids {
-        let server = launch_server(
+        let server = launch_server_with_options(
             &bin_dir,
+            server_options,
             &server_pid,
-            &controller_pid_for_servers,
+            &vec![controller_pid_for_servers],
             &server_pids,
         )
         .expect("Failed to launch client");
@@ -103,8 +119,9 @@ pub fn bench_common(num_clients: usize, num_servers: usize, workload: &str) {
 
     // Launch controller.
     println!("launching controllers");
-    let mut controller = launch_controller(
+    let mut controller = launch_controller_with_options(
         &bin_dir,
+        controller_options,
         &controller_pid_for_clients,
         &controller_pid_for_servers,
         &num_clients,
@@ -118,10 +135,11 @@ pub fn bench_common(num_clients: usize, num_servers: usize, workload: &str) {
     println!("launching clients");
     let mut clients: Vec<Child> = Vec::new();
     for client_pid in &client_pids {
-        let client = launch_client(
+        let client = launch_client_with_options(
             &bin_dir,
+            client_options,
             &client_pid,
-            &controller_pid_for_clients,
+            &vec![controller_pid_for_clients],
             &input_dir,
             &server_pids,
             &result_dir,
@@ -145,25 +163,90 @@ pub fn bench_common(num_clients: usize, num_servers: usize, workload: &str) {
         result_paths.push(result_file);
     }
     // verify result
-    match read_result(result_paths) {
+    let throughput_mops = match read_result(result_paths) {
         Ok(result) => {
-            print_performance(&result);
+            print_performance(&result)
             // panic!("debug");
         }
         Err(_) => {
             panic!("Fail to read result file");
         }
-    }
+    };
 
     for mut server in servers {
         let _ = server.kill();
     }
+    throughput_mops
 }
 
 #[test]
 fn bench() {
-    bench_common(8, 3, "read_mostly_low_contention");
-    bench_common(8, 3, "read_mostly_high_contention");
-    bench_common(8, 3, "write_mostly_low_contention");
-    bench_common(8, 3, "write_mostly_high_contention");
+    bench_common(8, 3, "read_mostly_low_contention", &[], &[], &[], "read_mostly_low_contention");
+    bench_common(8, 3, "read_mostly_high_contention", &[], &[], &[], "read_mostly_high_contention");
+    bench_common(8, 3, "write_mostly_low_contention", &[], &[], &[], "write_mostly_low_contention");
+    bench_common(8, 3, "write_mostly_high_contention", &[], &[], &[], "write_mostly_high_contention");
+}
+
+// The same workload with more worker threads per server each time, one
+// throughput line per run at the end
+#[test]
+fn bench_workers() {
+    let max_workers = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+    let mut throughputs = Vec::new();
+    let mut num_workers = 1;
+    while num_workers <= max_workers.max(4) {
+        let workers = num_workers.to_string();
+        let run_name = format!("workers_{}", num_workers);
+        let throughput_mops = bench_common(8, 3, "write_mostly_low_contention", &["--workers", &workers], &[], &[], &run_name);
+        throughputs.push((num_workers, throughput_mops));
+        num_workers *= 2;
+    }
+    println!("{} cores", max_workers);
+    for (num_workers, throughput_mops) in throughputs {
+        println!("workers:{}, Throughput(Mops):{}", num_workers, throughput_mops);
+    }
+}
+
+// The same workload with a larger window of requests in flight per client
+// each time, one throughput line per run at the end
+#[test]
+fn bench_window() {
+    let mut throughputs = Vec::new();
+    for window in [1, 2, 4, 8] {
+        let window = window.to_string();
+        let run_name = format!("window_{}", window);
+        let throughput_mops = bench_common(8, 3, "write_mostly_low_contention", &[], &[], &["--window", &window], &run_name);
+        throughputs.push((window, throughput_mops));
+    }
+    for (window, throughput_mops) in throughputs {
+        println!("window:{}, Throughput(Mops):{}", window, throughput_mops);
+    }
+}
+
+// The high contention workloads, all on 10 keys, with every Get going to
+// the primary and then with the reads of hot keys spread over the replicas,
+// one throughput line per run at the end
+#[test]
+fn bench_hot_keys() {
+    let mut throughputs = Vec::new();
+    for workload in ["read_mostly_high_contention", "write_mostly_high_contention"] {
+        let run_name = format!("{}_primary", workload);
+        let throughput_mops = bench_common(8, 3, workload, &[], &[], &[], &run_name);
+        throughputs.push((run_name, throughput_mops));
+
+        let run_name = format!("{}_hot_keys", workload);
+        let throughput_mops = bench_common(
+            8,
+            3,
+            workload,
+            &[],
+            &["--hot-key-rps", "200"],
+            &["--hot-key-reads", "--max-staleness-ms", "1000"],
+            &run_name,
+        );
+        throughputs.push((run_name, throughput_mops));
+    }
+    for (run_name, throughput_mops) in throughputs {
+        println!("{}, Throughput(Mops):{}", run_name, throughput_mops);
+    }
 }
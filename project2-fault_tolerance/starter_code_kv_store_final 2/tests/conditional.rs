@@ -0,0 +1,202 @@
+use kv_store::client::ClientNode;
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{Operation, Pid, ReplicationMode, ShardInfo};
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use kv_store::sim::{SimClock, Simulation};
+use std::collections::HashSet;
+
+const DEADLINE_MS: u64 = 300_000;
+
+// Acks the Replicate every secondary got and returns the answers to clients
+fn ack_all(primary: &mut ServerNode<SimClock>) -> Vec<(Pid, Operation)> {
+    let mut answers = Vec::new();
+    for (dst, operation) in primary.take_messages() {
+        match operation {
+            Operation::Replicate(key, _, version) => primary.step(dst, Operation::ReplicateRes(key, None, version)),
+            answer => answers.push((dst, answer)),
+        }
+    }
+    answers.extend(primary.take_messages());
+    answers
+}
+
+#[test]
+fn test_primary_runs_conditional_writes() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let primary_pid = info.locations[&info.shard_of("a")].primary;
+    let counter_primary = info.locations[&info.shard_of("counter")].primary;
+    let mut primary = ServerNode::new(primary_pid, SimClock::new(), 0, None);
+    primary.step(1, Operation::PutShardInfo(info.clone()));
+    primary.take_messages();
+    let some = |value: &str| Some(value.to_string());
+
+    // a Cas that holds is replicated and answered after the acks
+    primary.step(10, Operation::Cas("a".to_string(), None, some("1"), 0));
+    let sent = primary.take_messages();
+    assert_eq!(sent.len(), 2);
+    assert!(sent.iter().all(|(_, operation)| matches!(operation, Operation::Replicate(key, Some(value), _) if key == "a" && value == "1")));
+    for (dst, operation) in sent {
+        if let Operation::Replicate(key, _, version) = operation {
+            primary.step(dst, Operation::ReplicateRes(key, None, version));
+        }
+    }
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::CasRes(true, None, 0))]));
+
+    // one that does not hold writes nothing and is answered right away
+    primary.step(10, Operation::Cas("a".to_string(), some("2"), None, 1));
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::CasRes(false, Some(value), 1))] if value == "1"));
+    primary.step(10, Operation::PutIfAbsent("a".to_string(), "3".to_string(), 2));
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::PutIfAbsentRes(Some(value), 2))] if value == "1"));
+    assert_eq!(primary.kvs().get("a"), some("1"));
+
+    // a retry gets the first answer back and does not write again
+    primary.step(10, Operation::Cas("a".to_string(), some("1"), some("four"), 3));
+    let answers = ack_all(&mut primary);
+    assert!(matches!(&answers[..], [(10, Operation::CasRes(true, Some(value), 3))] if value == "1"));
+    let version = primary.kvs().version("a");
+    primary.step(10, Operation::Cas("a".to_string(), some("1"), some("four"), 3));
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::CasRes(true, Some(value), 3))] if value == "1"));
+    assert_eq!(primary.kvs().get_versioned("a"), (some("four"), version));
+
+    // increments of a number, not of anything else
+    if counter_primary == primary_pid {
+        primary.step(11, Operation::Incr("counter".to_string(), 5, 0));
+        assert!(matches!(&ack_all(&mut primary)[..], [(11, Operation::IncrRes(None, Some(5), 0))]));
+        primary.step(11, Operation::Incr("counter".to_string(), -7, 1));
+        assert!(matches!(&ack_all(&mut primary)[..], [(11, Operation::IncrRes(Some(old), Some(-2), 1))] if old == "5"));
+    }
+    primary.step(11, Operation::Incr("a".to_string(), 1, 2));
+    assert!(matches!(&primary.take_messages()[..], [(11, Operation::IncrRes(Some(value), None, 2))] if value == "four"));
+    primary.step(11, Operation::Put("n".to_string(), i64::MAX.to_string(), 3));
+    ack_all(&mut primary);
+    primary.step(11, Operation::Incr("n".to_string(), 1, 4));
+    assert!(matches!(&primary.take_messages()[..], [(_, Operation::IncrRes(_, None, 4))]));
+}
+
+// A client that restarts under the pid of an old one numbers its writes
+// anew. The primary does not run a Cas numbered below a later write of the
+// old client to the same key, it answers Stale and the client sends it again
+// numbered above that, along with the requests it has not sent yet.
+#[test]
+fn test_stale_write_is_renumbered() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let primary_pid = info.locations[&info.shard_of("a")].primary;
+    let mut primary = ServerNode::new(primary_pid, SimClock::new(), 0, None);
+    primary.step(1, Operation::PutShardInfo(info.clone()));
+    primary.take_messages();
+    let cas = |seq_no| Operation::Cas("a".to_string(), None, Some("new".to_string()), seq_no);
+
+    primary.step(10, Operation::Put("a".to_string(), "old".to_string(), 5));
+    assert!(matches!(&ack_all(&mut primary)[..], [(10, Operation::PutRes(None, 5))]));
+    primary.step(10, cas(3));
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::Stale(3, 5))]));
+    assert_eq!(primary.kvs().get("a"), Some("old".to_string()));
+
+    let operations = vec![cas(3), Operation::Put("b".to_string(), "v".to_string(), 4)];
+    let mut node = ClientNode::new(10, vec![0], operations, SimClock::new()).with_window(1);
+    node.take_messages();
+    node.step(0, Operation::GetShardInfoRes(info.clone()));
+    assert!(matches!(&node.take_messages()[..], [(dst, Operation::Cas(_, _, _, 3))] if *dst == primary_pid));
+    node.step(primary_pid, Operation::Stale(3, 5));
+    assert!(matches!(&node.take_messages()[..], [(dst, Operation::Cas(_, _, _, 6))] if *dst == primary_pid));
+    // a second copy of the answer renumbers nothing
+    node.step(primary_pid, Operation::Stale(3, 5));
+    assert!(node.take_messages().is_empty());
+    assert_eq!(node.last_seq_no(), 7);
+
+    primary.step(10, cas(6));
+    assert!(matches!(&primary.take_messages()[..], [(10, Operation::CasRes(false, Some(value), 6))] if value == "old"));
+    node.step(primary_pid, Operation::CasRes(false, Some("old".to_string()), 6));
+    assert!(matches!(&node.take_messages()[..], [(_, Operation::Put(key, _, 7))] if key == "b"));
+}
+
+// Every client adds 1 to one counter again and again. An Incr that runs
+// twice, e.g. for a retry after a lost answer, would leave it too high and
+// hand two clients the same number.
+#[test]
+fn test_sim_incr_counts_once() {
+    // requests and answers of two of the clients get lost and are retried
+    let faults = "drop src=5 prob=0.05; drop dst=6 prob=0.05; duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    let modes = [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }, ReplicationMode::Raft];
+    let num_clients = 4;
+    let num_incrs = 25;
+    for seed in 0..3 {
+        for replication in modes {
+            for faults in ["", faults] {
+                let mut sim = Simulation::new(seed);
+                sim.faults_mut().apply(faults).unwrap();
+                sim.add_cluster(vec![], 3, replication);
+                let client_pids: Vec<Pid> = (5..5 + num_clients as Pid).collect();
+                for client_pid in &client_pids {
+                    let operations = (0..num_incrs).map(|seq_no| Operation::Incr("counter".to_string(), 1, seq_no)).collect();
+                    sim.add(Box::new(ClientNode::new(*client_pid, vec![0], operations, sim.clock()).with_window(4)));
+                }
+                assert!(
+                    sim.run_until(DEADLINE_MS, |sim| sim.clients_done()),
+                    "seed {} {:?} faults {:?} did not finish",
+                    seed,
+                    replication,
+                    faults
+                );
+
+                let mut seen = HashSet::new();
+                for client_pid in &client_pids {
+                    for result in sim.results(client_pid) {
+                        assert_eq!(result.operation, "incr");
+                        let new_value: usize = result.new_value.parse().unwrap();
+                        assert!(seen.insert(new_value), "{} handed out twice, seed {} {:?}", new_value, seed, replication);
+                    }
+                }
+                let total = num_clients * num_incrs;
+                assert_eq!(seen, (1..=total).collect(), "seed {} {:?} faults {:?}", seed, replication, faults);
+
+                // every replica went with the primary's count
+                sim.faults_mut().apply("clear").unwrap();
+                let reader_pid = 5 + num_clients as Pid;
+                let reads = vec![Operation::Get("counter".to_string(), 0)];
+                sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock())));
+                assert!(sim.run_until(DEADLINE_MS * 2, |sim| sim.clients_done()));
+                assert_eq!(sim.results(&reader_pid)[0].observed_value, total.to_string());
+            }
+        }
+    }
+}
+
+// A Cas against what a client read last, as a compare-and-set loop would
+#[test]
+fn test_sim_cas_from_two_clients() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new(seed);
+        sim.add_cluster(vec![], 3, ReplicationMode::PrimaryBackup);
+        let operations = |client: usize| -> Vec<Operation> {
+            (0..10)
+                .map(|round| {
+                    let expected = match round {
+                        0 => None,
+                        round => Some(format!("{}", round - 1)),
+                    };
+                    Operation::Cas("lock".to_string(), expected, Some(format!("{}", round)), client * 100 + round)
+                })
+                .collect()
+        };
+        sim.add(Box::new(ClientNode::new(5, vec![0], operations(0), sim.clock())));
+        sim.add(Box::new(ClientNode::new(6, vec![0], operations(1), sim.clock())));
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+
+        // every step from round - 1 to round was taken by one of them at most,
+        // and the steps follow each other
+        let mut results = sim.results(&5);
+        results.extend(sim.results(&6));
+        let mut taken: Vec<String> = results
+            .iter()
+            .filter(|result| result.new_value != result.observed_value)
+            .map(|result| result.new_value.clone())
+            .collect();
+        taken.sort_by_key(|value| value.parse::<usize>().unwrap());
+        assert!(!taken.is_empty());
+        assert_eq!(taken, (0..taken.len()).map(|round| round.to_string()).collect::<Vec<_>>(), "seed {}", seed);
+    }
+}
@@ -0,0 +1,156 @@
+mod utils;
+
+use kv_store::kvs::KVSResult;
+use std::fs;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    group_result_by_key, launch_client_with_options, launch_controller_with_options,
+    launch_server_with_options, pid_list_arg, read_result,
+};
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Runs a controller group of num_controllers replicas and kills the first
+// num_killed of them before the clients start, so every client has to fail
+// over from the dead replicas at the head of its controller list.
+fn test_controller_group_common(
+    num_controllers: usize,
+    num_killed: usize,
+    num_clients: usize,
+    num_servers: usize,
+) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "ryw";
+    // number pf worker processes to run
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+    let raft_dir = "./data/raft/test_controllers";
+    let _ = fs::remove_dir_all(raft_dir);
+
+    // clients and servers keep the usual pids, the first controller replica
+    // too (0 for clients and 1 for servers), the other replicas come after
+    // the servers with the same two pids each
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+    let first_replica_pid = num_clients + num_servers + 2;
+    let mut controller_pids_for_clients: Vec<usize> = vec![0];
+    let mut controller_pids_for_servers: Vec<usize> = vec![1];
+    for i in 1..num_controllers {
+        controller_pids_for_clients.push(first_replica_pid + 2 * (i - 1));
+        controller_pids_for_servers.push(first_replica_pid + 2 * (i - 1) + 1);
+    }
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            &bin_dir,
+            &[],
+            &server_pid,
+            &controller_pids_for_servers,
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch the controller group.
+    println!("launching controllers");
+    let peers = pid_list_arg(&controller_pids_for_clients);
+    let mut controllers: Vec<Child> = Vec::new();
+    for i in 0..num_controllers {
+        let controller = launch_controller_with_options(
+            &bin_dir,
+            &["--peers", &peers, "--raft-dir", raft_dir],
+            &controller_pids_for_clients[i],
+            &controller_pids_for_servers[i],
+            &num_clients,
+            &num_servers,
+            &client_pids,
+            &server_pids,
+        )
+        .expect("Failed to launch controller");
+        controllers.push(controller);
+    }
+
+    // Let the group agree on the shard assignment, then take down a minority
+    sleep(Duration::from_secs(3));
+    for controller in controllers.iter_mut().take(num_killed) {
+        controller.kill().expect("Failed to kill controller");
+    }
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            &bin_dir,
+            &[],
+            &client_pid,
+            &controller_pids_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    // Sleep for enough time to let the system run
+    sleep(Duration::from_secs(15));
+
+    // Clean up (kill all remaining processes)
+    for mut controller in controllers {
+        let _ = controller.kill();
+    }
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid); // Combine the prefix with the index
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    let _ = fs::remove_dir_all(raft_dir);
+}
+
+#[test]
+fn test_controller_group() {
+    test_controller_group_common(3, 1, 2, 3);
+    test_controller_group_common(5, 2, 2, 3);
+}
@@ -0,0 +1,325 @@
+mod utils;
+
+use kv_store::faults::{FaultInjector, Verdict};
+use kv_store::kvs::{KVSResult, Operation, Pid};
+use kv_store::network::{create_network_context, recv, send, NetworkContext};
+use std::fs;
+use std::process::Child;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use utils::{
+    group_result_by_key, isolate_ports, launch_client_with_options, launch_controller_with_options,
+    launch_server_with_options, read_result, send_fault_control,
+};
+
+#[test]
+fn test_fault_config() {
+    let mut faults = FaultInjector::parse(
+        "seed 3\n\
+         # only Puts from 2 to 4 are lost\n\
+         drop src=2 dst=4 op=Put\n\
+         delay ms=250 op=Get; duplicate dst=5\n\
+         reorder prob=0 # never\n",
+    )
+    .unwrap();
+    assert_eq!(faults.decide(2, 4, "Put"), Verdict::Drop);
+    assert_eq!(faults.decide(3, 4, "Put"), Verdict::Deliver);
+    assert_eq!(faults.decide(2, 4, "Get"), Verdict::Delay(250));
+    assert_eq!(faults.decide(2, 5, "Delete"), Verdict::Duplicate);
+    assert_eq!(faults.decide(2, 6, "Delete"), Verdict::Deliver);
+
+    for bad in ["drop prob=2", "drop ms=10", "delay op=Get", "partition 1,2", "explode", "seed x"] {
+        assert!(FaultInjector::parse(bad).is_err(), "{} parsed", bad);
+    }
+    // a bad command changes nothing, not even the lines before it
+    assert!(faults.apply("clear\nbogus").is_err());
+    assert_eq!(faults.decide(2, 4, "Put"), Verdict::Drop);
+
+    faults.apply("clear").unwrap();
+    assert!(faults.is_empty());
+}
+
+#[test]
+fn test_partitions() {
+    let mut faults = FaultInjector::parse("partition 1,2 3").unwrap();
+    assert!(faults.is_partitioned(1, 3));
+    assert!(faults.is_partitioned(3, 2));
+    assert!(!faults.is_partitioned(1, 2));
+    assert!(!faults.is_partitioned(3, 4));
+    assert_eq!(faults.decide(2, 3, "Get"), Verdict::Drop);
+
+    faults.apply("heal").unwrap();
+    assert!(!faults.is_partitioned(1, 3));
+    assert_eq!(faults.decide(2, 3, "Get"), Verdict::Deliver);
+}
+
+#[test]
+fn test_faults_replay_with_the_same_seed() {
+    let verdicts = |config: &str| {
+        let mut faults = FaultInjector::parse(config).unwrap();
+        (0..200).map(|i| faults.decide(2, 3 + i % 3, "Put")).collect::<Vec<Verdict>>()
+    };
+    let config = "seed 7; drop prob=0.3; duplicate prob=0.3 dst=4; delay ms=5 prob=0.5";
+    assert_eq!(verdicts(config), verdicts(config));
+    assert_ne!(verdicts(config), verdicts("seed 8; drop prob=0.3; duplicate prob=0.3 dst=4; delay ms=5 prob=0.5"));
+}
+
+// Keeps calling recv on ctx for ms milliseconds in the background, which is
+// what releases its delayed messages. Returns what it received.
+fn pump(mut ctx: NetworkContext, ms: u64) -> thread::JoinHandle<Vec<(Pid, Operation)>> {
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while start.elapsed() < Duration::from_millis(ms) {
+            if let Ok(messages) = recv(&mut ctx, 20) {
+                received.extend(messages);
+            }
+        }
+        received
+    })
+}
+
+// Receives until count operations arrived or the timeout passed.
+fn recv_n(ctx: &mut NetworkContext, count: usize, timeout_ms: u64) -> Vec<(Pid, Operation)> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < Duration::from_millis(timeout_ms) {
+        if let Ok(messages) = recv(ctx, 50) {
+            received.extend(messages);
+        }
+    }
+    received
+}
+
+fn seq_nos(received: &[(Pid, Operation)]) -> Vec<usize> {
+    received
+        .iter()
+        .map(|(_, operation)| match operation {
+            Operation::Get(_, seq_no) | Operation::Put(_, _, seq_no) | Operation::Delete(_, seq_no) => *seq_no,
+            op => panic!("unexpected operation {:?}", op),
+        })
+        .collect()
+}
+
+// Every case runs a sender with its own injector against a receiver without
+// faults, so the cases do not share the process-wide injector.
+fn contexts(sender_pid: Pid, receiver_pid: Pid, config: &str) -> (NetworkContext, NetworkContext) {
+    let mut sender = create_network_context(&sender_pid).unwrap();
+    sender.set_faults(FaultInjector::parse(config).unwrap());
+    let mut receiver = create_network_context(&receiver_pid).unwrap();
+    receiver.set_faults(FaultInjector::new(0));
+    (sender, receiver)
+}
+
+#[test]
+fn test_drop_by_operation() {
+    let (mut sender, mut receiver) = contexts(230, 231, "drop op=Get");
+    send(&mut sender, &231, &Operation::Get("key".to_string(), 1)).unwrap();
+    send(&mut sender, &231, &Operation::Put("key".to_string(), "v".to_string(), 2)).unwrap();
+    let received = recv_n(&mut receiver, 2, 1000);
+    assert_eq!(seq_nos(&received), vec![2]);
+}
+
+#[test]
+fn test_delay() {
+    let (mut sender, mut receiver) = contexts(232, 233, "delay ms=400");
+    let sender_pump = pump(sender.clone(), 1500);
+    send(&mut sender, &233, &Operation::Get("key".to_string(), 1)).unwrap();
+    assert!(recv_n(&mut receiver, 1, 250).is_empty());
+    assert_eq!(seq_nos(&recv_n(&mut receiver, 1, 1000)), vec![1]);
+    sender_pump.join().unwrap();
+}
+
+#[test]
+fn test_duplicate() {
+    let (mut sender, mut receiver) = contexts(234, 235, "duplicate");
+    send(&mut sender, &235, &Operation::Delete("key".to_string(), 1)).unwrap();
+    assert_eq!(seq_nos(&recv_n(&mut receiver, 3, 1000)), vec![1, 1]);
+}
+
+#[test]
+fn test_reorder() {
+    let (mut sender, mut receiver) = contexts(236, 237, "reorder op=Get");
+    send(&mut sender, &237, &Operation::Get("key".to_string(), 1)).unwrap();
+    send(&mut sender, &237, &Operation::Put("key".to_string(), "v".to_string(), 2)).unwrap();
+    assert_eq!(seq_nos(&recv_n(&mut receiver, 2, 1000)), vec![2, 1]);
+}
+
+#[test]
+fn test_partition_by_control_message() {
+    let (mut sender, mut receiver) = contexts(238, 239, "");
+    receiver.set_allow_fault_control(true);
+    let mut tester = create_network_context(&240).unwrap();
+    tester.set_faults(FaultInjector::new(0));
+    let tester_pump = pump(tester.clone(), 3000);
+
+    // only the receiver learns about the partition, it still cuts both ways
+    send(&mut tester, &239, &Operation::FaultControl("partition 238 239".to_string())).unwrap();
+    // recv applies control messages and hands nothing back for them
+    assert!(recv_n(&mut receiver, 1, 300).is_empty());
+    send(&mut sender, &239, &Operation::Get("key".to_string(), 1)).unwrap();
+    assert!(recv_n(&mut receiver, 1, 500).is_empty());
+
+    send(&mut tester, &239, &Operation::FaultControl("heal".to_string())).unwrap();
+    assert!(recv_n(&mut receiver, 1, 300).is_empty());
+    send(&mut sender, &239, &Operation::Get("key".to_string(), 2)).unwrap();
+    assert_eq!(seq_nos(&recv_n(&mut receiver, 1, 1000)), vec![2]);
+    tester_pump.join().unwrap();
+}
+
+#[test]
+fn test_control_message_needs_allow_fault_control() {
+    let (mut sender, mut receiver) = contexts(241, 242, "");
+    let mut tester = create_network_context(&243).unwrap();
+    tester.set_faults(FaultInjector::new(0));
+    let tester_pump = pump(tester.clone(), 2000);
+
+    // the receiver was not allowed to take fault control, so it drops the
+    // partition and keeps receiving from the sender
+    send(&mut tester, &242, &Operation::FaultControl("partition 241 242".to_string())).unwrap();
+    assert!(recv_n(&mut receiver, 1, 300).is_empty());
+    send(&mut sender, &242, &Operation::Get("key".to_string(), 1)).unwrap();
+    assert_eq!(seq_nos(&recv_n(&mut receiver, 1, 1000)), vec![1]);
+    tester_pump.join().unwrap();
+}
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// The read your write workload on its own port block and result files (named
+// after name), every process started with the fault config in faults. After
+// partition_after, the first server is cut off the controller, which has to
+// fail its shards over. Runs for run_secs and checks the results.
+fn run_read_your_write_with_faults(
+    name: &str,
+    faults: &str,
+    partition_after: Option<Duration>,
+    run_secs: u64,
+    num_clients: usize,
+    num_servers: usize,
+) {
+    isolate_ports();
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = format!("./data/result/ryw_{}", name);
+    let faults_file = std::env::temp_dir().join(format!("kv_store_faults_{}_{}.txt", name, std::process::id()));
+    fs::write(&faults_file, faults).unwrap();
+    let faults_path = faults_file.to_str().unwrap().to_string();
+    let options = ["--faults", faults_path.as_str(), "--allow-fault-control"];
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            bin_dir,
+            &options,
+            server_pid,
+            &vec![controller_pid_for_servers],
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    let mut controller = launch_controller_with_options(
+        bin_dir,
+        &options,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            &options,
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    let mut elapsed = Duration::from_secs(0);
+    if let Some(after) = partition_after {
+        sleep(after);
+        println!("partitioning server {} from the controller", server_pids[0]);
+        let commands = format!("partition {} {}", server_pids[0], controller_pid_for_servers);
+        send_fault_control(&vec![server_pids[0] as Pid], &commands);
+        elapsed = after;
+    }
+    sleep(Duration::from_secs(run_secs) - elapsed);
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    let _ = fs::remove_file(&faults_file);
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        result_paths.push(format!("{}{}.txt", result_dir, &client_pid));
+    }
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
+
+#[test]
+fn test_read_your_write_with_faults() {
+    // duplicated and late messages must not let a client read an old value
+    run_read_your_write_with_faults(
+        "flaky",
+        "seed 11; duplicate prob=0.02; delay ms=20 prob=0.005; reorder prob=0.005",
+        None,
+        30,
+        2,
+        3,
+    );
+}
+
+#[test]
+fn test_read_your_write_across_partition() {
+    // the controller loses the primary of shard 0 but clients still reach it;
+    // it must stop serving reads before a secondary takes over
+    run_read_your_write_with_faults("partition", "seed 12", Some(Duration::from_secs(2)), 60, 1, 3);
+}
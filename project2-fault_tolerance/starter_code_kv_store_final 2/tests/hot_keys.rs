@@ -0,0 +1,188 @@
+use kv_store::client::{ClientNode, ReadPreference};
+use kv_store::controller::{assign_shards_to_servers, ControllerNode, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR};
+use kv_store::hotkeys::{pick_hot_keys, SpaceSaving, MAX_HOT_KEYS};
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardInfo};
+use kv_store::lease::Clock;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use kv_store::sim::Simulation;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+const DEADLINE_MS: u64 = 300_000;
+
+// A clock the test moves by hand
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+#[test]
+fn test_space_saving_keeps_the_heavy_keys() {
+    let mut counter = SpaceSaving::new(8);
+    let mut rng = StdRng::seed_from_u64(0);
+    for i in 0..10_000 {
+        // a third of the requests go to "a", a sixth to "b", the rest to
+        // keys seen a few times each
+        match i % 6 {
+            0 | 1 => counter.record("a"),
+            2 => counter.record("b"),
+            _ => counter.record(&format!("k{}", rng.gen_range(0..1000))),
+        }
+    }
+    let top = counter.top(2);
+    assert_eq!(top.iter().map(|(key, _)| key.as_str()).collect::<Vec<&str>>(), vec!["a", "b"]);
+    // a count is never below the key's requests
+    assert!(top[0].1 >= 10_000 / 3 && top[1].1 >= 10_000 / 6, "{:?}", top);
+
+    counter.clear();
+    assert!(counter.top(8).is_empty());
+}
+
+#[test]
+fn test_hot_keys_stay_hot_down_to_half_the_threshold() {
+    let rates: BTreeMap<String, u64> =
+        [("a", 250), ("b", 100), ("c", 60), ("d", 40)].iter().map(|(key, rate)| (key.to_string(), *rate)).collect();
+    assert_eq!(pick_hot_keys(&[], &rates, 100), vec!["a", "b"]);
+    // c was hot and is still above 50, d is not
+    let current = vec!["c".to_string(), "d".to_string()];
+    assert_eq!(pick_hot_keys(&current, &rates, 100), vec!["a", "b", "c"]);
+    assert!(pick_hot_keys(&current, &BTreeMap::new(), 100).is_empty());
+
+    // only the hottest are published
+    let rates: BTreeMap<String, u64> = (0..100).map(|i| (format!("k{:03}", i), 1000 + i)).collect();
+    let hot = pick_hot_keys(&[], &rates, 100);
+    assert_eq!(hot.len(), MAX_HOT_KEYS);
+    assert_eq!(hot[0], format!("k{:03}", 100 - MAX_HOT_KEYS));
+}
+
+// The HotKeys a node sent to the controller at pid 1
+fn reports(node: &mut ServerNode<ManualClock>) -> Vec<Vec<(String, u64)>> {
+    node.take_messages()
+        .into_iter()
+        .filter_map(|(dst_pid, operation)| match operation {
+            Operation::HotKeys(rates) if dst_pid == 1 => Some(rates),
+            _ => None,
+        })
+        .collect()
+}
+
+#[test]
+fn test_server_reports_hot_keys_with_heartbeats() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4], 4, 1, DEFAULT_VNODES);
+    let mut node = ServerNode::new(4, ManualClock(now.clone()), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    assert!(reports(&mut node).is_empty());
+
+    for seq_no in 0..30 {
+        node.step(10, Operation::Get("hot".to_string(), seq_no));
+    }
+    node.step(11, Operation::Put("cold".to_string(), "v".to_string(), 0));
+    node.step(11, Operation::Get("cold".to_string(), 1));
+
+    // a heartbeat right after the last one waits for a longer window
+    now.set(500);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    assert!(reports(&mut node).is_empty());
+    now.set(2000);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    assert_eq!(reports(&mut node), vec![vec![("hot".to_string(), 15), ("cold".to_string(), 1)]]);
+
+    // counting starts afresh
+    now.set(3000);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    assert_eq!(reports(&mut node), vec![vec![]]);
+}
+
+// Mostly reads of one key every client shares, and some writes and reads of
+// the client's own keys
+fn hot_workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..3));
+            match rng.gen_range(0..10) {
+                0..=7 => Operation::Get("hot".to_string(), seq_no),
+                8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Get(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+fn verify_read_your_write(results: &[KVSResult]) {
+    let mut last_written: HashMap<String, String> = HashMap::new();
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else {
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// A PrimaryBackup cluster of 3 servers (2, 3 and 4) whose controller finds
+// keys hot at hot_key_rps (none with 0), and num_clients clients from pid 5
+// on reading hot keys from every replica
+fn add_cluster_with_hot_keys(sim: &mut Simulation, seed: u64, num_clients: usize, hot_key_rps: u64, num_ops: usize) -> Vec<Pid> {
+    let server_pids: Vec<Pid> = vec![2, 3, 4];
+    for server_pid in &server_pids {
+        let seed = sim.next_seed();
+        sim.add(Box::new(ServerNode::new(*server_pid, sim.clock(), seed, None)));
+    }
+    let mut info = ShardInfo::new();
+    info.replication = ReplicationMode::PrimaryBackup;
+    assign_shards_to_servers(&mut info, &server_pids, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR, DEFAULT_VNODES);
+    let controller_seed = sim.next_seed();
+    let controller = ControllerNode::new(0, 1, server_pids, info, vec![0], sim.clock(), controller_seed, None);
+    sim.add(Box::new(controller.with_hot_key_threshold(hot_key_rps)));
+
+    let client_pids: Vec<Pid> = (5..5 + num_clients as Pid).collect();
+    for (client, client_pid) in client_pids.iter().enumerate() {
+        let operations = hot_workload(seed, client, num_ops);
+        let client = ClientNode::new(*client_pid, vec![0], operations, sim.clock())
+            .with_read_preference(ReadPreference::Primary, Some(500))
+            .with_hot_key_reads(true);
+        sim.add(Box::new(client));
+    }
+    client_pids
+}
+
+#[test]
+fn test_sim_hot_key_reads_spread_over_the_replicas() {
+    for seed in 0..3 {
+        for hot_key_rps in [0, 100] {
+            let mut sim = Simulation::new(seed);
+            let client_pids = add_cluster_with_hot_keys(&mut sim, seed, 4, hot_key_rps, 4000);
+            assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+
+            let mut answered = 0;
+            for client_pid in &client_pids {
+                verify_read_your_write(&sim.results(client_pid));
+                answered += sim.secondary_reads(client_pid).unwrap().0;
+            }
+            let hot_keys = sim.shard_info(&0).unwrap().hot_keys;
+            match hot_key_rps {
+                // without a threshold every read goes to the primary
+                0 => {
+                    assert!(hot_keys.is_empty());
+                    assert_eq!(answered, 0, "seed {}", seed);
+                }
+                _ => {
+                    assert_eq!(hot_keys, vec!["hot".to_string()], "seed {}", seed);
+                    assert!(answered > 1000, "seed {}: secondaries answered {} reads", seed, answered);
+                }
+            }
+        }
+    }
+}
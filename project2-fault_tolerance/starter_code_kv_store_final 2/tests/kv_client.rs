@@ -0,0 +1,205 @@
+mod utils;
+
+use kv_store::client::{KvClient, KvError};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use utils::{isolate_ports, launch_controller, launch_server, test_context};
+
+// Servers 3, 4 and 5 and the controller, for a client with pid 2
+fn launch_cluster(bin_dir: &str) -> (Child, Vec<Child>) {
+    let server_pids: Vec<usize> = vec![3, 4, 5];
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &1, &server_pids).expect("Failed to launch server");
+        servers.push(server);
+    }
+    let controller = launch_controller(bin_dir, &0, &1, &1, &server_pids.len(), &vec![2], &server_pids)
+        .expect("Failed to launch controller");
+    (controller, servers)
+}
+
+#[test]
+fn test_kv_client_calls() {
+    isolate_ports();
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    let mut client = KvClient::with_context(test_context(2), vec![0]);
+
+    let mut outcome = vec![
+        client.put("a", "1").map_err(|e| e.to_string()),
+        client.put("a", "2").map_err(|e| e.to_string()),
+        client.get("a").map_err(|e| e.to_string()),
+        client.get("b").map_err(|e| e.to_string()),
+        client.delete("a").map_err(|e| e.to_string()),
+        client.get("a").map_err(|e| e.to_string()),
+    ];
+    for key in 0..20 {
+        let key = format!("k{}", key);
+        outcome.push(client.put(&key, &key).map_err(|e| e.to_string()));
+    }
+    let mut read_back = Vec::new();
+    for key in 0..20 {
+        read_back.push(client.get(&format!("k{}", key)).map_err(|e| e.to_string()));
+    }
+
+    // the servers are gone, the client gives up at its timeout
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    let mut client = client.with_timeout(Some(2000));
+    let start = Instant::now();
+    let timed_out = client.get("a");
+    let waited = start.elapsed();
+    controller.kill().expect("Failed to kill controller");
+
+    let some = |value: &str| Ok(Some(value.to_string()));
+    assert_eq!(outcome[..6], [Ok(None), some("1"), some("2"), Ok(None), some("2"), Ok(None)]);
+    assert!(outcome[6..].iter().all(|result| *result == Ok(None)), "{:?}", outcome);
+    for (key, value) in read_back.into_iter().enumerate() {
+        assert_eq!(value, some(&format!("k{}", key)));
+    }
+    assert!(matches!(timed_out, Err(KvError::Timeout)), "{:?}", timed_out);
+    assert!(waited >= Duration::from_millis(2000) && waited < Duration::from_millis(4000), "{:?}", waited);
+}
+
+#[test]
+fn test_kv_client_batches() {
+    isolate_ports();
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    let mut client = KvClient::with_context(test_context(2), vec![0]);
+
+    let keys: Vec<String> = (0..20).map(|key| format!("k{}", key)).collect();
+    let keys: Vec<&str> = keys.iter().map(|key| key.as_str()).collect();
+    let entries: Vec<(&str, &str)> = keys.iter().map(|key| (*key, *key)).collect();
+    let outcome = vec![
+        client.multi_put(&entries).map_err(|e| e.to_string()),
+        client.multi_put(&[("k0", "again"), ("k1", "again")]).map_err(|e| e.to_string()),
+        client.multi_get(&keys).map_err(|e| e.to_string()),
+        client.multi_delete(&["k0", "k2", "absent"]).map_err(|e| e.to_string()),
+        client.multi_get(&["k0", "k1", "k2"]).map_err(|e| e.to_string()),
+        client.multi_get(&[]).map_err(|e| e.to_string()),
+    ];
+    let single = client.get("k3").map_err(|e| e.to_string());
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let some = |value: &str| Some(value.to_string());
+    assert_eq!(outcome[0], Ok(vec![None; 20]));
+    assert_eq!(outcome[1], Ok(vec![some("k0"), some("k1")]));
+    let mut expected: Vec<Option<String>> = keys.iter().map(|key| some(key)).collect();
+    expected[0] = some("again");
+    expected[1] = some("again");
+    assert_eq!(outcome[2], Ok(expected));
+    assert_eq!(outcome[3], Ok(vec![some("again"), some("k2"), None]));
+    assert_eq!(outcome[4], Ok(vec![None, some("again"), None]));
+    assert_eq!(outcome[5], Ok(vec![]));
+    assert_eq!(single, Ok(some("k3")));
+}
+
+#[test]
+fn test_kv_client_conditional_writes() {
+    isolate_ports();
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    let mut client = KvClient::with_context(test_context(2), vec![0]);
+
+    let some = |value: &str| Some(value.to_string());
+    let cas = vec![
+        client.cas("a", None, Some("1")).map_err(|e| e.to_string()),
+        client.cas("a", None, Some("2")).map_err(|e| e.to_string()),
+        client.cas("a", Some("1"), Some("2")).map_err(|e| e.to_string()),
+        client.cas("a", Some("2"), None).map_err(|e| e.to_string()),
+    ];
+    let put_if_absent = vec![
+        client.put_if_absent("b", "1").map_err(|e| e.to_string()),
+        client.put_if_absent("b", "2").map_err(|e| e.to_string()),
+    ];
+    let incr = vec![
+        client.incr("counter", 5).map_err(|e| e.to_string()),
+        client.incr("counter", -7).map_err(|e| e.to_string()),
+        client.incr("b", 1).map_err(|e| e.to_string()),
+        client.put("c", "x").map(|_| None).map_err(|e| e.to_string()),
+        client.incr("c", 1).map_err(|e| e.to_string()),
+    ];
+    let reads = vec![
+        client.get("a").map_err(|e| e.to_string()),
+        client.get("b").map_err(|e| e.to_string()),
+        client.get("counter").map_err(|e| e.to_string()),
+        client.get("c").map_err(|e| e.to_string()),
+    ];
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    assert_eq!(cas, vec![Ok((true, None)), Ok((false, some("1"))), Ok((true, some("1"))), Ok((true, some("2")))]);
+    assert_eq!(put_if_absent, vec![Ok(None), Ok(some("1"))]);
+    assert_eq!(incr, vec![Ok(Some(5)), Ok(Some(-2)), Ok(Some(2)), Ok(None), Ok(None)]);
+    assert_eq!(reads, vec![Ok(None), Ok(some("2")), Ok(some("-2")), Ok(some("x"))]);
+}
+
+#[test]
+fn test_kv_client_transactions() {
+    isolate_ports();
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    let mut client = KvClient::with_context(test_context(2), vec![0]);
+
+    // moves 30 from one key to another, the keys are on different shards
+    // for most assignments
+    let transfer = |client: &mut KvClient, amount: i64| -> Result<bool, KvError> {
+        let mut txn = client.begin();
+        let from: i64 = txn.get("alice")?.unwrap_or_default().parse().unwrap_or(0);
+        let to: i64 = txn.get("bob")?.unwrap_or_default().parse().unwrap_or(0);
+        txn.put("alice", &(from - amount).to_string());
+        txn.put("bob", &(to + amount).to_string());
+        txn.commit()
+    };
+    let setup = client.multi_put(&[("alice", "100"), ("bob", "0")]).map_err(|e| e.to_string());
+    let first = transfer(&mut client, 30).map_err(|e| e.to_string());
+
+    // a value read that changes before the commit aborts it
+    let mut txn = client.begin();
+    let seen = txn.get("alice").map_err(|e| e.to_string());
+    txn.put("bob", "stolen");
+    txn.delete("alice");
+    let own_write = txn.get("alice").map_err(|e| e.to_string());
+    let committed = {
+        let mut other = KvClient::with_context(test_context(6), vec![0]);
+        other.put("alice", "50").map_err(|e| e.to_string()).unwrap();
+        txn.commit().map_err(|e| e.to_string())
+    };
+    let empty = client.begin().commit().map_err(|e| e.to_string());
+    let reads = client.multi_get(&["alice", "bob"]).map_err(|e| e.to_string());
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let some = |value: &str| Some(value.to_string());
+    assert_eq!(setup, Ok(vec![None, None]));
+    assert_eq!(first, Ok(true));
+    assert_eq!(seen, Ok(some("70")));
+    assert_eq!(own_write, Ok(None));
+    assert_eq!(committed, Ok(false));
+    assert_eq!(empty, Ok(true));
+    assert_eq!(reads, Ok(vec![some("50"), some("30")]));
+}
+
+#[test]
+fn test_kv_client_without_controller() {
+    isolate_ports();
+    // nobody listens on 0
+    let mut client = KvClient::with_context(test_context(2), vec![0]);
+    assert!(matches!(client.get("a"), Err(KvError::NoShardInfo)));
+
+    // a controller that shows up later answers the next call
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    sleep(Duration::from_secs(1));
+    let result = client.put("a", "1");
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    assert!(matches!(result, Ok(None)), "{:?}", result);
+}
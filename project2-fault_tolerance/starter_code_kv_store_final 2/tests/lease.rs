@@ -0,0 +1,303 @@
+mod utils;
+
+use kv_store::controller::{assign_shards_to_servers, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR};
+use kv_store::kvs::{KVSResult, Operation, ReplicationMode, ShardInfo};
+use kv_store::lease::{Clock, Lease, LeaseTable, CLOCK_SKEW_MARGIN_MS, LEASE_MS};
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use std::cell::Cell;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{group_result_by_key, launch_client, launch_controller, launch_server, read_result};
+
+// A clock the test moves by hand. `rate_permille` lets one side of the lease
+// run faster or slower than the shared real time.
+#[derive(Clone)]
+struct ManualClock {
+    real_ms: Rc<Cell<u64>>,
+    offset_ms: u64,
+    rate_permille: u64,
+}
+
+impl ManualClock {
+    fn new(real_ms: &Rc<Cell<u64>>) -> Self {
+        ManualClock::skewed(real_ms, 0, 1000)
+    }
+
+    fn skewed(real_ms: &Rc<Cell<u64>>, offset_ms: u64, rate_permille: u64) -> Self {
+        ManualClock {
+            real_ms: Rc::clone(real_ms),
+            offset_ms,
+            rate_permille,
+        }
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.offset_ms + self.real_ms.get() * self.rate_permille / 1000
+    }
+}
+
+#[test]
+fn test_lease_expires_before_its_duration() {
+    let now = Rc::new(Cell::new(0));
+    let mut lease = Lease::new(ManualClock::new(&now));
+    assert!(!lease.is_valid());
+
+    lease.grant(LEASE_MS);
+    assert!(lease.is_valid());
+    now.set(LEASE_MS - CLOCK_SKEW_MARGIN_MS - 1);
+    assert!(lease.is_valid());
+    now.set(LEASE_MS - CLOCK_SKEW_MARGIN_MS);
+    assert!(!lease.is_valid());
+}
+
+#[test]
+fn test_lease_renewal_never_shortens() {
+    let now = Rc::new(Cell::new(0));
+    let mut lease = Lease::new(ManualClock::new(&now));
+    lease.grant(LEASE_MS);
+
+    now.set(LEASE_MS / 2);
+    lease.grant(LEASE_MS);
+    now.set(LEASE_MS);
+    assert!(lease.is_valid());
+
+    // a shorter grant arriving later keeps the longer one
+    lease.grant(CLOCK_SKEW_MARGIN_MS + 1);
+    now.set(LEASE_MS + LEASE_MS / 2 - CLOCK_SKEW_MARGIN_MS - 1);
+    assert!(lease.is_valid());
+}
+
+#[test]
+fn test_lease_table_waits_out_the_margin() {
+    let now = Rc::new(Cell::new(0));
+    let mut leases = LeaseTable::new(ManualClock::new(&now));
+    assert!(leases.expired(3));
+
+    leases.grant(3, LEASE_MS);
+    now.set(LEASE_MS + CLOCK_SKEW_MARGIN_MS - 1);
+    assert!(!leases.expired(3));
+    assert!(leases.expired(4));
+    now.set(LEASE_MS + CLOCK_SKEW_MARGIN_MS);
+    assert!(leases.expired(3));
+}
+
+// The controller grants a lease with a heartbeat that takes delay_ms to
+// arrive at a server whose clock runs at a different rate and from a
+// different origin. At no point may the server still serve reads while the
+// controller already thinks the lease is over.
+#[test]
+fn test_lease_never_overlaps_promotion() {
+    for delay_ms in [0, CLOCK_SKEW_MARGIN_MS / 2, CLOCK_SKEW_MARGIN_MS] {
+        for rate_permille in [950, 1000, 1050] {
+            let now = Rc::new(Cell::new(0));
+            let mut leases = LeaseTable::new(ManualClock::new(&now));
+            let mut lease = Lease::new(ManualClock::skewed(&now, 123456, rate_permille));
+
+            // two heartbeats, the second one renews the lease
+            for heartbeat_at in [0, LEASE_MS / 2] {
+                now.set(heartbeat_at);
+                leases.grant(3, LEASE_MS);
+                now.set(heartbeat_at + delay_ms);
+                lease.grant(LEASE_MS);
+            }
+
+            let mut real_ms = LEASE_MS / 2 + delay_ms;
+            while real_ms < 3 * LEASE_MS {
+                now.set(real_ms);
+                assert!(
+                    !(lease.is_valid() && leases.expired(3)),
+                    "overlap at {}ms with delay={}ms rate={}",
+                    real_ms,
+                    delay_ms,
+                    rate_permille
+                );
+                real_ms += 10;
+            }
+        }
+    }
+}
+
+// A primary whose lease ran out must not read even with a quorum: the
+// secondaries it asks may not have seen the writes of the primary that took
+// over yet
+#[test]
+fn test_quorum_reads_need_the_lease() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    info.replication = ReplicationMode::Quorum { write: 2, read: 2 };
+    info.lease_ms = LEASE_MS;
+    assign_shards_to_servers(&mut info, &[4, 5, 6], DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR, DEFAULT_VNODES);
+    let mut keys = (0..).map(|i| format!("key{}", i)).filter(|key| info.locations[&info.shard_of(key)].primary == 4);
+    let (first, second) = (keys.next().unwrap(), keys.next().unwrap());
+    let mut node = ServerNode::new(4, ManualClock::new(&now), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.take_messages();
+
+    node.step(10, Operation::Get(first, 0));
+    assert!(node.take_messages().iter().any(|(_, operation)| matches!(operation, Operation::ReadVersion(..))));
+
+    now.set(LEASE_MS);
+    node.step(10, Operation::Get(second.clone(), 1));
+    node.step(10, Operation::MultiGet(vec![second], 2));
+    assert!(node.take_messages().is_empty());
+}
+
+// Only a heartbeat that keeps the server primary of every shard it leads
+// renews the lease, one from before a failover never does
+#[test]
+fn test_demoted_primary_is_not_renewed() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    info.lease_ms = LEASE_MS;
+    info.epoch = 1;
+    assign_shards_to_servers(&mut info, &[4, 5, 6], DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR, DEFAULT_VNODES);
+    let mut led: Vec<_> = info.locations.iter().filter(|(_, location)| location.primary == 4).map(|(id, _)| *id).collect();
+    led.sort();
+    let (moved, kept) = (led[0], led[1]);
+    let key = (0..).map(|i| format!("key{}", i)).find(|key| info.shard_of(key) == kept).unwrap();
+    let mut failover = info.clone();
+    failover.epoch = 2;
+    let location = failover.locations.get_mut(&moved).unwrap();
+    location.primary = location.secondaries.remove(0);
+    location.secondaries.push(4);
+
+    let mut node = ServerNode::new(4, ManualClock::new(&now), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    now.set(LEASE_MS / 4);
+    node.step(1, Operation::PutShardInfo(failover.clone()));
+    now.set(LEASE_MS / 2);
+    node.step(1, Operation::PutShardInfo(info));
+    node.take_messages();
+
+    now.set(LEASE_MS - CLOCK_SKEW_MARGIN_MS);
+    node.step(10, Operation::Get(key.clone(), 0));
+    assert!(node.take_messages().is_empty());
+
+    // a heartbeat of the new layout renews it
+    node.step(1, Operation::PutShardInfo(failover));
+    node.take_messages();
+    node.step(10, Operation::Get(key, 1));
+    assert!(node.take_messages().iter().any(|(pid, operation)| *pid == 10 && matches!(operation, Operation::GetRes(..))));
+}
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Real processes: kill the primary of shard 0 and let the controller promote
+// one of its secondaries once the lease ran out. Clients must finish without
+// reading stale data.
+fn test_primary_failover_common(num_clients: usize, num_servers: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "ryw";
+    // number pf worker processes to run
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(
+            &bin_dir,
+            &server_pid,
+            &controller_pid_for_servers,
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch controller.
+    println!("launching controllers");
+    let mut controller = launch_controller(
+        &bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(
+            &bin_dir,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(2));
+    println!("killing server {}", server_pids[0]);
+    servers[0].kill().expect("Failed to kill server");
+
+    // Failure detection takes a few heartbeats and the promotion waits out
+    // the lease, so give it longer than the other tests
+    sleep(Duration::from_secs(60));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid); // Combine the prefix with the index
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
+
+#[test]
+fn test_primary_failover() {
+    test_primary_failover_common(1, 3);
+}
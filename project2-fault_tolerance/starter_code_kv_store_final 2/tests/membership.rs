@@ -0,0 +1,227 @@
+mod utils;
+
+use kv_store::kvs::{KVSResult, Operation, Pid};
+use kv_store::membership::Membership;
+use kv_store::network::{recv, send, NetworkContext, TransportKind};
+use std::fs;
+use std::net::SocketAddr;
+use std::process::Child;
+use std::sync::Arc;
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+use utils::{
+    allocate_port_block, group_result_by_key, isolate_ports, launch_client_with_options,
+    launch_controller_with_options, launch_server_with_options, read_result,
+};
+
+fn addr(text: &str) -> SocketAddr {
+    text.parse().unwrap()
+}
+
+#[test]
+fn test_default_membership_is_loopback_8000() {
+    let membership = Membership::loopback(8000);
+    assert_eq!(membership.addr_of(&3), Some(addr("127.0.0.1:8003")));
+    assert_eq!(membership.pid_of(&addr("127.0.0.1:8003")), Some(3));
+    // ports below the base used to underflow
+    assert_eq!(membership.pid_of(&addr("127.0.0.1:7999")), None);
+    assert_eq!(membership.pid_of(&addr("10.0.0.5:8003")), None);
+    assert_eq!(membership.addr_of(&60000), None);
+}
+
+#[test]
+fn test_membership_file() {
+    let membership = Membership::parse(
+        "# a cluster on two hosts\n\
+         base_port 9000\n\
+         \n\
+         3 10.0.0.5:8003\n\
+         4 10.0.0.5:8004\n",
+    )
+    .unwrap();
+    assert_eq!(membership.addr_of(&3), Some(addr("10.0.0.5:8003")));
+    assert_eq!(membership.pid_of(&addr("10.0.0.5:8004")), Some(4));
+    assert_eq!(membership.addr_of(&5), Some(addr("127.0.0.1:9005")));
+    assert_eq!(membership.pid_of(&addr("127.0.0.1:9005")), Some(5));
+    // a listed pid is not also reachable through the base port
+    assert_eq!(membership.pid_of(&addr("127.0.0.1:9003")), None);
+
+    let listed_only = Membership::parse("0 127.0.0.1:7000\n1 127.0.0.1:7001\n").unwrap();
+    assert_eq!(listed_only.addr_of(&2), None);
+    assert_eq!(listed_only.pid_of(&addr("127.0.0.1:7002")), None);
+
+    let mut overridden = Membership::parse("base_port 9000\n").unwrap();
+    overridden.set_base_port(9500);
+    assert_eq!(overridden.addr_of(&1), Some(addr("127.0.0.1:9501")));
+
+    assert!(Membership::parse("3\n").is_err());
+    assert!(Membership::parse("x 127.0.0.1:8003\n").is_err());
+    assert!(Membership::parse("3 somewhere\n").is_err());
+    assert!(Membership::parse("3 127.0.0.1:8003\n3 127.0.0.1:8004\n").is_err());
+    assert!(Membership::parse("3 127.0.0.1:8003\n4 127.0.0.1:8003\n").is_err());
+}
+
+// Receives until count operations arrived or the timeout passed.
+fn recv_n(ctx: &mut NetworkContext, count: usize, timeout_ms: u64) -> Vec<(Pid, Operation)> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < Duration::from_millis(timeout_ms) {
+        if let Ok(messages) = recv(ctx, 100) {
+            received.extend(messages);
+        }
+    }
+    received
+}
+
+// receiver_pid only knows sender_pid; stranger_pid sits where the default
+// membership would put it and must not get through.
+fn check_unknown_sender_rejected(kind: TransportKind, receiver_pid: Pid, sender_pid: Pid, stranger_pid: Pid) {
+    let members = Arc::new(
+        Membership::parse(&format!(
+            "{} 127.0.0.1:{}\n{} 127.0.0.1:{}\n",
+            receiver_pid,
+            8000 + receiver_pid,
+            sender_pid,
+            8000 + sender_pid
+        ))
+        .unwrap(),
+    );
+    let mut receiver = NetworkContext::with_membership(&receiver_pid, kind, Arc::clone(&members)).unwrap();
+    let mut sender = NetworkContext::with_membership(&sender_pid, kind, members).unwrap();
+    let mut stranger = NetworkContext::new(&stranger_pid, kind).unwrap();
+
+    // nobody outside the file can be addressed either
+    assert!(send(&mut sender, &stranger_pid, &Operation::GetShardInfo()).is_err());
+
+    let _ = send(&mut stranger, &receiver_pid, &Operation::Get("key".to_string(), 1));
+    send(&mut sender, &receiver_pid, &Operation::Get("key".to_string(), 2)).unwrap();
+
+    let received = recv_n(&mut receiver, 2, 1000);
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], (pid, Operation::Get(_, 2)) if pid == sender_pid));
+}
+
+#[test]
+fn test_unknown_sender_rejected() {
+    check_unknown_sender_rejected(TransportKind::Udp, 220, 221, 222);
+    check_unknown_sender_rejected(TransportKind::Tcp, 223, 224, 225);
+}
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// The read your write workload on the usual pids, started with the given
+// flags. result_dir keeps the result files of side by side clusters apart.
+fn run_read_your_write(options: &[&str], result_dir: &str, num_clients: usize, num_servers: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            bin_dir,
+            options,
+            server_pid,
+            &vec![controller_pid_for_servers],
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    let mut controller = launch_controller_with_options(
+        bin_dir,
+        options,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            options,
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    // Sleep for enough time to let the system run
+    sleep(Duration::from_secs(10));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        result_paths.push(format!("{}{}.txt", result_dir, &client_pid));
+    }
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
+
+// Two clusters with the same pids at once: one moved by the test harness
+// with --base-port, one described by a membership file.
+#[test]
+fn test_clusters_side_by_side() {
+    let base_port = allocate_port_block();
+    let members_file = std::env::temp_dir().join(format!("kv_store_members_{}.txt", base_port));
+    fs::write(&members_file, format!("base_port {}\n", base_port)).unwrap();
+    let members_path = members_file.to_str().unwrap().to_string();
+
+    let isolated = thread::spawn(|| {
+        isolate_ports();
+        run_read_your_write(&[], "./data/result/ryw_isolated", 2, 3);
+    });
+    let from_file = thread::spawn(move || {
+        run_read_your_write(&["--members", &members_path], "./data/result/ryw_members", 2, 3);
+    });
+    isolated.join().unwrap();
+    from_file.join().unwrap();
+    let _ = fs::remove_file(&members_file);
+}
@@ -0,0 +1,140 @@
+use kv_store::kvs::{Operation, Pid, ShardInfo};
+use kv_store::lease::{CLOCK_SKEW_MARGIN_MS, LEASE_MS};
+use kv_store::network::{create_network_context, recv, send, NetworkContext};
+use std::thread::{self, sleep};
+use std::time::{Duration, Instant};
+
+// Keeps calling recv on ctx for ms milliseconds in the background, which is
+// what processes acks and retransmits for it. Returns what it received.
+fn pump(mut ctx: NetworkContext, ms: u64) -> thread::JoinHandle<Vec<(Pid, Operation)>> {
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut received = Vec::new();
+        while start.elapsed() < Duration::from_millis(ms) {
+            if let Ok(messages) = recv(&mut ctx, 50) {
+                received.extend(messages);
+            }
+        }
+        received
+    })
+}
+
+// Receives until count operations arrived or the timeout passed.
+fn recv_n(ctx: &mut NetworkContext, count: usize, timeout_ms: u64) -> Vec<(Pid, Operation)> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < Duration::from_millis(timeout_ms) {
+        if let Ok(messages) = recv(ctx, 100) {
+            received.extend(messages);
+        }
+    }
+    received
+}
+
+#[test]
+fn test_large_message_is_fragmented() {
+    let mut sender = create_network_context(&200).unwrap();
+    let mut receiver = create_network_context(&201).unwrap();
+    let sender_pump = pump(sender.clone(), 3000);
+
+    let value = "v".repeat(1_000_000);
+    send(&mut sender, &201, &Operation::Put("key".to_string(), value.clone(), 7)).unwrap();
+
+    let received = recv_n(&mut receiver, 1, 3000);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].0, 200);
+    match &received[0].1 {
+        Operation::Put(key, received_value, seq_no) => {
+            assert_eq!(key, "key");
+            assert!(*received_value == value);
+            assert_eq!(*seq_no, 7);
+        }
+        op => panic!("unexpected operation {:?}", op),
+    }
+    sender_pump.join().unwrap();
+}
+
+#[test]
+fn test_messages_arrive_in_order_once() {
+    let mut sender = create_network_context(&202).unwrap();
+    let mut receiver = create_network_context(&203).unwrap();
+    let sender_pump = pump(sender.clone(), 5000);
+
+    for seq_no in 0..2000 {
+        send(&mut sender, &203, &Operation::Get(format!("key_{}", seq_no), seq_no)).unwrap();
+    }
+
+    let received = recv_n(&mut receiver, 2000, 5000);
+    assert_eq!(received.len(), 2000);
+    for (expected, (src_pid, operation)) in received.iter().enumerate() {
+        assert_eq!(*src_pid, 202);
+        match operation {
+            Operation::Get(_, seq_no) => assert_eq!(*seq_no, expected),
+            op => panic!("unexpected operation {:?}", op),
+        }
+    }
+
+    // retransmissions of acked messages must not show up again
+    assert!(recv(&mut receiver, 500).is_err());
+    sender_pump.join().unwrap();
+}
+
+#[test]
+fn test_retransmits_until_peer_is_up() {
+    let mut sender = create_network_context(&204).unwrap();
+    let sender_pump = pump(sender.clone(), 3000);
+
+    // nobody listens on 205 yet, the first transmission is lost
+    send(&mut sender, &205, &Operation::GetShardInfo()).unwrap();
+    sleep(Duration::from_millis(300));
+
+    let mut receiver = create_network_context(&205).unwrap();
+    let received = recv_n(&mut receiver, 1, 3000);
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], (204, Operation::GetShardInfo())));
+    sender_pump.join().unwrap();
+}
+
+#[test]
+fn test_restarted_sender_starts_a_new_stream() {
+    let mut receiver = create_network_context(&207).unwrap();
+
+    {
+        let mut sender = create_network_context(&206).unwrap();
+        let sender_pump = pump(sender.clone(), 1000);
+        for seq_no in 0..10 {
+            send(&mut sender, &207, &Operation::Delete("key".to_string(), seq_no)).unwrap();
+        }
+        assert_eq!(recv_n(&mut receiver, 10, 1000).len(), 10);
+        sender_pump.join().unwrap();
+    }
+
+    // same pid, new process: its ids start over and must not be taken as duplicates
+    let mut sender = create_network_context(&206).unwrap();
+    let sender_pump = pump(sender.clone(), 1000);
+    send(&mut sender, &207, &Operation::Delete("key".to_string(), 42)).unwrap();
+    let received = recv_n(&mut receiver, 1, 1000);
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], (206, Operation::Delete(_, 42))));
+    sender_pump.join().unwrap();
+}
+
+// A heartbeat granting a lease is not retransmitted past the clock skew
+// margin, unlike every other message
+#[test]
+fn test_lease_heartbeat_expires_instead_of_arriving_late() {
+    let mut sender = create_network_context(&208).unwrap();
+    let sender_pump = pump(sender.clone(), 4000);
+
+    let mut info = ShardInfo::new();
+    info.lease_ms = LEASE_MS;
+    send(&mut sender, &209, &Operation::PutShardInfo(info)).unwrap();
+    send(&mut sender, &209, &Operation::GetShardInfo()).unwrap();
+    sleep(Duration::from_millis(CLOCK_SKEW_MARGIN_MS + 300));
+
+    let mut receiver = create_network_context(&209).unwrap();
+    let received = recv_n(&mut receiver, 2, 3000);
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], (208, Operation::GetShardInfo())));
+    sender_pump.join().unwrap();
+}
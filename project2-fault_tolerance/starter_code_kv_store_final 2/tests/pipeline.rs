@@ -0,0 +1,299 @@
+mod utils;
+
+use kv_store::client::{ClientNode, REQUEST_TIMEOUT_MS};
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardInfo};
+use kv_store::lease::Clock;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::sim::Simulation;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    group_result_by_key, isolate_ports, launch_client_with_options, launch_controller, launch_server, read_result,
+};
+
+const DEADLINE_MS: u64 = 300_000;
+
+// A clock the test moves by hand
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+// (server, seq_no) of every request the node sent
+fn sent(node: &mut ClientNode<ManualClock>) -> Vec<(Pid, usize)> {
+    node.take_messages()
+        .into_iter()
+        .map(|(dst, operation)| match operation {
+            Operation::Get(_, seq_no) | Operation::Put(_, _, seq_no) | Operation::Delete(_, seq_no) => (dst, seq_no),
+            other => panic!("unexpected request {:?}", other),
+        })
+        .collect()
+}
+
+fn put(key: &str, seq_no: usize) -> Operation {
+    Operation::Put(key.to_string(), format!("v{}", seq_no), seq_no)
+}
+
+fn get(key: &str, seq_no: usize) -> Operation {
+    Operation::Get(key.to_string(), seq_no)
+}
+
+#[test]
+fn test_responses_matched_by_seq_no() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let operations = vec![put("a", 0), put("b", 1), get("a", 2), get("c", 3), get("d", 4)];
+    let mut node = ClientNode::new(2, vec![0], operations, ManualClock(now.clone())).with_window(3);
+    assert!(matches!(node.take_messages().as_slice(), [(0, Operation::GetShardInfo())]));
+
+    node.step(0, Operation::GetShardInfoRes(info.clone()));
+    // the Get of a waits for the Put of a
+    let primary = |key: &str| info.locations[&info.shard_of(key)].primary;
+    assert_eq!(sent(&mut node), vec![(primary("a"), 0), (primary("b"), 1), (primary("c"), 3)]);
+
+    // answers in any order, a duplicate and one for a seq_no never sent
+    node.step(primary("c"), Operation::GetRes(None, 3));
+    assert_eq!(sent(&mut node), vec![(primary("d"), 4)]);
+    node.step(primary("b"), Operation::PutRes(None, 1));
+    node.step(primary("b"), Operation::PutRes(None, 1));
+    node.step(primary("a"), Operation::GetRes(None, 7));
+    assert_eq!(node.in_flight(), 2);
+    assert!(sent(&mut node).is_empty());
+    node.step(primary("a"), Operation::PutRes(None, 0));
+    assert_eq!(sent(&mut node), vec![(primary("a"), 2)]);
+
+    // only the request that timed out goes again, after a fresh ShardInfo
+    now.set(REQUEST_TIMEOUT_MS / 2);
+    node.step(primary("a"), Operation::GetRes(Some("v0".to_string()), 2));
+    now.set(REQUEST_TIMEOUT_MS);
+    node.tick();
+    assert!(matches!(node.take_messages().as_slice(), [(0, Operation::GetShardInfo())]));
+    node.step(0, Operation::GetShardInfoRes(info.clone()));
+    assert_eq!(sent(&mut node), vec![(primary("d"), 4)]);
+    assert!(!node.is_done());
+    node.step(primary("d"), Operation::GetRes(None, 4));
+    assert!(node.is_done());
+
+    let answered: Vec<(String, String)> =
+        node.results().iter().map(|result| (result.operation.clone(), result.key.clone())).collect();
+    let expected = [("get", "c"), ("put", "b"), ("put", "a"), ("get", "a"), ("get", "d")];
+    assert_eq!(answered, expected.map(|(operation, key)| (operation.to_string(), key.to_string())));
+    assert_eq!(node.results()[3].observed_value, "v0");
+}
+
+// num_ops random operations of one client over its own keys
+fn workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..20));
+            match rng.gen_range(0..10) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+// Checks read your write and records the value every key was left with.
+// Results are in the order they were answered, which is the order they were
+// sent for the operations of one key.
+fn verify_read_your_write(results: &[KVSResult], last_written: &mut HashMap<String, String>) {
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            assert!(op_result.operation == "get");
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// Reads every key back with a fresh client once the workload is over
+fn check_no_write_lost(sim: &mut Simulation, client_pids: &[Pid], reader_pid: Pid) {
+    let mut last_written = HashMap::new();
+    for client_pid in client_pids {
+        let results = sim.results(client_pid);
+        assert_eq!(results.len(), 100);
+        verify_read_your_write(&results, &mut last_written);
+    }
+    let mut keys: Vec<String> = last_written.keys().cloned().collect();
+    keys.sort();
+    let reads = keys.iter().enumerate().map(|(seq_no, key)| Operation::Get(key.clone(), seq_no)).collect();
+    sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock()).with_window(8)));
+    assert!(sim.run_until(DEADLINE_MS * 2, |sim| sim.clients_done()));
+    for result in sim.results(&reader_pid) {
+        assert_eq!(result.observed_value, last_written[&result.key], "lost write of {}", result.key);
+    }
+}
+
+// add_cluster with clients of the given window
+fn add_pipelined_cluster(sim: &mut Simulation, seed: u64, window: usize, replication: ReplicationMode) -> Vec<Pid> {
+    let (_, server_pids) = sim.add_cluster(vec![], 3, replication);
+    let client_pids: Vec<Pid> = (5..9).collect();
+    for (client, client_pid) in client_pids.iter().enumerate() {
+        let operations = workload(seed, client, 100);
+        sim.add(Box::new(ClientNode::new(*client_pid, vec![0], operations, sim.clock()).with_window(window)));
+    }
+    assert_eq!(server_pids, vec![2, 3, 4]);
+    client_pids
+}
+
+#[test]
+fn test_sim_pipelined_clients() {
+    // requests and answers of two of the clients get lost, the clients retry
+    // them on their own
+    let faults = "drop src=5 prob=0.05; drop dst=6 prob=0.05; duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    let modes = [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }, ReplicationMode::Raft];
+    for seed in 0..5 {
+        for replication in modes {
+            for window in [1, 4, 16] {
+                for faults in ["", faults] {
+                    let mut sim = Simulation::new(seed);
+                    sim.faults_mut().apply(faults).unwrap();
+                    let client_pids = add_pipelined_cluster(&mut sim, seed, window, replication);
+                    assert!(
+                        sim.run_until(DEADLINE_MS, |sim| sim.clients_done()),
+                        "seed {} window {} {:?} faults {:?} did not finish",
+                        seed,
+                        window,
+                        replication,
+                        faults
+                    );
+                    check_no_write_lost(&mut sim, &client_pids, 9);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_pipelined_clients_primary_crash() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        let client_pids = add_pipelined_cluster(&mut sim, seed, 8, ReplicationMode::PrimaryBackup);
+        sim.run_for(100 + seed * 10);
+        sim.crash(&2);
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, 9);
+    }
+}
+
+// A window finishes the same workload in less simulated time than one
+// request at a time
+#[test]
+fn test_sim_window_speeds_up_clients() {
+    let mut took_ms = Vec::new();
+    for window in [1, 8] {
+        let mut sim = Simulation::new(0);
+        let client_pids = add_pipelined_cluster(&mut sim, 0, window, ReplicationMode::PrimaryBackup);
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()));
+        // from the first answer on, servers drop requests that come in
+        // before their first ShardInfo
+        let results = client_pids.iter().flat_map(|client_pid| sim.results(client_pid));
+        let first_ms = results.map(|result| (result.end_time / 1_000_000) as u64).min().unwrap();
+        took_ms.push(sim.now_ms() - first_ms);
+    }
+    assert!(took_ms[1] * 3 < took_ms[0], "took {:?}", took_ms);
+}
+
+fn verify_read_your_write_per_key(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_read_your_write_with_window() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_window";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            &["--window", "4"],
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write_per_key(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
@@ -0,0 +1,236 @@
+mod utils;
+
+use kv_store::admission::{BUSY_RETRY_AFTER_MS, CLIENT_QUEUE_CAPACITY, QUEUE_CAPACITY};
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardInfo};
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use kv_store::sim::{SimClock, Simulation};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{get_server_metrics, isolate_ports, launch_client, launch_controller, launch_server};
+
+const DEADLINE_MS: u64 = 300_000;
+
+// Requests for a key with a write in flight wait in arrival order and are
+// served one after the other as the secondary acks
+#[test]
+fn test_requests_for_a_busy_key_wait_in_order() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5], 4, 2, DEFAULT_VNODES);
+    let key = "hot".to_string();
+    let location = &info.locations[&info.shard_of(&key)];
+    let (primary, secondary) = (location.primary, location.secondaries[0]);
+
+    let mut node = ServerNode::new(primary, SimClock::new(), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.take_messages();
+
+    node.step(10, Operation::Put(key.clone(), "a".to_string(), 0));
+    node.step(11, Operation::Get(key.clone(), 0));
+    node.step(12, Operation::Put(key.clone(), "c".to_string(), 0));
+    node.step(13, Operation::Get(key.clone(), 0));
+    let metrics = node.metrics();
+    assert_eq!(metrics.parked_requests, 3);
+    assert_eq!(metrics.parked_keys, 1);
+    assert_eq!(metrics.max_key_depth, 3);
+    assert_eq!(metrics.total_parked, 3);
+    // only the first write went out
+    let sent = node.take_messages();
+    assert!(matches!(sent[..], [(pid, Operation::Replicate(_, _, 1))] if pid == secondary));
+
+    // the ack answers the first write, serves the read behind it and starts
+    // the second write
+    node.step(secondary, Operation::ReplicateRes(key.clone(), None, 1));
+    let sent = node.take_messages();
+    assert!(matches!(&sent[..], [
+        (10, Operation::PutRes(None, 0)),
+        (11, Operation::GetRes(Some(a), 0)),
+        (_, Operation::Replicate(_, _, 2)),
+    ] if a == "a"));
+    assert_eq!(node.metrics().parked_requests, 1);
+
+    node.step(secondary, Operation::ReplicateRes(key.clone(), None, 2));
+    let sent = node.take_messages();
+    assert!(matches!(&sent[..], [
+        (12, Operation::PutRes(Some(a), 0)),
+        (13, Operation::GetRes(Some(c), 0)),
+    ] if a == "a" && c == "c"));
+    let metrics = node.metrics();
+    assert_eq!(metrics.parked_requests, 0);
+    assert_eq!(metrics.parked_keys, 0);
+    assert_eq!(metrics.max_key_depth, 0);
+    assert_eq!(metrics.peak_key_depth, 3);
+}
+
+// A busy key parks no more requests than a worker queues, per client and
+// over every client; the others are answered Busy and counted as queued and
+// refused
+#[test]
+fn test_parked_requests_are_bounded() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5], 4, 2, DEFAULT_VNODES);
+    let key = "hot".to_string();
+    let primary = info.locations[&info.shard_of(&key)].primary;
+    let mut node = ServerNode::new(primary, SimClock::new(), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.step(10, Operation::Put(key.clone(), "a".to_string(), 0));
+    node.take_messages();
+
+    for seq_no in 1..=CLIENT_QUEUE_CAPACITY {
+        node.step(10, Operation::Get(key.clone(), seq_no));
+    }
+    assert!(node.take_messages().is_empty());
+    node.step(10, Operation::Get(key.clone(), 100));
+    assert!(matches!(node.take_messages()[..], [(10, Operation::Busy(100, BUSY_RETRY_AFTER_MS))]));
+
+    let mut client_pid = 11;
+    while node.metrics().parked_requests < QUEUE_CAPACITY {
+        node.step(client_pid, Operation::Get(key.clone(), 0));
+        client_pid += 1;
+    }
+    assert!(node.take_messages().is_empty());
+    node.step(client_pid, Operation::Get(key.clone(), 0));
+    assert!(matches!(node.take_messages()[..], [(pid, Operation::Busy(0, _))] if pid == client_pid));
+    let metrics = node.metrics();
+    assert_eq!(metrics.queued_requests, QUEUE_CAPACITY);
+    assert_eq!(metrics.busy_requests, 2);
+    assert_eq!(metrics.total_parked, QUEUE_CAPACITY as u64);
+}
+
+// num_ops operations of one client, half of them on a key every client uses
+fn hot_key_workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = match rng.gen_bool(0.5) {
+                true => "hot".to_string(),
+                false => format!("c{}_k{}", client, rng.gen_range(0..5)),
+            };
+            match rng.gen_range(0..10) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+// Read your write on the keys only one client uses
+fn verify_read_your_write(results: &[KVSResult]) {
+    let mut last_written: HashMap<String, String> = HashMap::new();
+    for op_result in results.iter().filter(|op_result| op_result.key != "hot") {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+#[test]
+fn test_sim_hot_key_queues_drain() {
+    for seed in 0..10 {
+        for replication in [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }] {
+            let mut sim = Simulation::new(seed);
+            let workloads = (0..4).map(|client| hot_key_workload(seed, client, 200)).collect();
+            let (client_pids, server_pids) = sim.add_cluster(workloads, 3, replication);
+            assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+
+            let mut written: HashSet<String> = HashSet::new();
+            written.insert("".to_string());
+            for client_pid in &client_pids {
+                let results = sim.results(client_pid);
+                verify_read_your_write(&results);
+                written.extend(results.iter().filter(|r| r.operation == "put").map(|r| r.new_value.clone()));
+            }
+            // reads of the hot key see some client's write, never a mix
+            for client_pid in &client_pids {
+                for op_result in sim.results(client_pid) {
+                    if op_result.key == "hot" && op_result.operation == "get" {
+                        let observed = &op_result.observed_value;
+                        assert!(written.contains(observed), "{} was never written", observed);
+                    }
+                }
+            }
+
+            let mut total_parked = 0;
+            for server_pid in &server_pids {
+                let metrics = sim.metrics(server_pid).unwrap();
+                assert_eq!(metrics.parked_requests, 0, "seed {}: {:?}", seed, metrics);
+                assert_eq!(metrics.parked_keys, 0);
+                total_parked += metrics.total_parked;
+            }
+            assert!(total_parked > 0, "seed {}: no request ever waited", seed);
+        }
+    }
+}
+
+// Every server answers Metrics, and its queues are empty once the clients
+// stopped
+#[test]
+fn test_metrics_of_running_servers() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/write_mostly_high_contention";
+    let result_dir = "./data/result/queues";
+    let num_clients = 4;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(bin_dir, client_pid, &controller_pid_for_clients, input_dir, &server_pids, result_dir)
+            .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(8));
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    // let the writes in flight finish
+    sleep(Duration::from_secs(1));
+
+    let server_pids: Vec<Pid> = server_pids.iter().map(|pid| *pid as Pid).collect();
+    let metrics = get_server_metrics(&server_pids);
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    assert_eq!(metrics.len(), server_pids.len(), "not every server answered: {:?}", metrics);
+    for (server_pid, server_metrics) in &metrics {
+        assert_eq!(server_metrics.parked_requests, 0, "server {}: {:?}", server_pid, server_metrics);
+        assert!(server_metrics.peak_key_depth as u64 <= server_metrics.total_parked);
+    }
+    let total_parked: u64 = metrics.iter().map(|(_, server_metrics)| server_metrics.total_parked).sum();
+    assert!(total_parked > 0, "no request ever waited: {:?}", metrics);
+}
@@ -0,0 +1,156 @@
+mod utils;
+
+use kv_store::kvs::{KVSResult, Pid};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    get_server_snapshots, group_result_by_key, launch_client, launch_controller_with_options,
+    launch_server, read_result,
+};
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Once the workload is over every late Replicate has been applied, so the
+// secondaries must have converged to their primary even though clients were
+// answered after only W acknowledgements.
+fn verify_replicas_converged(server_pids: &Vec<Pid>) {
+    let snapshots = get_server_snapshots(server_pids).unwrap();
+
+    for i in 0..snapshots.len() {
+        for (sk, sv) in snapshots[i].secondary_shards.iter() {
+            let mut primary_cnt = 0;
+            for j in 0..snapshots.len() {
+                if let Some(pv) = snapshots[j].primary_shards.get(sk) {
+                    assert!(i != j);
+                    assert!(*sv == *pv);
+                    primary_cnt += 1;
+                }
+            }
+            assert!(primary_cnt == 1);
+        }
+    }
+}
+
+fn test_quorum_common(num_clients: usize, num_servers: usize, write_quorum: usize, read_quorum: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "ryw";
+    // number pf worker processes to run
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(
+            &bin_dir,
+            &server_pid,
+            &controller_pid_for_servers,
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch controller in quorum mode, every server a replica of every shard.
+    println!("launching controllers");
+    let write_quorum = write_quorum.to_string();
+    let read_quorum = read_quorum.to_string();
+    let num_replicas = num_servers.to_string();
+    let mut controller = launch_controller_with_options(
+        &bin_dir,
+        &[
+            "--write-quorum",
+            &write_quorum,
+            "--read-quorum",
+            &read_quorum,
+            "--replication-factor",
+            &num_replicas,
+        ],
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(
+            &bin_dir,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    // Sleep for enough time to let the system run, quorum reads take an
+    // extra round trip so give it longer than the primary-backup tests
+    sleep(Duration::from_secs(20));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid); // Combine the prefix with the index
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+    verify_replicas_converged(&server_pids.iter().map(|&x| x as Pid).collect());
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
+
+#[test]
+fn test_quorum() {
+    test_quorum_common(1, 3, 2, 2);
+    test_quorum_common(3, 3, 2, 2);
+    test_quorum_common(2, 5, 3, 3);
+    test_quorum_common(3, 5, 1, 5);
+}
@@ -0,0 +1,561 @@
+mod utils;
+
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode};
+use kv_store::raft::{LogEntry, LogIndex, PersistentState, RaftMessage, RaftNode, Role, Term};
+use kv_store::sim::{Process, Simulation};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    group_result_by_key, launch_client, launch_controller_with_options, launch_server_with_options,
+    read_result,
+};
+
+// In-process raft group over a simulated network. Everything, including the
+// election timeouts and the message delivery order, comes from the seed, so
+// a failing seed replays exactly.
+struct Cluster {
+    members: Vec<Pid>,
+    nodes: HashMap<Pid, Option<RaftNode>>, // none while crashed
+    disks: HashMap<Pid, PersistentState>,  // what a crashed node had persisted
+    in_flight: Vec<(Pid, Pid, RaftMessage)>,
+    partition: HashMap<Pid, usize>, // nodes only talk within the same side
+    drop_rate: f64,
+    rng: StdRng,
+
+    // checked invariants
+    leaders: HashMap<Term, Pid>,
+    committed: HashMap<LogIndex, (Term, String)>,
+    applied: HashMap<Pid, Vec<String>>, // commands applied by each node's state machine
+}
+
+impl Cluster {
+    fn new(num_nodes: u32, seed: u64) -> Self {
+        let members: Vec<Pid> = (1..=num_nodes).collect();
+        let mut nodes = HashMap::new();
+        for pid in &members {
+            let node = RaftNode::new(*pid, members.clone(), seed * 100 + *pid as u64, None);
+            nodes.insert(*pid, Some(node));
+        }
+        Cluster {
+            members: members.clone(),
+            nodes,
+            disks: HashMap::new(),
+            in_flight: Vec::new(),
+            partition: members.iter().map(|pid| (*pid, 0)).collect(),
+            drop_rate: 0.0,
+            rng: StdRng::seed_from_u64(seed),
+            leaders: HashMap::new(),
+            committed: HashMap::new(),
+            applied: members.iter().map(|pid| (*pid, Vec::new())).collect(),
+        }
+    }
+
+    fn connected(&self, a: Pid, b: Pid) -> bool {
+        self.partition[&a] == self.partition[&b]
+    }
+
+    // One round: deliver everything in flight in a random order, then tick every live node.
+    fn step(&mut self) {
+        let mut messages = std::mem::take(&mut self.in_flight);
+        messages.shuffle(&mut self.rng);
+        for (from, to, msg) in messages {
+            if !self.connected(from, to) || self.rng.gen_bool(self.drop_rate) {
+                continue;
+            }
+            if let Some(Some(node)) = self.nodes.get_mut(&to) {
+                node.step(from, msg);
+            }
+            self.collect(to);
+        }
+        for pid in self.members.clone() {
+            if let Some(Some(node)) = self.nodes.get_mut(&pid) {
+                node.tick();
+            }
+            self.collect(pid);
+        }
+    }
+
+    fn run(&mut self, rounds: usize) {
+        for _ in 0..rounds {
+            self.step();
+        }
+    }
+
+    // Gathers the outgoing messages and newly committed entries of a node and
+    // checks election safety and state machine safety along the way.
+    fn collect(&mut self, pid: Pid) {
+        let node = match self.nodes.get_mut(&pid) {
+            Some(Some(node)) => node,
+            _ => return,
+        };
+        for (to, msg) in node.take_messages() {
+            self.in_flight.push((pid, to, msg));
+        }
+        if node.is_leader() {
+            let leader = *self.leaders.entry(node.term()).or_insert(pid);
+            assert_eq!(leader, pid, "two leaders in term {}", node.term());
+        }
+        for (index, entry) in node.take_committed() {
+            let command = describe(&entry);
+            let expected = self
+                .committed
+                .entry(index)
+                .or_insert((entry.term, command.clone()));
+            assert_eq!(*expected, (entry.term, command.clone()), "diverged at index {}", index);
+            self.applied.get_mut(&pid).unwrap().push(command);
+        }
+    }
+
+    fn leader(&self) -> Option<Pid> {
+        let mut leader: Option<(Term, Pid)> = None;
+        for (pid, node) in self.nodes.iter() {
+            if let Some(node) = node {
+                if node.role() == Role::Leader && leader.is_none_or(|(term, _)| node.term() > term) {
+                    leader = Some((node.term(), *pid));
+                }
+            }
+        }
+        leader.map(|(_, pid)| pid)
+    }
+
+    fn wait_for_leader(&mut self) -> Pid {
+        for _ in 0..200 {
+            self.step();
+            if let Some(leader) = self.leader() {
+                return leader;
+            }
+        }
+        panic!("no leader elected");
+    }
+
+    fn propose(&mut self, pid: Pid, key: &str, value: &str) -> Option<LogIndex> {
+        let node = self.nodes.get_mut(&pid).unwrap().as_mut().unwrap();
+        let index = node.propose(0, Operation::Put(key.to_string(), value.to_string(), 0));
+        self.collect(pid);
+        index
+    }
+
+    fn crash(&mut self, pid: Pid) {
+        let node = self.nodes.get_mut(&pid).unwrap().take().unwrap();
+        self.disks.insert(pid, node.persistent_state().clone());
+        self.in_flight.retain(|(from, to, _)| *from != pid && *to != pid);
+    }
+
+    // The restarted node replays its log into a fresh state machine.
+    fn restart(&mut self, pid: Pid, seed: u64) {
+        let state = self.disks.remove(&pid).unwrap();
+        let node = RaftNode::restore(pid, self.members.clone(), seed, state, None);
+        self.nodes.insert(pid, Some(node));
+        self.applied.insert(pid, Vec::new());
+    }
+
+    fn isolate(&mut self, side: &[Pid]) {
+        for pid in self.members.clone() {
+            self.partition.insert(pid, if side.contains(&pid) { 1 } else { 0 });
+        }
+    }
+
+    fn heal(&mut self) {
+        for pid in self.members.clone() {
+            self.partition.insert(pid, 0);
+        }
+    }
+
+    fn applied_values(&self, pid: Pid) -> Vec<String> {
+        self.applied[&pid]
+            .iter()
+            .filter(|command| command.as_str() != "noop")
+            .cloned()
+            .collect()
+    }
+}
+
+fn describe(entry: &LogEntry) -> String {
+    match &entry.command {
+        Some(Operation::Put(key, value, _)) => format!("{}={}", key, value),
+        Some(op) => format!("{:?}", op),
+        None => "noop".to_string(),
+    }
+}
+
+#[test]
+fn test_raft_elects_one_leader() {
+    for seed in 0..20 {
+        let mut cluster = Cluster::new(5, seed);
+        let leader = cluster.wait_for_leader();
+        cluster.run(50);
+        // nobody should have challenged a healthy leader
+        assert_eq!(cluster.leader(), Some(leader));
+    }
+}
+
+#[test]
+fn test_raft_replicates_to_all() {
+    let mut cluster = Cluster::new(3, 1);
+    let leader = cluster.wait_for_leader();
+    for i in 0..100 {
+        assert!(cluster.propose(leader, "x", &i.to_string()).is_some());
+    }
+    cluster.run(30);
+
+    let expected: Vec<String> = (0..100).map(|i| format!("x={}", i)).collect();
+    for pid in cluster.members.clone() {
+        assert_eq!(cluster.applied_values(pid), expected);
+    }
+}
+
+#[test]
+fn test_raft_followers_do_not_accept_proposals() {
+    let mut cluster = Cluster::new(3, 2);
+    let leader = cluster.wait_for_leader();
+    for pid in cluster.members.clone() {
+        if pid != leader {
+            assert!(cluster.propose(pid, "x", "1").is_none());
+        }
+    }
+}
+
+#[test]
+fn test_raft_leader_crash() {
+    let mut cluster = Cluster::new(5, 3);
+    let old_leader = cluster.wait_for_leader();
+    for i in 0..10 {
+        cluster.propose(old_leader, "x", &i.to_string());
+    }
+    cluster.run(10);
+
+    cluster.crash(old_leader);
+    let new_leader = cluster.wait_for_leader();
+    assert!(new_leader != old_leader);
+    for i in 10..20 {
+        cluster.propose(new_leader, "x", &i.to_string());
+    }
+    cluster.run(10);
+
+    // the old leader comes back from its disk and catches up
+    cluster.restart(old_leader, 42);
+    cluster.run(50);
+    let expected: Vec<String> = (0..20).map(|i| format!("x={}", i)).collect();
+    for pid in cluster.members.clone() {
+        assert_eq!(cluster.applied_values(pid), expected);
+    }
+}
+
+#[test]
+fn test_raft_minority_partition() {
+    let mut cluster = Cluster::new(5, 4);
+    let old_leader = cluster.wait_for_leader();
+    cluster.propose(old_leader, "x", "committed");
+    cluster.run(10);
+
+    // the old leader and one follower end up in the minority
+    let follower = *cluster.members.iter().find(|&&pid| pid != old_leader).unwrap();
+    cluster.isolate(&[old_leader, follower]);
+    cluster.propose(old_leader, "x", "lost");
+    cluster.run(50);
+    assert_eq!(cluster.applied_values(old_leader), vec!["x=committed".to_string()]);
+
+    // the majority side elects its own leader and keeps going
+    let mut majority_leader = None;
+    for _ in 0..200 {
+        cluster.step();
+        majority_leader = cluster.leader().filter(|pid| *pid != old_leader && *pid != follower);
+        if majority_leader.is_some() {
+            break;
+        }
+    }
+    let majority_leader = majority_leader.expect("majority did not elect a leader");
+    cluster.propose(majority_leader, "x", "new");
+    cluster.run(10);
+
+    // after healing the uncommitted entry of the old leader is overwritten
+    cluster.heal();
+    cluster.run(100);
+    let expected = vec!["x=committed".to_string(), "x=new".to_string()];
+    for pid in cluster.members.clone() {
+        assert_eq!(cluster.applied_values(pid), expected);
+    }
+}
+
+#[test]
+fn test_raft_persists_across_restart() {
+    let dir = std::env::temp_dir().join(format!("raft_test_{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let path = dir.join("node_1").to_str().unwrap().to_string();
+
+    // a single node group elects itself and commits on its own
+    let mut node = RaftNode::new(1, vec![1], 7, Some(path.clone()));
+    while !node.is_leader() {
+        node.tick();
+    }
+    node.propose(0, Operation::Put("x".to_string(), "1".to_string(), 0));
+    node.propose(0, Operation::Delete("x".to_string(), 1));
+    let term = node.term();
+    let last_index = node.last_index();
+    drop(node);
+
+    let restarted = RaftNode::new(1, vec![1], 8, Some(path));
+    assert_eq!(restarted.term(), term);
+    assert_eq!(restarted.persistent_state().voted_for, Some(1));
+    assert_eq!(restarted.last_index(), last_index);
+    assert_eq!(describe(restarted.entry(last_index - 1).unwrap()), "x=1");
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+// Random crashes, restarts, partitions and message loss. The invariants are
+// checked on every step by Cluster::collect; once the faults stop every
+// command proposed afterwards has to commit everywhere.
+#[test]
+fn test_raft_randomized() {
+    for seed in 0..30 {
+        let mut cluster = Cluster::new(5, seed);
+        let mut rng = StdRng::seed_from_u64(seed + 1000);
+        cluster.drop_rate = 0.1;
+        let mut crashed: HashSet<Pid> = HashSet::new();
+
+        for round in 0..400 {
+            match rng.gen_range(0..20) {
+                0 if crashed.len() < 2 => {
+                    let pid = *cluster.members.choose(&mut rng).unwrap();
+                    if crashed.insert(pid) {
+                        cluster.crash(pid);
+                    }
+                }
+                1 if !crashed.is_empty() => {
+                    let pid = *crashed.iter().next().unwrap();
+                    crashed.remove(&pid);
+                    cluster.restart(pid, seed * 1000 + round);
+                }
+                2 => {
+                    let side: Vec<Pid> = cluster.members.choose_multiple(&mut rng, 2).cloned().collect();
+                    cluster.isolate(&side);
+                }
+                3 => cluster.heal(),
+                _ => {}
+            }
+            if let Some(leader) = cluster.leader() {
+                cluster.propose(leader, "k", &format!("{}-{}", seed, round));
+            }
+            cluster.step();
+        }
+
+        // stop injecting faults
+        cluster.heal();
+        cluster.drop_rate = 0.0;
+        for pid in crashed.drain() {
+            cluster.restart(pid, seed);
+        }
+        // right after healing the leader we find may be a stale one about to be
+        // deposed, and its uncommitted entries may legitimately be lost
+        for _ in 0..10 {
+            let leader = cluster.wait_for_leader();
+            cluster.propose(leader, "k", "final");
+            cluster.run(100);
+            if cluster.committed.values().any(|(_, command)| command == "k=final") {
+                break;
+            }
+        }
+        cluster.run(100);
+
+        let reference = cluster.applied_values(cluster.members[0]);
+        assert!(reference.contains(&"k=final".to_string()), "seed {}", seed);
+        for pid in cluster.members.clone() {
+            assert_eq!(cluster.applied_values(pid), reference, "seed {}", seed);
+        }
+    }
+}
+
+// A client of the simulation that only records what it receives, its
+// requests are put on the network by the test
+struct Probe {
+    pid: Pid,
+    received: Rc<RefCell<Vec<Operation>>>,
+}
+
+impl Process for Probe {
+    fn pids(&self) -> Vec<Pid> {
+        vec![self.pid]
+    }
+    fn tick_ms(&self) -> u64 {
+        1000
+    }
+    fn tick(&mut self) {}
+    fn step(&mut self, _src_pid: Pid, operation: Operation) {
+        self.received.borrow_mut().push(operation);
+    }
+    fn take_messages(&mut self) -> Vec<(Pid, Pid, Operation)> {
+        Vec::new()
+    }
+}
+
+fn add_probe(sim: &mut Simulation, pid: Pid) -> Rc<RefCell<Vec<Operation>>> {
+    let received = Rc::new(RefCell::new(Vec::new()));
+    sim.add(Box::new(Probe { pid, received: received.clone() }));
+    received
+}
+
+// The leader commits a Put but its answer is lost. The client's retry commits
+// again after another client wrote the key, and must get the first answer
+// without undoing that write.
+#[test]
+fn test_raft_retry_after_lost_reply() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new(seed);
+        let (_, server_pids) = sim.add_cluster(Vec::new(), 3, ReplicationMode::Raft);
+        let first = add_probe(&mut sim, 100);
+        let second = add_probe(&mut sim, 101);
+        sim.run_for(2000);
+
+        let key = "key".to_string();
+        sim.faults_mut().apply("drop op=PutRes dst=100").unwrap();
+        sim.send(100, server_pids[0], Operation::Put(key.clone(), "a".to_string(), 0));
+        sim.run_for(1000);
+        assert!(first.borrow().is_empty(), "seed {}", seed);
+        sim.faults_mut().apply("clear").unwrap();
+
+        sim.send(101, server_pids[1], Operation::Put(key.clone(), "b".to_string(), 0));
+        sim.run_for(1000);
+        sim.send(100, server_pids[2], Operation::Put(key.clone(), "a".to_string(), 0));
+        sim.run_for(1000);
+        sim.send(101, server_pids[0], Operation::Get(key.clone(), 1));
+        sim.run_for(1000);
+
+        assert!(matches!(&first.borrow()[..], [Operation::PutRes(None, 0)]), "seed {}: {:?}", seed, first.borrow());
+        assert!(
+            matches!(&second.borrow()[..], [Operation::PutRes(Some(a), 0), Operation::GetRes(Some(b), 1)] if a == "a" && b == "b"),
+            "seed {}: {:?}",
+            seed,
+            second.borrow()
+        );
+    }
+}
+
+fn verify_read_your_write(result: &Vec<KVSResult>) {
+    let mut prev_put_value = "".to_string();
+    for op_result in result {
+        if op_result.operation == "put" {
+            prev_put_value = op_result.new_value.clone();
+        } else if op_result.operation == "delete" {
+            prev_put_value = "".to_string();
+        } else {
+            assert!(op_result.operation == "get");
+            assert!(op_result.observed_value == prev_put_value);
+        }
+    }
+}
+
+// Real processes: kill the first server (the listed primary of shard 0) while
+// the clients run, they have to finish through the remaining members.
+fn test_raft_failover_common(num_clients: usize, num_servers: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "ryw";
+    // number pf worker processes to run
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+    let raft_dir = "./data/raft/test";
+    let _ = fs::remove_dir_all(raft_dir);
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            &bin_dir,
+            &["--raft-dir", raft_dir],
+            &server_pid,
+            &vec![controller_pid_for_servers],
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch controller in raft mode.
+    println!("launching controllers");
+    let mut controller = launch_controller_with_options(
+        &bin_dir,
+        &["--raft"],
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(
+            &bin_dir,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(3));
+    println!("killing server {}", server_pids[0]);
+    servers[0].kill().expect("Failed to kill server");
+
+    // Sleep for enough time to let the system run
+    sleep(Duration::from_secs(30));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid); // Combine the prefix with the index
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                for client_result in key_result {
+                    verify_read_your_write(client_result);
+                }
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    let _ = fs::remove_dir_all(raft_dir);
+}
+
+#[test]
+fn test_raft_failover() {
+    test_raft_failover_common(1, 3);
+    test_raft_failover_common(2, 5);
+}
@@ -0,0 +1,289 @@
+mod utils;
+
+use kv_store::client::ClientNode;
+use kv_store::controller::{assign_shards_to_servers, DEFAULT_NUM_SHARDS};
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardInfo};
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::{ServerNode, ANSWERS_PER_CLIENT};
+use kv_store::sim::{SimClock, Simulation};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    get_server_snapshots, isolate_ports, launch_client, launch_controller, launch_server, read_result, send_admin,
+};
+
+const DEADLINE_MS: u64 = 300_000;
+// pid admin requests come from in the simulations
+const ADMIN_PID: Pid = 100;
+
+// num_ops random operations of one client over its own keys
+fn workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..20));
+            match rng.gen_range(0..10) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+// Checks read your write and returns the value every key was left with
+fn verify_read_your_write(results: &Vec<KVSResult>, last_written: &mut HashMap<String, String>) {
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            assert!(op_result.operation == "get");
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// Reads every key back with a fresh client once the workload is over, so a
+// key lost in a move shows up
+fn check_no_write_lost(sim: &mut Simulation, client_pids: &Vec<Pid>, reader_pid: Pid) {
+    let mut last_written = HashMap::new();
+    for client_pid in client_pids {
+        verify_read_your_write(&sim.results(client_pid), &mut last_written);
+    }
+    let mut keys: Vec<String> = last_written.keys().cloned().collect();
+    keys.sort();
+    let reads = keys.iter().enumerate().map(|(seq_no, key)| Operation::Get(key.clone(), seq_no)).collect();
+    sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock())));
+    assert!(sim.run_until(DEADLINE_MS * 2, |sim| sim.clients_done()));
+    for result in sim.results(&reader_pid) {
+        assert_eq!(result.observed_value, last_written[&result.key], "lost write of {}", result.key);
+    }
+}
+
+fn primaries_of(info: &ShardInfo, server_pid: Pid) -> usize {
+    info.locations.values().filter(|location| location.primary == server_pid).count()
+}
+
+#[test]
+fn test_sim_add_server_rebalances() {
+    for seed in 0..20 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..2).map(|client| workload(seed, client, 300)).collect();
+        let (client_pids, server_pids) = sim.add_cluster(workloads, 3, ReplicationMode::PrimaryBackup);
+        sim.run_for(100 + seed * 10);
+
+        let new_pid = server_pids[2] + 1;
+        let server_seed = sim.next_seed();
+        sim.add(Box::new(ServerNode::new(new_pid, sim.clock(), server_seed, None)));
+        sim.send(ADMIN_PID, 0, Operation::AddServer(new_pid));
+
+        let fair_share = DEFAULT_NUM_SHARDS / 4;
+        let balanced = |sim: &Simulation| primaries_of(&sim.shard_info(&0).unwrap(), new_pid) == fair_share;
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done() && balanced(sim)), "seed {} did not finish", seed);
+        let info = sim.shard_info(&0).unwrap();
+        for server_pid in &server_pids {
+            assert_eq!(primaries_of(&info, *server_pid), fair_share);
+        }
+        check_no_write_lost(&mut sim, &client_pids, new_pid + 1);
+    }
+}
+
+#[test]
+fn test_sim_move_shard_under_load() {
+    let faults = "duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    for seed in 0..20 {
+        for replication in [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }] {
+            let mut sim = Simulation::new(seed);
+            sim.faults_mut().apply(faults).unwrap();
+            let workloads = (0..2).map(|client| workload(seed, client, 300)).collect();
+            let (client_pids, server_pids) = sim.add_cluster(workloads, 3, replication);
+            sim.run_for(100 + seed * 10);
+
+            // every shard of the first server goes to the second one
+            let info = sim.shard_info(&0).unwrap();
+            let mut shard_ids: Vec<u32> = info.locations.keys().cloned().collect();
+            shard_ids.sort();
+            for shard_id in shard_ids {
+                if info.locations[&shard_id].primary == server_pids[0] {
+                    sim.send(ADMIN_PID, 0, Operation::MoveShard(shard_id, server_pids[1]));
+                }
+            }
+
+            let moved = |sim: &Simulation| primaries_of(&sim.shard_info(&0).unwrap(), server_pids[0]) == 0;
+            assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done() && moved(sim)), "seed {} did not finish", seed);
+            check_no_write_lost(&mut sim, &client_pids, server_pids[2] + 1);
+        }
+    }
+}
+
+#[test]
+fn test_sim_new_primary_crashes_during_moves() {
+    for seed in 0..20 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..2).map(|client| workload(seed, client, 300)).collect();
+        let (client_pids, server_pids) = sim.add_cluster(workloads, 3, ReplicationMode::PrimaryBackup);
+        sim.run_for(100);
+
+        let new_pid = server_pids[2] + 1;
+        let server_seed = sim.next_seed();
+        sim.add(Box::new(ServerNode::new(new_pid, sim.clock(), server_seed, None)));
+        sim.send(ADMIN_PID, 0, Operation::AddServer(new_pid));
+        // some moves finished, one may be half way
+        sim.run_for(50 + seed * 5);
+        sim.crash(&new_pid);
+
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, new_pid + 1);
+    }
+}
+
+// A write the network duplicated can come back after the client wrote the
+// key again, e.g. once a shard move released it. It must not undo that write,
+// a retry gets the answer the write had and an older one is answered Stale.
+#[test]
+fn test_late_duplicate_write_gets_its_first_answer() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4], 4, 1, DEFAULT_VNODES);
+    let key = "key".to_string();
+    let mut node = ServerNode::new(4, SimClock::new(), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.take_messages();
+
+    node.step(10, Operation::Put(key.clone(), "a".to_string(), 1));
+    node.step(10, Operation::Put(key.clone(), "b".to_string(), 2));
+    node.take_messages();
+    node.step(10, Operation::Put(key.clone(), "a".to_string(), 1));
+    node.step(10, Operation::Delete(key.clone(), 0));
+    let sent = node.take_messages();
+    assert!(matches!(&sent[..], [(10, Operation::PutRes(None, 1)), (10, Operation::Stale(0, 2))]));
+    assert_eq!(node.kvs().get(&key), Some("b".to_string()));
+
+    // a retry of the latest write gets its answer again, the writes of other
+    // clients still run
+    node.step(10, Operation::Put(key.clone(), "b".to_string(), 2));
+    node.step(11, Operation::Put(key.clone(), "c".to_string(), 1));
+    let sent = node.take_messages();
+    assert!(matches!(&sent[..], [(10, Operation::PutRes(Some(a), 2)), (11, Operation::PutRes(Some(b), 1))]
+        if a == "a" && b == "b"));
+    assert_eq!(node.kvs().get(&key), Some("c".to_string()));
+
+    // the answers kept per client and shard are bounded, a write older than
+    // all of them is answered Stale
+    let shard_id = info.shard_of(&key);
+    let keys: Vec<String> = (0..)
+        .map(|i| format!("other{}", i))
+        .filter(|other| info.shard_of(other) == shard_id)
+        .take(ANSWERS_PER_CLIENT + 1)
+        .collect();
+    for (i, other) in keys.iter().enumerate() {
+        node.step(10, Operation::Put(other.clone(), "v".to_string(), i + 3));
+    }
+    node.take_messages();
+    node.step(10, Operation::Put(keys[0].clone(), "w".to_string(), 3));
+    let newest = ANSWERS_PER_CLIENT + 3;
+    assert!(matches!(&node.take_messages()[..], [(10, Operation::Stale(3, n))] if *n == newest));
+    assert_eq!(node.kvs().get(&keys[0]), Some("v".to_string()));
+}
+
+fn get_latest_kv_from_input(result: &Vec<Vec<KVSResult>>) -> HashMap<String, String> {
+    let mut latest_kv = HashMap::new();
+    for client_result in result {
+        for op_result in client_result.iter().rev() {
+            if op_result.operation == "put" {
+                latest_kv.entry(op_result.key.clone()).or_insert(op_result.new_value.clone());
+            } else if op_result.operation == "delete" {
+                latest_kv.entry(op_result.key.clone()).or_insert("".to_string());
+            }
+        }
+    }
+    latest_kv
+}
+
+// The sharding workload on 3 servers, with a 4th one added while it runs.
+// Afterwards the new server is the primary of a fair share of the keys and
+// the primaries together still hold every key.
+#[test]
+fn test_add_server_while_running() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/sharding";
+    let result_dir = "./data/result/sharding_rebalance";
+    let num_clients = 2;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+    let new_pid = num_clients + num_servers + 2;
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(bin_dir, client_pid, &controller_pid_for_clients, input_dir, &server_pids, result_dir)
+            .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(2));
+    let mut all_server_pids = server_pids.clone();
+    all_server_pids.push(new_pid);
+    servers.push(
+        launch_server(bin_dir, &new_pid, &controller_pid_for_servers, &all_server_pids)
+            .expect("Failed to launch server"),
+    );
+    sleep(Duration::from_secs(1));
+    send_admin(&vec![controller_pid_for_clients as Pid], Operation::AddServer(new_pid as Pid));
+    sleep(Duration::from_secs(12));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    let result = read_result(result_paths).expect("Fail to read result file");
+    let latest_kv = get_latest_kv_from_input(&result);
+    let all_server_pids: Vec<Pid> = all_server_pids.iter().map(|pid| *pid as Pid).collect();
+    let snapshots = get_server_snapshots(&all_server_pids).unwrap();
+
+    let mut primary_keys: HashMap<String, String> = HashMap::new();
+    for snapshot in &snapshots {
+        for (key, value) in &snapshot.primary_shards {
+            assert!(primary_keys.insert(key.clone(), value.clone()).is_none(), "{} has two primaries", key);
+        }
+    }
+    for (key, value) in &latest_kv {
+        if !value.is_empty() {
+            assert_eq!(primary_keys.get(key), Some(value), "lost write of {}", key);
+        }
+    }
+    let new_share = snapshots.last().unwrap().primary_shards.len();
+    assert!(new_share > latest_kv.len() / 8, "the new server holds {} keys", new_share);
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
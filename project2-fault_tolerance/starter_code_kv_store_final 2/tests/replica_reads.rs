@@ -0,0 +1,329 @@
+mod utils;
+
+use kv_store::client::{ClientNode, ReadPreference};
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ReadBounds, ReplicationMode, ShardInfo};
+use kv_store::lease::Clock;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::{ServerNode, SYNC_INTERVAL_MS};
+use kv_store::sim::Simulation;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::process::Child;
+use std::rc::Rc;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    group_result_by_key, isolate_ports, launch_client_with_options, launch_controller, launch_server, read_result,
+};
+
+const DEADLINE_MS: u64 = 300_000;
+
+// A clock the test moves by hand
+#[derive(Clone)]
+struct ManualClock(Rc<Cell<u64>>);
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+// What the node answered client 10: the value and token of a served read,
+// none for a refusal
+fn answer(node: &mut ServerNode<ManualClock>) -> Option<(Option<String>, u64)> {
+    match node.take_messages().pop() {
+        Some((10, Operation::SessionRes(response, token))) => match *response {
+            Operation::GetRes(value, 0) => Some((value, token)),
+            other => panic!("unexpected response {:?}", other),
+        },
+        Some((10, Operation::ReadRefused(0))) => None,
+        other => panic!("unexpected message {:?}", other),
+    }
+}
+
+#[test]
+fn test_secondary_reads_stay_in_bounds() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let key = "key".to_string();
+    let shard_id = info.shard_of(&key);
+    let location = info.locations[&shard_id].clone();
+    let (primary, secondary, other) = (location.primary, location.secondaries[0], location.secondaries[1]);
+
+    let mut node = ServerNode::new(secondary, ManualClock(now.clone()), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.step(primary, Operation::Replicate(key.clone(), Some("a".to_string()), 5));
+    node.take_messages();
+    let read = |min_version, max_staleness_ms| {
+        Operation::ReadReplica(key.clone(), 0, ReadBounds { max_staleness_ms, min_version })
+    };
+
+    // nothing heard from the primary yet
+    node.step(10, read(0, None));
+    assert_eq!(answer(&mut node), None);
+    // only the shard's primary can vouch for our copy
+    node.step(other, Operation::ShardVersions(vec![(shard_id, 5)]));
+    node.step(10, read(0, None));
+    assert_eq!(answer(&mut node), None);
+
+    node.step(primary, Operation::ShardVersions(vec![(shard_id, 5)]));
+    now.set(100);
+    node.step(10, read(5, Some(100)));
+    assert_eq!(answer(&mut node), Some((Some("a".to_string()), 5)));
+    // behind the client's session token
+    node.step(10, read(6, None));
+    assert_eq!(answer(&mut node), None);
+    // too long since the primary last vouched
+    now.set(101);
+    node.step(10, read(0, Some(100)));
+    assert_eq!(answer(&mut node), None);
+    node.step(10, read(0, None));
+    assert_eq!(answer(&mut node), Some((Some("a".to_string()), 5)));
+
+    // a late, older ShardVersions does not count as a new one
+    node.step(primary, Operation::ShardVersions(vec![(shard_id, 3)]));
+    node.step(10, read(0, Some(100)));
+    assert_eq!(answer(&mut node), None);
+}
+
+// The versions a primary sent to pid for shard_id
+fn sent_versions(sent: &[(Pid, Operation)], pid: Pid, shard_id: u32) -> Vec<u64> {
+    let mut found = Vec::new();
+    for (dst_pid, operation) in sent {
+        if let Operation::ShardVersions(versions) = operation {
+            if *dst_pid == pid {
+                found.extend(versions.iter().filter(|(id, _)| *id == shard_id).map(|(_, version)| *version));
+            }
+        }
+    }
+    found
+}
+
+#[test]
+fn test_primary_vouches_only_for_acked_writes() {
+    let now = Rc::new(Cell::new(0));
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let key = "key".to_string();
+    let shard_id = info.shard_of(&key);
+    let location = info.locations[&shard_id].clone();
+    let secondaries = location.secondaries.clone();
+
+    let mut node = ServerNode::new(location.primary, ManualClock(now.clone()), 0, None);
+    node.step(1, Operation::PutShardInfo(info.clone()));
+    node.take_messages();
+
+    node.step(10, Operation::Session(Box::new(Operation::Put(key.clone(), "a".to_string(), 0))));
+    node.take_messages();
+    // the write is still waiting for acks
+    now.set(SYNC_INTERVAL_MS);
+    node.tick();
+    let sent = node.take_messages();
+    for secondary in &secondaries {
+        assert_eq!(sent_versions(&sent, *secondary, shard_id), vec![0]);
+    }
+
+    for secondary in &secondaries {
+        node.step(*secondary, Operation::ReplicateRes(key.clone(), None, 1));
+    }
+    let sent = node.take_messages();
+    assert!(matches!(&sent[..], [(10, Operation::SessionRes(response, 1))]
+        if matches!(**response, Operation::PutRes(None, 0))));
+    now.set(2 * SYNC_INTERVAL_MS);
+    node.tick();
+    let sent = node.take_messages();
+    for secondary in &secondaries {
+        assert_eq!(sent_versions(&sent, *secondary, shard_id), vec![1]);
+    }
+
+    // versions grow per shard, whatever the key
+    let other_key = (0..)
+        .map(|i| format!("key_{}", i))
+        .find(|other_key| info.shard_of(other_key) == shard_id)
+        .unwrap();
+    node.step(10, Operation::Put(other_key, "b".to_string(), 1));
+    let sent = node.take_messages();
+    assert!(sent.iter().all(|(_, operation)| matches!(operation, Operation::Replicate(_, _, 2))));
+}
+
+// num_ops mostly reads of one client over its own keys
+fn read_mostly_workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..10));
+            match rng.gen_range(0..10) {
+                0..=6 => Operation::Get(key, seq_no),
+                7..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+fn verify_read_your_write(results: &[KVSResult]) {
+    let mut last_written: HashMap<String, String> = HashMap::new();
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// A PrimaryBackup cluster of 3 servers (2, 3 and 4) and num_clients clients
+// from pid 5 on reading with the given preference
+fn add_cluster_with_preference(
+    sim: &mut Simulation,
+    seed: u64,
+    num_clients: usize,
+    preference: ReadPreference,
+    num_ops: usize,
+) -> (Vec<Pid>, Vec<Pid>) {
+    let (_, server_pids) = sim.add_cluster(vec![], 3, ReplicationMode::PrimaryBackup);
+    let client_pids: Vec<Pid> = (5..5 + num_clients as Pid).collect();
+    for (client, client_pid) in client_pids.iter().enumerate() {
+        let operations = read_mostly_workload(seed, client, num_ops);
+        let client = ClientNode::new(*client_pid, vec![0], operations, sim.clock());
+        sim.add(Box::new(client.with_read_preference(preference, Some(500))));
+    }
+    (client_pids, server_pids)
+}
+
+#[test]
+fn test_sim_secondary_reads_keep_read_your_write() {
+    let faults = "duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    for seed in 0..10 {
+        for preference in [ReadPreference::Nearest, ReadPreference::Any] {
+            for faults in ["", faults] {
+                let mut sim = Simulation::new(seed);
+                sim.faults_mut().apply(faults).unwrap();
+                let (client_pids, _) = add_cluster_with_preference(&mut sim, seed, 4, preference, 100);
+                assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+
+                let mut answered = 0;
+                for client_pid in &client_pids {
+                    verify_read_your_write(&sim.results(client_pid));
+                    answered += sim.secondary_reads(client_pid).unwrap().0;
+                }
+                assert!(answered > 0, "seed {}: no secondary answered a read", seed);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_secondary_cut_off_from_its_primary() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        let (client_pids, server_pids) = add_cluster_with_preference(&mut sim, seed, 2, ReadPreference::Any, 400);
+        sim.run_for(1000);
+        let refused_before: usize = client_pids.iter().map(|pid| sim.secondary_reads(pid).unwrap().1).sum();
+
+        // the last server stops hearing how far its copies are, after 500ms
+        // its reads are out of bounds
+        sim.faults_mut().apply(&format!("drop op=ShardVersions dst={}", server_pids[2])).unwrap();
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+
+        let mut refused = 0;
+        for client_pid in &client_pids {
+            verify_read_your_write(&sim.results(client_pid));
+            refused += sim.secondary_reads(client_pid).unwrap().1;
+        }
+        assert!(refused > refused_before, "seed {}: no read was refused", seed);
+    }
+}
+
+fn verify_read_your_write_per_key(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Read your write with clients reading from the nearest replica
+#[test]
+fn test_read_your_write_from_secondaries() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_nearest";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            &["--read-preference", "nearest", "--max-staleness-ms", "1000"],
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write_per_key(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
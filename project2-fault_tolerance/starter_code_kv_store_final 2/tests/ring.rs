@@ -0,0 +1,134 @@
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{Pid, ShardInfo};
+use kv_store::ring::{stable_hash, HashRing};
+use std::collections::HashMap;
+
+fn assignment(server_pids: &[Pid], num_shards: usize, replication_factor: usize) -> ShardInfo {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, server_pids, num_shards, replication_factor, 16);
+    info
+}
+
+// Which server is the primary of every key
+fn primaries(info: &ShardInfo, keys: &[String]) -> HashMap<String, Pid> {
+    keys.iter()
+        .map(|key| (key.clone(), info.locations[&info.shard_of(key)].primary))
+        .collect()
+}
+
+fn keys(count: usize) -> Vec<String> {
+    (0..count).map(|i| format!("key_{}", i)).collect()
+}
+
+#[test]
+fn test_stable_hash_does_not_change() {
+    // other processes and later builds have to route keys the same way
+    assert_eq!(stable_hash(b""), 0xefd01f60ba992926);
+    assert_eq!(stable_hash(b"key_1"), 0xee07d1b9edf979de);
+}
+
+#[test]
+fn test_lookup_wraps_around() {
+    let mut ring = HashRing::new();
+    assert_eq!(ring.lookup_hash(5), None);
+    ring.insert(100, 1);
+    ring.insert(200, 2);
+    assert_eq!(ring.lookup_hash(0), Some(1));
+    assert_eq!(ring.lookup_hash(100), Some(1));
+    assert_eq!(ring.lookup_hash(101), Some(2));
+    assert_eq!(ring.lookup_hash(201), Some(1));
+    assert_eq!(ring.walk(150), vec![2, 1]);
+    ring.remove(1);
+    assert_eq!(ring.lookup_hash(0), Some(2));
+}
+
+#[test]
+fn test_keys_keep_their_shard() {
+    let keys = keys(1000);
+    let small = assignment(&[4, 5, 6], 64, 3);
+    let large = assignment(&[4, 5, 6, 7, 8, 9, 10, 11], 64, 3);
+    for key in &keys {
+        assert_eq!(small.shard_of(key), large.shard_of(key));
+        assert!(small.shard_of(key) < 64);
+    }
+}
+
+#[test]
+fn test_shards_have_replication_factor_replicas() {
+    let server_pids = vec![4, 5, 6, 7, 8];
+    let info = assignment(&server_pids, 32, 3);
+    assert_eq!(info.num_shards, 32);
+    assert_eq!(info.locations.len(), 32);
+    for location in info.locations.values() {
+        let mut replicas = location.secondaries.clone();
+        replicas.push(location.primary);
+        replicas.sort();
+        replicas.dedup();
+        assert_eq!(replicas.len(), 3);
+        assert!(replicas.iter().all(|pid| server_pids.contains(pid)));
+    }
+    // no more replicas than servers
+    let info = assignment(&[4, 5], 8, 3);
+    assert!(info.locations.values().all(|location| location.secondaries.len() == 1));
+}
+
+#[test]
+fn test_primaries_and_secondaries_are_balanced() {
+    for num_servers in 2..=9 {
+        let server_pids: Vec<Pid> = (4..4 + num_servers).collect();
+        for (num_shards, replication_factor) in [(256, 3), (64, 2), (10, 1), (7, 5)] {
+            let info = assignment(&server_pids, num_shards, replication_factor);
+            let mut primaries: HashMap<Pid, usize> = HashMap::new();
+            let mut secondaries: HashMap<Pid, usize> = HashMap::new();
+            for location in info.locations.values() {
+                *primaries.entry(location.primary).or_insert(0) += 1;
+                for pid in &location.secondaries {
+                    *secondaries.entry(*pid).or_insert(0) += 1;
+                }
+            }
+            let spread = |counts: &HashMap<Pid, usize>| {
+                let counts: Vec<usize> = server_pids.iter().map(|pid| counts.get(pid).cloned().unwrap_or(0)).collect();
+                counts.iter().max().unwrap() - counts.iter().min().unwrap()
+            };
+            assert!(spread(&primaries) <= 1, "{} servers, {} shards: {:?}", num_servers, num_shards, primaries);
+            assert!(spread(&secondaries) <= 1, "{} servers, {} shards: {:?}", num_servers, num_shards, secondaries);
+        }
+    }
+}
+
+#[test]
+fn test_keys_are_spread_over_servers() {
+    let server_pids = vec![4, 5, 6, 7, 8];
+    let keys = keys(20000);
+    let mut counts: HashMap<Pid, usize> = HashMap::new();
+    for pid in primaries(&assignment(&server_pids, 256, 3), &keys).values() {
+        *counts.entry(*pid).or_insert(0) += 1;
+    }
+    let fair_share = keys.len() / server_pids.len();
+    for pid in &server_pids {
+        let count = counts.get(pid).cloned().unwrap_or(0);
+        assert!(count > fair_share * 3 / 4 && count < fair_share * 5 / 4, "server {} has {} keys", pid, count);
+    }
+}
+
+#[test]
+fn test_adding_a_server_moves_few_keys() {
+    let keys = keys(20000);
+    let before = primaries(&assignment(&[4, 5, 6, 7], 256, 3), &keys);
+    let after = primaries(&assignment(&[4, 5, 6, 7, 8], 256, 3), &keys);
+
+    let mut moved = 0;
+    let mut to_new_server = 0;
+    for key in &keys {
+        if before[key] != after[key] {
+            moved += 1;
+            if after[key] == 8 {
+                to_new_server += 1;
+            }
+        }
+    }
+    // the new server's fifth of the keys, plus a few shards that had to
+    // change hands because the old servers' fair share shrank
+    assert!(moved > keys.len() / 10 && moved < keys.len() * 7 / 20, "{} keys moved", moved);
+    assert!(to_new_server > moved * 2 / 3, "{} of {} keys moved to the new server", to_new_server, moved);
+}
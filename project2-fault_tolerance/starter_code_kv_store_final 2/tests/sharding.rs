@@ -5,7 +5,9 @@ use std::collections::HashMap;
 use std::process::Child;
 use std::thread::sleep;
 use std::time::Duration;
-use utils::{get_server_snapshots, launch_client, launch_controller, launch_server, read_result};
+use utils::{
+    get_server_snapshots, isolate_ports, launch_client, launch_controller_with_options, launch_server, read_result,
+};
 
 fn get_latest_kv_from_input(result: &Vec<Vec<KVSResult>>) -> HashMap<String, String> {
     let mut latest_kv = HashMap::new();
@@ -25,7 +27,7 @@ fn get_latest_kv_from_input(result: &Vec<Vec<KVSResult>>) -> HashMap<String, Str
     latest_kv
 }
 
-fn verify_result(result: &Vec<Vec<KVSResult>>, server_pids: &Vec<Pid>) {
+fn verify_result(result: &Vec<Vec<KVSResult>>, server_pids: &Vec<Pid>, num_replicas: usize) {
     let latest_kv = get_latest_kv_from_input(result);
     let snapshots = get_server_snapshots(server_pids).unwrap();
 
@@ -49,15 +51,24 @@ fn verify_result(result: &Vec<Vec<KVSResult>>, server_pids: &Vec<Pid>) {
             assert!(latest_kv.contains_key(k) && latest_kv.get(k).unwrap() == v);
         }
     }
+
+    // verify every key has num_replicas - 1 secondary copies
+    for k in latest_kv.keys() {
+        let copies = snapshots.iter().filter(|snapshot| snapshot.secondary_shards.contains_key(k)).count();
+        assert_eq!(copies, num_replicas - 1, "{} has {} secondary copies", k, copies);
+    }
 }
 
-fn test_sharding_common(num_clients: usize, num_servers: usize) {
+// options go to the controller, num_replicas is the replication factor they
+// ask for (every server when not given). Results go to data/result/<name>.
+fn test_sharding_common(name: &str, num_clients: usize, num_servers: usize, options: &[&str], num_replicas: usize) {
+    isolate_ports();
     // binary location. binaries include controller and worker
     let bin_dir = "./target/debug/";
     let workload = "sharding";
     // number pf worker processes to run
     let input_dir = format!("./data/input/{}", workload);
-    let result_dir = format!("./data/result/{}", workload);
+    let result_dir = format!("./data/result/{}", name);
 
     let controller_pid_for_clients: usize = 0;
     let controller_pid_for_servers: usize = 1;
@@ -80,8 +91,9 @@ fn test_sharding_common(num_clients: usize, num_servers: usize) {
 
     // Launch controller.
     println!("launching controllers");
-    let mut controller = launch_controller(
+    let mut controller = launch_controller_with_options(
         &bin_dir,
+        options,
         &controller_pid_for_clients,
         &controller_pid_for_servers,
         &num_clients,
@@ -124,7 +136,7 @@ fn test_sharding_common(num_clients: usize, num_servers: usize) {
     // verify result
     match read_result(result_paths) {
         Ok(result) => {
-            verify_result(&result, &server_pids.iter().map(|&x| x as Pid).collect());
+            verify_result(&result, &server_pids.iter().map(|&x| x as Pid).collect(), num_replicas);
             // panic!("debug");
         }
         Err(_) => {
@@ -139,8 +151,15 @@ fn test_sharding_common(num_clients: usize, num_servers: usize) {
 
 #[test]
 fn test_sharding() {
-    test_sharding_common(1, 3);
-    test_sharding_common(3, 3);
-    test_sharding_common(2, 5);
-    test_sharding_common(5, 2);
+    let all = ["--replication-factor", "5"];
+    test_sharding_common("sharding", 1, 3, &all, 3);
+    test_sharding_common("sharding", 3, 3, &all, 3);
+    test_sharding_common("sharding", 2, 5, &all, 5);
+    test_sharding_common("sharding", 5, 2, &all, 2);
+}
+
+#[test]
+fn test_sharding_with_replication_factor() {
+    test_sharding_common("sharding_rf", 2, 5, &["--shards", "256", "--replication-factor", "2"], 2);
+    test_sharding_common("sharding_rf", 3, 4, &["--shards", "7", "--replication-factor", "3"], 3);
 }
@@ -0,0 +1,133 @@
+mod utils;
+
+use kv_store::client::KvClient;
+use kv_store::shell::{parse_command, Command, Shell, DEFAULT_BENCH_KEYS};
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{isolate_ports, launch_controller, launch_server, launch_shell, test_context};
+
+#[test]
+fn test_parse_commands() {
+    let parsed = |line: &str| parse_command(line).unwrap();
+    assert_eq!(parsed("get a"), Command::Get("a".to_string()));
+    assert_eq!(parsed("  put a   1 "), Command::Put("a".to_string(), "1".to_string()));
+    assert_eq!(parsed("delete a"), Command::Delete("a".to_string()));
+    assert_eq!(parsed("mget a b"), Command::MultiGet(vec!["a".to_string(), "b".to_string()]));
+    assert_eq!(
+        parsed("mput a 1 b 2"),
+        Command::MultiPut(vec![("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())])
+    );
+    assert_eq!(parsed("mdelete a"), Command::MultiDelete(vec!["a".to_string()]));
+    assert_eq!(parsed("cas a - 1"), Command::Cas("a".to_string(), None, Some("1".to_string())));
+    assert_eq!(parsed("cas a 1 -"), Command::Cas("a".to_string(), Some("1".to_string()), None));
+    assert_eq!(parsed("putifabsent a 1"), Command::PutIfAbsent("a".to_string(), "1".to_string()));
+    assert_eq!(parsed("incr a"), Command::Incr("a".to_string(), 1));
+    assert_eq!(parsed("incr a -5"), Command::Incr("a".to_string(), -5));
+    assert_eq!(
+        parsed("txn get a put b 1 delete c"),
+        Command::Txn(vec![Command::Get("a".to_string()), Command::Put("b".to_string(), "1".to_string()), Command::Delete("c".to_string())])
+    );
+    assert_eq!(parsed("where a"), Command::Where("a".to_string()));
+    assert_eq!(parsed("shardinfo"), Command::ShardInfo);
+    assert_eq!(parsed("snapshot 4"), Command::Snapshot(4));
+    assert_eq!(parsed("bench 100"), Command::Bench { num_ops: 100, num_keys: DEFAULT_BENCH_KEYS });
+    assert_eq!(parsed("bench 100 0"), Command::Bench { num_ops: 100, num_keys: 1 });
+    assert_eq!(parsed("history"), Command::History);
+    assert_eq!(parsed("!3"), Command::Rerun(3));
+    assert_eq!(parsed("exit"), Command::Quit);
+    for bad in ["get", "put a", "put a 1 2", "mget", "mput a 1 b", "mdelete", "cas a 1", "incr a x", "txn", "txn put a", "txn where a", "snapshot x", "bench", "bench many", "!x", "explode"] {
+        assert!(parse_command(bad).is_err(), "{}", bad);
+    }
+}
+
+// Servers 3, 4 and 5 and the controller, for a client with pid 2
+fn launch_cluster(bin_dir: &str) -> (Child, Vec<Child>) {
+    let server_pids: Vec<usize> = vec![3, 4, 5];
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &1, &server_pids).expect("Failed to launch server");
+        servers.push(server);
+    }
+    let controller = launch_controller(bin_dir, &0, &1, &1, &server_pids.len(), &vec![2], &server_pids)
+        .expect("Failed to launch controller");
+    (controller, servers)
+}
+
+#[test]
+fn test_shell_script() {
+    isolate_ports();
+    let (mut controller, servers) = launch_cluster("./target/debug/");
+    let history_file = std::env::temp_dir().join(format!("kv_store_shell_history_{}.txt", std::process::id()));
+    let history_path = history_file.to_str().unwrap().to_string();
+    fs::write(&history_file, "get earlier\n").unwrap();
+
+    let client = KvClient::with_context(test_context(2), vec![0]);
+    let mut shell = Shell::new(client, vec![0]).with_history_file(&history_path).unwrap();
+    let script = "put a 1\nput a 2\n# a comment\n\nget a\nwhere a\nshardinfo\nsnapshot 3\n!4\nbogus\n\
+                  bench 50 10\ndelete a\ntxn get a put b 1\n!1\nhistory\nquit\nget never\n";
+    let mut output = Vec::new();
+    shell.run(script.as_bytes(), &mut output, false).unwrap();
+    let history = shell.history().clone();
+
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    let output = String::from_utf8(output).unwrap();
+    // the servers may not have their shards yet when the first put goes out
+    let lines: Vec<&str> = output.lines().filter(|line| !line.starts_with("Client recv timeout")).collect();
+    let _ = fs::remove_file(&history_file);
+
+    // every command is echoed, then its answer and how long it took
+    assert_eq!(lines[..9], ["kv> put a 1", "(none)", lines[2], "kv> put a 2", "1", lines[5], "kv> # a comment", "kv> get a", "2"]);
+    assert!(lines[2].starts_with('(') && lines[2].ends_with(" ms)"), "{}", lines[2]);
+    let after = |command: &str| lines[lines.iter().position(|line| *line == command).unwrap() + 1];
+    assert!(after("kv> where a").starts_with("key a: shard "), "{}", output);
+    assert!(after("kv> shardinfo").starts_with("epoch "), "{}", output);
+    assert!(after("kv> snapshot 3").starts_with("server 3: "), "{}", output);
+    assert!(lines.iter().any(|line| line.ends_with(" a = 2")), "{}", output);
+    assert_eq!(after("kv> !4"), "get a");
+    assert!(after("kv> bogus").starts_with("error: cannot parse"), "{}", output);
+    assert!(after("kv> bench 50 10").starts_with("50 ops in "), "{}", output);
+    assert_eq!(after("kv> delete a"), "2");
+    assert_eq!(after("kv> txn get a put b 1"), "a = (none)");
+    assert_eq!(after("a = (none)"), "committed");
+    assert_eq!(after("kv> !1"), "get earlier");
+    assert_eq!(lines.last(), Some(&"kv> quit"));
+
+    let expected = ["get earlier", "put a 1", "put a 2", "get a", "where a", "shardinfo", "snapshot 3", "get a"];
+    assert_eq!(history[..8], expected);
+    assert_eq!(history[8..], ["bench 50 10", "delete a", "txn get a put b 1", "get earlier"]);
+}
+
+// The client binary reads the same commands from stdin
+#[test]
+fn test_shell_binary() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let (mut controller, servers) = launch_cluster(bin_dir);
+    let mut shell = launch_shell(bin_dir, &2, &vec![0]).expect("Failed to launch client");
+    let mut stdin = shell.stdin.take().unwrap();
+    stdin.write_all(b"put k v\nget k\nwhere k\nquit\n").unwrap();
+    let stdout = BufReader::new(shell.stdout.take().unwrap());
+    let lines: Vec<String> = stdout
+        .lines()
+        .map(|line| line.unwrap())
+        .filter(|line| !line.starts_with("Client recv timeout"))
+        .collect();
+    let status = shell.wait().unwrap();
+
+    controller.kill().expect("Failed to kill controller");
+    for mut server in servers {
+        let _ = server.kill();
+    }
+    sleep(Duration::from_millis(100));
+    assert!(status.success());
+    let after = |command: &str| lines[lines.iter().position(|line| line == command).unwrap() + 1].clone();
+    assert_eq!(after("kv> put k v"), "(none)");
+    assert_eq!(after("kv> get k"), "v");
+    assert!(after("kv> where k").starts_with("key k: shard "), "{:?}", lines);
+}
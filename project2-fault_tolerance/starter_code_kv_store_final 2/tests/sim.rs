@@ -0,0 +1,126 @@
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode};
+use kv_store::sim::Simulation;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+
+// Every simulated run gets this long on the virtual clock to finish
+const DEADLINE_MS: u64 = 300_000;
+
+// num_ops random operations of one client over its own few keys, so read your
+// write holds for every key
+fn workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..3));
+            match rng.gen_range(0..10) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+fn verify_read_your_write(results: &Vec<KVSResult>) {
+    let mut last_written: HashMap<String, String> = HashMap::new();
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.remove(&op_result.key);
+        } else {
+            assert!(op_result.operation == "get");
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// A cluster of 3 servers and num_clients clients, each running num_ops
+// operations. Returns the simulation once every client is done.
+fn run_cluster(seed: u64, faults: &str, replication: ReplicationMode, num_clients: usize, num_ops: usize) -> (Simulation, Vec<Pid>) {
+    let mut sim = Simulation::new(seed);
+    sim.faults_mut().apply(faults).unwrap();
+    let workloads = (0..num_clients).map(|client| workload(seed, client, num_ops)).collect();
+    let (client_pids, _server_pids) = sim.add_cluster(workloads, 3, replication);
+    assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+    (sim, client_pids)
+}
+
+fn check_read_your_write(sim: &Simulation, client_pids: &Vec<Pid>, num_ops: usize) {
+    for client_pid in client_pids {
+        let results = sim.results(client_pid);
+        assert_eq!(results.len(), num_ops);
+        verify_read_your_write(&results);
+    }
+}
+
+#[test]
+fn test_sim_read_your_write_many_seeds() {
+    let faults = "duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    for seed in 0..200 {
+        let (sim, client_pids) = run_cluster(seed, faults, ReplicationMode::PrimaryBackup, 2, 30);
+        check_read_your_write(&sim, &client_pids, 30);
+    }
+}
+
+#[test]
+fn test_sim_replays_the_same_seed() {
+    let faults = "duplicate prob=0.05; reorder prob=0.1; delay ms=50 prob=0.05";
+    let summary = |seed: u64| {
+        let (sim, client_pids) = run_cluster(seed, faults, ReplicationMode::PrimaryBackup, 2, 30);
+        let results: Vec<(String, String, u128, u128)> = client_pids
+            .iter()
+            .flat_map(|pid| sim.results(pid))
+            .map(|r| (r.key, r.observed_value, r.begin_time, r.end_time))
+            .collect();
+        (sim.fingerprint(), sim.now_ms(), sim.delivered(), results)
+    };
+    assert_eq!(summary(5), summary(5));
+    assert_ne!(summary(5).0, summary(6).0);
+}
+
+#[test]
+fn test_sim_primary_crash() {
+    for seed in 0..20 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..2).map(|client| workload(seed, client, 40)).collect();
+        let (client_pids, server_pids) = sim.add_cluster(workloads, 3, ReplicationMode::PrimaryBackup);
+        sim.run_for(200 + seed * 10);
+        // the controller has to notice, wait out the lease and promote a secondary
+        sim.crash(&server_pids[0]);
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_read_your_write(&sim, &client_pids, 40);
+    }
+}
+
+#[test]
+fn test_sim_partitioned_primary() {
+    // cut off from the controller but not from the clients, the old primary
+    // must stop serving reads once its lease is over
+    for seed in 0..20 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..2).map(|client| workload(seed, client, 40)).collect();
+        let (client_pids, server_pids) = sim.add_cluster(workloads, 3, ReplicationMode::PrimaryBackup);
+        sim.run_for(300);
+        let commands = format!("partition {} 1", server_pids[0]);
+        sim.faults_mut().apply(&commands).unwrap();
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_read_your_write(&sim, &client_pids, 40);
+    }
+}
+
+#[test]
+fn test_sim_quorum_and_raft() {
+    let faults = "duplicate prob=0.05; reorder prob=0.05";
+    for seed in 0..20 {
+        let quorum = ReplicationMode::Quorum { write: 2, read: 2 };
+        let (sim, client_pids) = run_cluster(seed, faults, quorum, 2, 30);
+        check_read_your_write(&sim, &client_pids, 30);
+
+        let (sim, client_pids) = run_cluster(seed, faults, ReplicationMode::Raft, 2, 30);
+        check_read_your_write(&sim, &client_pids, 30);
+    }
+}
@@ -0,0 +1,275 @@
+use kv_store::client::ClientNode;
+use kv_store::controller::{assign_shards_to_servers, ControllerNode, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR};
+use kv_store::kvs::{Operation, Pid, ReplicationMode, ShardInfo, TxnRecord};
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::ServerNode;
+use kv_store::sim::{SimClock, Simulation};
+use kv_store::txnlog::TxnLog;
+use std::fs;
+
+const DEADLINE_MS: u64 = 300_000;
+
+// Acks the Replicate every secondary got
+fn ack_all_writes(primary: &mut ServerNode<SimClock>) {
+    for (dst, operation) in primary.take_messages() {
+        if let Operation::Replicate(key, _, version) = operation {
+            primary.step(dst, Operation::ReplicateRes(key, None, version));
+        }
+    }
+    primary.take_messages();
+}
+
+// Acks the TxnReplicate every secondary got and returns the other messages
+fn ack_records(node: &mut ServerNode<SimClock>) -> Vec<(Pid, Operation)> {
+    let mut others = Vec::new();
+    for (dst, operation) in node.take_messages() {
+        match operation {
+            Operation::TxnReplicate(shard_id, _, _, ack_id) => node.step(dst, Operation::TxnReplicateRes(shard_id, ack_id)),
+            other => others.push((dst, other)),
+        }
+    }
+    others.extend(node.take_messages());
+    others
+}
+
+// Two keys of the cluster's ShardInfo on different shards with different
+// primaries, so a transaction on both has two participants
+fn keys_on_two_primaries(info: &ShardInfo) -> (String, String) {
+    let primary_of = |key: &str| info.locations[&info.shard_of(key)].primary;
+    let keys: Vec<String> = (0..100).map(|key| format!("k{}", key)).collect();
+    let second = keys.iter().find(|key| primary_of(key) != primary_of(&keys[0])).unwrap();
+    (keys[0].clone(), second.clone())
+}
+
+// Two keys on shards no server replicates both of, so restarting every
+// replica of the first one's shard leaves the second one's alone
+fn keys_on_disjoint_shards(info: &ShardInfo) -> (String, String) {
+    let replicas = |key: &str| {
+        let location = &info.locations[&info.shard_of(key)];
+        let mut replicas = location.secondaries.clone();
+        replicas.push(location.primary);
+        replicas
+    };
+    let keys: Vec<String> = (0..100).map(|key| format!("k{}", key)).collect();
+    let first = replicas(&keys[0]);
+    let second = keys.iter().find(|key| replicas(key).iter().all(|pid| !first.contains(pid))).unwrap();
+    (keys[0].clone(), second.clone())
+}
+
+fn write_both(keys: &(String, String), tag: &str, seq_no: usize) -> Operation {
+    let writes = vec![(keys.0.clone(), Some(tag.to_string())), (keys.1.clone(), Some(tag.to_string()))];
+    Operation::TxnCommit(Vec::new(), writes, seq_no)
+}
+
+// What a reader gets for both keys once the cluster settled
+fn read_both(sim: &mut Simulation, reader_pid: Pid, keys: &(String, String)) -> (String, String) {
+    let reads = vec![Operation::Get(keys.0.clone(), 0), Operation::Get(keys.1.clone(), 1)];
+    sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock())));
+    assert!(sim.run_until(sim.now_ms() + DEADLINE_MS, |sim| sim.clients_done()), "reader did not finish");
+    let results = sim.results(&reader_pid);
+    (results[0].observed_value.clone(), results[1].observed_value.clone())
+}
+
+#[test]
+fn test_participant_locks_until_decided() {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 4, 3, DEFAULT_VNODES);
+    let shard_id = info.shard_of("a");
+    let primary_pid = info.locations[&shard_id].primary;
+    let mut primary = ServerNode::new(primary_pid, SimClock::new(), 0, None);
+    primary.step(1, Operation::PutShardInfo(info.clone()));
+    primary.take_messages();
+    primary.step(20, Operation::Put("b".to_string(), "x".to_string(), 0));
+    ack_all_writes(&mut primary);
+
+    // the record goes to both secondaries before the vote
+    let txn_id = (10, 0);
+    let writes = vec![("a".to_string(), Some("1".to_string()))];
+    primary.step(9, Operation::TxnPrepare(txn_id, shard_id, shard_id, vec![("a".to_string(), None)], writes.clone()));
+    let sent = primary.take_messages();
+    assert_eq!(sent.len(), 2);
+    assert!(sent.iter().all(|(_, operation)| matches!(operation, Operation::TxnReplicate(_, _, TxnRecord::Prepared(..), _))));
+    for (dst, operation) in sent {
+        if let Operation::TxnReplicate(shard_id, _, _, ack_id) = operation {
+            primary.step(dst, Operation::TxnReplicateRes(shard_id, ack_id));
+        }
+    }
+    assert!(matches!(&primary.take_messages()[..], [(9, Operation::TxnPrepareRes(_, _, _, true))]));
+
+    // a write of a locked key waits, another transaction on it is refused,
+    // and so is one whose read no longer holds
+    primary.step(11, Operation::Put("a".to_string(), "2".to_string(), 0));
+    assert!(primary.take_messages().is_empty());
+    primary.step(9, Operation::TxnPrepare((12, 0), shard_id, shard_id, Vec::new(), writes.clone()));
+    assert!(matches!(&primary.take_messages()[..], [(9, Operation::TxnPrepareRes(_, _, _, false))]));
+    if info.shard_of("b") == shard_id {
+        let reads = vec![("b".to_string(), Some("y".to_string()))];
+        primary.step(9, Operation::TxnPrepare((13, 0), shard_id, shard_id, reads, Vec::new()));
+        assert!(matches!(&primary.take_messages()[..], [(9, Operation::TxnPrepareRes(_, _, _, false))]));
+    }
+
+    // the commit applies the writes, unlocks once the secondaries have them
+    // and the Put goes on
+    primary.step(9, Operation::TxnDecide(txn_id, shard_id, shard_id, true));
+    assert_eq!(primary.kvs().get("a"), Some("1".to_string()));
+    let answers = ack_records(&mut primary);
+    assert!(matches!(&answers[0], (9, Operation::TxnDecideRes(..))));
+    assert!(answers[1..].iter().all(|(_, operation)| matches!(operation, Operation::Replicate(key, Some(value), _) if key == "a" && value == "2")));
+    assert_eq!(answers.len(), 3);
+
+    // a late decision is answered again, a late prepare dropped
+    primary.step(9, Operation::TxnDecide(txn_id, shard_id, shard_id, true));
+    assert!(matches!(&primary.take_messages()[..], [(9, Operation::TxnDecideRes(..))]));
+    primary.step(9, Operation::TxnPrepare(txn_id, shard_id, shard_id, Vec::new(), writes));
+    assert!(primary.take_messages().is_empty());
+}
+
+// Every client writes its own tag to two keys on different shards, again
+// and again. Whichever transactions commit, both keys end up with the tag
+// of the same one.
+#[test]
+fn test_sim_transactions_write_both_keys_or_none() {
+    let faults = "drop src=5 prob=0.05; drop dst=6 prob=0.05; duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    let modes = [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }];
+    for seed in 0..3 {
+        for replication in modes {
+            for faults in ["", faults] {
+                let mut sim = Simulation::new(seed);
+                sim.faults_mut().apply(faults).unwrap();
+                sim.add_cluster(vec![], 3, replication);
+                let keys = keys_on_two_primaries(&sim.shard_info(&0).unwrap());
+                let client_pids: Vec<Pid> = (5..9).collect();
+                for client_pid in &client_pids {
+                    let operations = (0..10).map(|round| write_both(&keys, &format!("{}_{}", client_pid, round), round)).collect();
+                    sim.add(Box::new(ClientNode::new(*client_pid, vec![0], operations, sim.clock()).with_window(2)));
+                }
+                assert!(
+                    sim.run_until(DEADLINE_MS, |sim| sim.clients_done()),
+                    "seed {} {:?} faults {:?} did not finish",
+                    seed,
+                    replication,
+                    faults
+                );
+
+                let outcomes: Vec<String> = client_pids
+                    .iter()
+                    .flat_map(|client_pid| sim.results(client_pid))
+                    .map(|result| {
+                        assert_eq!(result.operation, "txn");
+                        result.observed_value
+                    })
+                    .collect();
+                assert!(outcomes.iter().any(|outcome| outcome == "commit"), "seed {} {:?}: nothing committed", seed, replication);
+
+                sim.faults_mut().apply("clear").unwrap();
+                let (first, second) = read_both(&mut sim, 9, &keys);
+                assert!(!first.is_empty());
+                assert_eq!(first, second, "seed {} {:?} faults {:?}", seed, replication, faults);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_raft_refuses_transactions() {
+    let mut sim = Simulation::new(0);
+    sim.add_cluster(vec![], 3, ReplicationMode::Raft);
+    let keys = keys_on_two_primaries(&sim.shard_info(&0).unwrap());
+    sim.add(Box::new(ClientNode::new(5, vec![0], vec![write_both(&keys, "t", 0)], sim.clock())));
+    assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()));
+    assert_eq!(sim.results(&5)[0].observed_value, "abort");
+    assert_eq!(read_both(&mut sim, 6, &keys), ("".to_string(), "".to_string()));
+}
+
+// The decision never reaches the participants and one of their primaries
+// crashes. The secondary that takes over has the prepared part, keeps the
+// key locked and applies the commit once the coordinator sends it again.
+#[test]
+fn test_sim_commit_survives_participant_crash() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new(seed);
+        sim.add_cluster(vec![], 3, ReplicationMode::PrimaryBackup);
+        let info = sim.shard_info(&0).unwrap();
+        let keys = keys_on_two_primaries(&info);
+        sim.faults_mut().apply("drop op=TxnDecide").unwrap();
+        sim.add(Box::new(ClientNode::new(5, vec![0], vec![write_both(&keys, "t", 0)], sim.clock())));
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        assert_eq!(sim.results(&5)[0].observed_value, "commit");
+
+        sim.crash(&info.locations[&info.shard_of(&keys.1)].primary);
+        sim.faults_mut().apply("clear").unwrap();
+        assert_eq!(read_both(&mut sim, 6, &keys), ("t".to_string(), "t".to_string()), "seed {}", seed);
+    }
+}
+
+// No vote reaches the coordinator, which aborts after TXN_TIMEOUT_MS. The
+// participants unlock and later writes of the keys go through.
+#[test]
+fn test_sim_lost_votes_abort() {
+    for seed in 0..5 {
+        let mut sim = Simulation::new(seed);
+        sim.add_cluster(vec![], 3, ReplicationMode::PrimaryBackup);
+        let keys = keys_on_two_primaries(&sim.shard_info(&0).unwrap());
+        sim.faults_mut().apply("drop op=TxnPrepareRes").unwrap();
+        sim.add(Box::new(ClientNode::new(5, vec![0], vec![write_both(&keys, "t", 0)], sim.clock())));
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        assert_eq!(sim.results(&5)[0].observed_value, "abort");
+
+        sim.faults_mut().apply("clear").unwrap();
+        let puts = vec![Operation::Put(keys.0.clone(), "after".to_string(), 0)];
+        sim.add(Box::new(ClientNode::new(6, vec![0], puts, sim.clock())));
+        assert!(sim.run_until(sim.now_ms() + DEADLINE_MS, |sim| sim.clients_done()), "seed {} put did not finish", seed);
+        assert_eq!(read_both(&mut sim, 7, &keys), ("after".to_string(), "".to_string()), "seed {}", seed);
+    }
+}
+
+// The decision reaches nobody but the client, then every replica of the
+// coordinator's shard restarts. The new primary reads the decision back from
+// its txn log and sends it again, so the participant commits instead of
+// presuming an abort. (The coordinator's own key is gone with the restart,
+// PrimaryBackup keeps no data on disk.) Once the participant applied it,
+// every replica forgets the transaction.
+#[test]
+fn test_sim_decision_survives_restart_of_coordinator_shard() {
+    let dir = std::env::temp_dir().join(format!("txn_test_{}", std::process::id()));
+    let raft_dir = dir.to_str().unwrap().to_string();
+    for seed in 0..3 {
+        let _ = fs::remove_dir_all(&dir);
+        let mut sim = Simulation::new(seed);
+        let server_pids: Vec<Pid> = (2..8).collect();
+        for server_pid in &server_pids {
+            let seed = sim.next_seed();
+            sim.add(Box::new(ServerNode::new(*server_pid, sim.clock(), seed, Some(raft_dir.clone()))));
+        }
+        let mut info = ShardInfo::new();
+        assign_shards_to_servers(&mut info, &server_pids, DEFAULT_NUM_SHARDS, DEFAULT_REPLICATION_FACTOR, DEFAULT_VNODES);
+        let keys = keys_on_disjoint_shards(&info);
+        let controller_seed = sim.next_seed();
+        sim.add(Box::new(ControllerNode::new(0, 1, server_pids.clone(), info.clone(), vec![0], sim.clock(), controller_seed, None)));
+
+        sim.faults_mut().apply("drop op=TxnDecide").unwrap();
+        sim.add(Box::new(ClientNode::new(10, vec![0], vec![write_both(&keys, "t", 0)], sim.clock())));
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        assert_eq!(sim.results(&10)[0].observed_value, "commit");
+
+        let location = &info.locations[&info.shard_of(&keys.0)];
+        for pid in location.secondaries.iter().chain([location.primary].iter()) {
+            let seed = sim.next_seed();
+            sim.restart(pid, Box::new(ServerNode::new(*pid, sim.clock(), seed, Some(raft_dir.clone()))));
+        }
+        sim.faults_mut().apply("clear").unwrap();
+        assert_eq!(read_both(&mut sim, 11, &keys), ("".to_string(), "t".to_string()), "seed {}", seed);
+
+        sim.run_for(1000);
+        let logs: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path().to_str().unwrap().to_string())
+            .filter(|path| path.ends_with(".txn"))
+            .collect();
+        assert!(!logs.is_empty());
+        for path in logs {
+            assert!(TxnLog::open(Some(path.clone())).is_empty(), "seed {}: {} still has records", seed, path);
+        }
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
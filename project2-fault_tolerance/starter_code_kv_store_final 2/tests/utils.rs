@@ -1,10 +1,120 @@
-use kv_store::kvs::{KVSResult, KVSSnapshot, Operation, Pid};
-use kv_store::network::{create_network_context, recv, send}; // Assuming network.rs is in the same crate
+use kv_store::kvs::{KVSResult, KVSSnapshot, Operation, Pid, ServerMetrics};
+use kv_store::membership::Membership;
+use kv_store::network::{default_membership, default_transport, recv, send, NetworkContext}; // Assuming network.rs is in the same crate
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{BufRead, BufReader, Error, ErrorKind};
-use std::path::Path;
-use std::process::{Child, Command};
+use std::net::{TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+// Port blocks handed out to tests, above the default 8000+pid range. A block
+// has room for every pid the tests use.
+const FIRST_TEST_PORT: u16 = 20000;
+const PORT_BLOCK_SIZE: u16 = 500;
+const NUM_PORT_BLOCKS: u16 = 90;
+
+static NEXT_PORT_BLOCK: AtomicU16 = AtomicU16::new(0);
+
+static NEXT_RAFT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // base port of the cluster the calling test runs, none for the default 8000
+    static BASE_PORT: Cell<Option<u16>> = const { Cell::new(None) };
+    // where the servers the calling test launches keep raft and transaction
+    // state, made on first use
+    static RAFT_DIR: RefCell<Option<TempDir>> = const { RefCell::new(None) };
+}
+
+// A directory removed once the test thread that made it ends, even by a panic
+struct TempDir(PathBuf);
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// The --raft-dir of the calling test's servers, under the system's temp
+// directory rather than the source tree, so a later test with the same pids
+// does not read back what this one left
+fn test_raft_dir() -> String {
+    RAFT_DIR.with(|cell| {
+        let mut dir = cell.borrow_mut();
+        let dir = dir.get_or_insert_with(|| {
+            let id = NEXT_RAFT_DIR.fetch_add(1, Ordering::SeqCst);
+            TempDir(std::env::temp_dir().join(format!("kv_store_raft_{}_{}", std::process::id(), id)))
+        });
+        dir.0.to_str().unwrap().to_string()
+    })
+}
+
+// Finds a block of ports nobody listens on. Test binaries start at different
+// blocks so they do not race each other for the same one.
+pub fn allocate_port_block() -> u16 {
+    let offset = (std::process::id() % NUM_PORT_BLOCKS as u32) as u16;
+    loop {
+        let block = (offset + NEXT_PORT_BLOCK.fetch_add(1, Ordering::SeqCst)) % NUM_PORT_BLOCKS;
+        let base_port = FIRST_TEST_PORT + block * PORT_BLOCK_SIZE;
+        let free = (0..20).all(|pid| {
+            UdpSocket::bind(("127.0.0.1", base_port + pid)).is_ok()
+                && TcpListener::bind(("127.0.0.1", base_port + pid)).is_ok()
+        });
+        if free {
+            return base_port;
+        }
+    }
+}
+
+// Moves the calling test onto a port block of its own: every process the
+// launch_* helpers start from this thread afterwards gets `--base-port`, and
+// get_server_snapshots talks to that cluster. Tests that call this can run
+// next to each other.
+pub fn isolate_ports() -> u16 {
+    let base_port = allocate_port_block();
+    BASE_PORT.with(|cell| cell.set(Some(base_port)));
+    base_port
+}
+
+// A context for the test process itself, on the calling test's port block.
+pub fn test_context(pid: Pid) -> NetworkContext {
+    let membership = match BASE_PORT.with(|cell| cell.get()) {
+        Some(base_port) => Arc::new(Membership::loopback(base_port)),
+        None => default_membership(),
+    };
+    NetworkContext::with_membership(&pid, default_transport(), membership).unwrap()
+}
+
+// Sends an operation to running processes from the test, e.g. an admin
+// request to the controller.
+pub fn send_admin(pids: &Vec<Pid>, operation: Operation) {
+    let magic_pid = 101;
+    let mut ctx = test_context(magic_pid);
+    for pid in pids {
+        if let Err(e) = send(&mut ctx, pid, &operation) {
+            println!("Can not send {:?} to {}: {:?}", operation, pid, e);
+        }
+    }
+    // let the reliable transport deliver before the context goes away
+    let _ = recv(&mut ctx, 500);
+}
+
+// Sends fault injection commands (see faults.rs) to running processes, e.g.
+// "partition 4 1" to cut server 4 off the controller.
+pub fn send_fault_control(pids: &Vec<Pid>, commands: &str) {
+    send_admin(pids, Operation::FaultControl(commands.to_string()));
+}
+
+fn port_args() -> Vec<String> {
+    match BASE_PORT.with(|cell| cell.get()) {
+        Some(base_port) => vec!["--base-port".to_string(), base_port.to_string()],
+        None => Vec::new(),
+    }
+}
 
 pub fn launch_controller(
     bin_dir: &str,
@@ -14,10 +124,36 @@ pub fn launch_controller(
     num_servers: &usize,
     client_pids: &Vec<usize>,
     server_pids: &Vec<usize>,
+) -> std::io::Result<Child> {
+    launch_controller_with_options(
+        bin_dir,
+        &[],
+        controller_pid_for_clients,
+        controller_pid_for_servers,
+        num_clients,
+        num_servers,
+        client_pids,
+        server_pids,
+    )
+}
+
+// Same as launch_controller, with extra flags (e.g. ["--write-quorum", "2"])
+// passed before the positional arguments.
+pub fn launch_controller_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    controller_pid_for_clients: &usize,
+    controller_pid_for_servers: &usize,
+    num_clients: &usize,
+    num_servers: &usize,
+    client_pids: &Vec<usize>,
+    server_pids: &Vec<usize>,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/controller", bin_dir));
 
     command
+        .args(options)
+        .args(port_args())
         .arg(&(controller_pid_for_clients.to_string()))
         .arg(&(controller_pid_for_servers.to_string()))
         .arg(&(num_clients.to_string()))
@@ -34,6 +170,12 @@ pub fn launch_controller(
     command.spawn()
 }
 
+// Joins pids into the comma separated list the binaries take for a
+// controller group, e.g. "0,2,4".
+pub fn pid_list_arg(pids: &Vec<usize>) -> String {
+    pids.iter().map(|pid| pid.to_string()).collect::<Vec<String>>().join(",")
+}
+
 pub fn launch_client(
     bin_dir: &str,
     self_pid: &usize,
@@ -41,12 +183,28 @@ pub fn launch_client(
     input_dir: &str,
     server_pids: &Vec<usize>,
     result_dir: &str,
+) -> std::io::Result<Child> {
+    launch_client_with_options(bin_dir, &[], self_pid, &vec![*controller_pid], input_dir, server_pids, result_dir)
+}
+
+// Same as launch_client, with extra flags (e.g. ["--transport", "tcp"]) passed
+// before the positional arguments and the pids of every controller replica.
+pub fn launch_client_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    self_pid: &usize,
+    controller_pids: &Vec<usize>,
+    input_dir: &str,
+    server_pids: &Vec<usize>,
+    result_dir: &str,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/client", bin_dir));
 
     command
+        .args(options)
+        .args(port_args())
         .arg(&(self_pid.to_string()))
-        .arg(&(controller_pid.to_string()))
+        .arg(&pid_list_arg(controller_pids))
         .arg(input_dir)
         .arg(result_dir);
 
@@ -57,17 +215,47 @@ pub fn launch_client(
     command.spawn()
 }
 
+// A client reading shell commands (see shell.rs) from a pipe the test writes
+pub fn launch_shell(bin_dir: &str, self_pid: &usize, controller_pids: &Vec<usize>) -> std::io::Result<Child> {
+    Command::new(format!("{}/client", bin_dir))
+        .arg("--shell")
+        .args(port_args())
+        .arg(self_pid.to_string())
+        .arg(pid_list_arg(controller_pids))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+}
+
 pub fn launch_server(
     bin_dir: &str,
     self_pid: &usize,
     controller_pid: &usize,
     server_pids: &Vec<usize>,
+) -> std::io::Result<Child> {
+    launch_server_with_options(bin_dir, &[], self_pid, &vec![*controller_pid], server_pids)
+}
+
+// Same as launch_server, with extra flags (e.g. ["--raft-dir", dir]) passed
+// before the positional arguments and the pids of every controller replica.
+// Without a --raft-dir the server gets the calling test's own.
+pub fn launch_server_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    self_pid: &usize,
+    controller_pids: &Vec<usize>,
+    server_pids: &Vec<usize>,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/server", bin_dir));
 
+    command.args(options);
+    if !options.contains(&"--raft-dir") {
+        command.arg("--raft-dir").arg(test_raft_dir());
+    }
     command
+        .args(port_args())
         .arg(&(self_pid.to_string()))
-        .arg(&(controller_pid.to_string()));
+        .arg(&pid_list_arg(controller_pids));
 
     for server_pid in server_pids {
         command.arg(&(server_pid.to_string()));
@@ -171,7 +359,7 @@ pub fn group_result_by_key(
 
 pub fn get_server_snapshots(server_pids: &Vec<Pid>) -> Result<Vec<KVSSnapshot>, std::io::Error> {
     let magic_pid = 100;
-    let mut ctx = create_network_context(&magic_pid).unwrap();
+    let mut ctx = test_context(magic_pid);
     let mut snapshots = Vec::new();
     for server_pid in server_pids {
         if let Err(e) = send(&mut ctx, &server_pid, &Operation::Snapshot()) {
@@ -199,3 +387,30 @@ pub fn get_server_snapshots(server_pids: &Vec<Pid>) -> Result<Vec<KVSSnapshot>,
     }
     Ok(snapshots)
 }
+
+// The request queue depths of every server that answered, in order
+pub fn get_server_metrics(server_pids: &Vec<Pid>) -> Vec<(Pid, ServerMetrics)> {
+    let magic_pid = 102;
+    let mut ctx = test_context(magic_pid);
+    let mut metrics = Vec::new();
+    for server_pid in server_pids {
+        if let Err(e) = send(&mut ctx, server_pid, &Operation::Metrics()) {
+            println!("Can not send metrics request to server: {:?}", e);
+            continue;
+        }
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(1) {
+            if let Ok(responses) = recv(&mut ctx, 100) {
+                let found = responses.into_iter().find_map(|(pid, operation)| match operation {
+                    Operation::MetricsRes(server_metrics) if pid == *server_pid => Some(server_metrics),
+                    _ => None,
+                });
+                if let Some(server_metrics) = found {
+                    metrics.push((*server_pid, server_metrics));
+                    break;
+                }
+            }
+        }
+    }
+    metrics
+}
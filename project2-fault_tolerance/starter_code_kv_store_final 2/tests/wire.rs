@@ -0,0 +1,447 @@
+mod utils;
+
+use bincode::Options;
+use kv_store::kvs::{
+    KVSResult, KVSSnapshot, Operation, Pid, ReadBounds, ReplicationMode, ServerMetrics, ShardInfo, ShardLoc, TxnRecord,
+};
+use kv_store::network::{default_membership, recv, send, NetworkContext, Transport, TransportKind};
+use kv_store::raft::{LogEntry, RaftMessage};
+use kv_store::tcp::TcpTransport;
+use kv_store::wire::{decode, encode, encode_version, message_tag, WireFormat, HEADER_LEN, MIN_WIRE_VERSION, WIRE_VERSION};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use utils::{
+    group_result_by_key, isolate_ports, launch_client_with_options, launch_controller_with_options,
+    launch_server_with_options, read_result,
+};
+
+// One operation of every variant
+fn every_operation() -> Vec<Operation> {
+    let mut shard_info = ShardInfo::new();
+    shard_info.locations.insert(0, ShardLoc { primary: 4, secondaries: vec![5, 6] });
+    shard_info.hot_keys = vec!["key".to_string()];
+    shard_info.lease_ms = 3000;
+    let mut snapshot = KVSSnapshot::new();
+    snapshot.primary_shards.insert("k".to_string(), "v".to_string());
+    let entry = LogEntry {
+        term: 3,
+        client: 2,
+        command: Some(Operation::Put("k".to_string(), "v".to_string(), 1)),
+    };
+    vec![
+        Operation::Get("key".to_string(), 1),
+        Operation::Put("key".to_string(), "value".to_string(), 2),
+        Operation::Delete("key".to_string(), 3),
+        Operation::GetRes(Some("value".to_string()), 1),
+        Operation::PutRes(None, 2),
+        Operation::DeleteRes(Some("value".to_string()), 3),
+        Operation::GetShardInfo(),
+        Operation::GetShardInfoRes(shard_info.clone()),
+        Operation::PutShardInfo(shard_info),
+        Operation::PutShardInfoRes(),
+        Operation::ControllerRaft(RaftMessage::RequestVote { term: 2, last_log_index: 7, last_log_term: 1 }),
+        Operation::Replicate("key".to_string(), None, 9),
+        Operation::ReplicateRes("key".to_string(), Some("old".to_string()), 9),
+        Operation::ReadVersion("key".to_string(), 4),
+        Operation::ReadVersionRes("key".to_string(), Some("value".to_string()), 9, 4),
+        Operation::Raft(
+            1,
+            RaftMessage::AppendEntries {
+                term: 3,
+                prev_log_index: 1,
+                prev_log_term: 2,
+                entries: vec![entry],
+                leader_commit: 1,
+            },
+        ),
+        Operation::RaftForward(2, Box::new(Operation::Get("key".to_string(), 5))),
+        Operation::Snapshot(),
+        Operation::SnapshotRes(snapshot),
+        Operation::FaultControl("drop prob=0.1".to_string()),
+        Operation::AddServer(7),
+        Operation::MoveShard(3, 7),
+        Operation::MigrateShard(3, 7, 2),
+        Operation::MigrateShardRes(3, 2),
+        Operation::ShardData(3, 2, vec![("k".to_string(), Some("v".to_string()), 4), ("gone".to_string(), None, 5)]),
+        Operation::ShardDataDone(3, 2, 2),
+        Operation::ShardDataRes(3, 2, 2),
+        Operation::Forward(2, Box::new(Operation::Delete("key".to_string(), 6))),
+        Operation::Metrics(),
+        Operation::MetricsRes(ServerMetrics {
+            parked_requests: 3,
+            parked_keys: 2,
+            max_key_depth: 2,
+            peak_key_depth: 5,
+            total_parked: 40,
+            queued_requests: 6,
+            busy_requests: 9,
+        }),
+        Operation::Session(Box::new(Operation::Put("key".to_string(), "value".to_string(), 7))),
+        Operation::ReadReplica("key".to_string(), 8, ReadBounds { max_staleness_ms: Some(500), min_version: 12 }),
+        Operation::SessionRes(Box::new(Operation::GetRes(None, 8)), 12),
+        Operation::ReadRefused(8),
+        Operation::ShardVersions(vec![(0, 12), (3, 0)]),
+        Operation::MultiGet(vec!["a".to_string(), "b".to_string()], 9),
+        Operation::MultiPut(vec![("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string())], 10),
+        Operation::MultiDelete(vec!["a".to_string()], 11),
+        Operation::MultiGetRes(vec![("a".to_string(), Some("1".to_string())), ("b".to_string(), None)], 9),
+        Operation::MultiPutRes(vec![("a".to_string(), None), ("b".to_string(), Some("0".to_string()))], 10),
+        Operation::MultiDeleteRes(vec![("a".to_string(), Some("1".to_string()))], 11),
+        Operation::ReplicateBatch(3, vec![("a".to_string(), Some("1".to_string())), ("b".to_string(), None)], 13),
+        Operation::ReplicateBatchRes(3, 13),
+        Operation::ReadVersions(3, vec!["a".to_string(), "b".to_string()], 5),
+        Operation::ReadVersionsRes(3, vec![("a".to_string(), Some("1".to_string()), 13), ("b".to_string(), None, 0)], 5),
+        Operation::Cas("key".to_string(), Some("old".to_string()), None, 12),
+        Operation::PutIfAbsent("key".to_string(), "value".to_string(), 13),
+        Operation::Incr("counter".to_string(), -3, 14),
+        Operation::CasRes(true, Some("old".to_string()), 12),
+        Operation::PutIfAbsentRes(None, 13),
+        Operation::IncrRes(Some("10".to_string()), Some(7), 14),
+        Operation::TxnCommit(vec![("a".to_string(), None)], vec![("b".to_string(), Some("1".to_string()))], 15),
+        Operation::TxnCommitRes(true, 15),
+        Operation::TxnPrepare((2, 15), 1, 3, vec![("a".to_string(), None)], vec![("b".to_string(), None)]),
+        Operation::TxnPrepareRes((2, 15), 1, 3, false),
+        Operation::TxnDecide((2, 15), 1, 3, true),
+        Operation::TxnDecideRes((2, 15), 1, 3),
+        Operation::TxnStatus((2, 15), 1, 3),
+        Operation::TxnReplicate(3, (2, 15), TxnRecord::Prepared(1, vec![("b".to_string(), None)], vec!["b".to_string()]), 6),
+        Operation::TxnReplicateRes(3, 6),
+        Operation::HotKeys(vec![("key".to_string(), 1200), ("other".to_string(), 0)]),
+        Operation::Busy(8, 10),
+        Operation::TxnForget((2, 15), 3),
+        Operation::Stale(8, 12),
+    ]
+}
+
+#[test]
+fn test_round_trip_every_operation() {
+    let operations = every_operation();
+    for operation in &operations {
+        for format in [WireFormat::Binary, WireFormat::Json] {
+            let decoded = decode(&encode(operation, format)).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", operation));
+        }
+    }
+    // every variant has a tag of its own
+    let mut tags: Vec<u8> = operations.iter().map(message_tag).collect();
+    tags.sort();
+    tags.dedup();
+    assert_eq!(tags.len(), operations.len());
+}
+
+#[test]
+fn test_binary_header() {
+    let operation = Operation::Put("key".to_string(), "v".repeat(100), 1_000_000);
+    let data = encode(&operation, WireFormat::Binary);
+    assert_eq!(data[0], WIRE_VERSION);
+    assert_eq!(data[1], message_tag(&operation));
+    let length = u32::from_be_bytes([data[2], data[3], data[4], data[5]]) as usize;
+    assert_eq!(length, data.len() - HEADER_LEN);
+    assert!(data.len() < encode(&operation, WireFormat::Json).len());
+}
+
+#[test]
+fn test_undecodable_messages_are_errors() {
+    let data = encode(&Operation::Get("key".to_string(), 1), WireFormat::Binary);
+
+    assert!(decode(&[]).is_err());
+    assert!(decode(&data[..3]).is_err());
+    // cut short and with trailing garbage, so the length does not match
+    assert!(decode(&data[..data.len() - 1]).is_err());
+    let mut longer = data.clone();
+    longer.push(0);
+    assert!(decode(&longer).is_err());
+    // a version we do not know
+    let mut newer = data.clone();
+    newer[0] = WIRE_VERSION + 1;
+    assert!(decode(&newer).is_err());
+    let mut older = data.clone();
+    older[0] = MIN_WIRE_VERSION - 1;
+    assert!(decode(&older).is_err());
+    assert!(encode_version(&Operation::Get("key".to_string(), 1), MIN_WIRE_VERSION - 1).is_err());
+    // a tag that does not match the payload
+    let mut retagged = data.clone();
+    retagged[1] = message_tag(&Operation::Snapshot());
+    assert!(decode(&retagged).is_err());
+    // a payload that is not an operation
+    let mut garbage = data[..HEADER_LEN].to_vec();
+    garbage[2..HEADER_LEN].copy_from_slice(&4u32.to_be_bytes());
+    garbage.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+    assert!(decode(&garbage).is_err());
+    assert!(decode(b"{\"Get\":").is_err());
+}
+
+#[test]
+fn test_every_supported_version_round_trips() {
+    for version in MIN_WIRE_VERSION..=WIRE_VERSION {
+        for operation in every_operation() {
+            let data = encode_version(&operation, version).unwrap();
+            assert_eq!(data[0], version);
+            let decoded = decode(&data).unwrap();
+            assert_eq!(message_tag(&decoded), message_tag(&operation));
+        }
+    }
+    // what an older version cannot carry comes back as the default
+    let mut info = ShardInfo::new();
+    info.num_shards = 8;
+    info.epoch = 3;
+    info.hot_keys = vec!["key".to_string()];
+    match decode(&encode_version(&Operation::PutShardInfo(info.clone()), 4).unwrap()).unwrap() {
+        Operation::PutShardInfo(decoded) => {
+            assert_eq!((decoded.num_shards, decoded.epoch), (8, 3));
+            assert!(decoded.hot_keys.is_empty());
+        }
+        op => panic!("unexpected operation {:?}", op),
+    }
+    match decode(&encode_version(&Operation::PutShardInfo(info), 5).unwrap()).unwrap() {
+        Operation::PutShardInfo(decoded) => assert_eq!(decoded.hot_keys, vec!["key".to_string()]),
+        op => panic!("unexpected operation {:?}", op),
+    }
+    let metrics = ServerMetrics {
+        parked_requests: 3,
+        queued_requests: 6,
+        busy_requests: 9,
+        ..ServerMetrics::default()
+    };
+    let expected = ServerMetrics {
+        parked_requests: 3,
+        ..ServerMetrics::default()
+    };
+    match decode(&encode_version(&Operation::MetricsRes(metrics), 5).unwrap()).unwrap() {
+        Operation::MetricsRes(decoded) => assert_eq!(decoded, expected),
+        op => panic!("unexpected operation {:?}", op),
+    }
+    let decided = Operation::TxnReplicate(1, (2, 15), TxnRecord::Decided(true, vec![1, 3]), 7);
+    match decode(&encode_version(&decided, 6).unwrap()).unwrap() {
+        Operation::TxnReplicate(_, _, record, _) => assert_eq!(record, TxnRecord::Decided(true, Vec::new())),
+        op => panic!("unexpected operation {:?}", op),
+    }
+    for format in [WireFormat::Binary, WireFormat::Json] {
+        match decode(&encode(&decided, format)).unwrap() {
+            Operation::TxnReplicate(_, _, record, _) => assert_eq!(record, TxnRecord::Decided(true, vec![1, 3])),
+            op => panic!("unexpected operation {:?}", op),
+        }
+    }
+}
+
+// PutShardInfo exactly as a version 4 process lays it out, written without
+// going through this crate's encoder
+#[derive(Serialize)]
+struct ShardInfoV4 {
+    locations: HashMap<u32, ShardLoc>,
+    replication: ReplicationMode,
+    lease_ms: u64,
+    num_shards: u32,
+    epoch: u64,
+}
+
+#[test]
+fn test_decode_messages_of_older_processes() {
+    let mut locations = HashMap::new();
+    locations.insert(0, ShardLoc { primary: 4, secondaries: vec![5] });
+    let info = ShardInfoV4 {
+        locations,
+        replication: ReplicationMode::PrimaryBackup,
+        lease_ms: 3000,
+        num_shards: 1,
+        epoch: 7,
+    };
+    // PutShardInfo is variant 8, which bincode writes as a one byte varint
+    let mut payload = vec![8];
+    payload.extend(bincode::DefaultOptions::new().serialize(&info).unwrap());
+    let mut data = vec![4, message_tag(&Operation::PutShardInfo(ShardInfo::new()))];
+    data.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    data.extend_from_slice(&payload);
+    match decode(&data).unwrap() {
+        Operation::PutShardInfo(decoded) => {
+            assert_eq!(decoded.locations[&0].secondaries, vec![5]);
+            assert_eq!((decoded.lease_ms, decoded.num_shards, decoded.epoch), (3000, 1, 7));
+            assert!(decoded.hot_keys.is_empty());
+        }
+        op => panic!("unexpected operation {:?}", op),
+    }
+
+    // and as JSON, which has no version
+    let json = br#"{"PutShardInfo":{"locations":{},"replication":"PrimaryBackup","lease_ms":0,"num_shards":4,"epoch":2}}"#;
+    match decode(json).unwrap() {
+        Operation::PutShardInfo(decoded) => assert!(decoded.num_shards == 4 && decoded.hot_keys.is_empty()),
+        op => panic!("unexpected operation {:?}", op),
+    }
+    let json = br#"{"MetricsRes":{"parked_requests":1,"parked_keys":1,"max_key_depth":1,"peak_key_depth":2,"total_parked":5}}"#;
+    match decode(json).unwrap() {
+        Operation::MetricsRes(decoded) => assert_eq!((decoded.total_parked, decoded.busy_requests), (5, 0)),
+        op => panic!("unexpected operation {:?}", op),
+    }
+    let json = br#"{"TxnReplicate":[1,[2,15],{"Decided":true},7]}"#;
+    match decode(json).unwrap() {
+        Operation::TxnReplicate(_, _, record, _) => assert_eq!(record, TxnRecord::Decided(true, Vec::new())),
+        op => panic!("unexpected operation {:?}", op),
+    }
+}
+
+// Receives until count operations arrived or the timeout passed.
+fn recv_n(ctx: &mut NetworkContext, count: usize, timeout_ms: u64) -> Vec<(Pid, Operation)> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < Duration::from_millis(timeout_ms) {
+        if let Ok(messages) = recv(ctx, 100) {
+            received.extend(messages);
+        }
+    }
+    received
+}
+
+#[test]
+fn test_json_and_binary_peers_talk() {
+    let mut old_peer = NetworkContext::new(&260, TransportKind::Tcp).unwrap();
+    old_peer.set_wire_format(WireFormat::Json);
+    let mut new_peer = NetworkContext::new(&261, TransportKind::Tcp).unwrap();
+
+    send(&mut old_peer, &261, &Operation::Get("key".to_string(), 1)).unwrap();
+    let received = recv_n(&mut new_peer, 1, 1000);
+    assert!(matches!(received[..], [(260, Operation::Get(_, 1))]));
+
+    send(&mut new_peer, &260, &Operation::GetRes(Some("value".to_string()), 1)).unwrap();
+    let received = recv_n(&mut old_peer, 1, 1000);
+    assert!(matches!(received[..], [(261, Operation::GetRes(_, 1))]));
+
+    assert_eq!(old_peer.dropped_messages(), 0);
+    assert_eq!(new_peer.dropped_messages(), 0);
+}
+
+#[test]
+fn test_peers_on_the_previous_version_talk() {
+    let mut old_peer = NetworkContext::new(&264, TransportKind::Tcp).unwrap();
+    old_peer.set_wire_version(WIRE_VERSION - 1).unwrap();
+    let mut new_peer = NetworkContext::new(&265, TransportKind::Tcp).unwrap();
+    assert!(new_peer.set_wire_version(WIRE_VERSION + 1).is_err());
+
+    send(&mut old_peer, &265, &Operation::Metrics()).unwrap();
+    let received = recv_n(&mut new_peer, 1, 1000);
+    assert!(matches!(received[..], [(264, Operation::Metrics())]));
+
+    send(&mut new_peer, &264, &Operation::GetRes(Some("value".to_string()), 1)).unwrap();
+    let received = recv_n(&mut old_peer, 1, 1000);
+    assert!(matches!(received[..], [(265, Operation::GetRes(_, 1))]));
+
+    assert_eq!(old_peer.dropped_messages(), 0);
+    assert_eq!(new_peer.dropped_messages(), 0);
+}
+
+#[test]
+fn test_malformed_messages_are_dropped_and_counted() {
+    let membership = default_membership();
+    let mut raw = TcpTransport::bind(&262, membership.addr_of(&262).unwrap(), membership.clone()).unwrap();
+    let mut receiver = NetworkContext::new(&263, TransportKind::Tcp).unwrap();
+
+    raw.send(&263, b"not an operation").unwrap();
+    raw.send(&263, &[WIRE_VERSION, 0, 0, 0, 0, 9, 1]).unwrap();
+    raw.send(&263, &encode(&Operation::Snapshot(), WireFormat::Binary)).unwrap();
+
+    // the good message still gets through behind the two bad ones
+    let received = recv_n(&mut receiver, 1, 1000);
+    assert!(matches!(received[..], [(262, Operation::Snapshot())]));
+    assert_eq!(receiver.dropped_messages(), 2);
+    // clones share the count
+    assert_eq!(receiver.clone().dropped_messages(), 2);
+}
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// A rolling upgrade caught half way: the controller and every other server
+// still send JSON, the rest of the cluster sends binary.
+#[test]
+fn test_read_your_write_with_mixed_wire_formats() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_mixed_wire";
+    let json = ["--wire", "json"];
+    let num_clients = 2;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for (index, server_pid) in server_pids.iter().enumerate() {
+        let options: &[&str] = if index % 2 == 0 { &json } else { &[] };
+        let server =
+            launch_server_with_options(bin_dir, options, server_pid, &vec![controller_pid_for_servers], &server_pids)
+                .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    let mut controller = launch_controller_with_options(
+        bin_dir,
+        &json,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            bin_dir,
+            &[],
+            client_pid,
+            &vec![controller_pid_for_clients],
+            input_dir,
+            &server_pids,
+            result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        result_paths.push(format!("{}{}.txt", result_dir, &client_pid));
+    }
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
@@ -0,0 +1,308 @@
+mod utils;
+
+use kv_store::client::ClientNode;
+use kv_store::controller::assign_shards_to_servers;
+use kv_store::kvs::{KVSResult, Operation, Pid, ReplicationMode, ShardInfo, KVS};
+use kv_store::raft::RaftMessage;
+use kv_store::ring::DEFAULT_VNODES;
+use kv_store::server::{worker_of, Dispatcher, Route};
+use kv_store::sim::Simulation;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::HashMap;
+use std::process::Child;
+use std::thread;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{
+    get_server_metrics, group_result_by_key, isolate_ports, launch_client, launch_controller,
+    launch_server_with_options, read_result,
+};
+
+const DEADLINE_MS: u64 = 300_000;
+const NUM_WORKERS: usize = 4;
+
+fn worker(route: Route) -> Option<usize> {
+    match route {
+        Route::Worker(worker) => Some(worker),
+        _ => None,
+    }
+}
+
+#[test]
+fn test_dispatcher_sends_a_shard_to_one_worker() {
+    let mut dispatcher = Dispatcher::new(NUM_WORKERS);
+    // nothing to route by before the first ShardInfo
+    assert_eq!(worker(dispatcher.route(&Operation::Get("key".to_string(), 1))), Some(0));
+
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, &[4, 5, 6], 64, 3, DEFAULT_VNODES);
+    assert!(matches!(dispatcher.route(&Operation::PutShardInfo(info.clone())), Route::Every));
+    assert!(matches!(dispatcher.route(&Operation::Metrics()), Route::Dispatcher));
+
+    let mut used = vec![false; NUM_WORKERS];
+    for i in 0..100 {
+        let key = format!("key_{}", i);
+        let shard_id = info.shard_of(&key);
+        let owner = worker_of(shard_id, NUM_WORKERS);
+        used[owner] = true;
+        let get = Operation::Get(key.clone(), 1);
+        let operations = vec![
+            Operation::Put(key.clone(), "v".to_string(), 1),
+            get.clone(),
+            Operation::Delete(key.clone(), 1),
+            Operation::Forward(2, Box::new(get.clone())),
+            Operation::RaftForward(2, Box::new(get)),
+            Operation::Replicate(key.clone(), None, 3),
+            Operation::ReplicateRes(key.clone(), None, 3),
+            Operation::ReadVersion(key.clone(), 7),
+            Operation::ReadVersionRes(key.clone(), None, 3, 7),
+            Operation::Raft(shard_id, RaftMessage::RequestVote { term: 1, last_log_index: 0, last_log_term: 0 }),
+            Operation::MigrateShard(shard_id, 5, 2),
+            Operation::ShardData(shard_id, 2, vec![]),
+            Operation::ShardDataDone(shard_id, 2, 0),
+            Operation::ShardDataRes(shard_id, 2, 0),
+            Operation::MultiGet(vec![key.clone()], 1),
+            Operation::MultiPut(vec![(key.clone(), "v".to_string())], 1),
+            Operation::MultiDelete(vec![key.clone()], 1),
+            Operation::Session(Box::new(Operation::MultiGet(vec![key.clone()], 1))),
+            Operation::ReplicateBatch(shard_id, vec![(key.clone(), None)], 3),
+            Operation::ReplicateBatchRes(shard_id, 3),
+            Operation::ReadVersions(shard_id, vec![key.clone()], 7),
+            Operation::ReadVersionsRes(shard_id, vec![], 7),
+        ];
+        for operation in &operations {
+            assert_eq!(worker(dispatcher.route(operation)), Some(owner), "{:?}", operation);
+        }
+    }
+    assert!(used.iter().all(|used| *used));
+}
+
+#[test]
+fn test_kvs_is_shared_by_threads() {
+    let kvs = KVS::new();
+    let threads: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let kvs = kvs.clone();
+            thread::spawn(move || {
+                for i in 0..1000 {
+                    let key = format!("t{}_k{}", thread_id, i);
+                    kvs.apply_versioned(key.clone(), Some("old".to_string()), 1);
+                    kvs.apply_versioned(key.clone(), Some(format!("v{}", i)), 3);
+                    // late and out of order, ignored
+                    kvs.apply_versioned(key.clone(), Some("stale".to_string()), 2);
+                    if i % 10 == 0 {
+                        kvs.apply_versioned(key, None, 4);
+                    }
+                }
+            })
+        })
+        .collect();
+    for handle in threads {
+        handle.join().unwrap();
+    }
+
+    let table = kvs.inner_table();
+    assert_eq!(table.len(), 8 * 900);
+    assert_eq!(kvs.get_versioned("t3_k7"), (Some("v7".to_string()), 3));
+    assert_eq!(kvs.get_versioned("t3_k10"), (None, 4));
+    let entries = kvs.versioned_entries();
+    assert_eq!(entries.len(), 8 * 1000);
+    for (key, value, version) in entries {
+        assert_eq!(value, table.get(&key).cloned());
+        assert_eq!(version, if value.is_some() { 3 } else { 4 });
+    }
+}
+
+// num_ops random operations of one client over its own keys
+fn workload(seed: u64, client: usize, num_ops: usize) -> Vec<Operation> {
+    let mut rng = StdRng::seed_from_u64(seed ^ (client as u64) << 32);
+    (0..num_ops)
+        .map(|seq_no| {
+            let key = format!("c{}_k{}", client, rng.gen_range(0..20));
+            match rng.gen_range(0..10) {
+                0..=3 => Operation::Get(key, seq_no),
+                4..=8 => Operation::Put(key, format!("v{}_{}", client, seq_no), seq_no),
+                _ => Operation::Delete(key, seq_no),
+            }
+        })
+        .collect()
+}
+
+// Checks read your write and records the value every key was left with
+fn verify_read_your_write(results: &[KVSResult], last_written: &mut HashMap<String, String>) {
+    for op_result in results {
+        if op_result.operation == "put" {
+            last_written.insert(op_result.key.clone(), op_result.new_value.clone());
+        } else if op_result.operation == "delete" {
+            last_written.insert(op_result.key.clone(), "".to_string());
+        } else {
+            assert!(op_result.operation == "get");
+            let expected = last_written.get(&op_result.key).cloned().unwrap_or_default();
+            assert_eq!(op_result.observed_value, expected, "stale read of {}", op_result.key);
+        }
+    }
+}
+
+// Reads every key back with a fresh client once the workload is over
+fn check_no_write_lost(sim: &mut Simulation, client_pids: &[Pid], reader_pid: Pid) {
+    let mut last_written = HashMap::new();
+    for client_pid in client_pids {
+        verify_read_your_write(&sim.results(client_pid), &mut last_written);
+    }
+    let mut keys: Vec<String> = last_written.keys().cloned().collect();
+    keys.sort();
+    let reads = keys.iter().enumerate().map(|(seq_no, key)| Operation::Get(key.clone(), seq_no)).collect();
+    sim.add(Box::new(ClientNode::new(reader_pid, vec![0], reads, sim.clock())));
+    assert!(sim.run_until(DEADLINE_MS * 2, |sim| sim.clients_done()));
+    for result in sim.results(&reader_pid) {
+        assert_eq!(result.observed_value, last_written[&result.key], "lost write of {}", result.key);
+    }
+}
+
+#[test]
+fn test_sim_workers_in_every_replication_mode() {
+    let faults = "duplicate prob=0.05; reorder prob=0.05; delay ms=30 prob=0.05";
+    let modes = [ReplicationMode::PrimaryBackup, ReplicationMode::Quorum { write: 2, read: 2 }, ReplicationMode::Raft];
+    for seed in 0..10 {
+        for replication in modes {
+            let mut sim = Simulation::new(seed);
+            sim.faults_mut().apply(faults).unwrap();
+            let workloads = (0..4).map(|client| workload(seed, client, 60)).collect();
+            let (client_pids, server_pids) = sim.add_cluster_with_workers(workloads, 3, replication, NUM_WORKERS);
+            assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+            check_no_write_lost(&mut sim, &client_pids, server_pids[2] + 1);
+            for server_pid in &server_pids {
+                assert_eq!(sim.metrics(server_pid).unwrap().parked_requests, 0);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_sim_workers_primary_crash() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..4).map(|client| workload(seed, client, 60)).collect();
+        let (client_pids, server_pids) =
+            sim.add_cluster_with_workers(workloads, 3, ReplicationMode::PrimaryBackup, NUM_WORKERS);
+        sim.run_for(200 + seed * 10);
+        sim.crash(&server_pids[0]);
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done()), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, server_pids[2] + 1);
+    }
+}
+
+#[test]
+fn test_sim_workers_move_shards() {
+    for seed in 0..10 {
+        let mut sim = Simulation::new(seed);
+        let workloads = (0..2).map(|client| workload(seed, client, 200)).collect();
+        let (client_pids, server_pids) =
+            sim.add_cluster_with_workers(workloads, 3, ReplicationMode::PrimaryBackup, NUM_WORKERS);
+        sim.run_for(100 + seed * 10);
+
+        // every shard of the first server goes to the second one
+        let info = sim.shard_info(&0).unwrap();
+        let mut shard_ids: Vec<u32> = info.locations.keys().cloned().collect();
+        shard_ids.sort();
+        for shard_id in shard_ids {
+            if info.locations[&shard_id].primary == server_pids[0] {
+                sim.send(100, 0, Operation::MoveShard(shard_id, server_pids[1]));
+            }
+        }
+        let moved = |sim: &Simulation| {
+            let info = sim.shard_info(&0).unwrap();
+            info.locations.values().all(|location| location.primary != server_pids[0])
+        };
+        assert!(sim.run_until(DEADLINE_MS, |sim| sim.clients_done() && moved(sim)), "seed {} did not finish", seed);
+        check_no_write_lost(&mut sim, &client_pids, server_pids[2] + 1);
+    }
+}
+
+fn verify_read_your_write_per_key(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Read your write against servers of 4 workers each, which answer Metrics
+// for all of their workers at once
+#[test]
+fn test_read_your_write_with_workers() {
+    isolate_ports();
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_workers";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            bin_dir,
+            &["--workers", "4"],
+            server_pid,
+            &vec![controller_pid_for_servers],
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller(
+        bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(bin_dir, client_pid, &controller_pid_for_clients, input_dir, &server_pids, result_dir)
+            .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    let metrics = get_server_metrics(&server_pids.iter().map(|pid| *pid as Pid).collect());
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    assert_eq!(metrics.len(), num_servers, "not every server answered: {:?}", metrics);
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write_per_key(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
@@ -0,0 +1,19 @@
+import os
+
+def generate_trace_files(file_paths, num_incrs_per_file):
+
+    # Every client first tries to claim the same key, then counts on one
+    # shared counter, so the counter's exclusive copy moves between servers
+    for i, file_path in enumerate(file_paths):
+        with open(file_path, "w") as f:
+            f.write(f"putifabsent owner client_{i}\n")
+            for _ in range(num_incrs_per_file):
+                f.write("incr counter 1\n")
+            f.write("cas owner - -\n")
+
+if __name__ == '__main__':
+    num_files = 3
+    file_path = './cond'  # Directory to store trace files
+    num_incrs_per_file = 500
+
+    generate_trace_files([file_path + str(i + 2) + ".txt" for i in range(num_files)], num_incrs_per_file)
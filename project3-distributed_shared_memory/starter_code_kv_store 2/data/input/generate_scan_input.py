@@ -0,0 +1,26 @@
+import os
+
+def generate_trace_files(writer_path, reader_path, num_keys):
+
+    # The writer fills the key space in order, for the controller to split
+    # under range partitioning, and the reader scans across the ranges once
+    # they are spread over the servers
+    with open(writer_path, "w") as f:
+        for i in range(num_keys):
+            f.write(f"put key_{i:03} v{i}\n")
+        f.write("scan key_000 - 1000\n")
+
+    with open(reader_path, "w") as f:
+        f.write("scan key_000 - 1000\n")
+        f.write("scan key_050 key_150 30\n")
+        f.write("put key_120 new\n")
+        f.write("delete key_121\n")
+        f.write("scan key_100 key_130 1000\n")
+        f.write(f"scan key_{num_keys - 1:03} - 10\n")
+        f.write("scan zzz - 10\n")
+
+if __name__ == '__main__':
+    file_path = './scan'  # Directory to store trace files
+    num_keys = 200
+
+    generate_trace_files(file_path + "2.txt", file_path + "3.txt", num_keys)
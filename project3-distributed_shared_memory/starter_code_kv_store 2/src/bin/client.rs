@@ -1,3 +1,4 @@
+use kv_store::cli::{take_faults, take_flag, take_transport};
 use kv_store::kvs::*;
 use kv_store::network::*; // Assuming network.rs is in the same crate
 use std::env;
@@ -66,6 +67,41 @@ fn get_timestamp() -> Result<u128, SystemTimeError> {
     }
 }
 
+// The value a conditional write left its key with, "" if it is absent
+fn conditional_value(request: &Operation, response: &Operation) -> String {
+    let value = match (request, response) {
+        (Operation::Cas(_, _, new, _), Operation::CasRes(true, _, _)) => new.clone(),
+        (Operation::PutIfAbsent(_, value, _), Operation::PutIfAbsentRes(None, _)) => Some(value.clone()),
+        (_, Operation::IncrRes(_, Some(new), _)) => Some(new.to_string()),
+        (_, Operation::CasRes(_, old, _))
+        | (_, Operation::PutIfAbsentRes(old, _))
+        | (_, Operation::IncrRes(old, _, _)) => old.clone(),
+        _ => None,
+    };
+    value.unwrap_or_default()
+}
+
+// A scan comes back in one ScanRes per range it went through, the last one
+// says there is no more. None if the rest does not come in time.
+fn recv_scan(ctx: &mut NetworkContext, messages: &[(Pid, Operation)]) -> Option<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    let mut messages = messages.to_vec();
+    loop {
+        for (_, operation) in messages {
+            if let Operation::ScanRes(chunk, more, _) = operation {
+                entries.extend(chunk);
+                if !more {
+                    return Some(entries);
+                }
+            }
+        }
+        messages = match recv(ctx, 1000) {
+            Ok(messages) => messages,
+            Err(_) => return None,
+        };
+    }
+}
+
 fn run_client(
     self_pid: Pid,
     controller_pid: Pid,
@@ -85,16 +121,25 @@ fn run_client(
         let shard_id = match operation {
             Operation::Get(key_, _) => {
                 key = key_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
+                shard_info.shard_of(key)
             }
             Operation::Put(key_, value_, _) => {
                 key = key_;
                 put_value = value_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
+                shard_info.shard_of(key)
             }
             Operation::Delete(key_, _) => {
                 key = key_;
-                get_shard_id_from_key(&key, shard_info.locations.len())
+                shard_info.shard_of(key)
+            }
+            Operation::Cas(key_, _, _, _) | Operation::PutIfAbsent(key_, _, _) | Operation::Incr(key_, _, _) => {
+                key = key_;
+                shard_info.shard_of(key)
+            }
+            Operation::Scan(start_, end_, _, _) => {
+                key = start_;
+                put_value = end_.as_deref().unwrap_or("");
+                shard_info.shard_of(key)
             }
             _ => todo!(),
         };
@@ -142,6 +187,41 @@ fn run_client(
                             begin_time: begin_time,
                             end_time: end_time,
                         }),
+                        Operation::CasRes(_, observed_value, _)
+                        | Operation::PutIfAbsentRes(observed_value, _)
+                        | Operation::IncrRes(observed_value, _, _) => results.push(KVSResult {
+                            operation: match result_operation {
+                                Operation::CasRes(..) => "cas".to_string(),
+                                Operation::PutIfAbsentRes(..) => "putifabsent".to_string(),
+                                _ => "incr".to_string(),
+                            },
+                            key: key.clone(),
+                            observed_value: match observed_value {
+                                Some(val) => val.clone(),
+                                None => "".to_string(),
+                            },
+                            new_value: conditional_value(operation, result_operation),
+                            begin_time: begin_time,
+                            end_time: end_time,
+                        }),
+                        Operation::ScanRes(..) => match recv_scan(&mut ctx, &messages) {
+                            Some(entries) => results.push(KVSResult {
+                                operation: "scan".to_string(),
+                                key: key.clone(),
+                                observed_value: entries
+                                    .iter()
+                                    .map(|(key, value)| format!("{}={}", key, value))
+                                    .collect::<Vec<String>>()
+                                    .join(" "),
+                                new_value: put_value.to_string(),
+                                begin_time: begin_time,
+                                end_time: get_timestamp().unwrap(),
+                            }),
+                            None => {
+                                println!("Client scan timeout, retry...");
+                                continue;
+                            }
+                        },
                         _ => todo!(),
                     }
                     operation_index += 1;
@@ -157,6 +237,13 @@ fn run_client(
     Ok(results)
 }
 
+fn parse_optional(value: &str) -> Option<String> {
+    match value {
+        "-" => None,
+        value => Some(value.to_string()),
+    }
+}
+
 fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error> {
     let path = Path::new(file_path);
     let file = File::open(&path)?;
@@ -176,6 +263,22 @@ fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error>
             ["delete", key] => {
                 operations.push(Operation::Delete(key.to_string(), index));
             }
+            // "-" stands for an absent value
+            ["cas", key, expected, new] => {
+                operations.push(Operation::Cas(key.to_string(), parse_optional(expected), parse_optional(new), index));
+            }
+            ["putifabsent", key, value] => {
+                operations.push(Operation::PutIfAbsent(key.to_string(), value.to_string(), index));
+            }
+            // "-" stands for no end
+            ["scan", start, end, limit] => match limit.parse() {
+                Ok(limit) => operations.push(Operation::Scan(start.to_string(), parse_optional(end), limit, index)),
+                Err(_) => eprintln!("Skipping invalid line {}: {:?}", index + 1, parts),
+            },
+            ["incr", key, delta] => match delta.parse() {
+                Ok(delta) => operations.push(Operation::Incr(key.to_string(), delta, index)),
+                Err(_) => eprintln!("Skipping invalid line {}: {:?}", index + 1, parts),
+            },
             _ => {
                 eprintln!("Skipping invalid line {}: {:?}", index + 1, parts);
             }
@@ -186,17 +289,20 @@ fn parse_operations_from_file(file_path: &str) -> Result<Vec<Operation>, Error>
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let transport = take_transport(&mut args);
+    let faults = take_faults(&mut args);
+    let allow_fault_control = take_flag(&mut args, "--allow-fault-control");
 
     if args.len() < 6 {
-        eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+        eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
         return;
     }
 
     let self_pid: Pid = match args[1].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
             return;
         }
     };
@@ -204,7 +310,7 @@ fn main() {
     let controller_pid: Pid = match args[2].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
             return;
         }
     };
@@ -212,7 +318,7 @@ fn main() {
     let operation_file_dir: String = match args[3].parse() {
         Ok(path) => path,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
             return;
         }
     };
@@ -220,7 +326,7 @@ fn main() {
     let result_dir: String = match args[4].parse() {
         Ok(path) => path,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
             return;
         }
     };
@@ -230,11 +336,28 @@ fn main() {
     let server_pids = match server_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: client <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
+            eprintln!("Usage: client [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <operation_file_dir> <result_dir> <server_pid_list>");
             return;
         }
     };
 
+    match transport {
+        Ok(kind) => set_default_transport(kind),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match faults {
+        Ok(Some(faults)) => set_default_faults(faults),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    set_allow_fault_control(allow_fault_control);
+
     let operation_filename = operation_file_dir + &self_pid.to_string() + ".txt";
     let operations = match parse_operations_from_file(&operation_filename) {
         Ok(ops) => ops,
@@ -1,31 +1,70 @@
+use kv_store::cli::{take_faults, take_flag, take_option, take_transport};
 use kv_store::kvs::*;
 use kv_store::network::*; // Assuming network.rs is in the same crate
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::env;
 use std::io::{Error, ErrorKind};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
-fn assign_shards_to_servers(
+// Range partitioning: a range is split once its home reports more keys or
+// more requests between two heartbeats than this
+struct SplitLimits {
+    keys: usize,
+    requests: usize,
+}
+
+const DEFAULT_SPLIT_KEYS: usize = 10000;
+const DEFAULT_SPLIT_REQUESTS: usize = 5000;
+
+// Splits a range at split_key. The upper half goes to the least loaded
+// server, which the current home hands its entries to before the new shard
+// info goes out, and forwards the requests for it that still come in.
+fn split_range(
+    ctx: &mut NetworkContext,
     shard_info: &Arc<Mutex<ShardInfo>>,
-    num_shards: usize,
-    server_pids: &Vec<Pid>,
+    server_pids: &[Pid],
+    loads: &[ShardLoad],
+    shard_id: ShardId,
+    split_key: String,
 ) {
-    let mut info = shard_info.lock().unwrap();
-    for shard_id in 0..num_shards {
-        let primary = server_pids[shard_id];
-        let secondaries = server_pids
-            .clone()
-            .into_iter()
-            .filter(|&x| x != primary)
-            .collect();
-        info.locations
-            .entry(shard_id as ShardId)
-            .or_insert(ShardLoc {
-                primary: primary,
-                secondaries: secondaries,
-            });
+    let info = shard_info.lock().unwrap().clone();
+    let location = info.locations[&shard_id].clone();
+    let end = info.range_of(shard_id).unwrap().1;
+    let new_home = pick_new_home(&info, loads, server_pids);
+
+    if new_home != location.primary {
+        if let Err(e) = send(ctx, &location.primary, &Operation::MoveRange(split_key.clone(), end, new_home)) {
+            println!("Controller failed to move range: {:?}", e);
+            return;
+        }
+        let mut moved = false;
+        for _ in 0..10 {
+            if let Ok(messages) = recv(ctx, 1000) {
+                moved = messages.iter().any(|(pid, operation)| {
+                    *pid == location.primary && matches!(operation, Operation::MoveRangeRes(_))
+                });
+                if moved {
+                    break;
+                }
+            }
+        }
+        if !moved {
+            println!("Controller got no answer moving range of shard {}", shard_id);
+            return;
+        }
     }
+
+    let mut secondaries = location.secondaries.clone();
+    if let Some(index) = secondaries.iter().position(|pid| *pid == new_home) {
+        secondaries[index] = location.primary;
+    }
+    let new_shard_id = info.locations.keys().max().unwrap() + 1;
+    let new_location = ShardLoc {
+        primary: new_home,
+        secondaries,
+    };
+    shard_info.lock().unwrap().split_range(shard_id, split_key, new_shard_id, new_location);
 }
 
 fn run_controller(
@@ -33,9 +72,16 @@ fn run_controller(
     controller_pid_for_servers: Pid,
     _client_pids: Vec<Pid>,
     server_pids: Vec<Pid>,
+    num_shards: usize,
+    replication_factor: usize,
+    split_limits: Option<SplitLimits>,
 ) {
-    let shard_info: Arc<Mutex<ShardInfo>> = Arc::new(Mutex::new(ShardInfo::new()));
-    assign_shards_to_servers(&shard_info, server_pids.len(), &server_pids);
+    let mut info = ShardInfo::new();
+    match split_limits {
+        Some(_) => assign_ranges_to_servers(&mut info, &server_pids, replication_factor),
+        None => assign_shards_to_servers(&mut info, &server_pids, num_shards, replication_factor),
+    }
+    let shard_info: Arc<Mutex<ShardInfo>> = Arc::new(Mutex::new(info));
 
     // Spawn shardinfo server thread
     let mut shard_info_ = shard_info.clone();
@@ -75,6 +121,7 @@ fn run_controller(
     shard_info_ = shard_info.clone();
     let server_monitor = thread::spawn(move || {
         let mut ctx = create_network_context(&controller_pid_for_servers).unwrap();
+        let mut loads: HashMap<ShardId, ShardLoad> = HashMap::new();
         loop {
             for server_pid in &server_pids {
                 if let Err(e) = send(
@@ -89,7 +136,12 @@ fn run_controller(
             while poll_pids.len() > 0 {
                 match recv(&mut ctx, 1000) {
                     Ok(messages) => {
-                        for (pid, _operation) in &messages {
+                        for (pid, operation) in &messages {
+                            if let Operation::ShardLoadReport(reports) = operation {
+                                for load in reports {
+                                    loads.insert(load.shard_id, load.clone());
+                                }
+                            }
                             poll_pids.remove(&pid);
                         }
                     }
@@ -98,6 +150,17 @@ fn run_controller(
                     }
                 }
             }
+
+            // one split per heartbeat, and the loads of the split range are
+            // only known again after the next one
+            if let Some(limits) = &split_limits {
+                let reported: Vec<ShardLoad> = loads.values().cloned().collect();
+                let info = shard_info_.lock().unwrap().clone();
+                if let Some((shard_id, split_key)) = pick_split(&info, &reported, limits.keys, limits.requests) {
+                    split_range(&mut ctx, &shard_info_, &server_pids, &reported, shard_id, split_key);
+                    loads.clear();
+                }
+            }
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
     });
@@ -107,17 +170,25 @@ fn run_controller(
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let transport = take_transport(&mut args);
+    let faults = take_faults(&mut args);
+    let allow_fault_control = take_flag(&mut args, "--allow-fault-control");
+    let num_shards = take_option(&mut args, "--shards");
+    let replication_factor = take_option(&mut args, "--replication-factor");
+    let partitioning = take_option(&mut args, "--partitioning");
+    let split_keys = take_option(&mut args, "--split-keys");
+    let split_requests = take_option(&mut args, "--split-requests");
 
     if args.len() < 6 {
-        eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+        eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
         return;
     }
 
     let controller_pid_for_clients: Pid = match args[1].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -125,7 +196,7 @@ fn main() {
     let controller_pid_for_servers: Pid = match args[2].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -133,7 +204,7 @@ fn main() {
     let num_clients: usize = match args[3].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -141,7 +212,7 @@ fn main() {
     let _num_servers: usize = match args[4].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -154,7 +225,7 @@ fn main() {
     let client_pids = match client_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
             return;
         }
     };
@@ -167,7 +238,74 @@ fn main() {
     let server_pids = match server_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: controller <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            eprintln!("Usage: controller [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] [--shards <n>] [--replication-factor <r>] [--partitioning <hash|range>] [--split-keys <n>] [--split-requests <n>] <controller_pid_for_clients> <controller_pid_for_servers> <num_clients> <num_servers> <client_pid_list> <server_pid_list>");
+            return;
+        }
+    };
+
+    match transport {
+        Ok(kind) => set_default_transport(kind),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match faults {
+        Ok(Some(faults)) => set_default_faults(faults),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    set_allow_fault_control(allow_fault_control);
+
+    let num_shards: usize = match num_shards.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_NUM_SHARDS,
+        _ => {
+            eprintln!("--shards must be a positive number");
+            return;
+        }
+    };
+
+    // every server holds a copy of every shard unless told otherwise
+    let replication_factor: usize = match replication_factor.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => server_pids.len(),
+        _ => {
+            eprintln!("--replication-factor must be a positive number");
+            return;
+        }
+    };
+
+    let split_keys: usize = match split_keys.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_SPLIT_KEYS,
+        _ => {
+            eprintln!("--split-keys must be a positive number");
+            return;
+        }
+    };
+
+    let split_requests: usize = match split_requests.map(|n| n.parse()) {
+        Some(Ok(n)) if n > 0 => n,
+        None => DEFAULT_SPLIT_REQUESTS,
+        _ => {
+            eprintln!("--split-requests must be a positive number");
+            return;
+        }
+    };
+
+    // range partitioning starts with one range and splits it as it grows
+    let split_limits = match partitioning.as_deref() {
+        None | Some("hash") => None,
+        Some("range") => Some(SplitLimits {
+            keys: split_keys,
+            requests: split_requests,
+        }),
+        Some(other) => {
+            eprintln!("unknown partitioning {:?}, expected hash or range", other);
             return;
         }
     };
@@ -177,5 +315,8 @@ fn main() {
         controller_pid_for_servers,
         client_pids,
         server_pids,
+        num_shards,
+        replication_factor,
+        split_limits,
     );
 }
@@ -1,3 +1,4 @@
+use kv_store::cli::{take_faults, take_flag, take_transport};
 use kv_store::kvs::*;
 use kv_store::network::*; // Assuming network.rs is in the same crate
 // use std::collections::{HashMap, HashSet};
@@ -63,6 +64,50 @@ fn handle_delete(
     return Err(Error::new(ErrorKind::Other, "error"));
 }
 
+fn handle_conditional(
+    kvs_handle: &mut KVSHandle,
+    _self_pid: &Pid,
+    client_pid: Pid,
+    operation: Operation,
+) -> Result<Operation, Error> {
+    kvs_handle.conditional(operation, client_pid, false);
+    return Err(Error::new(ErrorKind::Other, "error"));
+}
+
+fn handle_scan(
+    kvs_handle: &mut KVSHandle,
+    client_pid: Pid,
+    start: String,
+    end: Option<String>,
+    limit: usize,
+    psn: usize,
+) -> Result<Operation, Error> {
+    kvs_handle.scan(start, end, limit, client_pid, psn);
+    return Err(Error::new(ErrorKind::Other, "error"));
+}
+
+fn handle_shmem_scan_res(
+    kvs_handle: &mut KVSHandle,
+    client_pid: Pid,
+    entries: Vec<(String, String)>,
+    stopped_at: Option<String>,
+    psn: usize,
+) -> Result<Operation, Error> {
+    kvs_handle.shmem_scan_res(entries, stopped_at, client_pid, psn);
+    return Err(Error::new(ErrorKind::Other, "error"));
+}
+
+fn  handle_shmem_acquire_res(
+    kvs_handle: &mut KVSHandle,
+    _self_pid: &Pid,
+    key: &String,
+    value: Option<String>,
+) -> Result<Operation, Error> {
+    kvs_handle.shmem_acquire_res(key, value);
+
+    return Err(Error::new(ErrorKind::Other, "error"));
+}
+
 fn  handle_shmem_get_res(
     kvs_handle: &mut KVSHandle,
     _self_pid: &Pid,
@@ -148,6 +193,82 @@ fn handle_shmem_delete(
 
 }
 
+fn handle_shmem_acquire(
+    kvs: &KVS,
+    key: String,
+    requestor: Pid,
+    client: Pid,
+    psn: usize,
+    ctx: &mut NetworkContext,
+) -> Result<Operation, Error> {
+    let _value = kvs.acquire(&key, requestor, client, psn, ctx);
+    return Err(Error::new(ErrorKind::Other, "error"));
+
+}
+
+fn handle_shmem_conditional(
+    kvs: &KVS,
+    operation: Operation,
+    client: Pid,
+    ctx: &mut NetworkContext,
+) -> Result<Operation, Error> {
+    kvs.conditional_no_cache(&operation, client, ctx);
+    return Err(Error::new(ErrorKind::Other, "error"));
+
+}
+
+fn handle_shmem_scan(
+    kvs: &KVS,
+    (start, end): (String, Option<String>),
+    limit: usize,
+    requestor: Pid,
+    client: Pid,
+    psn: usize,
+    ctx: &mut NetworkContext,
+) -> Result<Operation, Error> {
+    kvs.scan((&start, end), limit, requestor, client, psn, ctx);
+    return Err(Error::new(ErrorKind::Other, "error"));
+
+}
+
+fn handle_move_range(
+    kvs: &KVS,
+    start: String,
+    end: Option<String>,
+    new_home: Pid,
+    ctx: &mut NetworkContext,
+) -> Result<Operation, Error> {
+    // without an answer the controller leaves the range where it is
+    match kvs.hand_over(start, end, new_home, ctx) {
+        Some(num_keys) => Ok(Operation::MoveRangeRes(num_keys)),
+        None => Err(Error::other("range not handed over")),
+    }
+}
+
+fn handle_shmem_take_range(
+    kvs: &KVS,
+    start: String,
+    end: Option<String>,
+    entries: Vec<(String, String)>,
+) -> Result<Operation, Error> {
+    kvs.take_over(start, end, entries);
+    Ok(Operation::ShmemTakeRangeRes())
+}
+
+// Under range partitioning the home answers every heartbeat with the load
+// of its ranges, for the controller to split the ones grown too large or
+// too hot
+fn handle_shard_load(
+    kvs: &KVS,
+    new_shard_info: &ShardInfo,
+    self_pid: &Pid,
+) -> Result<Operation, Error> {
+    if new_shard_info.ranges.is_empty() {
+        return Err(Error::new(ErrorKind::Other, "error"));
+    }
+    Ok(Operation::ShardLoadReport(kvs.shard_loads(new_shard_info, *self_pid)))
+}
+
 fn handle_snapshot(
     info: &Arc<RwLock<ShardInfo>>,
     kvs: &KVS,
@@ -156,7 +277,7 @@ fn handle_snapshot(
     let mut snapshot = KVSSnapshot::new();
     let shard_info = info.read().unwrap();
     for (k, v) in kvs.inner_table() {
-        let shard_id = get_shard_id_from_key(&k, shard_info.locations.len());
+        let shard_id = shard_info.shard_of(&k);
         if shard_info.locations[&shard_id].primary == *self_pid {
             snapshot.primary_shards.insert(k.clone(), v.clone());
         } else if shard_info.locations[&shard_id]
@@ -216,6 +337,9 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
                 Operation::Delete(key, psn) => {
                     handle_delete(&mut kvs_handle, &self_pid, pid, &shard_info_worker, &key, psn)
                 },
+                operation @ (Operation::Cas(..) | Operation::PutIfAbsent(..) | Operation::Incr(..)) => {
+                    handle_conditional(&mut kvs_handle, &self_pid, pid, operation)
+                },
                 Operation::PutShardInfo(new_shard_info) => {
 
                     handle_put_shard_info(&mut kvs_handle, &shard_info_worker, &new_shard_info)
@@ -232,6 +356,15 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
                 Operation::ShmemDeleteRes(pid, key, value, psn) => {
                     handle_shmem_delete_res(&mut kvs_handle, &self_pid, pid, &key, value, psn)
                 },
+                Operation::ShmemAcquireRes(_pid, key, value, _psn) => {
+                    handle_shmem_acquire_res(&mut kvs_handle, &self_pid, &key, value)
+                },
+                Operation::Scan(start, end, limit, psn) => {
+                    handle_scan(&mut kvs_handle, pid, start, end, limit, psn)
+                },
+                Operation::ShmemScanRes(pid, entries, stopped_at, psn) => {
+                    handle_shmem_scan_res(&mut kvs_handle, pid, entries, stopped_at, psn)
+                },
 
                 // Handle other opcodes here
                 _ => Err(Error::new(ErrorKind::InvalidInput, "Invalid operation")),
@@ -263,6 +396,23 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
         loop {
             let (pid, operation) = shmem_req_rx.recv().unwrap();
 
+            // a request the old home of its range passed on is answered as if
+            // it came from the requestor, and one for a range this server
+            // handed over goes on to the new home
+            let (pid, operation) = match operation {
+                Operation::ShmemForward(requestor, operation) => (requestor, *operation),
+                operation => (pid, operation),
+            };
+            if let Some(key) = shmem_request_key(&operation) {
+                if let Some(new_home) = kvs.moved_to(&key) {
+                    if let Err(e) = send(&mut ctx_shmem_manager, &new_home, &Operation::ShmemForward(pid, Box::new(operation))) {
+                        println!("Server failed to forward request: {:?}", e);
+                    }
+                    continue;
+                }
+                kvs.count_request(&key);
+            }
+
             let result = match operation {
                 Operation::ShmemPut(client_pid, key, value, psn) => {
                     handle_shmem_put(&kvs, key, value, pid, client_pid, psn,  &mut ctx_shmem_manager)
@@ -273,6 +423,24 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
                 Operation::ShmemDelete(client_pid, key, psn) => {
                     handle_shmem_delete(&kvs, key, pid, client_pid, psn,  &mut ctx_shmem_manager)
                 },
+                Operation::ShmemAcquire(client_pid, key, psn) => {
+                    handle_shmem_acquire(&kvs, key, pid, client_pid, psn,  &mut ctx_shmem_manager)
+                },
+                Operation::ShmemConditional(client_pid, operation) => {
+                    handle_shmem_conditional(&kvs, *operation, client_pid, &mut ctx_shmem_manager)
+                },
+                Operation::ShmemScan(client_pid, start, end, limit, psn) => {
+                    handle_shmem_scan(&kvs, (start, end), limit, pid, client_pid, psn, &mut ctx_shmem_manager)
+                },
+                Operation::MoveRange(start, end, new_home) => {
+                    handle_move_range(&kvs, start, end, new_home, &mut ctx_shmem_manager)
+                },
+                Operation::ShmemTakeRange(start, end, entries) => {
+                    handle_shmem_take_range(&kvs, start, end, entries)
+                },
+                Operation::PutShardInfo(new_shard_info) => {
+                    handle_shard_load(&kvs, &new_shard_info, &self_pid)
+                },
                 Operation::Snapshot() => {
                     handle_snapshot(&shard_info_shmem_manager, &kvs, &self_pid)
                 }
@@ -283,7 +451,10 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
             match result {
                 Ok(operation) => {
                     match operation {
-                        Operation::Snapshot() => {
+                        Operation::Snapshot()
+                        | Operation::MoveRangeRes(_)
+                        | Operation::ShmemTakeRangeRes()
+                        | Operation::ShardLoadReport(_) => {
                             if let Err(e) = send(&mut ctx_shmem_manager, &pid, &operation) {
                                 println!("Server failed to send response: {:?}", e);
                             }
@@ -315,10 +486,28 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
                             Operation::ShmemDelete(_, _, _) => {
                                 shmem_req_tx.send((pid, operation)).unwrap()
                             },
+                            Operation::ShmemAcquire(_, _, _) => {
+                                shmem_req_tx.send((pid, operation)).unwrap()
+                            },
+                            Operation::ShmemConditional(_, _) => {
+                                shmem_req_tx.send((pid, operation)).unwrap()
+                            },
                             Operation::Snapshot() => {
                                 shmem_req_tx.send((pid, operation)).unwrap()
                             },
-                            Operation::ShmemInvRes(_) => {
+                            Operation::ShmemScan(..)
+                            | Operation::ShmemForward(..)
+                            | Operation::ShmemTakeRange(..)
+                            | Operation::MoveRange(..) => {
+                                shmem_req_tx.send((pid, operation)).unwrap()
+                            },
+                            // the worker takes the new shard info, the home
+                            // reports its load
+                            Operation::PutShardInfo(_) => {
+                                shmem_req_tx.send((pid, operation.clone())).unwrap();
+                                client_req_tx.send((pid, operation)).unwrap()
+                            },
+                            Operation::ShmemInvRes(_) | Operation::ShmemTakeRangeRes() => {
                                 dir_res_tx.send((pid, operation)).unwrap()
                             },
                             Operation::ShmemPutRes(_, _, _, _, _) => {
@@ -364,17 +553,20 @@ fn run_server(self_pid: Pid, _controller_pid: Pid, _server_pids: Vec<Pid>) {
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let transport = take_transport(&mut args);
+    let faults = take_faults(&mut args);
+    let allow_fault_control = take_flag(&mut args, "--allow-fault-control");
 
     if args.len() < 4 {
-        eprintln!("Usage: server <self_pid > <controller_pid> <server_pid_list>");
+        eprintln!("Usage: server [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid > <controller_pid> <server_pid_list>");
         return;
     }
 
     let self_pid: Pid = match args[1].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: server <self_pid> <controller_pid> <server_pid_list>");
+            eprintln!("Usage: server [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <server_pid_list>");
             return;
         }
     };
@@ -382,7 +574,7 @@ fn main() {
     let controller_pid: Pid = match args[2].parse() {
         Ok(n) => n,
         Err(_) => {
-            eprintln!("Usage: server <self_pid> <controller_pid> <server_pid_list>");
+            eprintln!("Usage: server [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <server_pid_list>");
             return;
         }
     };
@@ -392,10 +584,27 @@ fn main() {
     let server_pids = match server_pids {
         Ok(v) => v,
         Err(_) => {
-            eprintln!("Usage: server <self_pid> <controller_pid> <server_pid_list>");
+            eprintln!("Usage: server [--transport <udp|tcp>] [--faults <file>] [--allow-fault-control] <self_pid> <controller_pid> <server_pid_list>");
             return;
         }
     };
 
+    match transport {
+        Ok(kind) => set_default_transport(kind),
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    match faults {
+        Ok(Some(faults)) => set_default_faults(faults),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("{}", e);
+            return;
+        }
+    }
+    set_allow_fault_control(allow_fault_control);
+
     run_server(self_pid, controller_pid, server_pids);
 }
@@ -0,0 +1,47 @@
+// Helpers shared by the binaries to parse optional `--name <value>` flags
+// before the positional pid lists.
+
+use crate::faults::FaultInjector;
+use crate::network::{parse_transport, TransportKind};
+
+// Removes `--name <value>` from the argument list and returns the value, so the
+// remaining positional arguments can be parsed as before.
+pub fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|arg| arg == name)?;
+    if index + 1 >= args.len() {
+        args.remove(index);
+        return None;
+    }
+    let value = args.remove(index + 1);
+    args.remove(index);
+    Some(value)
+}
+
+// Removes a `--name` switch from the argument list, returns whether it was there.
+pub fn take_flag(args: &mut Vec<String>, name: &str) -> bool {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => {
+            args.remove(index);
+            true
+        }
+        None => false,
+    }
+}
+
+// Removes `--transport udp|tcp` from the argument list, udp when it is not there.
+pub fn take_transport(args: &mut Vec<String>) -> std::io::Result<TransportKind> {
+    match take_option(args, "--transport") {
+        Some(name) => parse_transport(&name),
+        None => Ok(TransportKind::Udp),
+    }
+}
+
+// Removes `--faults <file>` from the argument list and loads the fault
+// injection config from it, or from KV_STORE_FAULTS without the flag. None
+// when neither is given.
+pub fn take_faults(args: &mut Vec<String>) -> std::io::Result<Option<FaultInjector>> {
+    match take_option(args, "--faults") {
+        Some(path) => Ok(Some(FaultInjector::load(&path)?)),
+        None => FaultInjector::from_env(),
+    }
+}
@@ -3,11 +3,13 @@ extern crate serde_json;
 use crate::network::*; // Assuming network.rs is in the same crate
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::convert::TryInto;
 use std::hash::{Hash, Hasher};
+use std::ops::Bound;
 use std::ops::DerefMut;
 use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 pub type Pid = u32;
 pub type ShardId = u32;
@@ -16,6 +18,9 @@ pub type Message = (Pid, Operation);
 /* Use this value to enable/disable caching in the Key-Value Store */
 pub const USE_CACHE: bool = true;
 
+// How long a home waits for the new home of a range to take its entries
+const TAKE_RANGE_TIMEOUT_MS: u64 = 5000;
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShardLoc {
     pub primary: Pid,
@@ -25,6 +30,9 @@ pub struct ShardLoc {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShardInfo {
     pub locations: HashMap<ShardId, ShardLoc>,
+    // Range partitioning: the first key of every shard's range, in key order,
+    // the first one "". Empty when keys are hashed to shards.
+    pub ranges: Vec<(String, ShardId)>,
 }
 
 impl ShardInfo {
@@ -32,10 +40,56 @@ impl ShardInfo {
     pub fn new() -> Self {
         ShardInfo {
             locations: HashMap::new(),
+            ranges: Vec::new(),
+        }
+    }
+
+    // The shard key belongs to: the range it falls in under range
+    // partitioning, its hash otherwise
+    pub fn shard_of(&self, key: &str) -> ShardId {
+        if self.ranges.is_empty() {
+            return get_shard_id_from_key(key, self.locations.len());
+        }
+        let index = self.ranges.partition_point(|(first_key, _)| first_key.as_str() <= key);
+        self.ranges[index - 1].1
+    }
+
+    // The first key of a shard's range and the first key past it, none for
+    // the last range. None if keys are hashed or the shard has no range.
+    pub fn range_of(&self, shard_id: ShardId) -> Option<(String, Option<String>)> {
+        let index = self.ranges.iter().position(|(_, id)| *id == shard_id)?;
+        let end = self.ranges.get(index + 1).map(|(first_key, _)| first_key.clone());
+        Some((self.ranges[index].0.clone(), end))
+    }
+
+    // Splits a shard's range at key: the keys from key on make up new_shard_id,
+    // placed at location. False if key is not past the range's first key and
+    // inside it.
+    pub fn split_range(&mut self, shard_id: ShardId, key: String, new_shard_id: ShardId, location: ShardLoc) -> bool {
+        let (first_key, end) = match self.range_of(shard_id) {
+            Some(range) => range,
+            None => return false,
+        };
+        if key <= first_key || end.is_some_and(|end| key >= end) || self.locations.contains_key(&new_shard_id) {
+            return false;
         }
+        let index = self.ranges.partition_point(|(first_key, _)| *first_key < key);
+        self.ranges.insert(index, (key, new_shard_id));
+        self.locations.insert(new_shard_id, location);
+        true
     }
 }
 
+// What the home of a range reports to the controller with every heartbeat
+// under range partitioning
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShardLoad {
+    pub shard_id: ShardId,
+    pub keys: usize,
+    pub requests: usize,           // since the last report
+    pub split_key: Option<String>, // the middle of the range, by requests if there were any
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum InvType {
     ToInv,
@@ -87,6 +141,47 @@ pub enum Operation {
     Snapshot(), // none value indicate delete
     SnapshotRes(KVSSnapshot), // old value
 
+    // test -> any process
+    FaultControl(String), // fault injection commands, applied by recv and never handed to the caller
+
+    // client -> server, conditional writes, run with the key's exclusive copy
+    Cas(String, Option<String>, Option<String>, usize), // key + expected + new + psn; none expected for an absent key, none new deletes it
+    PutIfAbsent(String, String, usize),                 // key + value + psn
+    Incr(String, i64, usize),                           // key + delta + psn; an absent key counts as 0
+
+    // server -> client
+    CasRes(bool, Option<String>, usize),                // swapped + old value + psn
+    PutIfAbsentRes(Option<String>, usize),              // old value + psn; the put happened only if old value is none
+    IncrRes(Option<String>, Option<i64>, usize),        // old value + new value + psn; none new value if old value is not a number
+
+    // server (kvs_handler) -> server (kvs)
+    ShmemAcquire(Pid, String, usize),                   // client pid + key + psn; the exclusive copy, without writing
+    ShmemConditional(Pid, Box<Operation>),              // client pid + conditional write; run by the home when there is no cache
+    // server (kvs) -> server (kvs_handler)
+    ShmemAcquireRes(Pid, String, Option<String>, usize), // client pid + key + value + psn
+
+    // client -> server, the entries of [start, end) in key order
+    Scan(String, Option<String>, usize, usize),         // start + end, none for no end + limit + psn
+    // server -> client, one per range the scan went through, the last one says no more
+    ScanRes(Vec<(String, String)>, bool, usize),        // entries + more to come + psn
+
+    // server (kvs_handler) -> server (kvs)
+    ShmemScan(Pid, String, Option<String>, usize, usize),        // client pid + start + end + limit + psn
+    // server (kvs) -> server (kvs_handler)
+    ShmemScanRes(Pid, Vec<(String, String)>, Option<String>, usize), // client pid + entries + where the scan stopped, none for no end + psn
+
+    // controller -> server (kvs), range partitioning: hand [start, end) to another home
+    MoveRange(String, Option<String>, Pid),             // start + end + new home
+    // server (kvs) -> controller
+    MoveRangeRes(usize),                                // keys moved
+    // server (kvs) -> controller, in answer to PutShardInfo under range partitioning
+    ShardLoadReport(Vec<ShardLoad>),
+
+    // server (kvs) -> server (kvs)
+    ShmemTakeRange(String, Option<String>, Vec<(String, String)>), // start + end + entries
+    ShmemTakeRangeRes(),
+    ShmemForward(Pid, Box<Operation>),                  // requestor + request for a range handed over
+
                 // TODO: add operations for cache maintainance, like invalidation
 }
 
@@ -122,6 +217,70 @@ impl KVSSnapshot {
     }
 }
 
+// Logical shards of a cluster unless the controller is told otherwise
+pub const DEFAULT_NUM_SHARDS: usize = 64;
+
+// Places num_shards shards on the servers: shard i's home is server i % N,
+// so every server is the home of num_shards / N shards give or take one, and
+// its replication_factor - 1 secondaries are the servers holding the fewest
+// secondaries so far, the ones right after the home first among equals.
+pub fn assign_shards_to_servers(
+    info: &mut ShardInfo,
+    server_pids: &[Pid],
+    num_shards: usize,
+    replication_factor: usize,
+) {
+    let num_servers = server_pids.len();
+    let num_secondaries = replication_factor.clamp(1, num_servers) - 1;
+    let mut counts: HashMap<Pid, usize> = HashMap::new();
+    info.locations.clear();
+    for shard_id in 0..num_shards {
+        let mut choices: Vec<(usize, usize, Pid)> = (1..num_servers)
+            .map(|offset| {
+                let pid = server_pids[(shard_id + offset) % num_servers];
+                (counts.get(&pid).cloned().unwrap_or(0), offset, pid)
+            })
+            .collect();
+        choices.sort();
+        let secondaries: Vec<Pid> = choices.into_iter().take(num_secondaries).map(|(_, _, pid)| pid).collect();
+        for pid in &secondaries {
+            *counts.entry(*pid).or_insert(0) += 1;
+        }
+        info.locations.insert(
+            shard_id as ShardId,
+            ShardLoc {
+                primary: server_pids[shard_id % num_servers],
+                secondaries,
+            },
+        );
+    }
+
+    // a home can not be its own secondary, which may leave the counts two
+    // apart at the end: hand secondaries over until they are not
+    loop {
+        let count = |pid: &Pid| counts.get(pid).cloned().unwrap_or(0);
+        let most = *server_pids.iter().max_by_key(|pid| (count(pid), std::cmp::Reverse(**pid))).unwrap();
+        let fewest = *server_pids.iter().min_by_key(|pid| (count(pid), **pid)).unwrap();
+        if count(&most) <= count(&fewest) + 1 {
+            break;
+        }
+        let mut shard_ids: Vec<ShardId> = info.locations.keys().cloned().collect();
+        shard_ids.sort();
+        let found = shard_ids.into_iter().find(|shard_id| {
+            let location = &info.locations[shard_id];
+            location.secondaries.contains(&most) && location.primary != fewest && !location.secondaries.contains(&fewest)
+        });
+        let location = match found {
+            Some(shard_id) => info.locations.get_mut(&shard_id).unwrap(),
+            None => break,
+        };
+        let index = location.secondaries.iter().position(|pid| *pid == most).unwrap();
+        location.secondaries[index] = fewest;
+        *counts.get_mut(&most).unwrap() -= 1;
+        *counts.entry(fewest).or_insert(0) += 1;
+    }
+}
+
 pub fn get_shard_id_from_key(key: &str, num_shards: usize) -> ShardId {
     assert!(num_shards > 0);
 
@@ -131,12 +290,125 @@ pub fn get_shard_id_from_key(key: &str, num_shards: usize) -> ShardId {
     ((hash_value as usize) % num_shards).try_into().unwrap()
 }
 
+// Range partitioning starts with one range holding every key, homed at the
+// first server. The controller splits it as it grows.
+pub fn assign_ranges_to_servers(info: &mut ShardInfo, server_pids: &[Pid], replication_factor: usize) {
+    assign_shards_to_servers(info, server_pids, 1, replication_factor);
+    info.ranges = vec![("".to_string(), 0)];
+}
+
+// The range to split next and where: the one with the most requests, then the
+// most keys, among those above max_requests or max_keys whose home found a
+// key to split at
+pub fn pick_split(info: &ShardInfo, loads: &[ShardLoad], max_keys: usize, max_requests: usize) -> Option<(ShardId, String)> {
+    loads
+        .iter()
+        .filter(|load| load.keys > max_keys || load.requests > max_requests)
+        .filter(|load| match (info.range_of(load.shard_id), &load.split_key) {
+            (Some((first_key, end)), Some(key)) => *key > first_key && end.is_none_or(|end| *key < end),
+            _ => false,
+        })
+        .max_by_key(|load| (load.requests, load.keys, std::cmp::Reverse(load.shard_id)))
+        .map(|load| (load.shard_id, load.split_key.clone().unwrap()))
+}
+
+// The home for the upper half of a split range: the server whose ranges had
+// the fewest requests, then keys, then ranges
+pub fn pick_new_home(info: &ShardInfo, loads: &[ShardLoad], server_pids: &[Pid]) -> Pid {
+    let mut totals: HashMap<Pid, (usize, usize, usize)> = server_pids.iter().map(|pid| (*pid, (0, 0, 0))).collect();
+    for (shard_id, location) in &info.locations {
+        let total = match totals.get_mut(&location.primary) {
+            Some(total) => total,
+            None => continue,
+        };
+        total.2 += 1;
+        if let Some(load) = loads.iter().find(|load| load.shard_id == *shard_id) {
+            total.0 += load.requests;
+            total.1 += load.keys;
+        }
+    }
+    *server_pids.iter().min_by_key(|pid| (totals[*pid], **pid)).unwrap()
+}
+
+// The key a request to a home is for, the start for a scan
+pub fn shmem_request_key(operation: &Operation) -> Option<String> {
+    match operation {
+        Operation::ShmemGet(_, key, _)
+        | Operation::ShmemPut(_, key, _, _)
+        | Operation::ShmemDelete(_, key, _)
+        | Operation::ShmemAcquire(_, key, _)
+        | Operation::ShmemScan(_, key, _, _, _) => Some(key.clone()),
+        Operation::ShmemConditional(_, operation) => Some(conditional_key(operation).0),
+        _ => None,
+    }
+}
+
+fn key_range<'a>(start: &'a str, end: &'a Option<String>) -> (Bound<&'a str>, Bound<&'a str>) {
+    match end {
+        Some(end) => (Bound::Included(start), Bound::Excluded(end.as_str())),
+        None => (Bound::Included(start), Bound::Unbounded),
+    }
+}
+
+fn in_range(key: &str, start: &str, end: &Option<String>) -> bool {
+    key >= start && end.as_ref().is_none_or(|end| key < end.as_str())
+}
+
+// The key and psn of a Cas, PutIfAbsent or Incr
+fn conditional_key(operation: &Operation) -> (String, usize) {
+    match operation {
+        Operation::Cas(key, _, _, psn) | Operation::PutIfAbsent(key, _, psn) | Operation::Incr(key, _, psn) => {
+            (key.clone(), *psn)
+        }
+        _ => panic!("not a conditional write: {:?}", operation),
+    }
+}
+
+// Runs a conditional write against the key's current value. Returns the
+// write to make, if any (a none value deletes the key), and the response.
+fn run_conditional(operation: &Operation, current: Option<String>) -> (Option<Option<String>>, Operation) {
+    match operation {
+        Operation::Cas(_, expected, new, psn) => {
+            if current == *expected {
+                (Some(new.clone()), Operation::CasRes(true, current, *psn))
+            } else {
+                (None, Operation::CasRes(false, current, *psn))
+            }
+        }
+        Operation::PutIfAbsent(_, value, psn) => match current {
+            None => (Some(Some(value.clone())), Operation::PutIfAbsentRes(None, *psn)),
+            Some(_) => (None, Operation::PutIfAbsentRes(current, *psn)),
+        },
+        Operation::Incr(_, delta, psn) => {
+            let number = match &current {
+                Some(value) => value.parse::<i64>().ok(),
+                None => Some(0),
+            };
+            match number.and_then(|number| number.checked_add(*delta)) {
+                Some(new) => (Some(Some(new.to_string())), Operation::IncrRes(current, Some(new), *psn)),
+                None => (None, Operation::IncrRes(current, None, *psn)),
+            }
+        }
+        _ => panic!("not a conditional write: {:?}", operation),
+    }
+}
+
+// A scan a kvs_handler runs for a client
+enum ScanState {
+    // range partitioning: one home after the other, from the start on
+    Ranged { end: Option<String>, remaining: usize },
+    // hash partitioning: every home scans the whole range, the entries are
+    // sorted and cut to limit once the last one answered
+    Hashed { limit: usize, waiting: usize, entries: Vec<(String, String)> },
+}
+
 pub struct KVSHandle {
     pub shard_info: ShardInfo,
     pub shmem_res_rx: Arc<Mutex<mpsc::Receiver<Message>>>,
     pub ctx: Arc<Mutex<NetworkContext>>,
     pub cache: HashMap<String, (Perm, Option<String>)>,
-    pub pending_ops: HashMap<String, VecDeque<(Pid, Operation)>> // (Key, Waiting Operations)
+    pub pending_ops: HashMap<String, VecDeque<(Pid, Operation)>>, // (Key, Waiting Operations)
+    scans: HashMap<(Pid, usize), ScanState>, // (client pid, psn)
 }
 
 impl KVSHandle {
@@ -150,7 +422,8 @@ impl KVSHandle {
             shmem_res_rx: shmem_res_rx,
             ctx: ctx,
             cache: HashMap::new(),
-            pending_ops: HashMap::new()
+            pending_ops: HashMap::new(),
+            scans: HashMap::new(),
         }
     }
 
@@ -169,6 +442,9 @@ impl KVSHandle {
             Operation::Delete(key, psn) => {
                 self.delete(&key, client_pid, psn, true)
             },
+            Operation::Cas(..) | Operation::PutIfAbsent(..) | Operation::Incr(..) => {
+                self.conditional(operation, client_pid, true)
+            },
             _ => {
                 println!("Unreachable hand");
                 return None;
@@ -176,24 +452,9 @@ impl KVSHandle {
         }
     }
 
-    pub fn shmem_put_res(&mut self, key: &String, old_value: Option<String>, new_value: String, client_pid: Pid, psn: usize) {
-        let _res = send(
-            &mut self.ctx.lock().unwrap(),
-            &client_pid, 
-            &Operation::PutRes(old_value.clone(), psn)
-        );
-
-        // println!("*** Before PUT Cache = {:?}", self.cache);
-        // Update cache
-        if let Some(val) = self.cache.get_mut(key) {
-            val.0 = Perm::Exclusive;
-            val.1 = Some(new_value);
-        }
-        else {
-            self.cache.insert(key.clone(), (Perm::Exclusive, Some(new_value)));
-        }
-        // println!("*** After PUT Cache = {:?}", self.cache);
-
+    // Runs the operations that waited for key's result, until one of them
+    // has to wait for the home again
+    fn run_pending_ops(&mut self, key: &String) {
         loop {
             if let Some(ops) = self.pending_ops.get_mut(key) {
                 if ops.len() == 0 {
@@ -214,6 +475,27 @@ impl KVSHandle {
                 break;
             }
         }
+    }
+
+    pub fn shmem_put_res(&mut self, key: &String, old_value: Option<String>, new_value: String, client_pid: Pid, psn: usize) {
+        let _res = send(
+            &mut self.ctx.lock().unwrap(),
+            &client_pid, 
+            &Operation::PutRes(old_value.clone(), psn)
+        );
+
+        // println!("*** Before PUT Cache = {:?}", self.cache);
+        // Update cache
+        if let Some(val) = self.cache.get_mut(key) {
+            val.0 = Perm::Exclusive;
+            val.1 = Some(new_value);
+        }
+        else {
+            self.cache.insert(key.clone(), (Perm::Exclusive, Some(new_value)));
+        }
+        // println!("*** After PUT Cache = {:?}", self.cache);
+
+        self.run_pending_ops(key);
     }   
 
     pub fn shmem_get_res(&mut self, key: &String, value: Option<String>, client_pid: Pid, psn: usize) {
@@ -234,26 +516,7 @@ impl KVSHandle {
         // println!("*** After GET Cache = {:?}", self.cache);
 
 
-        loop {
-            if let Some(ops) = self.pending_ops.get_mut(key) {
-                if ops.len() == 0 {
-                    self.pending_ops.remove(key);
-                    break;
-                }
-                else {
-                    // Pop element
-                    let (client_pid, op) = ops.pop_front().unwrap();
-    
-                    if self.run_popped_operation(client_pid, op).is_none() {
-                        break;
-                    }
-                }
-            }
-            else {
-                println!("Should not reach here because a result has come");
-                break;
-            }
-        }
+        self.run_pending_ops(key);
     }
 
     pub fn shmem_delete_res(&mut self, key: &String, value: Option<String>, client_pid: Pid, psn: usize) {
@@ -272,26 +535,14 @@ impl KVSHandle {
             self.cache.insert(key.clone(), (Perm::Exclusive, None));
         }
 
-        loop {
-            if let Some(ops) = self.pending_ops.get_mut(key) {
-                if ops.len() == 0 {
-                    self.pending_ops.remove(key);
-                    break;
-                }
-                else {
-                    // Pop element
-                    let (client_pid, op) = ops.pop_front().unwrap();
-    
-                    if self.run_popped_operation(client_pid, op).is_none() {
-                        break;
-                    }
-                }
-            }
-            else {
-                println!("Should not reach here because a result has come");
-                break;
-            }
-        }
+        self.run_pending_ops(key);
+    }
+
+    // The home handed us the exclusive copy, the conditional write that asked
+    // for it is first in the key's pending operations
+    pub fn shmem_acquire_res(&mut self, key: &String, value: Option<String>) {
+        self.cache.insert(key.clone(), (Perm::Exclusive, value));
+        self.run_pending_ops(key);
     }
 
     pub fn invalid_cache(&mut self, key: String, inv_type:InvType, home_pid: Pid) -> Option<String>{
@@ -338,7 +589,7 @@ impl KVSHandle {
     }
 
     pub fn put_no_cache(&mut self, key: String, value: String, client_pid: Pid, psn: usize) {
-        let shard_id = get_shard_id_from_key(&key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(&key);
         let home = self.shard_info.locations[&shard_id].primary;
         
         if let Err(e) = send(
@@ -395,7 +646,7 @@ impl KVSHandle {
 
         }
 
-        let shard_id = get_shard_id_from_key(&key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(&key);
         let home = self.shard_info.locations[&shard_id].primary;
 
         if let Err(e) = send(
@@ -416,7 +667,7 @@ impl KVSHandle {
     }
 
     pub fn get_no_cache(&mut self, key: &str, client_pid: Pid, psn: usize) {
-        let shard_id = get_shard_id_from_key(&key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(&key);
         let home = self.shard_info.locations[&shard_id].primary;
         
         if let Err(e) = send(
@@ -466,7 +717,7 @@ impl KVSHandle {
             return Some("Query Answered".to_string());
         }
 
-        let shard_id = get_shard_id_from_key(key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(key);
         let home = self.shard_info.locations[&shard_id].primary;
 
         if let Err(e) = send(
@@ -487,7 +738,7 @@ impl KVSHandle {
     }
 
     pub fn delete_no_cache(&mut self, key: &str, client_pid: Pid, psn: usize) {
-        let shard_id = get_shard_id_from_key(&key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(&key);
         let home = self.shard_info.locations[&shard_id].primary;
         
         if let Err(e) = send(
@@ -542,7 +793,7 @@ impl KVSHandle {
             return Some("Query Answered".to_string());
         }
 
-        let shard_id = get_shard_id_from_key(key, self.shard_info.locations.len());
+        let shard_id = self.shard_info.shard_of(key);
         let home = self.shard_info.locations[&shard_id].primary;
 
         if let Err(e) = send(
@@ -564,21 +815,181 @@ impl KVSHandle {
 
         
     }
+
+    pub fn conditional_no_cache(&mut self, operation: Operation, client_pid: Pid) {
+        let (key, _psn) = conditional_key(&operation);
+        let shard_id = self.shard_info.shard_of(&key);
+        let home = self.shard_info.locations[&shard_id].primary;
+
+        // the home runs it on its table and answers the client
+        if let Err(e) = send(
+            self.ctx.lock().unwrap().deref_mut(),
+            &home,
+            &Operation::ShmemConditional(client_pid, Box::new(operation)),
+        ) {
+            println!("Failed to send shmem conditional: {:?}", e);
+        }
+    }
+
+    // Cas, PutIfAbsent and Incr read and write the key in one step, so they
+    // only run on the exclusive copy. Without it we ask the home for it and
+    // the operation waits first in line for the key until it is here.
+    pub fn conditional(&mut self, operation: Operation, client_pid: Pid, bypass_pending_check: bool) -> Option<String> {
+        if !USE_CACHE {
+            self.conditional_no_cache(operation, client_pid);
+            return None;
+        }
+
+        let (key, psn) = conditional_key(&operation);
+        if !bypass_pending_check && self.pending_ops.contains_key(&key) {
+            let ops = self.pending_ops.get_mut(&key).unwrap();
+            ops.push_back((client_pid, operation));
+            return None;
+        }
+
+        if self.cache.contains_key(&key) && self.cache.get(&key).unwrap().0 == Perm::Exclusive {
+            let cache_line = self.cache.get_mut(&key).unwrap();
+            let (write, response) = run_conditional(&operation, cache_line.1.clone());
+            if let Some(value) = write {
+                cache_line.1 = value;
+            }
+
+            let _ = send(
+                self.ctx.lock().unwrap().deref_mut(),
+                &client_pid,
+                &response
+            );
+            return Some("Query Answered".to_string());
+        }
+
+        let shard_id = self.shard_info.shard_of(&key);
+        let home = self.shard_info.locations[&shard_id].primary;
+
+        if let Err(e) = send(
+            self.ctx.lock().unwrap().deref_mut(),
+            &home,
+            &Operation::ShmemAcquire(client_pid, key.clone(), psn),
+        ) {
+            println!("Failed to send shmem acquire: {:?}", e);
+            return None;
+        }
+
+        self.pending_ops.entry(key).or_default().push_front((client_pid, operation));
+        return None;
+    }
+
+    // Scans go straight to the homes, which recall the exclusive copies in
+    // the range first, so they neither wait for nor fill this cache
+    pub fn scan(&mut self, start: String, end: Option<String>, limit: usize, client_pid: Pid, psn: usize) {
+        if !self.shard_info.ranges.is_empty() {
+            self.scans.insert((client_pid, psn), ScanState::Ranged { end: end.clone(), remaining: limit });
+            self.scan_next(start, &end, limit, client_pid, psn);
+            return;
+        }
+
+        let mut homes: Vec<Pid> = self.shard_info.locations.values().map(|location| location.primary).collect();
+        homes.sort();
+        homes.dedup();
+        for home in &homes {
+            if let Err(e) = send(
+                self.ctx.lock().unwrap().deref_mut(),
+                home,
+                &Operation::ShmemScan(client_pid, start.clone(), end.clone(), limit, psn),
+            ) {
+                println!("Failed to send shmem scan: {:?}", e);
+            }
+        }
+        self.scans.insert((client_pid, psn), ScanState::Hashed { limit, waiting: homes.len(), entries: Vec::new() });
+    }
+
+    // Asks the home of start for the part of [start, end) in its range
+    fn scan_next(&mut self, start: String, end: &Option<String>, limit: usize, client_pid: Pid, psn: usize) {
+        let shard_id = self.shard_info.shard_of(&start);
+        let home = self.shard_info.locations[&shard_id].primary;
+        let range_end = self.shard_info.range_of(shard_id).and_then(|(_, range_end)| range_end);
+        let part_end = match (end, range_end) {
+            (Some(end), Some(range_end)) => Some(end.clone().min(range_end)),
+            (Some(end), None) => Some(end.clone()),
+            (None, range_end) => range_end,
+        };
+
+        if let Err(e) = send(
+            self.ctx.lock().unwrap().deref_mut(),
+            &home,
+            &Operation::ShmemScan(client_pid, start, part_end, limit, psn),
+        ) {
+            println!("Failed to send shmem scan: {:?}", e);
+        }
+    }
+
+    pub fn shmem_scan_res(&mut self, entries: Vec<(String, String)>, stopped_at: Option<String>, client_pid: Pid, psn: usize) {
+        let (more, next) = match self.scans.get_mut(&(client_pid, psn)) {
+            Some(ScanState::Hashed { limit, waiting, entries: gathered }) => {
+                gathered.extend(entries);
+                *waiting -= 1;
+                if *waiting > 0 {
+                    return;
+                }
+                gathered.sort();
+                gathered.truncate(*limit);
+                let _ = send(
+                    self.ctx.lock().unwrap().deref_mut(),
+                    &client_pid,
+                    &Operation::ScanRes(gathered.clone(), false, psn)
+                );
+                self.scans.remove(&(client_pid, psn));
+                return;
+            },
+            Some(ScanState::Ranged { end, remaining }) => {
+                *remaining = remaining.saturating_sub(entries.len());
+                let more = *remaining > 0 && match (&stopped_at, &*end) {
+                    (Some(stopped_at), Some(end)) => stopped_at < end,
+                    (Some(_), None) => true,
+                    (None, _) => false,
+                };
+                (more, (end.clone(), *remaining))
+            },
+            None => return,
+        };
+
+        // ranges with nothing in them are left out of the stream
+        if !entries.is_empty() || !more {
+            let _ = send(
+                self.ctx.lock().unwrap().deref_mut(),
+                &client_pid,
+                &Operation::ScanRes(entries, more, psn)
+            );
+        }
+
+        if more {
+            let (end, remaining) = next;
+            self.scan_next(stopped_at.unwrap(), &end, remaining, client_pid, psn);
+        } else {
+            self.scans.remove(&(client_pid, psn));
+        }
+    }
 }
 
+// A range a home handed over: start + end + new home
+type MovedRange = (String, Option<String>, Pid);
+
 #[derive(Clone)]
 pub struct KVS {
-    table: Arc<RwLock<HashMap<String, String>>>,
-    directory: Arc<RwLock<HashMap<String, HashSet<(Perm, Pid)>>>>, // directory
+    table: Arc<RwLock<BTreeMap<String, String>>>,   // in key order for scans
+    directory: Arc<RwLock<BTreeMap<String, HashSet<(Perm, Pid)>>>>, // directory
     dir_res_rx: Arc<Mutex<mpsc::Receiver<Message>>>, //data
+    moved: Arc<RwLock<Vec<MovedRange>>>,            // ranges handed to another home
+    requests: Arc<Mutex<BTreeMap<String, usize>>>,  // requests per key since the last load report
 }
 
 impl KVS {
     pub fn new(dir_res_rx: Arc<Mutex<mpsc::Receiver<Message>>>) -> Self {
         KVS {
-            table: Arc::new(RwLock::new(HashMap::new())),
-            directory: Arc::new(RwLock::new(HashMap::new())),
-            dir_res_rx: dir_res_rx
+            table: Arc::new(RwLock::new(BTreeMap::new())),
+            directory: Arc::new(RwLock::new(BTreeMap::new())),
+            dir_res_rx: dir_res_rx,
+            moved: Arc::new(RwLock::new(Vec::new())),
+            requests: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
     pub fn put_no_cache(&self, key: String, value: String, requestor_pid: Pid, client_pid: Pid, 
@@ -930,8 +1341,308 @@ impl KVS {
 
     }
 
+    // Hands the exclusive copy of key to the requestor without writing it, for
+    // a conditional write the requestor runs on its cache. Every other copy
+    // is invalidated, an exclusive one sends its value back first.
+    pub fn acquire(&self, key: &str, requestor_pid: Pid, client_pid: Pid, psn: usize, ctx: &mut NetworkContext) -> Option<String> {
+        let mut directory = self.directory.write().unwrap();
+
+        if let Some(k) = directory.get(key) {
+            let mut counter = 0;
+
+            for (perm, pid) in k {
+                if pid.eq(&requestor_pid) {
+                    continue;
+                }
+
+                if let Err(_) = send(
+                    ctx,
+                    pid,
+                    &Operation::ShmemInv(key.to_string(), InvType::ToInv)
+                ) {
+                    println!("Error sending invalid message 5");
+                    continue;
+                }
+
+                if perm.eq(&Perm::Shared) {
+                    counter += 1;
+                    continue;
+                }
+
+                // the exclusive copy may be newer than the table
+                let (_pid, operation) = self.dir_res_rx.lock().unwrap().recv().unwrap();
+                match operation {
+                    Operation::ShmemInvRes(Some(value)) => {
+                        self.table.write().unwrap().insert(key.to_string(), value);
+                    },
+                    Operation::ShmemInvRes(None) => {
+                        self.table.write().unwrap().remove(key);
+                    },
+                    _ => {
+                        println!("Wait WHAT?? 6");
+                    }
+                }
+            }
+
+            while counter > 0 {
+                let (_pid, operation) = self.dir_res_rx.lock().unwrap().recv().unwrap();
+                match operation {
+                    Operation::ShmemInvRes(_) => {
+                        counter -= 1;
+                    },
+                    _ => {
+                        println!("Wait WHAT??");
+                    }
+                }
+            }
+        }
+
+        // record that the requestor have the exclusive copy
+        let mut new_set: HashSet<(Perm, u32)> = HashSet::new();
+        new_set.insert((Perm::Exclusive, requestor_pid));
+        directory.insert(key.to_string(), new_set);
+
+        let value = self.table.read().unwrap().get(key).cloned();
+        let _res = send(
+            ctx,
+            &requestor_pid,
+            &Operation::ShmemAcquireRes(client_pid, key.to_string(), value.clone(), psn),
+        );
+
+        value
+    }
+
+    // A conditional write run on the table, when servers keep no cache
+    pub fn conditional_no_cache(&self, operation: &Operation, client_pid: Pid, ctx: &mut NetworkContext) {
+        let (key, _psn) = conditional_key(operation);
+        let mut table = self.table.write().unwrap();
+        let (write, response) = run_conditional(operation, table.get(&key).cloned());
+        match write {
+            Some(Some(value)) => {
+                table.insert(key, value);
+            },
+            Some(None) => {
+                table.remove(&key);
+            },
+            None => {}
+        }
+
+        let _res = send(ctx, &client_pid, &response);
+    }
+
+    // The entries of [start, end) in key order, at most limit of them. An
+    // exclusive copy may be newer than the table, so the ones in the range
+    // are recalled to shared first, as for get. The scan stops where a range
+    // handed to another home begins and says where it stopped.
+    pub fn scan(&self, (start, end): (&str, Option<String>), limit: usize, requestor_pid: Pid, client_pid: Pid,
+                psn: usize, ctx: &mut NetworkContext) {
+        let mut end = end;
+        for (moved_start, _, _) in self.moved.read().unwrap().iter() {
+            if moved_start.as_str() > start && end.as_ref().is_none_or(|end| moved_start < end) {
+                end = Some(moved_start.clone());
+            }
+        }
+
+        let mut directory = self.directory.write().unwrap();
+        for (key, holders) in directory.range_mut::<str, _>(key_range(start, &end)) {
+            let exclusive: Vec<Pid> = holders.iter().filter(|(perm, _)| *perm == Perm::Exclusive).map(|(_, pid)| *pid).collect();
+            for pid in exclusive {
+                if send(ctx, &pid, &Operation::ShmemInv(key.clone(), InvType::ToShared)).is_err() {
+                    println!("Error sending invalid message 7");
+                    continue;
+                }
+
+                let (_pid, operation) = self.dir_res_rx.lock().unwrap().recv().unwrap();
+                match operation {
+                    Operation::ShmemInvRes(Some(value)) => {
+                        self.table.write().unwrap().insert(key.clone(), value);
+                    },
+                    Operation::ShmemInvRes(None) => {
+                        self.table.write().unwrap().remove(key);
+                    },
+                    _ => {
+                        println!("Wait WHAT?? 7");
+                    }
+                }
+                holders.remove(&(Perm::Exclusive, pid));
+                holders.insert((Perm::Shared, pid));
+            }
+        }
+
+        let entries: Vec<(String, String)> = self.table.read().unwrap()
+            .range::<str, _>(key_range(start, &end))
+            .take(limit)
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect();
+        let _res = send(
+            ctx,
+            &requestor_pid,
+            &Operation::ShmemScanRes(client_pid, entries, end, psn),
+        );
+    }
+
+    // The home a request went to instead of this one, if its key is in a
+    // range handed over
+    pub fn moved_to(&self, key: &str) -> Option<Pid> {
+        self.moved.read().unwrap().iter()
+            .find(|(start, end, _)| in_range(key, start, end))
+            .map(|(_, _, new_home)| *new_home)
+    }
+
+    // Hands [start, end) to new_home: every cached copy in the range is
+    // invalidated, an exclusive one sends its value back first, and the
+    // entries go over once the directory is clear. Requests for the range
+    // that still come here are forwarded from then on. If the entries cannot
+    // be sent or the new home does not take them in time they go back into
+    // the table and the range stays here: none.
+    pub fn hand_over(&self, start: String, end: Option<String>, new_home: Pid, ctx: &mut NetworkContext) -> Option<usize> {
+        let mut directory = self.directory.write().unwrap();
+        let keys: Vec<String> = directory.range::<str, _>(key_range(&start, &end)).map(|(key, _)| key.clone()).collect();
+        for key in keys {
+            let mut counter = 0;
+            for (perm, pid) in directory.remove(&key).unwrap() {
+                if send(ctx, &pid, &Operation::ShmemInv(key.clone(), InvType::ToInv)).is_err() {
+                    println!("Error sending invalid message 8");
+                    continue;
+                }
+
+                if perm.eq(&Perm::Shared) {
+                    counter += 1;
+                    continue;
+                }
+
+                let (_pid, operation) = self.dir_res_rx.lock().unwrap().recv().unwrap();
+                match operation {
+                    Operation::ShmemInvRes(Some(value)) => {
+                        self.table.write().unwrap().insert(key.clone(), value);
+                    },
+                    Operation::ShmemInvRes(None) => {
+                        self.table.write().unwrap().remove(&key);
+                    },
+                    _ => {
+                        println!("Wait WHAT?? 8");
+                    }
+                }
+            }
+
+            while counter > 0 {
+                let (_pid, operation) = self.dir_res_rx.lock().unwrap().recv().unwrap();
+                match operation {
+                    Operation::ShmemInvRes(_) => {
+                        counter -= 1;
+                    },
+                    _ => {
+                        println!("Wait WHAT??");
+                    }
+                }
+            }
+        }
+
+        let mut table = self.table.write().unwrap();
+        let keys: Vec<String> = table.range::<str, _>(key_range(&start, &end)).map(|(key, _)| key.clone()).collect();
+        let entries: Vec<(String, String)> = keys.into_iter().map(|key| {
+            let value = table.remove(&key).unwrap();
+            (key, value)
+        }).collect();
+        let num_keys = entries.len();
+
+        if let Err(e) = send(ctx, &new_home, &Operation::ShmemTakeRange(start.clone(), end.clone(), entries.clone())) {
+            println!("Failed to send range: {:?}", e);
+            table.extend(entries);
+            return None;
+        }
+        // the new home has the entries before anything is forwarded to it
+        let deadline = Instant::now() + Duration::from_millis(TAKE_RANGE_TIMEOUT_MS);
+        loop {
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            match self.dir_res_rx.lock().unwrap().recv_timeout(timeout) {
+                Ok((_pid, Operation::ShmemTakeRangeRes())) => break,
+                Ok(_) => {
+                    println!("Wait WHAT?? 9");
+                }
+                Err(e) => {
+                    println!("{} did not take range: {:?}", new_home, e);
+                    table.extend(entries);
+                    return None;
+                }
+            }
+        }
+
+        self.moved.write().unwrap().push((start, end, new_home));
+        Some(num_keys)
+    }
+
+    // Becomes the home of [start, end), which may have been handed over from
+    // here before
+    pub fn take_over(&self, start: String, end: Option<String>, entries: Vec<(String, String)>) {
+        self.table.write().unwrap().extend(entries);
+
+        let mut moved = self.moved.write().unwrap();
+        let mut kept = Vec::new();
+        for (moved_start, moved_end, new_home) in moved.drain(..) {
+            let overlaps = end.as_ref().is_none_or(|end| moved_start < *end)
+                && moved_end.as_ref().is_none_or(|moved_end| start < *moved_end);
+            if !overlaps {
+                kept.push((moved_start, moved_end, new_home));
+                continue;
+            }
+            if moved_start < start {
+                kept.push((moved_start, Some(start.clone()), new_home));
+            }
+            if let Some(end) = &end {
+                if moved_end.as_ref().is_none_or(|moved_end| end < moved_end) {
+                    kept.push((end.clone(), moved_end, new_home));
+                }
+            }
+        }
+        *moved = kept;
+    }
+
+    pub fn count_request(&self, key: &str) {
+        *self.requests.lock().unwrap().entry(key.to_string()).or_insert(0) += 1;
+    }
+
+    // The keys and requests of every range this server is the home of, with
+    // the key to split it at: the middle by requests, by keys if that is the
+    // range's first key or there were none. Starts counting requests anew.
+    pub fn shard_loads(&self, info: &ShardInfo, self_pid: Pid) -> Vec<ShardLoad> {
+        let requests = std::mem::take(self.requests.lock().unwrap().deref_mut());
+        let table = self.table.read().unwrap();
+        let mut loads = Vec::new();
+        for (shard_id, location) in &info.locations {
+            if location.primary != self_pid {
+                continue;
+            }
+            let (start, end) = match info.range_of(*shard_id) {
+                Some(range) => range,
+                None => continue,
+            };
+
+            let keys = table.range::<str, _>(key_range(&start, &end)).count();
+            let counts: Vec<(&String, &usize)> = requests.range::<str, _>(key_range(&start, &end)).collect();
+            let total: usize = counts.iter().map(|(_, count)| **count).sum();
+            let mut so_far = 0;
+            let mut split_key = counts.iter()
+                .find(|(_, count)| {
+                    so_far += **count;
+                    so_far * 2 >= total
+                })
+                .map(|(key, _)| (*key).clone())
+                .filter(|key| *key > start);
+            if split_key.is_none() {
+                split_key = table.range::<str, _>(key_range(&start, &end)).nth(keys / 2)
+                    .map(|(key, _)| key.clone())
+                    .filter(|key| *key > start);
+            }
+
+            loads.push(ShardLoad { shard_id: *shard_id, keys, requests: total, split_key });
+        }
+        loads.sort_by_key(|load| load.shard_id);
+        loads
+    }
+
     pub fn inner_table(&self) -> HashMap<String, String> {
         let locked_table = self.table.read().unwrap();
-        locked_table.clone()
+        locked_table.iter().map(|(key, value)| (key.clone(), value.clone())).collect()
     }
 }
@@ -1,2 +1,6 @@
+pub mod cli;
+pub mod faults;
 pub mod kvs;
 pub mod network;
+pub mod tcp;
+pub mod udp;
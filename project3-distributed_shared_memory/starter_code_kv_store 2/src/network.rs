@@ -2,104 +2,284 @@
 extern crate serde_json;
 
 // use nanomsg::{Protocol, Socket};
+use crate::faults::{FaultInjector, Verdict, DEFAULT_FAULT_SEED};
 use crate::kvs::{Operation, Pid};
-use std::io::Result;
-use std::net::{SocketAddr, UdpSocket};
+use crate::tcp::TcpTransport;
+use crate::udp::UdpTransport;
+use lazy_static::lazy_static;
+use std::io::{Error, ErrorKind, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
 // Usage:
+// // pick the transport once, before any context is created (udp by default)
+// set_default_transport(TransportKind::Tcp);
 // // create network context
 // let ctx = create_network_context(&self_pid).unwrap();
 // // send to dst_pid an operation
 // send(&mut ctx, &dst_pid, Operation::Get("x", 0));
 // // recv from any sources, returns the data and src addr
 // recv_from(&mut ctx, 1000);
+//
+// send and recv encode operations and hand the bytes to a Transport:
+// UdpTransport (udp.rs) sends one datagram per message, TcpTransport (tcp.rs)
+// keeps a connection per peer and delivers messages reliably and in order.
+//
+// Between the two sits the process' FaultInjector (faults.rs), which tests
+// configure to drop, delay, duplicate or reorder messages and to partition
+// the cluster. It does nothing unless configured. FaultControl messages
+// reconfigure it at runtime, recv only applies them in processes started
+// with --allow-fault-control. Both transports already refuse senders whose
+// address maps to no pid of the cluster (get_pid_from_ip_port).
+
+// Moves whole messages between pids. Clones of a transport share its state,
+// so every thread of a component can send and recv on its own clone.
+pub trait Transport: Send {
+    fn send(&mut self, dst_pid: &Pid, data: &[u8]) -> Result<()>;
+    // Waits up to timeout for messages, returns every one that is ready
+    fn recv(&mut self, timeout: Duration) -> Result<Vec<(Pid, Vec<u8>)>>;
+    fn try_clone(&self) -> Result<Box<dyn Transport>>;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TransportKind {
+    Udp,
+    Tcp,
+}
+
+static DEFAULT_TRANSPORT: AtomicU8 = AtomicU8::new(TransportKind::Udp as u8);
+
+pub fn set_default_transport(kind: TransportKind) {
+    DEFAULT_TRANSPORT.store(kind as u8, Ordering::SeqCst);
+}
+
+pub fn default_transport() -> TransportKind {
+    if DEFAULT_TRANSPORT.load(Ordering::SeqCst) == TransportKind::Tcp as u8 {
+        TransportKind::Tcp
+    } else {
+        TransportKind::Udp
+    }
+}
+
+lazy_static! {
+    // one per process, shared by every context so a FaultControl received on
+    // any of them applies to all
+    static ref DEFAULT_FAULTS: Arc<Mutex<FaultInjector>> = Arc::new(Mutex::new(FaultInjector::new(DEFAULT_FAULT_SEED)));
+}
+
+pub fn set_default_faults(faults: FaultInjector) {
+    *DEFAULT_FAULTS.lock().unwrap() = faults;
+}
+
+// Off by default: anyone who can reach a port could otherwise partition the
+// cluster with a single FaultControl.
+static DEFAULT_ALLOW_FAULT_CONTROL: AtomicBool = AtomicBool::new(false);
+
+pub fn set_allow_fault_control(allow: bool) {
+    DEFAULT_ALLOW_FAULT_CONTROL.store(allow, Ordering::SeqCst);
+}
+
+// How often recv wakes up to release delayed messages
+const FAULT_TICK_MS: u64 = 10;
+// A reordered message goes out at the latest after this long
+const REORDER_HOLD_MS: u64 = 20;
+
+pub fn parse_transport(name: &str) -> Result<TransportKind> {
+    match name {
+        "udp" => Ok(TransportKind::Udp),
+        "tcp" => Ok(TransportKind::Tcp),
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown transport {}, expected udp or tcp", name),
+        )),
+    }
+}
 
 pub struct NetworkContext {
-    // sockets: HashMap<Pid, UdpSocket>
-    socket: UdpSocket,
+    transport: Box<dyn Transport>,
     self_pid: Pid,
+    faults: Arc<Mutex<FaultInjector>>,
+    held: Arc<Mutex<Vec<HeldMessage>>>, // delayed or reordered, shared by every clone
+    allow_fault_control: bool,
+}
+
+struct HeldMessage {
+    dst_pid: Pid,
+    data: Vec<u8>,
+    release_at: Instant,
+    reordered: bool, // also released by the next message to dst_pid
 }
 
 impl NetworkContext {
     // Constructor function
-    pub fn new(self_pid: &Pid) -> Result<NetworkContext> {
-        let socket = UdpSocket::bind(&get_ip_port_from_pid(&self_pid))?;
-        println!("create socket: {}", &get_ip_port_from_pid(&self_pid));
+    pub fn new(self_pid: &Pid, kind: TransportKind) -> Result<NetworkContext> {
+        let transport: Box<dyn Transport> = match kind {
+            TransportKind::Udp => Box::new(UdpTransport::bind(self_pid)?),
+            TransportKind::Tcp => Box::new(TcpTransport::bind(self_pid)?),
+        };
+        println!("create socket: {} ({:?})", &get_ip_port_from_pid(&self_pid), kind);
         Ok(NetworkContext {
-            socket: socket,
+            transport,
             self_pid: self_pid.clone(),
+            faults: Arc::clone(&DEFAULT_FAULTS),
+            held: Arc::new(Mutex::new(Vec::new())),
+            allow_fault_control: DEFAULT_ALLOW_FAULT_CONTROL.load(Ordering::SeqCst),
         })
     }
+
+    // Gives this context (and its later clones) an injector of its own
+    // instead of the process-wide one, for tests that run several pids in
+    // one process.
+    pub fn set_faults(&mut self, faults: FaultInjector) {
+        self.faults = Arc::new(Mutex::new(faults));
+    }
+
+    // Whether recv applies FaultControl messages, for contexts a test creates
+    // without going through the binaries' --allow-fault-control.
+    pub fn set_allow_fault_control(&mut self, allow: bool) {
+        self.allow_fault_control = allow;
+    }
+
+    // Sends the held messages that are due, or every one to dst_pid that was
+    // only held to be reordered. One that fails to go out is lost.
+    fn release_held(&mut self, next_to: Option<Pid>) {
+        let now = Instant::now();
+        let mut released = Vec::new();
+        self.held.lock().unwrap().retain(|message| {
+            let due = message.release_at <= now || (message.reordered && Some(message.dst_pid) == next_to);
+            if due {
+                released.push((message.dst_pid, message.data.clone()));
+            }
+            !due
+        });
+        for (dst_pid, data) in released {
+            let _ = self.transport.send(&dst_pid, &data);
+        }
+    }
+
+    fn hold(&mut self, dst_pid: &Pid, data: &str, hold_ms: u64, reordered: bool) {
+        self.held.lock().unwrap().push(HeldMessage {
+            dst_pid: *dst_pid,
+            data: data.as_bytes().to_vec(),
+            release_at: Instant::now() + Duration::from_millis(hold_ms),
+            reordered,
+        });
+    }
 }
 
 impl Clone for NetworkContext {
     fn clone(&self) -> Self {
-        let new_socket = self.socket.try_clone().expect("Failed to clone UdpSocket");
+        let new_transport = self.transport.try_clone().expect("Failed to clone transport");
         NetworkContext {
-            socket: new_socket,
+            transport: new_transport,
             self_pid: self.self_pid,
+            faults: Arc::clone(&self.faults),
+            held: Arc::clone(&self.held),
+            allow_fault_control: self.allow_fault_control,
         }
     }
 }
 
-fn get_ip_port_from_pid(pid: &Pid) -> String {
-    format!("127.0.0.1:{}", 8000 + pid).to_string()
+// Every pid listens on this port plus the pid, on the loopback address
+const BASE_PORT: u16 = 8000;
+
+pub(crate) fn get_ip_port_from_pid(pid: &Pid) -> String {
+    format!("127.0.0.1:{}", BASE_PORT as Pid + pid)
 }
 
-fn get_pid_from_ip_port(ip_port: &SocketAddr) -> Pid {
-    (ip_port.port() - 8000) as Pid
+// The pid that sends from ip_port, an error for an address no process of the
+// cluster listens on
+pub(crate) fn get_pid_from_ip_port(ip_port: &SocketAddr) -> Result<Pid> {
+    match ip_port.port().checked_sub(BASE_PORT) {
+        Some(pid) if ip_port.ip().is_loopback() => Ok(pid as Pid),
+        _ => Err(Error::new(ErrorKind::InvalidInput, format!("{} is not a cluster address", ip_port))),
+    }
 }
 
 pub fn create_network_context(self_pid: &Pid) -> Result<NetworkContext> {
-    let ctx = NetworkContext::new(self_pid)?;
-    ctx.socket.set_read_timeout(Some(Duration::from_secs(1)))?;
-    Ok(ctx)
+    NetworkContext::new(self_pid, default_transport())
+}
+
+// The variant name fault rules match on, e.g. Put
+fn operation_name(operation: &Operation) -> String {
+    match serde_json::to_value(operation) {
+        Ok(serde_json::Value::Object(map)) => map.keys().next().cloned().unwrap_or_default(),
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
 }
 
 pub fn send(ctx: &mut NetworkContext, dst_pid: &Pid, operation: &Operation) -> Result<usize> {
     let data = serde_json::to_string(operation).unwrap();
-    // println!("send: src:{} dst:{} data:{}",  &get_ip_port_from_pid(&(ctx.self_pid)), &get_ip_port_from_pid(&dst_pid), &data);
     // println!("send: {} -> {} data:{}", &ctx.self_pid, &dst_pid, &data);
-    match ctx
-        .socket
-        .send_to(data.as_bytes(), &get_ip_port_from_pid(&dst_pid))
-    {
-        Ok(_) => Ok(data.len()),
-        Err(e) => Err(e),
+    ctx.release_held(None);
+    let verdict = {
+        let mut faults = ctx.faults.lock().unwrap();
+        match operation {
+            Operation::FaultControl(_) => Verdict::Deliver,
+            _ if faults.is_empty() => Verdict::Deliver,
+            _ => faults.decide(ctx.self_pid, *dst_pid, &operation_name(operation)),
+        }
+    };
+    match verdict {
+        Verdict::Deliver => {
+            ctx.transport.send(dst_pid, data.as_bytes())?;
+            ctx.release_held(Some(*dst_pid));
+        }
+        Verdict::Drop => {}
+        Verdict::Delay(ms) => ctx.hold(dst_pid, &data, ms, false),
+        Verdict::Duplicate => {
+            ctx.transport.send(dst_pid, data.as_bytes())?;
+            ctx.transport.send(dst_pid, data.as_bytes())?;
+            ctx.release_held(Some(*dst_pid));
+        }
+        Verdict::Reorder => ctx.hold(dst_pid, &data, REORDER_HOLD_MS, true),
     }
+    Ok(data.len())
 }
 
 pub fn recv(ctx: &mut NetworkContext, timeout_ms: u64) -> Result<Vec<(Pid, Operation)>> {
-    ctx.socket
-        .set_read_timeout(Some(Duration::from_millis(timeout_ms)))?;
-
-    let mut results = Vec::new();
     let start_time = Instant::now();
     let timeout = Duration::from_millis(timeout_ms as u64);
 
-    const MAX_BUF_SIZE: usize = 1000000;
-    let mut buf = [0; MAX_BUF_SIZE];
     loop {
-        match ctx.socket.recv_from(&mut buf) {
-            Ok((bytes_read, ip_port)) => {
-                if bytes_read > 0 {
-                    let data = String::from_utf8_lossy(&buf[0..bytes_read]);
-                    let operation = serde_json::from_str(&data).unwrap();
-                    let src_pid = get_pid_from_ip_port(&ip_port);
-                    // println!("recv: {} -> {} data:{}", &src_pid, &(ctx.self_pid), &data);
-                    results.push((src_pid, operation));
+        ctx.release_held(None);
+        let remaining = timeout.saturating_sub(start_time.elapsed());
+        // wake up in time for held messages, which another clone may hold
+        // back while this one waits
+        let wait = if ctx.faults.lock().unwrap().is_empty() && ctx.held.lock().unwrap().is_empty() {
+            remaining
+        } else {
+            remaining.min(Duration::from_millis(FAULT_TICK_MS))
+        };
+        let messages = match ctx.transport.recv(wait) {
+            Ok(messages) => messages,
+            Err(e) if start_time.elapsed() >= timeout => return Err(e),
+            Err(_) => continue,
+        };
+        let mut operations = Vec::new();
+        for (src_pid, data) in messages {
+            match serde_json::from_slice::<Operation>(&data) {
+                Ok(Operation::FaultControl(commands)) => {
+                    if !ctx.allow_fault_control {
+                        println!("Ignoring fault control from {}: not allowed in this process", src_pid);
+                    } else if let Err(e) = ctx.faults.lock().unwrap().apply(&commands) {
+                        println!("Ignoring fault control from {}: {}", src_pid, e);
+                    }
+                }
+                Ok(operation) => {
+                    if ctx.faults.lock().unwrap().is_partitioned(src_pid, ctx.self_pid) {
+                        continue;
+                    }
+                    operations.push((src_pid, operation))
                 }
+                Err(e) => println!("Dropping malformed message from {}: {}", src_pid, e),
             }
-            Err(_) => {}
         }
-
-        if !results.is_empty() {
-            return Ok(results);
-        }
-
-        if start_time.elapsed() >= timeout {
-            return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"));
+        if !operations.is_empty() {
+            return Ok(operations);
         }
     }
 }
@@ -0,0 +1,174 @@
+use crate::kvs::Pid;
+use crate::network::{get_ip_port_from_pid, Transport};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Error, ErrorKind, Read, Result, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, sleep};
+use std::time::Duration;
+
+// A transport over TCP. Every process listens on the same port a UDP context
+// would bind. The first message to a peer opens a connection and introduces
+// itself with a frame carrying the sender's pid; the connection is then kept
+// for every later message to that peer. Each frame is a u32 length followed
+// by the message. A thread per accepted connection reads frames into a
+// channel that recv drains. When a write fails, the connection is dropped
+// and opened again once, which covers a peer that restarted; a peer that
+// stays down makes send fail. Connecting happens without any lock held, and
+// a write only locks the connection it goes over, so a peer that is slow or
+// down does not hold up sends to the others.
+//
+// project2 and project3 are separate crates without a common dependency, so
+// each has its own copy of this file, as of network.rs and faults.rs. The
+// project2 one also checks senders against its membership file; a fix to the
+// rest goes into both.
+
+const CONNECT_TIMEOUT_MS: u64 = 500;
+const ACCEPT_POLL_MS: u64 = 10;
+// a length above this means the stream is garbage
+const MAX_FRAME_SIZE: usize = 256 * 1024 * 1024;
+
+type Message = (Pid, Vec<u8>);
+
+pub struct TcpTransport {
+    self_pid: Pid,
+    incoming: Arc<Mutex<Receiver<Message>>>,
+    outgoing: Arc<Mutex<HashMap<Pid, Arc<Mutex<TcpStream>>>>>,
+    // the listener stops once every clone is dropped
+    alive: Arc<()>,
+}
+
+impl TcpTransport {
+    pub fn bind(self_pid: &Pid) -> Result<TcpTransport> {
+        let listener = TcpListener::bind(get_ip_port_from_pid(self_pid))?;
+        listener.set_nonblocking(true)?;
+        let (tx, rx) = mpsc::channel();
+        let alive = Arc::new(());
+        let weak = Arc::downgrade(&alive);
+        thread::spawn(move || accept_connections(listener, tx, weak));
+        Ok(TcpTransport {
+            self_pid: *self_pid,
+            incoming: Arc::new(Mutex::new(rx)),
+            outgoing: Arc::new(Mutex::new(HashMap::new())),
+            alive,
+        })
+    }
+
+    fn connect(&self, dst_pid: &Pid) -> Result<TcpStream> {
+        let addr: SocketAddr = get_ip_port_from_pid(dst_pid).parse().unwrap();
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_millis(CONNECT_TIMEOUT_MS))?;
+        stream.set_nodelay(true)?;
+        write_frame(&mut stream, &(self.self_pid as u64).to_be_bytes())?;
+        Ok(stream)
+    }
+}
+
+fn accept_connections(listener: TcpListener, tx: Sender<Message>, alive: Weak<()>) {
+    while alive.upgrade().is_some() {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let tx = tx.clone();
+                thread::spawn(move || read_connection(stream, tx));
+            }
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => sleep(Duration::from_millis(ACCEPT_POLL_MS)),
+            Err(e) => println!("accept failed: {:?}", e),
+        }
+    }
+}
+
+fn read_connection(mut stream: TcpStream, tx: Sender<Message>) {
+    if stream.set_nonblocking(false).is_err() {
+        return;
+    }
+    let src_pid = match read_frame(&mut stream) {
+        Ok(hello) if hello.len() == 8 => u64::from_be_bytes(hello[..].try_into().unwrap()) as Pid,
+        _ => {
+            println!("Dropping connection without a valid handshake");
+            return;
+        }
+    };
+    // ends when the peer closes the connection or the transport is gone
+    while let Ok(data) = read_frame(&mut stream) {
+        if tx.send((src_pid, data)).is_err() {
+            return;
+        }
+    }
+}
+
+fn write_frame(stream: &mut TcpStream, data: &[u8]) -> Result<()> {
+    let mut frame = Vec::with_capacity(4 + data.len());
+    frame.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    frame.extend_from_slice(data);
+    stream.write_all(&frame)
+}
+
+fn read_frame(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "frame too large"));
+    }
+    let mut data = vec![0; len];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, dst_pid: &Pid, data: &[u8]) -> Result<()> {
+        // a cached connection may be broken, the second try uses a fresh one
+        for attempt in 0..2 {
+            let cached = self.outgoing.lock().unwrap().get(dst_pid).cloned();
+            let stream = match cached {
+                Some(stream) => stream,
+                None => {
+                    let stream = Arc::new(Mutex::new(self.connect(dst_pid)?));
+                    // another thread may have connected meanwhile, keep one
+                    let mut outgoing = self.outgoing.lock().unwrap();
+                    Arc::clone(outgoing.entry(*dst_pid).or_insert(stream))
+                }
+            };
+            let result = write_frame(&mut stream.lock().unwrap(), data);
+            match result {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    let mut outgoing = self.outgoing.lock().unwrap();
+                    if outgoing.get(dst_pid).is_some_and(|cached| Arc::ptr_eq(cached, &stream)) {
+                        outgoing.remove(dst_pid);
+                    }
+                    if attempt == 1 {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Vec<Message>> {
+        let incoming = self.incoming.lock().unwrap();
+        let first = match incoming.recv_timeout(timeout) {
+            Ok(message) => message,
+            Err(RecvTimeoutError::Timeout) => return Err(Error::new(ErrorKind::TimedOut, "Timeout")),
+            Err(RecvTimeoutError::Disconnected) => {
+                return Err(Error::new(ErrorKind::NotConnected, "listener stopped"))
+            }
+        };
+        let mut messages = vec![first];
+        while let Ok(message) = incoming.try_recv() {
+            messages.push(message);
+        }
+        Ok(messages)
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(TcpTransport {
+            self_pid: self.self_pid,
+            incoming: Arc::clone(&self.incoming),
+            outgoing: Arc::clone(&self.outgoing),
+            alive: Arc::clone(&self.alive),
+        }))
+    }
+}
@@ -0,0 +1,58 @@
+use crate::kvs::Pid;
+use crate::network::{get_ip_port_from_pid, get_pid_from_ip_port, Transport};
+use std::io::Result;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+// The default transport: one datagram per message on a single UdpSocket, no
+// acks or retransmissions. Messages must fit in one datagram.
+
+const MAX_BUF_SIZE: usize = 1000000;
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+}
+
+impl UdpTransport {
+    pub fn bind(self_pid: &Pid) -> Result<UdpTransport> {
+        let socket = UdpSocket::bind(get_ip_port_from_pid(self_pid))?;
+        socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+        Ok(UdpTransport { socket })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, dst_pid: &Pid, data: &[u8]) -> Result<()> {
+        self.socket.send_to(data, get_ip_port_from_pid(dst_pid))?;
+        Ok(())
+    }
+
+    fn recv(&mut self, timeout: Duration) -> Result<Vec<(Pid, Vec<u8>)>> {
+        // a zero timeout would block forever
+        self.socket
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+
+        let start_time = Instant::now();
+        let mut buf = vec![0; MAX_BUF_SIZE];
+        loop {
+            if let Ok((bytes_read, ip_port)) = self.socket.recv_from(&mut buf) {
+                if bytes_read > 0 {
+                    match get_pid_from_ip_port(&ip_port) {
+                        Ok(src_pid) => return Ok(vec![(src_pid, buf[0..bytes_read].to_vec())]),
+                        Err(e) => println!("Dropping datagram: {}", e),
+                    }
+                }
+            }
+
+            if start_time.elapsed() >= timeout {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "Timeout"));
+            }
+        }
+    }
+
+    fn try_clone(&self) -> Result<Box<dyn Transport>> {
+        Ok(Box::new(UdpTransport {
+            socket: self.socket.try_clone()?,
+        }))
+    }
+}
@@ -0,0 +1,134 @@
+mod utils;
+
+use kv_store::kvs::KVSResult;
+use std::collections::HashSet;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{launch_client, launch_controller, launch_server, read_result};
+
+// Every client claims "owner" with a PutIfAbsent, counts on "counter" with
+// Incr and ends with a Cas on "owner" that can not hold. Only one claim may
+// win, and no number may be handed out twice or skipped.
+fn verify_result(result: &Vec<Vec<KVSResult>>) {
+    let mut owners = Vec::new();
+    let mut numbers = HashSet::new();
+    let mut total = 0;
+
+    for client_result in result {
+        let mut prev_number = 0;
+
+        for op_result in client_result {
+            match op_result.operation.as_str() {
+                "putifabsent" => {
+                    assert!(op_result.key == "owner");
+                    owners.push(op_result.new_value.clone());
+                }
+                "incr" => {
+                    let number: usize = op_result.new_value.parse().unwrap();
+                    assert!(number > prev_number, "counter went from {} to {}", prev_number, number);
+                    assert!(numbers.insert(number), "{} handed out twice", number);
+                    prev_number = number;
+                    total += 1;
+                }
+                _ => {
+                    assert!(op_result.operation == "cas");
+                    assert!(op_result.observed_value == op_result.new_value, "cas owner - - swapped");
+                    owners.push(op_result.new_value.clone());
+                }
+            }
+        }
+    }
+
+    assert!(owners.windows(2).all(|pair| pair[0] == pair[1]), "owners = {:?}", owners);
+    assert!(numbers == (1..=total).collect(), "numbers handed out are not 1..={}", total);
+}
+
+fn test_conditional_common(num_clients: usize, num_servers: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "cond";
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(
+            &bin_dir,
+            &server_pid,
+            &controller_pid_for_servers,
+            &server_pids,
+        )
+        .expect("Failed to launch client");
+        servers.push(server);
+    }
+
+    // Launch controller.
+    println!("launching controllers");
+    let mut controller = launch_controller(
+        &bin_dir,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(
+            &bin_dir,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    // Sleep for enough time to let the system run
+    sleep(Duration::from_secs(10));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid);
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            verify_result(&result);
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
+
+#[test]
+fn test_conditional_writes() {
+    test_conditional_common(3, 1);
+    test_conditional_common(3, 3);
+}
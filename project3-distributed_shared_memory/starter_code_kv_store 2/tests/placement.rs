@@ -0,0 +1,140 @@
+mod utils;
+
+use kv_store::kvs::{assign_shards_to_servers, get_shard_id_from_key, KVSResult, Pid, ShardInfo};
+use std::collections::HashMap;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{group_result_by_key, launch_client, launch_controller_with_options, launch_server, read_result};
+
+fn assignment(server_pids: &[Pid], num_shards: usize, replication_factor: usize) -> ShardInfo {
+    let mut info = ShardInfo::new();
+    assign_shards_to_servers(&mut info, server_pids, num_shards, replication_factor);
+    info
+}
+
+#[test]
+fn test_shard_count_does_not_depend_on_servers() {
+    for num_servers in 1..=9 {
+        let server_pids: Vec<Pid> = (4..4 + num_servers).collect();
+        let info = assignment(&server_pids, 256, 3);
+        assert_eq!(info.locations.len(), 256);
+        for location in info.locations.values() {
+            assert!(server_pids.contains(&location.primary));
+            assert!(!location.secondaries.contains(&location.primary));
+            assert_eq!(location.secondaries.len(), 3.min(num_servers as usize) - 1);
+        }
+    }
+    // a key's shard only depends on the number of shards
+    let few = assignment(&[4, 5], 64, 2);
+    let many = assignment(&[4, 5, 6, 7, 8], 64, 2);
+    for i in 0..100 {
+        let key = format!("key_{}", i);
+        assert_eq!(
+            get_shard_id_from_key(&key, few.locations.len()),
+            get_shard_id_from_key(&key, many.locations.len())
+        );
+    }
+}
+
+#[test]
+fn test_primaries_and_secondaries_are_balanced() {
+    for num_servers in 1..=9 {
+        let server_pids: Vec<Pid> = (4..4 + num_servers).collect();
+        for (num_shards, replication_factor) in [(256, 3), (64, 2), (7, 5)] {
+            let info = assignment(&server_pids, num_shards, replication_factor);
+            let mut primaries: HashMap<Pid, usize> = HashMap::new();
+            let mut secondaries: HashMap<Pid, usize> = HashMap::new();
+            for location in info.locations.values() {
+                *primaries.entry(location.primary).or_insert(0) += 1;
+                for pid in &location.secondaries {
+                    *secondaries.entry(*pid).or_insert(0) += 1;
+                }
+            }
+            let spread = |counts: &HashMap<Pid, usize>| {
+                let counts: Vec<usize> = server_pids.iter().map(|pid| counts.get(pid).cloned().unwrap_or(0)).collect();
+                counts.iter().max().unwrap() - counts.iter().min().unwrap()
+            };
+            assert!(spread(&primaries) <= 1);
+            assert!(spread(&secondaries) <= 1);
+        }
+    }
+}
+
+fn verify_result(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// Read your write on 3 servers with many more shards than servers
+#[test]
+fn test_read_your_write_with_many_shards() {
+    let bin_dir = "./target/debug/";
+    let input_dir = "./data/input/ryw";
+    let result_dir = "./data/result/ryw_many_shards";
+    let num_clients = 3;
+    let num_servers = 3;
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(bin_dir, server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+    let mut controller = launch_controller_with_options(
+        bin_dir,
+        &["--shards", "256", "--replication-factor", "2"],
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client(bin_dir, client_pid, &controller_pid_for_clients, input_dir, &server_pids, result_dir)
+            .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    sleep(Duration::from_secs(10));
+
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    let result_paths = client_pids.iter().map(|pid| format!("{}{}.txt", result_dir, pid)).collect();
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_result(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
@@ -0,0 +1,230 @@
+mod utils;
+
+use kv_store::kvs::{
+    assign_ranges_to_servers, pick_new_home, pick_split, KVSResult, Operation, Pid, ShardInfo, ShardLoad, ShardLoc, KVS,
+};
+use kv_store::network::{create_network_context, recv, send};
+use std::collections::BTreeMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::process::Child;
+use std::thread::sleep;
+use std::time::Duration;
+use utils::{launch_client, launch_controller_with_options, launch_server, read_result};
+
+fn load(shard_id: u32, keys: usize, requests: usize, split_key: Option<&str>) -> ShardLoad {
+    ShardLoad {
+        shard_id,
+        keys,
+        requests,
+        split_key: split_key.map(|key| key.to_string()),
+    }
+}
+
+#[test]
+fn test_ranges_split_in_key_order() {
+    let mut info = ShardInfo::new();
+    assign_ranges_to_servers(&mut info, &[4, 5, 6], 2);
+    assert_eq!(info.ranges, vec![("".to_string(), 0)]);
+    assert_eq!(info.shard_of(""), 0);
+    assert_eq!(info.shard_of("zzz"), 0);
+
+    let at = |pid: Pid| ShardLoc { primary: pid, secondaries: vec![] };
+    assert!(info.split_range(0, "m".to_string(), 1, at(5)));
+    assert!(info.split_range(0, "f".to_string(), 2, at(6)));
+    assert!(info.split_range(1, "t".to_string(), 3, at(4)));
+    // the key has to be inside the range, past its first key
+    assert!(!info.split_range(1, "m".to_string(), 4, at(4)));
+    assert!(!info.split_range(1, "u".to_string(), 4, at(4)));
+    assert!(!info.split_range(7, "b".to_string(), 4, at(4)));
+
+    for (key, shard_id) in [("", 0), ("a", 0), ("f", 2), ("l", 2), ("m", 1), ("s", 1), ("t", 3), ("zzz", 3)] {
+        assert_eq!(info.shard_of(key), shard_id, "{}", key);
+    }
+    assert_eq!(info.range_of(2), Some(("f".to_string(), Some("m".to_string()))));
+    assert_eq!(info.range_of(3), Some(("t".to_string(), None)));
+    assert_eq!(info.locations[&1].primary, 5);
+}
+
+#[test]
+fn test_split_picks_the_largest_or_hottest_range() {
+    let mut info = ShardInfo::new();
+    assign_ranges_to_servers(&mut info, &[4, 5, 6], 1);
+    info.split_range(0, "m".to_string(), 1, ShardLoc { primary: 5, secondaries: vec![] });
+
+    // nothing above the limits, or no key to split at
+    assert_eq!(pick_split(&info, &[load(0, 10, 10, Some("c")), load(1, 10, 10, Some("p"))], 100, 100), None);
+    assert_eq!(pick_split(&info, &[load(0, 500, 0, None), load(1, 500, 0, Some("m"))], 100, 100), None);
+
+    let loads = [load(0, 500, 10, Some("c")), load(1, 10, 900, Some("p"))];
+    assert_eq!(pick_split(&info, &loads, 100, 100), Some((1, "p".to_string())));
+    assert_eq!(pick_split(&info, &loads, 100, 1000), Some((0, "c".to_string())));
+
+    // the upper half goes to the server with the least load, one without a
+    // range at all first
+    assert_eq!(pick_new_home(&info, &loads, &[4, 5, 6]), 6);
+    assert_eq!(pick_new_home(&info, &loads, &[4, 5]), 4);
+}
+
+// A new home that never takes the entries leaves the range here, entries
+// and all
+#[test]
+fn test_hand_over_to_a_silent_home_keeps_the_range() {
+    let (_dir_res_tx, dir_res_rx) = mpsc::channel();
+    let kvs = KVS::new(Arc::new(Mutex::new(dir_res_rx)));
+    let entries = vec![("apple".to_string(), "1".to_string()), ("mango".to_string(), "2".to_string())];
+    kvs.take_over("a".to_string(), None, entries);
+    let mut ctx = create_network_context(&102).unwrap();
+
+    assert_eq!(kvs.hand_over("m".to_string(), None, 103, &mut ctx), None);
+    let table = kvs.inner_table();
+    assert_eq!(table.len(), 2);
+    assert_eq!(table["mango"], "2");
+    assert_eq!(kvs.moved_to("mango"), None);
+}
+
+// Replays every client's scans against one model of the store, the clients
+// in the order they ran
+fn verify_result(result: &Vec<Vec<KVSResult>>) {
+    let mut model: BTreeMap<String, String> = BTreeMap::new();
+    let mut num_scans = 0;
+
+    for client_result in result {
+        for op_result in client_result {
+            match op_result.operation.as_str() {
+                "put" => {
+                    model.insert(op_result.key.clone(), op_result.new_value.clone());
+                }
+                "delete" => {
+                    model.remove(&op_result.key);
+                }
+                _ => {
+                    assert!(op_result.operation == "scan");
+                    let expected: Vec<String> = model
+                        .iter()
+                        .filter(|(key, _)| **key >= op_result.key)
+                        .filter(|(key, _)| op_result.new_value.is_empty() || **key < op_result.new_value)
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect();
+                    let observed: Vec<&str> = op_result.observed_value.split_whitespace().collect();
+                    // the only scan its limit cuts short asks for 30
+                    assert!(observed.len() == expected.len() || observed.len() == 30);
+                    assert!(
+                        expected[..observed.len()] == observed[..],
+                        "scan from {} to {:?}: observed {:?}",
+                        op_result.key,
+                        op_result.new_value,
+                        observed
+                    );
+                    num_scans += 1;
+                }
+            }
+        }
+    }
+
+    assert_eq!(num_scans, 6);
+}
+
+fn get_shard_info(controller_pid: Pid) -> ShardInfo {
+    let mut ctx = create_network_context(&101).unwrap();
+    send(&mut ctx, &controller_pid, &Operation::GetShardInfo(None)).unwrap();
+    match recv(&mut ctx, 1000).unwrap().pop() {
+        Some((_, Operation::GetShardInfoRes(info))) => info,
+        other => panic!("no shard info: {:?}", other),
+    }
+}
+
+fn test_scan_common(options: &[&str]) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "scan";
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+
+    let num_clients = 2;
+    let num_servers = 3;
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server(&bin_dir, &server_pid, &controller_pid_for_servers, &server_pids)
+            .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch controller.
+    println!("launching controllers");
+    let mut controller = launch_controller_with_options(
+        &bin_dir,
+        options,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+    sleep(Duration::from_secs(1));
+
+    // The writer first, then the reader once the ranges had time to split
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        println!("launching client {}", client_pid);
+        let client = launch_client(
+            &bin_dir,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+        sleep(Duration::from_secs(10));
+    }
+
+    let info = get_shard_info(controller_pid_for_clients as Pid);
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+    for mut server in servers {
+        let _ = server.kill();
+    }
+
+    if options.contains(&"range") {
+        let mut homes: Vec<Pid> = info.ranges.iter().map(|(_, shard_id)| info.locations[shard_id].primary).collect();
+        homes.sort();
+        homes.dedup();
+        assert!(info.ranges.len() > 2, "ranges = {:?}", info.ranges);
+        assert!(homes.len() > 1, "every range is on {:?}", homes);
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid);
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            verify_result(&result);
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+}
+
+#[test]
+fn test_scans_across_shards() {
+    test_scan_common(&[]);
+    test_scan_common(&["--partitioning", "range", "--split-keys", "40"]);
+}
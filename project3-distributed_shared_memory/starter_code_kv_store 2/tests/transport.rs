@@ -0,0 +1,230 @@
+mod utils;
+
+use kv_store::kvs::{KVSResult, Operation, Pid};
+use kv_store::network::{recv, send, NetworkContext, TransportKind};
+use std::net::UdpSocket;
+use std::process::Child;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use utils::{
+    group_result_by_key, launch_client_with_options, launch_controller_with_options,
+    launch_server_with_options, read_result,
+};
+
+fn tcp_context(pid: Pid) -> NetworkContext {
+    NetworkContext::new(&pid, TransportKind::Tcp).unwrap()
+}
+
+// Receives until count operations arrived or the timeout passed.
+fn recv_n(ctx: &mut NetworkContext, count: usize, timeout_ms: u64) -> Vec<(Pid, Operation)> {
+    let start = Instant::now();
+    let mut received = Vec::new();
+    while received.len() < count && start.elapsed() < Duration::from_millis(timeout_ms) {
+        if let Ok(messages) = recv(ctx, 100) {
+            received.extend(messages);
+        }
+    }
+    received
+}
+
+#[test]
+fn test_tcp_large_message() {
+    let mut sender = tcp_context(210);
+    let mut receiver = tcp_context(211);
+
+    let value = "v".repeat(1_000_000);
+    send(&mut sender, &211, &Operation::Put("key".to_string(), value.clone(), 7)).unwrap();
+
+    let received = recv_n(&mut receiver, 1, 3000);
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].0, 210);
+    match &received[0].1 {
+        Operation::Put(key, received_value, seq_no) => {
+            assert_eq!(key, "key");
+            assert!(*received_value == value);
+            assert_eq!(*seq_no, 7);
+        }
+        op => panic!("unexpected operation {:?}", op),
+    }
+}
+
+#[test]
+fn test_tcp_messages_arrive_in_order() {
+    let mut sender = tcp_context(212);
+    let mut receiver = tcp_context(213);
+
+    for seq_no in 0..2000 {
+        send(&mut sender, &213, &Operation::Get(format!("key_{}", seq_no), seq_no)).unwrap();
+    }
+
+    let received = recv_n(&mut receiver, 2000, 5000);
+    assert_eq!(received.len(), 2000);
+    for (expected, (src_pid, operation)) in received.iter().enumerate() {
+        assert_eq!(*src_pid, 212);
+        match operation {
+            Operation::Get(_, seq_no) => assert_eq!(*seq_no, expected),
+            op => panic!("unexpected operation {:?}", op),
+        }
+    }
+    assert!(recv(&mut receiver, 300).is_err());
+}
+
+#[test]
+fn test_tcp_send_to_missing_peer_fails() {
+    let mut sender = tcp_context(214);
+    assert!(send(&mut sender, &215, &Operation::GetShardInfo(None)).is_err());
+}
+
+#[test]
+fn test_tcp_reconnects_after_peer_restart() {
+    let mut sender = tcp_context(216);
+    {
+        let mut receiver = tcp_context(217);
+        send(&mut sender, &217, &Operation::Delete("key".to_string(), 0)).unwrap();
+        assert_eq!(recv_n(&mut receiver, 1, 1000).len(), 1);
+    }
+    sleep(Duration::from_millis(100));
+
+    // the cached connection is dead; messages written to it before the
+    // sender notices are lost, so keep sending until one gets through
+    let mut receiver = tcp_context(217);
+    let mut received = Vec::new();
+    for seq_no in 1..20 {
+        let _ = send(&mut sender, &217, &Operation::Delete("key".to_string(), seq_no));
+        received = recv_n(&mut receiver, 1, 200);
+        if !received.is_empty() {
+            break;
+        }
+    }
+    assert_eq!(received.len(), 1);
+    assert!(matches!(received[0], (216, Operation::Delete(_, _))));
+}
+
+// A datagram from a port no pid listens on is dropped, the ones of pids still
+// get through
+#[test]
+fn test_udp_drops_datagrams_from_unknown_ports() {
+    let mut receiver = NetworkContext::new(&220, TransportKind::Udp).unwrap();
+    let mut sender = NetworkContext::new(&221, TransportKind::Udp).unwrap();
+    let stranger = UdpSocket::bind("127.0.0.1:7220").unwrap();
+    let data = serde_json::to_string(&Operation::Get("key".to_string(), 0)).unwrap();
+    stranger.send_to(data.as_bytes(), "127.0.0.1:8220").unwrap();
+    assert!(recv(&mut receiver, 300).is_err());
+
+    send(&mut sender, &220, &Operation::Get("key".to_string(), 1)).unwrap();
+    let received = recv_n(&mut receiver, 1, 3000);
+    assert!(matches!(&received[..], [(221, Operation::Get(_, 1))]));
+}
+
+fn verify_read_your_write(result: &Vec<Vec<KVSResult>>) {
+    for client_result in result {
+        let mut prev_put_value = "".to_string();
+        for op_result in client_result {
+            if op_result.operation == "put" {
+                prev_put_value = op_result.new_value.clone();
+            } else if op_result.operation == "delete" {
+                prev_put_value = "".to_string();
+            } else {
+                assert!(op_result.operation == "get");
+                assert!(op_result.observed_value == prev_put_value);
+            }
+        }
+    }
+}
+
+// The read your write workload with every process on the tcp transport.
+fn test_read_your_write_over_tcp_common(num_clients: usize, num_servers: usize) {
+    // binary location. binaries include controller and worker
+    let bin_dir = "./target/debug/";
+    let workload = "ryw";
+    let input_dir = format!("./data/input/{}", workload);
+    let result_dir = format!("./data/result/{}", workload);
+    let options = ["--transport", "tcp"];
+
+    let controller_pid_for_clients: usize = 0;
+    let controller_pid_for_servers: usize = 1;
+    let client_pids: Vec<usize> = (2..num_clients + 2).collect();
+    let server_pids: Vec<usize> = ((num_clients + 2)..=(num_clients + num_servers + 1)).collect();
+
+    // Launch servers.
+    println!("launching servers");
+    let mut servers: Vec<Child> = Vec::new();
+    for server_pid in &server_pids {
+        let server = launch_server_with_options(
+            &bin_dir,
+            &options,
+            &server_pid,
+            &controller_pid_for_servers,
+            &server_pids,
+        )
+        .expect("Failed to launch server");
+        servers.push(server);
+    }
+
+    // Launch controller.
+    println!("launching controllers");
+    let mut controller = launch_controller_with_options(
+        &bin_dir,
+        &options,
+        &controller_pid_for_clients,
+        &controller_pid_for_servers,
+        &num_clients,
+        &num_servers,
+        &client_pids,
+        &server_pids,
+    )
+    .expect("Failed to launch controller");
+
+    // Launch clients.
+    println!("launching clients");
+    let mut clients: Vec<Child> = Vec::new();
+    for client_pid in &client_pids {
+        let client = launch_client_with_options(
+            &bin_dir,
+            &options,
+            &client_pid,
+            &controller_pid_for_clients,
+            &input_dir,
+            &server_pids,
+            &result_dir,
+        )
+        .expect("Failed to launch client");
+        clients.push(client);
+    }
+
+    // Sleep for enough time to let the system run
+    sleep(Duration::from_secs(10));
+
+    // Clean up (kill all remaining processes)
+    controller.kill().expect("Failed to kill controller");
+    for mut client in clients {
+        let _ = client.kill();
+    }
+
+    let mut result_paths = Vec::new();
+    for client_pid in &client_pids {
+        let result_file = format!("{}{}.txt", result_dir, &client_pid);
+        result_paths.push(result_file);
+    }
+    // verify result
+    match read_result(result_paths) {
+        Ok(result) => {
+            let grouped_result = group_result_by_key(result).unwrap();
+            for (_key, key_result) in grouped_result.iter() {
+                verify_read_your_write(key_result);
+            }
+        }
+        Err(_) => {
+            panic!("Fail to read result file");
+        }
+    }
+
+    for mut server in servers {
+        let _ = server.kill();
+    }
+}
+
+#[test]
+fn test_read_your_write_over_tcp() {
+    test_read_your_write_over_tcp_common(3, 3);
+}
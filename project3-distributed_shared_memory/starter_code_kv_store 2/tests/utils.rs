@@ -14,10 +14,35 @@ pub fn launch_controller(
     num_servers: &usize,
     client_pids: &Vec<usize>,
     server_pids: &Vec<usize>,
+) -> std::io::Result<Child> {
+    launch_controller_with_options(
+        bin_dir,
+        &[],
+        controller_pid_for_clients,
+        controller_pid_for_servers,
+        num_clients,
+        num_servers,
+        client_pids,
+        server_pids,
+    )
+}
+
+// Same as launch_controller, with extra flags (e.g. ["--transport", "tcp"])
+// passed before the positional arguments.
+pub fn launch_controller_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    controller_pid_for_clients: &usize,
+    controller_pid_for_servers: &usize,
+    num_clients: &usize,
+    num_servers: &usize,
+    client_pids: &Vec<usize>,
+    server_pids: &Vec<usize>,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/controller", bin_dir));
 
     command
+        .args(options)
         .arg(&(controller_pid_for_clients.to_string()))
         .arg(&(controller_pid_for_servers.to_string()))
         .arg(&(num_clients.to_string()))
@@ -41,10 +66,24 @@ pub fn launch_client(
     input_dir: &str,
     server_pids: &Vec<usize>,
     result_dir: &str,
+) -> std::io::Result<Child> {
+    launch_client_with_options(bin_dir, &[], self_pid, controller_pid, input_dir, server_pids, result_dir)
+}
+
+// Same as launch_client, with extra flags passed before the positional arguments.
+pub fn launch_client_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    self_pid: &usize,
+    controller_pid: &usize,
+    input_dir: &str,
+    server_pids: &Vec<usize>,
+    result_dir: &str,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/client", bin_dir));
 
     command
+        .args(options)
         .arg(&(self_pid.to_string()))
         .arg(&(controller_pid.to_string()))
         .arg(input_dir)
@@ -62,10 +101,22 @@ pub fn launch_server(
     self_pid: &usize,
     controller_pid: &usize,
     server_pids: &Vec<usize>,
+) -> std::io::Result<Child> {
+    launch_server_with_options(bin_dir, &[], self_pid, controller_pid, server_pids)
+}
+
+// Same as launch_server, with extra flags passed before the positional arguments.
+pub fn launch_server_with_options(
+    bin_dir: &str,
+    options: &[&str],
+    self_pid: &usize,
+    controller_pid: &usize,
+    server_pids: &Vec<usize>,
 ) -> std::io::Result<Child> {
     let mut command = Command::new(format!("{}/server", bin_dir));
 
     command
+        .args(options)
         .arg(&(self_pid.to_string()))
         .arg(&(controller_pid.to_string()));
 
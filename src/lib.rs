@@ -119,9 +119,10 @@ impl KVS
         key.hash(&mut hasher);
         let bin_index = (hasher.finish() % KVS::NUM_BINS as u64) as usize;
 
-        // Add the pair
-        let mut locked = self.buckets.write().unwrap();
-        let bucket = locked.get_mut(bin_index).unwrap();
+        // Only the bucket is locked for writing, so deletes do not block
+        // the other buckets
+        let locked = self.buckets.read().unwrap();
+        let bucket = locked.get(bin_index).unwrap();
 
         let mut inner_vec = bucket.write().unwrap();
         
@@ -1,4 +1,4 @@
-use kv_store::{KVS};
+use kvs_engine::{KVS};
 use std::sync::{Arc, Barrier, Mutex};
 use std::thread;
 use std::collections::HashMap;